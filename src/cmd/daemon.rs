@@ -1,21 +1,37 @@
+use std::path::Path;
 use std::time::Duration;
 use std::{env, process};
 
 use clap::Clap;
-use color_eyre::eyre::{bail, Result};
+use color_eyre::eyre::{bail, eyre, Result};
 use futures::stream::StreamExt;
 use nix::sys::signal;
 use nix::unistd::Pid;
+use semver::Version;
 use signal_hook::consts::signal::*;
 use signal_hook_tokio::Signals;
-use tokio::{fs, task};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
 
-use crate::cmd::peer::List;
-use crate::cmd::repo::Clone;
-use crate::cmd::wg::{Down, Render, Status as WgStatus, Up};
+use crate::cmd::repo::{head_revision, Clone, Pull};
+use crate::cmd::wg::{wg_config_path, Down, Render};
 use crate::cmd::{Command, Fireguard};
-use crate::config::Config;
-use crate::upgrade::UpgradeBin;
+use crate::config::{Config, HealthConfig};
+use crate::control::{ControlClient, ControlCommand, ControlServer, Request, Response};
+use crate::error::FireguardError;
+use crate::firewall::Firewall;
+use crate::health::HealthChecker;
+use crate::metrics::Metrics;
+use crate::release::release_source;
+use crate::rendezvous::{RendezvousClient, RendezvousServer};
+use crate::roaming::EndpointResolver;
+use crate::shutdown::{Shutdown, ShutdownReport};
+use crate::state::{now, DaemonState, SharedState};
+use crate::systemd::{self, SystemdUnit};
+use crate::upgrade::{UpgradeBin, UpgradeChannel, UpgradePolicy};
+use crate::verify::ReleaseVerifier;
 
 /// Daemon - Manage Fireguard daemon
 #[derive(Clap, Debug)]
@@ -29,17 +45,28 @@ pub struct Daemon {
 }
 
 #[derive(Clap, Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum Action {
     /// Run Fireguard daemon. If `repository-url` is set a new clone in the `repository` folder will be
     /// peformed. If `private-key` is set, a new render of the Wireguard configuration will be
-    /// performed. A signal handler is installer for TERM and INT with graceful shutdown. HUP for
-    /// now does nothing. The main process PID is stored in a PID file.
+    /// performed. A signal handler is installer for TERM and INT with graceful shutdown, HUP
+    /// reloads the configuration. The main process PID is stored in a PID file and a control
+    /// socket is served next to it.
     Serve(Serve),
-    /// Stop the Fireguard daemon by sending a SIGTERM to its PID from the PID file.
+    /// Stop the Fireguard daemon through its control socket, falling back to a SIGTERM to the PID
+    /// from the PID file.
     Stop(Stop),
     /// Fireguard daemon status, exposing information about the different running components and
     /// the current configuration.
     Status(Status),
+    /// Reload the trust repository configuration into the running Wireguard tunnel.
+    Reload(Reload),
+    /// Pull the trust repository now instead of waiting for the next sync.
+    Pull(PullNow),
+    /// Live Wireguard peers of the running daemon.
+    Peers(Peers),
+    /// Generate and install a systemd unit running the daemon for this repository.
+    InstallService(InstallService),
 }
 
 impl Command for Daemon {}
@@ -49,6 +76,10 @@ impl Daemon {
             Action::Serve(ref action) => action.exec(fg, &self.repository).await?,
             Action::Stop(ref action) => action.exec(fg, &self.repository).await?,
             Action::Status(ref action) => action.exec(fg, &self.repository).await?,
+            Action::Reload(ref action) => action.exec(fg, &self.repository).await?,
+            Action::Pull(ref action) => action.exec(fg, &self.repository).await?,
+            Action::Peers(ref action) => action.exec(fg, &self.repository).await?,
+            Action::InstallService(ref action) => action.exec(fg, &self.repository).await?,
         }
         Ok(())
     }
@@ -63,7 +94,13 @@ pub struct Serve {
     #[clap(short = 'U', long = "repository-url")]
     pub repository_url: Option<String>,
     /// Private key
-    #[clap(short = 'P', long = "private-key", requires_all = &["username", "peername"])]
+    #[clap(
+        short = 'P',
+        long = "private-key",
+        env = "FIREGUARD_PRIVATE_KEY",
+        hide_env_values = true,
+        requires_all = &["username", "peername"]
+    )]
     pub private_key: Option<String>,
     /// User name
     #[clap(short = 'u', long = "username")]
@@ -74,77 +111,426 @@ pub struct Serve {
     /// Wireguard config file path
     #[clap(short = 'c', long = "config-dir", default_value = "/etc/wireguard")]
     pub config_dir: String,
+    /// Peer to send the internet traffic through, it has to be an exit node
+    #[clap(short = 'x', long = "use-exit-node", requires = "private-key")]
+    pub exit_node: Option<String>,
     /// How much to wait between upgrade checks
     #[clap(short = 'w', long = "wait-between-checks", default_value = "43200")]
     pub wait_between_checks: u64,
-    /// Github releases URL
+    /// How much to wait between trust repository pulls, 0 disables the sync loop
+    #[clap(short = 'i', long = "pull-interval", default_value = "0")]
+    pub pull_interval: u64,
+    /// Address to expose Prometheus metrics on, like 127.0.0.1:9586. Disabled if empty
+    #[clap(short = 'm', long = "metrics-address")]
+    pub metrics_address: Option<String>,
+    /// Local peer health checking config, overriding the `[health]` section of the repository
+    #[clap(short = 'H', long = "health-config")]
+    pub health_config: Option<String>,
+    /// How much to wait between peer endpoint resolutions, 0 disables them
+    #[clap(long = "resolve-interval", default_value = "60")]
+    pub resolve_interval: u64,
+    /// Seconds to wait for each component to stop during shutdown
+    #[clap(short = 't', long = "shutdown-timeout", default_value = "10")]
+    pub shutdown_timeout: u64,
+    /// Periodically check for new releases and upgrade to them once their signature is verified
+    #[clap(short = 'a', long = "auto-upgrade")]
+    pub auto_upgrade: bool,
+    /// Release channel to follow
+    #[clap(long = "upgrade-channel", default_value = "stable", possible_values = &["stable", "prerelease"])]
+    pub upgrade_channel: UpgradeChannel,
+    /// Only ever run this version, upgrading or downgrading to it
+    #[clap(long = "pin-version", conflicts_with = "max-version")]
+    pub pin_version: Option<Version>,
+    /// Never upgrade past this version
+    #[clap(long = "max-version")]
+    pub max_version: Option<Version>,
+    /// Seconds the upgraded process has to report healthy before rolling back
+    #[clap(long = "upgrade-timeout", default_value = "120")]
+    pub upgrade_timeout: u64,
+    /// Releases URL: the GitHub releases API, an HTTP directory or a local path with an index.json
     #[clap(
         short = 'r',
         long = "release-url",
-        default_value = "https://api.github.com/repos/blackmesalab/fireguard/releases/latest"
+        default_value = "https://api.github.com/repos/blackmesalab/fireguard/releases"
     )]
     pub release_url: String,
 }
 
+/// Why the daemon main loop stopped.
+enum Exit {
+    Shutdown,
+    /// Another daemon, with this PID, is taking over.
+    Handover(u32),
+}
+
 impl Command for Serve {}
 impl Serve {
-    async fn handle_signals(&self, config: Config, repository: String) -> Result<()> {
-        let signals = Signals::new(&[SIGHUP, SIGTERM, SIGINT, SIGQUIT])?;
-        let mut signals = signals.fuse();
-        let t = task::spawn(async move {
-            while let Some(signal) = signals.next().await {
-                match signal {
-                    SIGHUP => {}
+    async fn render(&self, fg: &Fireguard, repository: &str) -> Result<()> {
+        if let Some(pkey) = self.private_key.as_ref() {
+            let render = Render {
+                username: self.username.clone().unwrap(),
+                peername: self.peername.clone().unwrap(),
+                private_key: pkey.clone(),
+                config_dir: self.config_dir.clone(),
+                exit_node: self.exit_node.clone(),
+            };
+            render.exec(fg, repository).await?;
+        }
+        Ok(())
+    }
+
+    /// Install the firewall rules the policies give the peer on the tunnel interface, removing the
+    /// stale ones when the repository has no policies left.
+    async fn firewall(&self, fg: &Fireguard, config: &Config, repository: &str) -> Result<()> {
+        let host = match (self.username.as_ref(), self.peername.as_ref()) {
+            (Some(username), Some(peername)) => format!("{}-{}", username, peername),
+            _ => return Ok(()),
+        };
+        match Firewall::new(config, repository, &host)? {
+            Some(firewall) => firewall.apply(&fg.runner).await?,
+            None => Firewall::remove(repository, &fg.runner).await?,
+        }
+        Ok(())
+    }
+
+    async fn reload(&self, fg: &Fireguard, repository: &str, state: &SharedState) -> Result<()> {
+        systemd::notify_reloading();
+        let result = self.apply_config(fg, repository, state).await;
+        systemd::notify_ready("Configuration reloaded");
+        result
+    }
+
+    async fn apply_config(&self, fg: &Fireguard, repository: &str, state: &SharedState) -> Result<()> {
+        info!("Reloading Fireguard configuration for repository {}", repository);
+        self.render(fg, repository).await?;
+        let config = self.load_config(repository, &fg.config_dir, &fg.config_file).await?;
+        fg.wg_driver.tunnel(repository, &fg.runner)?.sync(&wg_config_path(&self.config_dir, repository)).await?;
+        self.firewall(fg, &config, repository).await?;
+        let mut state = state.write();
+        state.update_config(&config);
+        state.last_reload = Some(now());
+        state.reloads += 1;
+        Ok(())
+    }
+
+    async fn pull(&self, fg: &Fireguard, repository: &str, state: &SharedState) -> Result<()> {
+        let pull = Pull {};
+        let result = pull.exec(fg, repository).await;
+        let revision = head_revision(fg, repository).await.ok();
+        let changed = {
+            let mut state = state.write();
+            state.last_pull = Some(now());
+            state.pulls += 1;
+            match result {
+                Ok(()) => {
+                    state.last_successful_pull = state.last_pull;
+                    state.last_pull_error = None;
+                }
+                Err(ref e) => {
+                    state.last_pull_error = Some(e.to_string());
+                    state.pull_failures += 1;
+                }
+            }
+            let changed = state.revision != revision;
+            state.revision = revision;
+            changed
+        };
+        result?;
+        if changed {
+            self.reload(fg, repository, state).await?;
+        } else {
+            debug!("Trust repository {} did not change, skipping reload", repository);
+        }
+        Ok(())
+    }
+
+    async fn handle_command(
+        &self,
+        fg: &Fireguard,
+        repository: &str,
+        state: &SharedState,
+        request: &Request,
+    ) -> Response {
+        let result = match request {
+            Request::Reload => self.reload(fg, repository, state).await.map(|_| "Configuration reloaded".to_string()),
+            Request::PullNow => self.pull(fg, repository, state).await.map(|_| "Trust repository pulled".to_string()),
+            Request::Shutdown => Ok("Shutting down Fireguard".to_string()),
+            Request::Handover { pid } => Ok(format!("Handing over to Fireguard PID {}", pid)),
+            _ => Err(eyre!("Request {:?} is not handled by the main loop", request)),
+        };
+        match result {
+            Ok(message) => Response::Done(message),
+            Err(e) => {
+                error!("Error handling control request {:?}: {}", request, e);
+                Response::Error(e.to_string())
+            }
+        }
+    }
+
+    async fn run(
+        &self,
+        fg: &Fireguard,
+        repository: &str,
+        state: &SharedState,
+        mut commands: mpsc::Receiver<ControlCommand>,
+    ) -> Result<Exit> {
+        let mut signals = Signals::new([SIGHUP, SIGTERM, SIGINT, SIGQUIT])?;
+        let handle = signals.handle();
+        let period = Duration::from_secs(self.pull_interval.max(1));
+        let mut sync = time::interval_at(Instant::now() + period, period);
+        let old_pid = fg.old_pid.as_ref().and_then(|pid| pid.parse::<u32>().ok());
+        let watchdog_period = systemd::watchdog_interval(old_pid);
+        let mut watchdog = time::interval(watchdog_period.unwrap_or(period));
+        let exit = loop {
+            tokio::select! {
+                Some(signal) = signals.next() => match signal {
+                    SIGHUP => {
+                        info!("Received signal {:#?}, reloading Fireguard", signal);
+                        self.reload(fg, repository, state)
+                            .await
+                            .unwrap_or_else(|e| error!("Unable to reload configuration: {}", e));
+                    }
                     SIGTERM | SIGINT | SIGQUIT => {
                         warn!("Received signal {:#?}, shutting down Fireguard", signal);
-                        let down = Down {};
-                        down.exec(None, &repository).await.unwrap_or_else(|_| error!("Unable to shut down Wireguard"));
-                        config
-                            .remove_pid_file("fireguard")
-                            .await
-                            .unwrap_or_else(|_| error!("Unable to remove PID file for wireguard"));
-                        break;
+                        break Exit::Shutdown;
                     }
                     _ => error!("Signal {:?} is not handled", signal),
+                },
+                Some(command) = commands.recv() => {
+                    let response = self.handle_command(fg, repository, state, &command.request).await;
+                    if command.reply.send(response).is_err() {
+                        warn!("Control client went away before receiving the response");
+                    }
+                    match command.request {
+                        Request::Shutdown => {
+                            warn!("Received shutdown request from control socket, shutting down Fireguard");
+                            break Exit::Shutdown;
+                        }
+                        Request::Handover { pid } => {
+                            warn!("Received handover request from Fireguard PID {}", pid);
+                            break Exit::Handover(pid);
+                        }
+                        _ => {}
+                    }
+                },
+                _ = watchdog.tick(), if watchdog_period.is_some() => systemd::notify_watchdog(),
+                _ = sync.tick(), if self.pull_interval > 0 => {
+                    self.pull(fg, repository, state)
+                        .await
+                        .unwrap_or_else(|e| error!("Unable to sync trust repository {}: {}", repository, e));
                 }
             }
-        });
-        t.await?;
+        };
+        handle.close();
+        Ok(exit)
+    }
+
+    fn upgrader(&self) -> Result<UpgradeBin> {
+        let policy = UpgradePolicy {
+            channel: self.upgrade_channel,
+            pinned: self.pin_version.clone(),
+            max_version: self.max_version.clone(),
+        };
+        Ok(UpgradeBin::new(
+            Duration::from_secs(self.wait_between_checks),
+            release_source(&self.release_url)?,
+            policy,
+            Duration::from_secs(self.upgrade_timeout),
+        ))
+    }
+
+    async fn start_components(
+        &self,
+        fg: &Fireguard,
+        config: &Config,
+        state: &SharedState,
+        shutdown: &mut Shutdown,
+        server: ControlServer,
+        commands: mpsc::Sender<ControlCommand>,
+    ) -> Result<()> {
+        config.write_pid_file("fireguard", process::id()).await?;
+        let control = server.run_in_background(commands, shutdown.subscribe());
+        shutdown.register("control server", control);
+        if let Some(address) = self.metrics_address.as_ref() {
+            let metrics = Metrics::new(state.clone()).run_in_background(address, shutdown.subscribe()).await?;
+            shutdown.register("metrics", metrics);
+        }
+        let health = match self.health_config.as_ref() {
+            Some(path) => Some(HealthConfig::load(Path::new(path)).await?),
+            None => config.health.clone(),
+        };
+        let stale_after =
+            health.as_ref().map(|health| health.stale_after).unwrap_or_else(|| HealthConfig::default().stale_after);
+        if self.resolve_interval > 0 {
+            let interval = Duration::from_secs(self.resolve_interval);
+            let resolver =
+                EndpointResolver::new(interval, stale_after, state.clone()).run_in_background(shutdown.subscribe());
+            shutdown.register("endpoint resolver", resolver);
+        }
+        if let (Some(rendezvous), Some(private_key)) = (config.rendezvous.as_ref(), self.private_key.as_ref()) {
+            let host = format!(
+                "{}-{}",
+                self.username.as_deref().unwrap_or_default(),
+                self.peername.as_deref().unwrap_or_default()
+            );
+            if rendezvous.peer == host {
+                let server = RendezvousServer::new(rendezvous.port, private_key, stale_after, state.clone())
+                    .run_in_background(shutdown.subscribe())
+                    .await?;
+                shutdown.register("rendezvous service", server);
+            } else {
+                let (server_key, server) = rendezvous.server(config)?;
+                let interval = Duration::from_secs(rendezvous.interval.max(1));
+                let client =
+                    RendezvousClient::new(server, &server_key, private_key, interval, stale_after, state.clone())
+                        .run_in_background(shutdown.subscribe());
+                shutdown.register("rendezvous client", client);
+            }
+        }
+        if let Some(health) = health {
+            let checker = HealthChecker::new(health, state.clone()).run_in_background(shutdown.subscribe());
+            shutdown.register("health checks", checker);
+        }
+        if self.auto_upgrade {
+            ReleaseVerifier::builtin()?;
+            let upgrade = self
+                .upgrader()?
+                .with_state(state.clone())
+                .with_control_socket(config.socket_file("fireguard"))
+                .with_executable(fg.executable()?)
+                .run_in_background(&fg.args, shutdown.subscribe())
+                .await?;
+            shutdown.register("upgrade checks", upgrade);
+        }
+        Ok(())
+    }
+
+    /// Stop every daemon component in order, each bounded by the shutdown timeout, carrying on
+    /// when one of them fails.
+    async fn shutdown(
+        &self,
+        config: &Config,
+        repository: &str,
+        state: &SharedState,
+        mut shutdown: Shutdown,
+    ) -> ShutdownReport {
+        systemd::notify_stopping();
+        let timeout = Duration::from_secs(self.shutdown_timeout);
+        info!("Shutting down Fireguard, waiting up to {} seconds for each component", timeout.as_secs());
+        let mut report = ShutdownReport::new();
+        for (name, handle) in shutdown.trigger() {
+            report.join(name, timeout, handle).await;
+        }
+        let down = Down { config_dir: self.config_dir.clone() };
+        let (driver, runner) = {
+            let state = state.read();
+            (state.wg_driver, state.runner.clone())
+        };
+        report.step("wireguard tunnel", timeout, down.exec(driver, repository, &runner)).await;
+        state.write().tunnel_up = false;
+        report.step("pid file", timeout, async { Ok(config.remove_pid_file("fireguard").await?) }).await;
+        report.step("control socket", timeout, ControlServer::remove(&config.socket_file("fireguard"))).await;
+        report.log();
+        report
+    }
+
+    /// Leave the tunnel, PID file and control socket to the daemon with `pid`: only the
+    /// background components are stopped, so their sockets and ports are free for it.
+    async fn hand_over(&self, repository: &str, mut shutdown: Shutdown, pid: u32) -> Result<()> {
+        let timeout = Duration::from_secs(self.shutdown_timeout);
+        let mut report = ShutdownReport::new();
+        for (name, handle) in shutdown.trigger() {
+            report.join(name, timeout, handle).await;
+        }
+        report.log();
+        systemd::notify(&format!("MAINPID={}\nSTATUS=Handed over to PID {}", pid, pid));
+        warn!("Wireguard tunnel {} handed over to Fireguard PID {}, exiting", repository, pid);
+        Ok(())
+    }
+
+    /// Ask the daemon with `pid` to hand its tunnel over and wait for it to exit, terminating it
+    /// if it does not support the handover.
+    async fn take_over(&self, config: &Config, pid: i32) -> Result<()> {
+        info!("Taking over from Fireguard PID {}", pid);
+        let request = Request::Handover { pid: process::id() };
+        let handed_over = match ControlClient::request(&config.socket_file("fireguard"), request).await {
+            Ok(Response::Done(message)) => {
+                info!("{}", message);
+                true
+            }
+            Ok(response) => {
+                warn!("Fireguard PID {} refused the handover: {:?}", pid, response);
+                false
+            }
+            Err(e) => {
+                warn!("Unable to request the handover to Fireguard PID {}: {}", pid, e);
+                false
+            }
+        };
+        if !handed_over {
+            self.upgrader()?.terminate_old_process(pid).map_err(FireguardError::upgrade)?;
+        }
+        // Give the old process enough time to stop all its components.
+        let deadline = Instant::now() + Duration::from_secs(self.shutdown_timeout * 5);
+        while signal::kill(Pid::from_raw(pid), None).is_ok() {
+            if Instant::now() > deadline {
+                let message = format!("Fireguard PID {} is still running, unable to take over", pid);
+                return Err(FireguardError::Upgrade(message).into());
+            }
+            time::sleep(Duration::from_millis(100)).await;
+        }
+        info!("Fireguard PID {} exited, taking over", pid);
         Ok(())
     }
 
     pub async fn exec(&self, fg: &Fireguard, repository: &str) -> Result<()> {
-        let upgrade = UpgradeBin::new(
-            Duration::from_secs(self.wait_between_checks),
-            &self.release_url,
-            &format!("v{}", env!("CARGO_PKG_VERSION")),
-        );
-        if let Some(pid) = fg.old_pid.as_ref() {
-            let pid = pid.parse::<i32>()?;
-            upgrade.terminate_old_process(pid)?;
-            upgrade.flip_binary_on_disk(env::current_exe()?).await?;
+        if fg.old_pid.is_some() {
+            let executable = fg.executable()?;
+            self.upgrader()?.flip_binary_on_disk(executable).await.map_err(FireguardError::upgrade)?;
         }
         info!("Starting Fireguard daemon in foreground");
+        systemd::set_journal_field("FIREGUARD_REPOSITORY", repository);
         if let Some(repo) = self.repository_url.as_ref() {
             let clone = Clone {};
             clone.exec(fg, repo).await?;
         }
-        if let Some(pkey) = self.private_key.as_ref() {
-            let render = Render {
-                username: self.username.clone().unwrap(),
-                peername: self.peername.clone().unwrap(),
-                private_key: pkey.clone(),
-                config_dir: self.config_dir.clone(),
-            };
-            render.exec(fg, repository).await?;
-        }
+        self.render(fg, repository).await?;
         let config = self.load_config(repository, &fg.config_dir, &fg.config_file).await?;
-        let up = Up {};
-        up.exec(None, repository).await?;
-        config.write_pid_file("fireguard", process::id()).await?;
-        // upgrade.run_in_background(&fg.args).await?;
-        info!("Fireguard daemon started successfully");
-        self.handle_signals(config, repository.to_string()).await?;
+        let state = DaemonState::new(repository).shared();
+        state.write().update_config(&config);
+        state.write().wg_driver = fg.wg_driver;
+        state.write().runner = fg.runner.clone();
+        state.write().revision = head_revision(fg, repository).await.ok();
+        if let Some(pid) = fg.old_pid.as_ref() {
+            self.take_over(&config, pid.parse::<i32>()?).await?;
+        }
+        let (tx, rx) = mpsc::channel(16);
+        let server = ControlServer::bind(&config.socket_file("fireguard"), state.clone()).await?;
+        let wg = fg.wg_driver.tunnel(repository, &fg.runner)?;
+        let wg_config = wg_config_path(&self.config_dir, repository);
+        wg.up_or_sync(&wg_config).await?;
+        state.write().tunnel_up = true;
+        self.firewall(fg, &config, repository).await?;
+        let mut shutdown = Shutdown::new();
+        let result = match self.start_components(fg, &config, &state, &mut shutdown, server, tx).await {
+            Ok(()) => {
+                info!("Fireguard daemon started successfully");
+                systemd::notify_ready(&format!("Wireguard tunnel {} is up", repository));
+                self.run(fg, repository, &state, rx).await
+            }
+            Err(e) => {
+                error!("Unable to start Fireguard daemon components: {}", e);
+                Err(e)
+            }
+        };
+        if let Ok(Exit::Handover(pid)) = result {
+            return self.hand_over(repository, shutdown, pid).await;
+        }
+        let report = self.shutdown(&config, repository, &state, shutdown).await;
+        result?;
+        if !report.success() {
+            bail!("Fireguard shutdown failed for {} components", report.failures().len());
+        }
         Ok(())
     }
 }
@@ -158,39 +544,184 @@ impl Stop {
     pub async fn exec(&self, fg: &Fireguard, repository: &str) -> Result<()> {
         info!("Stopping foreground Fireguard daemon");
         let config = self.load_config(repository, &fg.config_dir, &fg.config_file).await?;
-        let pid = fs::read_to_string(&config.pid_file("fireguard")).await?.parse::<i32>()?;
-        debug!("Sending SIGTERM to PID {}", pid);
-        signal::kill(Pid::from_raw(pid), signal::SIGTERM)?;
-        Ok(())
+        match ControlClient::request(&config.socket_file("fireguard"), Request::Shutdown).await {
+            Ok(response) => print_response(response, false),
+            Err(e) => {
+                warn!("{}, falling back to PID file", e);
+                let pid = fs::read_to_string(config.pid_file("fireguard")).await?.parse::<i32>()?;
+                debug!("Sending SIGTERM to PID {}", pid);
+                signal::kill(Pid::from_raw(pid), signal::SIGTERM)?;
+                Ok(())
+            }
+        }
     }
 }
 
-/// Stop Fireguard daemon
+/// Fireguard daemon status
 #[derive(Clap, Debug)]
-pub struct Status {}
+pub struct Status {
+    /// Print the raw JSON response
+    #[clap(short = 'j', long = "json")]
+    pub json: bool,
+}
 
 impl Command for Status {}
 impl Status {
     pub async fn exec(&self, fg: &Fireguard, repository: &str) -> Result<()> {
         let config = self.load_config(repository, &fg.config_dir, &fg.config_file).await?;
-        match fs::read_to_string(config.pid_file("fireguard")).await {
-            Ok(pid) => {
-                info!("Fireguard daemon is running with PID {}", pid);
-                let peer_list = List {};
-                peer_list
-                    .exec(fg, config, repository)
-                    .await
-                    .unwrap_or_else(|_| error!("Unable to read {} peer list", repository));
-                let status = WgStatus {};
-                status
-                    .exec(None, repository)
-                    .await
-                    .unwrap_or_else(|_| error!("Unable to get {} Wireguard status", repository));
-                Ok(())
+        let response = ControlClient::request(&config.socket_file("fireguard"), Request::Status).await?;
+        print_response(response, self.json)
+    }
+}
+
+/// Reload the Fireguard daemon configuration
+#[derive(Clap, Debug)]
+pub struct Reload {}
+
+impl Command for Reload {}
+impl Reload {
+    pub async fn exec(&self, fg: &Fireguard, repository: &str) -> Result<()> {
+        let config = self.load_config(repository, &fg.config_dir, &fg.config_file).await?;
+        let response = ControlClient::request(&config.socket_file("fireguard"), Request::Reload).await?;
+        print_response(response, false)
+    }
+}
+
+/// Pull the Fireguard daemon trust repository now
+#[derive(Clap, Debug)]
+pub struct PullNow {}
+
+impl Command for PullNow {}
+impl PullNow {
+    pub async fn exec(&self, fg: &Fireguard, repository: &str) -> Result<()> {
+        let config = self.load_config(repository, &fg.config_dir, &fg.config_file).await?;
+        let response = ControlClient::request(&config.socket_file("fireguard"), Request::PullNow).await?;
+        print_response(response, false)
+    }
+}
+
+/// Live Wireguard peers of the Fireguard daemon
+#[derive(Clap, Debug)]
+pub struct Peers {
+    /// Print the raw JSON response
+    #[clap(short = 'j', long = "json")]
+    pub json: bool,
+}
+
+impl Command for Peers {}
+impl Peers {
+    pub async fn exec(&self, fg: &Fireguard, repository: &str) -> Result<()> {
+        let config = self.load_config(repository, &fg.config_dir, &fg.config_file).await?;
+        let response = ControlClient::request(&config.socket_file("fireguard"), Request::Peers).await?;
+        print_response(response, self.json)
+    }
+}
+
+/// Generate and install a systemd unit running the Fireguard daemon for a repository
+#[derive(Clap, Debug)]
+pub struct InstallService {
+    /// User name
+    #[clap(short = 'u', long = "username")]
+    pub username: String,
+    /// Peer name
+    #[clap(short = 'p', long = "peername")]
+    pub peername: String,
+    /// Private key, stored in a root only environment file next to the repository
+    #[clap(short = 'P', long = "private-key", env = "FIREGUARD_PRIVATE_KEY", hide_env_values = true)]
+    pub private_key: Option<String>,
+    /// Wireguard config file path
+    #[clap(short = 'c', long = "config-dir", default_value = "/etc/wireguard")]
+    pub config_dir: String,
+    /// Systemd units directory
+    #[clap(short = 'd', long = "unit-dir", default_value = "/etc/systemd/system")]
+    pub unit_dir: String,
+    /// Seconds systemd waits for a watchdog notification before restarting the daemon
+    #[clap(short = 'w', long = "watchdog-sec", default_value = "60")]
+    pub watchdog_sec: u64,
+    /// Enable and start the unit after installing it
+    #[clap(short = 'e', long = "enable")]
+    pub enable: bool,
+    /// Extra arguments appended to `daemon serve`
+    #[clap(last = true)]
+    pub serve_args: Vec<String>,
+}
+
+impl Command for InstallService {}
+impl InstallService {
+    pub async fn exec(&self, fg: &Fireguard, repository: &str) -> Result<()> {
+        let config_dir = fs::canonicalize(&fg.config_dir).await?;
+        let environment_file = config_dir.join(format!("{}.env", repository));
+        let unit = SystemdUnit {
+            repository: repository.to_string(),
+            username: self.username.clone(),
+            peername: self.peername.clone(),
+            executable: env::current_exe()?.display().to_string(),
+            config_dir: config_dir.display().to_string(),
+            wireguard_dir: self.config_dir.clone(),
+            environment_file: environment_file.display().to_string(),
+            watchdog_sec: self.watchdog_sec,
+            extra_args: self.serve_args.clone(),
+        };
+        if let Some(pkey) = self.private_key.as_ref() {
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(&environment_file)
+                .await?;
+            file.write_all(format!("FIREGUARD_PRIVATE_KEY={}\n", pkey).as_bytes()).await?;
+            info!("Private key for {} stored in {}", repository, environment_file.display());
+        }
+        let unit_path = Path::new(&self.unit_dir).join(unit.name());
+        fs::write(&unit_path, unit.render()?).await?;
+        info!("Systemd unit for repository {} written to {}", repository, unit_path.display());
+        if self.enable {
+            let reload = fg.runner.exec("systemctl", &["daemon-reload"], None, false).await;
+            if !reload.success() {
+                bail!("Unable to reload systemd units: {}", reload.stderr());
+            }
+            let enable = fg.runner.exec("systemctl", &["enable", "--now", &unit.name()], None, false).await;
+            if !enable.success() {
+                bail!("Unable to enable systemd unit {}: {}", unit.name(), enable.stderr());
             }
-            Err(_) => {
-                bail!("Fireguard PID not found, did you start Fireguard with `daemon serve` command?");
+            info!("Systemd unit {} enabled and started", unit.name());
+        } else {
+            info!("Enable it with: systemctl daemon-reload && systemctl enable --now {}", unit.name());
+        }
+        Ok(())
+    }
+}
+
+fn print_response(response: Response, json: bool) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(&response)?);
+        return Ok(());
+    }
+    match response {
+        Response::Status(state) => {
+            info!("Fireguard {} daemon is running with PID {} for {}", state.version, state.pid, state.repository);
+            info!("Wireguard tunnel up: {}, peers in repository: {}", state.tunnel_up, state.peers);
+            info!("Trust repository revision: {}", state.revision.unwrap_or_else(|| "unknown".to_string()));
+            if let Some(error) = state.last_pull_error {
+                warn!("Last trust repository pull failed: {}", error);
+            }
+        }
+        Response::Peers(peers) => {
+            for peer in peers {
+                info!(
+                    "Peer {} ({}): endpoint {}, latest handshake {}, rx {} bytes, tx {} bytes",
+                    peer.name.unwrap_or_else(|| "unknown".to_string()),
+                    peer.peer.public_key,
+                    peer.peer.endpoint.unwrap_or_else(|| "none".to_string()),
+                    peer.peer.latest_handshake.map(|t| t.to_string()).unwrap_or_else(|| "never".to_string()),
+                    peer.peer.transfer_rx.unwrap_or_default(),
+                    peer.peer.transfer_tx.unwrap_or_default(),
+                );
             }
         }
+        Response::Done(message) => info!("{}", message),
+        Response::Error(error) => bail!("Fireguard daemon error: {}", error),
     }
+    Ok(())
 }
@@ -1,16 +1,16 @@
 use std::path::Path;
 
 use clap::Clap;
-use color_eyre::eyre::{bail, Result};
+use color_eyre::eyre::Result;
 use ipnet::Ipv4Net;
 use serde::{Deserialize, Serialize};
 use tera::{Context, Tera};
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
 
-use crate::cmd::{Command, Fireguard};
+use crate::cmd::{ensure_dir, Command, Fireguard};
 use crate::config::Config;
-use crate::shell::Shell;
+use crate::error::FireguardError;
 
 const DNSMASQ_LIST_TMPL: &str = r#"# {{ host.repository }} - {{ host.name }} dnsmasq dynamic list
 {% for peer in peers -%}
@@ -89,22 +89,12 @@ pub struct Render {
 
 impl Command for Render {}
 impl Render {
-    async fn pre_checks(&self, _fg: &Fireguard) -> Result<()> {
-        if !Shell::runnable("dnsmasq") {
-            bail!("Missing command dependency")
+    async fn pre_checks(&self, fg: &Fireguard) -> Result<()> {
+        if !fg.runner.runnable("dnsmasq") {
+            return Err(FireguardError::Dependency("dnsmasq is not installed".to_string()).into());
         }
         let config = Path::new(&self.config_dir);
-        if config.is_dir() {
-            Ok(())
-        } else {
-            bail!(
-                "Please create DNSMasq config directory {} as root: mkdir -p {} && chown {} {}",
-                self.config_dir,
-                self.config_dir,
-                whoami::username(),
-                self.config_dir
-            );
-        }
+        Ok(ensure_dir(config)?)
     }
 
     pub async fn exec(&self, fg: &Fireguard, config: Config, repository: &str) -> Result<()> {
@@ -132,7 +122,7 @@ impl Render {
             )?,
         )?;
         let mut file = File::create(&dns_config_path).await?;
-        file.write_all(&dns_config.as_bytes()).await?;
+        file.write_all(dns_config.as_bytes()).await?;
         info!("DNS configuration written to {}", dns_config_path.display());
         Ok(())
     }
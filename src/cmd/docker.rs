@@ -1,14 +1,19 @@
-//! THIS IS NOT WORKING, AND NEEDS A LOT OF THOUGHS AND LOVE.
+use std::env;
+use std::fs as std_fs;
 
 use clap::Clap;
 use color_eyre::eyre::{bail, Result};
 
+use crate::cmd::daemon::Action as DaemonAction;
+use crate::cmd::wg::Action as WgAction;
 use crate::cmd::{Daemon, Dns, Fireguard, Peer, Repo, Wg};
-use crate::shell::Shell;
-use crate::utils::enforce_host_config;
-use crate::utils::install_wireguard_kernel_module;
+use crate::container::{NetworkMode, Runtime, Sandbox, PRIVATE_KEY_ENV};
+use crate::host::enable_forwarding;
+use crate::wg::backend::kernel_module_loaded;
 
-/// Docker - Docker command management
+const DEFAULT_WIREGUARD_DIR: &str = "/etc/wireguard";
+
+/// Docker - Run Fireguard inside a Docker or Podman container
 #[derive(Clap, Debug)]
 pub struct Docker {
     /// Docker subcommands
@@ -20,11 +25,25 @@ pub struct Docker {
     /// Docker image version
     #[clap(short = 'v', long = "docker-image-version")]
     pub docker_image_version: Option<String>,
-    /// Volumes to mount inside the container
-    #[clap(short = 'V', long = "docker-volumes")]
-    pub docker_volumes: Option<Vec<String>>,
+    /// Extra volumes to mount inside the container, like /srv/fireguard:/srv/fireguard
+    #[clap(short = 'V', long = "docker-volumes", number_of_values = 1)]
+    pub docker_volumes: Vec<String>,
+    /// Run the daemon container in the background
+    #[clap(long = "detach")]
+    pub detach: bool,
+    /// Container runtime, docker or podman. Detected from the installed binaries if not set
+    #[clap(long = "runtime")]
+    pub runtime: Option<Runtime>,
+    /// Container network, host or namespace for a network namespace dedicated to the tunnel
+    #[clap(long = "network", default_value = "host")]
+    pub network: NetworkMode,
+    /// Ports to publish with the namespace network, like 51820:51820/udp
+    #[clap(long = "publish", number_of_values = 1)]
+    pub publish: Vec<String>,
 }
 
+// The wrapped commands are only parsed to validate the command line, they run inside the container.
+#[allow(dead_code)]
 #[derive(Clap, Debug)]
 pub enum Action {
     /// Trust repositories management under Docker
@@ -35,8 +54,102 @@ pub enum Action {
     Wg(Wg),
     /// DNS management under Docker
     Dns(Dns),
-    /// Daemon management
+    /// Daemon management under Docker
     Daemon(Daemon),
+    /// Stop and remove the daemon container
+    Stop(Stop),
+    /// Show the daemon container logs
+    Logs(Logs),
+    /// Show the daemon container status
+    Status(Status),
+}
+
+/// Name of the container running the daemon for `repository`.
+fn container_name(repository: &str) -> String {
+    format!("fireguard-{}", repository)
+}
+
+/// Split the leading options from the rest of the command line, which starts at the first
+/// positional argument. Options listed in `with_value` consume the following argument.
+fn split_options<'a>(args: &'a [String], with_value: &[&str]) -> (&'a [String], &'a [String]) {
+    let mut index = 0;
+    while index < args.len() && args[index].starts_with('-') {
+        if with_value.contains(&args[index].as_str()) {
+            index += 1;
+        }
+        index += 1;
+    }
+    let index = index.min(args.len());
+    (&args[..index], &args[index..])
+}
+
+/// Subcommands taking the private key, as `(command, action)`.
+const PRIVATE_KEY_COMMANDS: &[(&str, &str)] = &[("daemon", "serve"), ("daemon", "install-service"), ("wg", "render")];
+
+/// Whether the Fireguard subcommand `command` takes a private key, other subcommands use `-P` for
+/// other options, like the port of `peer add`.
+fn takes_private_key(command: &[String]) -> bool {
+    match command.split_first() {
+        Some((name, rest)) => {
+            let (_, action) = split_options(rest, &["-r", "--repository"]);
+            action
+                .first()
+                .map(|action| PRIVATE_KEY_COMMANDS.contains(&(name.as_str(), action.as_str())))
+                .unwrap_or(false)
+        }
+        None => false,
+    }
+}
+
+/// Build the Fireguard command line to run inside the container from the host one, dropping
+/// the `docker` subcommand with its options. The private key is removed from the command line
+/// and returned separately, so it can be passed through the environment.
+fn container_args(args: &[String]) -> Result<(Vec<String>, Option<String>)> {
+    let (globals, rest) = split_options(
+        args,
+        &["-c", "--config-dir", "-C", "--config-file", "-W", "--wg-driver", "-o", "--old-pid", "--replace"],
+    );
+    if rest.first().map(String::as_str) != Some("docker") {
+        bail!("Unable to find the docker subcommand in {:?}", args);
+    }
+    let (_, command) = split_options(
+        &rest[1..],
+        &[
+            "-d",
+            "--docker-image-name",
+            "-v",
+            "--docker-image-version",
+            "-V",
+            "--docker-volumes",
+            "--runtime",
+            "--network",
+            "--publish",
+        ],
+    );
+    let mut container_args = globals.to_vec();
+    let mut private_key = None;
+    if !takes_private_key(command) {
+        container_args.extend(command.iter().cloned());
+        return Ok((container_args, private_key));
+    }
+    let mut command = command.iter();
+    while let Some(arg) = command.next() {
+        if arg == "-P" || arg == "--private-key" {
+            private_key = command.next().cloned();
+        } else if let Some(key) = arg.strip_prefix("--private-key=") {
+            private_key = Some(key.to_string());
+        } else if let Some(key) = arg.strip_prefix("-P").filter(|key| !key.is_empty()) {
+            private_key = Some(key.to_string());
+        } else {
+            container_args.push(arg.clone());
+        }
+    }
+    Ok((container_args, private_key))
+}
+
+/// Absolute path of `path`, as Docker wants for bind mounts.
+fn absolute_path(path: &str) -> String {
+    std_fs::canonicalize(path).map(|p| p.to_string_lossy().to_string()).unwrap_or_else(|_| path.to_string())
 }
 
 impl Docker {
@@ -48,28 +161,246 @@ impl Docker {
         }
     }
 
-    pub async fn exec(&self, fg: &Fireguard) -> Result<()> {
-        install_wireguard_kernel_module().await?;
-        enforce_host_config().await?;
-        let mut fg_args = fg.args.clone();
-        fg_args.retain(|x| x != "docker");
-        let args = fg_args.join(" ");
-        let mut docker_cmd = "run -t --rm --privileged --net=host".to_string();
-        // TODO: document how to use volumes, especially if there are plans for custom paths.
-        if let Some(volumes) = self.docker_volumes.as_ref() {
-            docker_cmd += &format!(" -v {}", volumes.join("-v "));
-        } else {
-            docker_cmd += " -v /etc/fireguard:/etc/fireguard";
-            docker_cmd += " -v /etc/wireguard:/etc/wireguard";
-        }
-        docker_cmd += &format!(" {} {}", self.docker_image(), args);
-        info!("Running command `{}` inside Docker container {}", args, self.docker_image());
-        let result = Shell::exec("docker", &docker_cmd, None, false).await;
-        if result.success() {
-            debug!("Command {} succeeded inside Docker container {}", args, self.docker_image());
-            Ok(())
+    fn runtime(&self) -> Result<Runtime> {
+        match self.runtime {
+            Some(runtime) => Ok(runtime),
+            None => Runtime::detect(),
+        }
+    }
+
+    /// Wireguard configuration directory used by the command, mounted in the container.
+    fn wireguard_dir(&self) -> &str {
+        match self.action {
+            Action::Daemon(ref daemon) => match daemon.action {
+                DaemonAction::Serve(ref serve) => &serve.config_dir,
+                DaemonAction::InstallService(ref install) => &install.config_dir,
+                _ => DEFAULT_WIREGUARD_DIR,
+            },
+            Action::Wg(ref wg) => match wg.action {
+                WgAction::Render(ref render) => &render.config_dir,
+                _ => DEFAULT_WIREGUARD_DIR,
+            },
+            _ => DEFAULT_WIREGUARD_DIR,
+        }
+    }
+
+    /// The daemon containers are named after the repository, so they can be managed later.
+    fn container_name(&self) -> Option<String> {
+        match self.action {
+            Action::Daemon(ref daemon) => Some(container_name(&daemon.repository)),
+            _ => None,
+        }
+    }
+
+    fn run_args(
+        &self,
+        runtime: Runtime,
+        sandbox: &Sandbox,
+        config_dir: &str,
+        command: &[String],
+        private_key: bool,
+        tty: bool,
+    ) -> Vec<String> {
+        let mut args = vec!["run".to_string()];
+        if self.detach {
+            args.extend(vec!["--detach".to_string(), "--restart".to_string(), "unless-stopped".to_string()]);
         } else {
-            bail!("Error running command {} inside docker container {}", args, self.docker_image())
+            args.extend(vec!["--rm".to_string(), "--interactive".to_string()]);
+            if tty {
+                args.push("--tty".to_string());
+            }
+        }
+        if let Some(name) = self.container_name() {
+            args.extend(vec!["--name".to_string(), name]);
+        }
+        args.extend(sandbox.args());
+        let config_dir = absolute_path(config_dir);
+        let wireguard_dir = absolute_path(self.wireguard_dir());
+        for volume in [format!("{}:{}", config_dir, config_dir), format!("{}:{}", wireguard_dir, wireguard_dir)]
+            .iter()
+            .chain(self.docker_volumes.iter())
+        {
+            args.extend(vec!["--volume".to_string(), volume.clone()]);
+        }
+        if private_key {
+            // Only the variable name is on the command line, the runtime reads the value from its
+            // own environment.
+            args.extend(vec!["--env".to_string(), PRIVATE_KEY_ENV.to_string()]);
+        }
+        args.push(runtime.image(&self.docker_image()));
+        args.push("/usr/bin/fireguard".to_string());
+        args.extend(command.iter().cloned());
+        args
+    }
+
+    async fn run(&self, fg: &Fireguard) -> Result<()> {
+        if self.detach && self.container_name().is_none() {
+            bail!("Only the daemon can be run in a detached container");
         }
+        let runtime = self.runtime()?;
+        let rootless = runtime.rootless(&fg.runner).await;
+        if !kernel_module_loaded()
+            && !rootless
+            && !fg.runner.exec("modprobe", &["wireguard"], None, true).await.success()
+        {
+            // The image ships a userspace implementation the container falls back to.
+            warn!("Unable to load the Wireguard kernel module on the host, the tunnel will run in userspace");
+        }
+        let sandbox = Sandbox::new(self.network, rootless, kernel_module_loaded(), &self.publish);
+        sandbox.validate()?;
+        if self.network == NetworkMode::Host {
+            enable_forwarding(&fg.runner).await?;
+        }
+        let (command, private_key) = container_args(&fg.args)?;
+        let private_key = private_key.or_else(|| env::var(PRIVATE_KEY_ENV).ok());
+        let tty = nix::unistd::isatty(0).unwrap_or(false);
+        let args = self.run_args(runtime, &sandbox, &fg.config_dir, &command, private_key.is_some(), tty);
+        info!(
+            "Running command `fireguard {}` inside {}{} container {} with {} network",
+            command.join(" "),
+            if rootless { "rootless " } else { "" },
+            runtime,
+            self.docker_image(),
+            self.network
+        );
+        runtime.run(&args, private_key.as_deref()).await?;
+        if self.detach {
+            info!("Fireguard daemon running in the background in container {}", self.container_name().unwrap());
+        }
+        Ok(())
+    }
+
+    pub async fn exec(&self, fg: &Fireguard) -> Result<()> {
+        match self.action {
+            Action::Stop(ref action) => action.exec(self.runtime()?).await,
+            Action::Logs(ref action) => action.exec(self.runtime()?).await,
+            Action::Status(ref action) => action.exec(self.runtime()?).await,
+            _ => self.run(fg).await,
+        }
+    }
+}
+
+/// Stop and remove the daemon container
+#[derive(Clap, Debug)]
+pub struct Stop {
+    /// Repository name
+    #[clap(short = 'r', long = "repository")]
+    pub repository: String,
+}
+
+impl Stop {
+    pub async fn exec(&self, runtime: Runtime) -> Result<()> {
+        let name = container_name(&self.repository);
+        info!("Stopping Fireguard daemon container {}", name);
+        runtime.run(&["stop".to_string(), name.clone()], None).await?;
+        // Detached containers are not removed automatically, free the name for the next run.
+        if runtime.run(&["rm".to_string(), name.clone()], None).await.is_err() {
+            debug!("Container {} was already removed", name);
+        }
+        Ok(())
+    }
+}
+
+/// Show the daemon container logs
+#[derive(Clap, Debug)]
+pub struct Logs {
+    /// Repository name
+    #[clap(short = 'r', long = "repository")]
+    pub repository: String,
+    /// Follow the log output
+    #[clap(short = 'f', long = "follow")]
+    pub follow: bool,
+    /// Number of lines to show from the end of the logs
+    #[clap(short = 'n', long = "tail")]
+    pub tail: Option<String>,
+}
+
+impl Logs {
+    pub async fn exec(&self, runtime: Runtime) -> Result<()> {
+        let mut args = vec!["logs".to_string()];
+        if self.follow {
+            args.push("--follow".to_string());
+        }
+        if let Some(tail) = self.tail.as_ref() {
+            args.extend(vec!["--tail".to_string(), tail.clone()]);
+        }
+        args.push(container_name(&self.repository));
+        runtime.run(&args, None).await
+    }
+}
+
+/// Show the daemon container status
+#[derive(Clap, Debug)]
+pub struct Status {
+    /// Repository name
+    #[clap(short = 'r', long = "repository")]
+    pub repository: String,
+}
+
+impl Status {
+    pub async fn exec(&self, runtime: Runtime) -> Result<()> {
+        let args = vec![
+            "ps".to_string(),
+            "--all".to_string(),
+            "--filter".to_string(),
+            format!("name=^{}$", container_name(&self.repository)),
+            "--format".to_string(),
+            "table {{.Names}}\t{{.Image}}\t{{.Status}}\t{{.CreatedAt}}".to_string(),
+        ];
+        runtime.run(&args, None).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split(' ').map(String::from).collect()
+    }
+
+    #[test]
+    fn test_container_args() {
+        let (command, key) = container_args(&args(
+            "-c /etc/fireguard docker -d image -V /a:/a --detach daemon -r avalon serve -P secret",
+        ))
+        .unwrap();
+        assert_eq!(command, args("-c /etc/fireguard daemon -r avalon serve"));
+        assert_eq!(key, Some("secret".to_string()));
+        let (command, key) = container_args(&args("docker wg -r avalon render --private-key=secret -u alice")).unwrap();
+        assert_eq!(command, args("wg -r avalon render -u alice"));
+        assert_eq!(key, Some("secret".to_string()));
+        let (command, key) = container_args(&args("-D docker repo list")).unwrap();
+        assert_eq!(command, args("-D repo list"));
+        assert_eq!(key, None);
+        assert!(container_args(&args("repo list")).is_err());
+        let (command, key) =
+            container_args(&args("docker daemon -r avalon install-service -P secret -u alice")).unwrap();
+        assert_eq!(command, args("daemon -r avalon install-service -u alice"));
+        assert_eq!(key, Some("secret".to_string()));
+    }
+
+    #[test]
+    fn test_container_args_keep_other_p_options() {
+        // -P is the port of peer add, not a private key.
+        let (command, key) = container_args(&args("docker peer -r avalon add -u alice -p laptop -P 51820")).unwrap();
+        assert_eq!(command, args("peer -r avalon add -u alice -p laptop -P 51820"));
+        assert_eq!(key, None);
+    }
+
+    #[test]
+    fn test_run_args() {
+        let docker = Docker::parse_from(args("docker -v 0.1.0 -V /srv:/srv --detach daemon -r avalon serve"));
+        let sandbox = Sandbox::new(NetworkMode::Host, false, true, &[]);
+        let run =
+            docker.run_args(Runtime::Docker, &sandbox, "/etc/fireguard", &args("daemon -r avalon serve"), true, false);
+        assert_eq!(
+            run,
+            args(
+                "run --detach --restart unless-stopped --name fireguard-avalon --cap-add NET_ADMIN --network host \
+                 --volume /etc/fireguard:/etc/fireguard --volume /etc/wireguard:/etc/wireguard --volume /srv:/srv \
+                 --env FIREGUARD_PRIVATE_KEY blackmesalab/fireguard:0.1.0 /usr/bin/fireguard daemon -r avalon serve"
+            )
+        );
     }
 }
@@ -0,0 +1,75 @@
+use clap::Clap;
+use color_eyre::eyre::Result;
+
+use crate::cmd::Fireguard;
+use crate::error::FireguardError;
+use crate::host::{plan, HostFacts, PackageManager};
+
+/// Host - Host preparation
+#[derive(Clap, Debug)]
+pub struct Host {
+    /// Host subcommands
+    #[clap(subcommand)]
+    pub action: Action,
+}
+
+#[derive(Clap, Debug)]
+pub enum Action {
+    /// Install Wireguard and enable forwarding, using the distribution package manager
+    Prepare(Prepare),
+}
+
+impl Host {
+    pub async fn exec(&self, fg: &Fireguard) -> Result<()> {
+        match self.action {
+            Action::Prepare(ref action) => action.exec(fg).await,
+        }
+    }
+}
+
+/// Install Wireguard and enable forwarding, using the distribution package manager
+#[derive(Clap, Debug)]
+pub struct Prepare {
+    /// Print the planned actions without running them
+    #[clap(short = 'n', long = "dry-run")]
+    pub dry_run: bool,
+    /// Leave IPv6 forwarding disabled
+    #[clap(long = "no-ipv6")]
+    pub no_ipv6: bool,
+}
+
+impl Prepare {
+    pub async fn exec(&self, fg: &Fireguard) -> Result<()> {
+        let facts = HostFacts::gather(&fg.config_dir, &fg.runner).await?;
+        info!(
+            "Detected {} using {} with kernel {}, Wireguard module {}",
+            facts.os.name,
+            PackageManager::detect(&facts.os)?,
+            facts.kernel.release,
+            if facts.module { "available" } else { "missing" }
+        );
+        let actions = plan(&facts, !self.no_ipv6)?;
+        if actions.is_empty() {
+            info!("Host is already prepared to run Fireguard");
+            return Ok(());
+        }
+        if self.dry_run {
+            for action in actions.iter() {
+                info!("Would run: {}", action);
+            }
+            return Ok(());
+        }
+        if !nix::unistd::geteuid().is_root() {
+            return Err(FireguardError::Permission(
+                "host preparation must run as root, use --dry-run to only show the planned actions".to_string(),
+            )
+            .into());
+        }
+        for action in actions.iter() {
+            info!("Running: {}", action);
+            action.run(&fg.runner).await?;
+        }
+        info!("Host is prepared to run Fireguard");
+        Ok(())
+    }
+}
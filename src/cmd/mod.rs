@@ -1,6 +1,7 @@
 mod daemon;
 mod dns;
 mod docker;
+mod host;
 mod peer;
 mod repo;
 mod wg;
@@ -10,13 +11,17 @@ use std::path::{Path, PathBuf};
 
 use async_trait::async_trait;
 use clap::Clap;
-use color_eyre::eyre::{bail, Result};
+use color_eyre::eyre::Result;
 
 use crate::config::Config;
+use crate::error::FireguardError;
+use crate::runner::Runner;
+use crate::wg::WgDriver;
 
 use daemon::Daemon;
 use dns::Dns;
 use docker::Docker;
+use host::Host;
 use peer::Peer;
 use repo::Repo;
 use wg::Wg;
@@ -37,34 +42,39 @@ pub struct Fireguard {
     /// Enable debug logging
     #[clap(short = 'D', long = "debug")]
     pub debug: bool,
+    /// Wireguard interface management, netlink or wg-quick
+    #[clap(short = 'W', long = "wg-driver", default_value = "netlink")]
+    pub wg_driver: WgDriver,
     /// Old Fireguard PID, used to upgrade the binary on the flight
     #[clap(short = 'o', long = "old-pid")]
     pub old_pid: Option<String>,
+    /// Installed executable to replace, used to upgrade the binary on the flight
+    #[clap(long = "replace")]
+    pub replace: Option<String>,
     /// Cmdline args vec, do not use, it is autofilled
     #[clap(long = "args", default_values = &[])]
     pub args: Vec<String>,
+    /// Runner of the external commands
+    #[clap(skip)]
+    pub runner: Runner,
 }
 
 impl Fireguard {
     async fn pre_checks(&mut self) -> Result<()> {
         let config = Path::new(&self.config_dir);
-        if config.is_dir() {
-            let mut args = env::args().collect::<Vec<String>>();
-            debug!("Command line args: [{}]", args.join(", "));
-            if args[0].starts_with("target/") {
-                args.remove(0);
-            }
-            debug!("Command line args after sanification: [{}]", args.join(", "));
-            self.args = args;
-            Ok(())
-        } else {
-            bail!(
-                "Please create directory {} as root: mkdir -p {} && chown {} {}",
-                self.config_dir,
-                self.config_dir,
-                whoami::username(),
-                self.config_dir
-            );
+        // Preparing the host creates the config directory.
+        if !matches!(self.action, Action::Host(_)) {
+            ensure_dir(config)?;
+        }
+        debug!("Command line args: [{}]", self.args.join(", "));
+        Ok(())
+    }
+
+    /// Installed Fireguard executable, the running one unless an upgrade passed `--replace`.
+    pub fn executable(&self) -> Result<PathBuf> {
+        match self.replace.as_ref() {
+            Some(path) => Ok(PathBuf::from(path)),
+            None => Ok(env::current_exe()?),
         }
     }
 
@@ -76,6 +86,8 @@ impl Fireguard {
             Action::Wg(ref action) => action.exec(self).await?,
             Action::Dns(ref action) => action.exec(self).await?,
             Action::Daemon(ref action) => action.exec(self).await?,
+            Action::Docker(ref action) => action.exec(self).await?,
+            Action::Host(ref action) => action.exec(self).await?,
         }
         Ok(())
     }
@@ -93,6 +105,10 @@ pub enum Action {
     Dns(Dns),
     /// Daemon management
     Daemon(Daemon),
+    /// Run Fireguard commands inside a Docker or Podman container
+    Docker(Docker),
+    /// Host preparation, installing Wireguard and enabling forwarding
+    Host(Host),
 }
 
 #[async_trait]
@@ -102,16 +118,32 @@ pub trait Command {
     }
 
     async fn load_config(&self, repository: &str, config_dir: &str, config_file: &str) -> Result<Config> {
+        if !Path::new(config_dir).join(repository).is_dir() {
+            return Err(FireguardError::repository(
+                repository,
+                format!("not found in {}, clone it with: fireguard repo -r <url> clone", config_dir),
+            )
+            .into());
+        }
         let path = self.config_file(repository, config_dir, config_file);
         debug!("Loading network topology from {}", path.display());
-        match Config::load(&path).await {
-            Ok(hosts) => {
-                debug!("Available peers in {}: {:?}", repository, hosts.peers.keys());
-                Ok(hosts)
-            }
-            Err(e) => {
-                bail!("Error listing available peers in {}: {}", repository, e)
-            }
-        }
+        let hosts = Config::load(&path).await?;
+        debug!("Available peers in {}: {:?}", repository, hosts.peers.keys());
+        Ok(hosts)
+    }
+}
+
+/// Fail with the commands creating `dir` when it does not exist.
+pub fn ensure_dir(dir: &Path) -> Result<(), FireguardError> {
+    if dir.is_dir() {
+        Ok(())
+    } else {
+        let message = format!(
+            "missing directory, please create it as root: mkdir -p {} && chown {} {}",
+            dir.display(),
+            whoami::username(),
+            dir.display()
+        );
+        Err(FireguardError::config(Some(dir), message))
     }
 }
@@ -5,7 +5,9 @@ use tokio::io::AsyncWriteExt;
 
 use crate::cmd::{Command, Fireguard};
 use crate::config::{Config, Peer as ConfigPeer};
+use crate::error::FireguardError;
 use crate::ip::IpPool;
+use crate::topology;
 use crate::wg::WgKeys;
 
 /// Peer - peers management for a trust repository
@@ -76,9 +78,12 @@ pub struct Add {
     /// Allowed IPs
     #[clap(short = 'a', long = "allowed-ips")]
     pub allowed_ips: Option<Vec<String>>,
-    /// Peer public endpoint if there is public connectivity
+    /// Peer public endpoint if there is public connectivity, like host, host:port or [ipv6]:port
     #[clap(short = 'e', long = "endpoint")]
     pub endpoint: Option<String>,
+    /// Further endpoint tried when the previous ones do not answer
+    #[clap(short = 'E', long = "extra-endpoint", number_of_values = 1, requires = "endpoint")]
+    pub endpoints: Vec<String>,
     /// Public key, autogenerated if empty
     #[clap(short = 'K', long = "public-key")]
     pub public_key: Option<String>,
@@ -91,6 +96,15 @@ pub struct Add {
     /// Routing table, default 0 (disable)
     #[clap(short = 't', long = "table", default_value = "0")]
     pub table: u32,
+    /// Peer relaying the traffic of this one, when it has no public connectivity
+    #[clap(short = 'R', long = "relay-via")]
+    pub relay_via: Option<String>,
+    /// Network behind this peer to route the other peers to, like its LAN
+    #[clap(short = 'A', long = "advertise-route", number_of_values = 1)]
+    pub advertised_routes: Vec<String>,
+    /// Let the other peers out to the internet through this one
+    #[clap(short = 'X', long = "exit-node")]
+    pub exit_node: bool,
     /// Force add even if the peer already exists
     #[clap(short = 'F', long = "force")]
     pub force: bool,
@@ -117,11 +131,18 @@ impl Add {
     }
 
     async fn build_peer(&self, fg: &Fireguard, config: &mut Config, repository: &str) -> Result<()> {
+        if let Some(relay) = self.relay_via.as_ref() {
+            if config.get_peer(relay).is_none() {
+                return Err(
+                    FireguardError::PeerNotFound { repository: repository.to_string(), peer: relay.clone() }.into()
+                );
+            }
+        }
         let keys: WgKeys;
         if let Some(pkey) = self.public_key.as_ref() {
             keys = WgKeys::new(pkey, "");
         } else {
-            keys = WgKeys::generate().await?;
+            keys = WgKeys::generate(&fg.runner).await?;
         }
         info!(
             "Generated public key for {}, username: {}, peername: {}: {}",
@@ -133,13 +154,13 @@ impl Add {
         let pool_ip = pool.ip()?;
         let pool_ips = vec![format!("{}/32", pool_ip)];
         let allowed_ips = self.allowed_ips.as_ref().unwrap_or(&pool_ips);
-        let peer = ConfigPeer::new(
+        let mut peer = ConfigPeer::new(
             &self.username,
             &self.peername,
             &format!("{}/{}", pool_ip, config.network_addr.prefix_len()),
             self.port,
             &keys.public,
-            &allowed_ips,
+            allowed_ips,
             self.keep_alive,
             self.endpoint.clone(),
             table,
@@ -151,16 +172,22 @@ impl Add {
             None,
             None,
         );
+        peer.relay_via = self.relay_via.clone();
+        peer.advertised_routes = self.advertised_routes.clone();
+        peer.exit_node = self.exit_node;
+        peer.endpoints = self.endpoints.clone();
+        peer.candidates()?;
         debug!(
             "Peer {}-{} {:?}:{} will be added to repository {}:\n{:#?}",
             self.username, self.peername, self.endpoint, self.port, repository, peer
         );
         config.add_peer(&format!("{}-{}", self.username, self.peername), peer);
+        topology::validate(config)?;
         config.save(&self.config_file(repository, &fg.config_dir, &fg.config_file)).await?;
         // TODO: support places without tmp like Windows
         let priv_key = format!("/tmp/fireguard-{}-{}-{}.priv", repository, self.username, self.peername);
         let mut file = File::create(&priv_key).await?;
-        file.write_all(&keys.private.as_bytes()).await?;
+        file.write_all(keys.private.as_bytes()).await?;
         warn!("IMPORTANT! Private key for host {}-{} has been written to {}", self.username, self.peername, priv_key);
         warn!("Save it if you want to be able to access to {}", repository);
         Ok(())
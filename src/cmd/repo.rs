@@ -1,5 +1,6 @@
 use std::io;
 use std::path::Path;
+use std::time::Duration;
 
 use clap::Clap;
 use color_eyre::eyre::{bail, Result};
@@ -7,8 +8,12 @@ use tokio::fs;
 use tokio_stream::wrappers::ReadDirStream;
 use tokio_stream::StreamExt;
 
-use crate::cmd::{Command, Fireguard};
-use crate::shell::Shell;
+use crate::cmd::{ensure_dir, Command, Fireguard};
+use crate::error::FireguardError;
+use crate::shell::ShellCommand;
+
+/// Clones and pulls hanging on an unreachable remote are given up after this long.
+const GIT_TIMEOUT: Duration = Duration::from_secs(300);
 
 /// Repo - trust repositories management
 #[derive(Clap, Debug)]
@@ -39,17 +44,7 @@ impl Command for Repo {}
 impl Repo {
     async fn pre_checks(&self, fg: &Fireguard) -> Result<()> {
         let config = Path::new(&fg.config_dir);
-        if config.is_dir() {
-            Ok(())
-        } else {
-            bail!(
-                "Please create Fireguar config directory {} as root: mkdir -p {} && chown {} {}",
-                fg.config_dir,
-                fg.config_dir,
-                whoami::username(),
-                fg.config_dir
-            );
-        }
+        Ok(ensure_dir(config)?)
     }
 
     pub async fn exec(&self, fg: &Fireguard) -> Result<()> {
@@ -63,11 +58,11 @@ impl Repo {
             }
         }
         match self.action {
-            Action::Clone(ref action) => action.exec(fg, &self.repository.as_ref().unwrap()).await?,
+            Action::Clone(ref action) => action.exec(fg, self.repository.as_ref().unwrap()).await?,
             Action::List(ref action) => action.exec(fg).await?,
-            Action::Remove(ref action) => action.exec(fg, &self.repository.as_ref().unwrap()).await?,
-            Action::Pull(ref action) => action.exec(fg, &self.repository.as_ref().unwrap()).await?,
-            Action::Commit(ref action) => action.exec(fg, &self.repository.as_ref().unwrap()).await?,
+            Action::Remove(ref action) => action.exec(fg, self.repository.as_ref().unwrap()).await?,
+            Action::Pull(ref action) => action.exec(fg, self.repository.as_ref().unwrap()).await?,
+            Action::Commit(ref action) => action.exec(fg, self.repository.as_ref().unwrap()).await?,
         }
         Ok(())
     }
@@ -91,12 +86,13 @@ impl Clone {
         fs::create_dir_all(&config_path).await?;
 
         info!("Cloning trust repository {} in Fireguard config directory {}", repository, config_path.display());
-        let result = Shell::exec("git", &format!("clone {} {}", repository, config_path.display()), None, false).await;
+        let clone = ShellCommand::new("git").args(&["clone", repository, &config_path.to_string_lossy()]);
+        let result = fg.runner.run(&clone.timeout(GIT_TIMEOUT)).await;
         if result.success() {
             info!("Trust repository cloned in {}", path.display());
             Ok(())
         } else {
-            bail!("Error cloning trust repository: {}", result.stderr());
+            Err(FireguardError::repository(repository, format!("clone failed: {}", result.stderr().trim())).into())
         }
     }
 }
@@ -117,9 +113,7 @@ impl List {
                 info!("Avalilable trust repositoriers in Fireguard config directory: {:?}", repos);
                 Ok(())
             }
-            Err(e) => {
-                bail!("Error listing trust repositorires: {}", e);
-            }
+            Err(e) => Err(FireguardError::io(Path::new(&fg.config_dir), e).into()),
         }
     }
 }
@@ -139,7 +133,8 @@ impl Remove {
                 Ok(())
             }
             Err(e) => {
-                bail!("Error removing trust repository {}: {}", path.display(), e);
+                Err(FireguardError::repository(repository, format!("unable to remove {}: {}", path.display(), e))
+                    .into())
             }
         }
     }
@@ -154,16 +149,28 @@ impl Pull {
     pub async fn exec(&self, fg: &Fireguard, repository: &str) -> Result<()> {
         let path = Path::new(&fg.config_dir).join(repository);
         info!("Updating trust repository {}", path.display());
-        let result = Shell::exec("git", "pull", Some(&format!("{}", path.display())), false).await;
+        let result = fg.runner.run(&ShellCommand::new("git").arg("pull").cwd(&path).timeout(GIT_TIMEOUT)).await;
         if result.success() {
             info!("Trust repository {} successfully updated:", path.display());
             Ok(())
         } else {
-            bail!("Error updating trust repository {}: {}", path.display(), result.stderr());
+            Err(FireguardError::repository(repository, format!("pull failed: {}", result.stderr().trim())).into())
         }
     }
 }
 
+/// Current HEAD revision of a Fireguard trust repository
+pub async fn head_revision(fg: &Fireguard, repository: &str) -> Result<String> {
+    let path = Path::new(&fg.config_dir).join(repository);
+    let result = fg.runner.exec("git", &["rev-parse", "HEAD"], Some(&path), true).await;
+    if result.success() {
+        Ok(result.stdout().trim().to_string())
+    } else {
+        Err(FireguardError::repository(repository, format!("unable to read revision: {}", result.stderr().trim()))
+            .into())
+    }
+}
+
 /// Commit a Fireguard trust repository
 #[derive(Clap, Debug)]
 pub struct Commit {}
@@ -1,11 +1,14 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use clap::Clap;
-use color_eyre::eyre::{bail, Result};
+use color_eyre::eyre::Result;
 use tokio::fs::read_to_string;
 
-use crate::cmd::{Command, Fireguard};
-use crate::wg::{WgConfig, WgQuick};
+use crate::cmd::{ensure_dir, Command, Fireguard};
+use crate::firewall::Firewall;
+use crate::host::enable_forwarding;
+use crate::runner::Runner;
+use crate::wg::{WgConfig, WgDriver};
 
 /// Wg - Wireguard management
 #[derive(Clap, Debug)]
@@ -28,30 +31,23 @@ pub enum Action {
     Down(Down),
     /// Show the Wireguard userspace tunnel status and stats
     Status(Status),
+    /// Apply the firewall rules of the current host to the Wireguard interface
+    Firewall(ApplyFirewall),
 }
 
 impl Wg {
     async fn pre_checks(&self, fg: &Fireguard) -> Result<()> {
         let config = Path::new(&fg.config_dir);
-        if config.is_dir() {
-            Ok(())
-        } else {
-            bail!(
-                "Please create Fireguard config directory {} as root: mkdir -p {} && chown {} {}",
-                fg.config_dir,
-                fg.config_dir,
-                whoami::username(),
-                fg.config_dir
-            );
-        }
+        Ok(ensure_dir(config)?)
     }
     pub async fn exec(&self, fg: &Fireguard) -> Result<()> {
         self.pre_checks(fg).await?;
         match self.action {
             Action::Render(ref action) => action.exec(fg, &self.repository).await?,
-            Action::Up(ref action) => action.exec(None, &self.repository).await?,
-            Action::Down(ref action) => action.exec(None, &self.repository).await?,
-            Action::Status(ref action) => action.exec(None, &self.repository).await?,
+            Action::Up(ref action) => action.exec(fg.wg_driver, &self.repository, &fg.runner).await?,
+            Action::Down(ref action) => action.exec(fg.wg_driver, &self.repository, &fg.runner).await?,
+            Action::Status(ref action) => action.exec(fg.wg_driver, &self.repository, &fg.runner).await?,
+            Action::Firewall(ref action) => action.exec(fg, &self.repository).await?,
         }
         Ok(())
     }
@@ -67,76 +63,124 @@ pub struct Render {
     #[clap(short = 'p', long = "peername")]
     pub peername: String,
     /// Private key
-    #[clap(short = 'P', long = "private-key")]
+    #[clap(short = 'P', long = "private-key", env = "FIREGUARD_PRIVATE_KEY", hide_env_values = true)]
     pub private_key: String,
     /// Config file path
     #[clap(short = 'c', long = "config-dir", default_value = "/etc/wireguard")]
     pub config_dir: String,
+    /// Peer to send the internet traffic through, it has to be an exit node
+    #[clap(short = 'x', long = "use-exit-node")]
+    pub exit_node: Option<String>,
 }
 
 impl Command for Render {}
 impl Render {
     async fn pre_checks(&self, _fg: &Fireguard) -> Result<()> {
         let config = Path::new(&self.config_dir);
-        if config.is_dir() {
-            Ok(())
-        } else {
-            bail!(
-                "Please create Wireguard config directory {} as root: mkdir -p {} && chown {} {}",
-                self.config_dir,
-                self.config_dir,
-                whoami::username(),
-                self.config_dir
-            );
-        }
+        Ok(ensure_dir(config)?)
     }
 
     pub async fn exec(&self, fg: &Fireguard, repository: &str) -> Result<()> {
         self.pre_checks(fg).await?;
         let config = self.load_config(repository, &fg.config_dir, &fg.config_file).await?;
-        let wg_config_path = Path::new(&self.config_dir).join(&format!("{}.conf", repository));
-        let wg_config = WgConfig::new(config.peers, repository, &self.username, &self.peername, &self.private_key)?;
+        let wg_config_path = wg_config_path(&self.config_dir, repository);
+        let wg_config = WgConfig::with_exit_node(
+            &config,
+            repository,
+            &self.username,
+            &self.peername,
+            &self.private_key,
+            self.exit_node.as_deref(),
+        )?;
         wg_config.render(&wg_config_path).await?;
+        if wg_config.relay() || wg_config.router() {
+            info!("Peer {}-{} forwards the traffic of other peers", self.username, self.peername);
+            if let Err(e) = enable_forwarding(&fg.runner).await {
+                warn!("{}, the peers will not be forwarded until fireguard host prepare runs", e);
+            }
+        }
         let data = read_to_string(&wg_config_path).await?;
         info!("Wireguard configuration written to {}:\n{}", wg_config_path.display(), data.trim());
         Ok(())
     }
 }
 
+/// Path of the Wireguard configuration rendered for `repository`.
+pub fn wg_config_path(config_dir: &str, repository: &str) -> PathBuf {
+    Path::new(config_dir).join(format!("{}.conf", repository))
+}
+
 /// Start the Wireguard tunnel for the current host after rendering the config
 #[derive(Clap, Debug)]
-pub struct Up {}
+pub struct Up {
+    /// Wireguard config file path
+    #[clap(short = 'c', long = "config-dir", default_value = "/etc/wireguard")]
+    pub config_dir: String,
+}
 
 impl Command for Up {}
 impl Up {
-    pub async fn exec(&self, _fg: Option<&Fireguard>, repository: &str) -> Result<()> {
-        let bt = WgQuick::new(repository)?;
-        bt.up().await?;
-        Ok(())
+    pub async fn exec(&self, driver: WgDriver, repository: &str, runner: &Runner) -> Result<()> {
+        let wg = driver.tunnel(repository, runner)?;
+        Ok(wg.up(&wg_config_path(&self.config_dir, repository)).await?)
     }
 }
 
 /// Stop the Wireguard tunnel for the current host
 #[derive(Clap, Debug)]
-pub struct Down {}
+pub struct Down {
+    /// Wireguard config file path
+    #[clap(short = 'c', long = "config-dir", default_value = "/etc/wireguard")]
+    pub config_dir: String,
+}
 
 impl Command for Down {}
 impl Down {
-    pub async fn exec(&self, _fg: Option<&Fireguard>, repository: &str) -> Result<()> {
-        let bt = WgQuick::new(repository)?;
-        Ok(bt.down().await?)
+    pub async fn exec(&self, driver: WgDriver, repository: &str, runner: &Runner) -> Result<()> {
+        let wg = driver.tunnel(repository, runner)?;
+        let result = wg.down(&wg_config_path(&self.config_dir, repository)).await;
+        Firewall::remove(repository, runner).await?;
+        Ok(result?)
     }
 }
 
-/// Start the Wireguard tunnel for the current host after rendering the config
+/// Show the Wireguard tunnel status and the backend running it
 #[derive(Clap, Debug)]
 pub struct Status {}
 
 impl Command for Status {}
 impl Status {
-    pub async fn exec(&self, _fg: Option<&Fireguard>, repository: &str) -> Result<()> {
-        let bt = WgQuick::new(repository)?;
-        bt.status().await?;
+    pub async fn exec(&self, driver: WgDriver, repository: &str, runner: &Runner) -> Result<()> {
+        let wg = driver.tunnel(repository, runner)?;
+        Ok(wg.status().await?)
+    }
+}
+
+/// Apply the firewall rules of the current host to the Wireguard interface
+#[derive(Clap, Debug)]
+pub struct ApplyFirewall {
+    /// User name
+    #[clap(short = 'u', long = "username")]
+    pub username: String,
+    /// Peer name
+    #[clap(short = 'p', long = "peername")]
+    pub peername: String,
+    /// Only print the nftables rules
+    #[clap(short = 'n', long = "dry-run")]
+    pub dry_run: bool,
+}
+
+impl Command for ApplyFirewall {}
+impl ApplyFirewall {
+    pub async fn exec(&self, fg: &Fireguard, repository: &str) -> Result<()> {
+        let config = self.load_config(repository, &fg.config_dir, &fg.config_file).await?;
+        let host = format!("{}-{}", self.username, self.peername);
+        match Firewall::new(&config, repository, &host)? {
+            Some(firewall) if self.dry_run => info!("Firewall rules of {}:\n{}", host, firewall.script().trim()),
+            Some(firewall) => firewall.apply(&fg.runner).await?,
+            None if self.dry_run => info!("Repository {} has no firewall rules for {}", repository, host),
+            None => Firewall::remove(repository, &fg.runner).await?,
+        }
         Ok(())
     }
 }
@@ -1,19 +1,46 @@
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::path::{Path, PathBuf};
 
-use color_eyre::eyre::Result;
 use ipnet::Ipv4Net;
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
 
+use crate::endpoint::Endpoint;
+use crate::error::{FireguardError, Result};
+use crate::firewall::FirewallConfig;
+use crate::policy::Policy;
+use crate::topology::Topology;
+
+/// Trust repository configuration, the `nodes.toml` file shared by all the peers.
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Config {
     pub repository: String,
     pub network: String,
     pub domain: String,
+    /// How the peers connect to each other, a full mesh by default.
+    #[serde(default, skip_serializing_if = "Topology::is_mesh")]
+    pub topology: Topology,
+    /// Peer relaying the traffic of all the others in the hub-spoke topology.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hub: Option<String>,
     pub peers: HashMap<String, Peer>,
+    /// Named groups of peers, for the policies.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub groups: HashMap<String, Vec<String>>,
+    /// Who may reach whom, every peer reaches every other one when empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub policies: Vec<Policy>,
+    /// Firewall settings of every peer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub firewall: Option<FirewallConfig>,
+    /// Rendezvous service helping the peers behind NAT to connect directly.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rendezvous: Option<RendezvousConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health: Option<HealthConfig>,
     #[serde(skip_deserializing, skip_serializing)]
     pub network_addr: Ipv4Net,
     #[serde(skip_deserializing, skip_serializing)]
@@ -23,19 +50,37 @@ pub struct Config {
 }
 
 impl Config {
+    /// Parse the configuration, without a directory to keep the daemon files in.
+    pub fn parse(data: &str) -> Result<Self> {
+        let mut config: Config = toml::from_str(data).map_err(|e| FireguardError::config(None, e))?;
+        config.network_addr = config
+            .network
+            .parse::<Ipv4Net>()
+            .map_err(|e| FireguardError::config(None, format!("network {}: {}", config.network, e)))?;
+        Ok(config)
+    }
+
+    /// Load the configuration from `path`, keeping the daemon files next to it.
     pub async fn load(path: &Path) -> Result<Self> {
-        let data = fs::read_to_string(path).await?;
-        let mut config: Config = toml::from_str(&data)?;
-        config.network_addr = config.network.parse::<Ipv4Net>()?;
-        config.config_dir = fs::canonicalize(path.parent().unwrap_or(&Path::new("."))).await?;
+        let data = fs::read_to_string(path).await.map_err(|e| FireguardError::io(path, e))?;
+        let mut config = Config::parse(&data).map_err(|e| match e {
+            FireguardError::Config { message, .. } => FireguardError::config(Some(path), message),
+            e => e,
+        })?;
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        config.config_dir = fs::canonicalize(dir).await.map_err(|e| FireguardError::io(dir, e))?;
         Ok(config)
     }
 
+    /// Serialize the configuration back to TOML.
+    pub fn to_toml(&self) -> Result<String> {
+        let _lock = self.mutex.lock();
+        toml::to_string(self).map_err(|e| FireguardError::config(None, e))
+    }
+
     pub async fn save(&self, path: &Path) -> Result<()> {
-        let _ = self.mutex.lock();
-        let data = toml::to_string(self)?;
-        fs::write(path, data).await?;
-        Ok(())
+        let data = self.to_toml()?;
+        fs::write(path, data).await.map_err(|e| FireguardError::io(path, e))
     }
 
     pub fn get_peer(&self, peer: &str) -> Option<&Peer> {
@@ -43,39 +88,109 @@ impl Config {
     }
 
     pub fn add_peer(&mut self, name: &str, peer: Peer) {
-        let _ = self.mutex.lock();
+        let _lock = self.mutex.lock();
         self.peers.insert(name.to_string(), peer);
     }
 
     pub fn remove_peer(&mut self, name: &str) -> Option<Peer> {
-        let _ = self.mutex.lock();
+        let _lock = self.mutex.lock();
         self.peers.remove(name)
     }
 
     pub fn get_peers_ips(&self) -> Vec<String> {
-        self.peers.values().into_iter().map(|v| v.address.clone()).collect::<Vec<String>>()
+        self.peers.values().map(|v| v.address.clone()).collect::<Vec<String>>()
     }
 
     pub fn pid_file(&self, daemon: &str) -> PathBuf {
-        Path::new(&self.config_dir).join(&format!("{}.pid", daemon))
+        Path::new(&self.config_dir).join(format!("{}.pid", daemon))
+    }
+
+    pub fn socket_file(&self, daemon: &str) -> PathBuf {
+        Path::new(&self.config_dir).join(format!("{}.sock", daemon))
     }
 
     pub async fn write_pid_file(&self, daemon: &str, pid: u32) -> Result<()> {
         let path = self.pid_file(daemon);
-        let mut file = fs::File::create(&path).await?;
-        file.write(&format!("{}", pid).as_bytes()).await?;
+        let mut file = fs::File::create(&path).await.map_err(|e| FireguardError::io(&path, e))?;
+        file.write_all(format!("{}", pid).as_bytes()).await.map_err(|e| FireguardError::io(&path, e))?;
         info!("Written PID {} for {} on file for {}", pid, daemon, path.display());
         Ok(())
     }
 
     pub async fn remove_pid_file(&self, daemon: &str) -> Result<()> {
         let path = self.pid_file(daemon);
-        fs::remove_file(&path).await?;
+        fs::remove_file(&path).await.map_err(|e| FireguardError::io(&path, e))?;
         info!("PID file {} removed from disk", path.display());
         Ok(())
     }
 }
 
+/// Peer health checking settings, from the `[health]` section of the repository or a local file.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct HealthConfig {
+    /// Seconds between health checks
+    pub interval: u64,
+    /// Seconds after the latest handshake when a peer is considered stale
+    pub stale_after: u64,
+    /// Ping the peer tunnel address when the handshake is stale
+    pub probe: bool,
+    /// Consecutive checks with the same outcome needed before a peer changes state
+    pub debounce: u32,
+    /// Commands run on peer up/down transitions
+    pub commands: Vec<String>,
+    /// URLs receiving a JSON POST on peer up/down transitions
+    pub webhooks: Vec<String>,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig { interval: 30, stale_after: 180, probe: false, debounce: 3, commands: vec![], webhooks: vec![] }
+    }
+}
+
+impl HealthConfig {
+    pub async fn load(path: &Path) -> Result<Self> {
+        let data = fs::read_to_string(path).await.map_err(|e| FireguardError::io(path, e))?;
+        toml::from_str(&data).map_err(|e| FireguardError::config(Some(path), e))
+    }
+}
+
+/// Rendezvous service settings, from the `[rendezvous]` section of the repository.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(default)]
+pub struct RendezvousConfig {
+    /// Peer running the service, it needs a public endpoint
+    pub peer: String,
+    /// UDP port of the service
+    pub port: u16,
+    /// Seconds between the queries of the other peers
+    pub interval: u64,
+}
+
+impl Default for RendezvousConfig {
+    fn default() -> Self {
+        RendezvousConfig { peer: String::new(), port: 51900, interval: 30 }
+    }
+}
+
+impl RendezvousConfig {
+    /// Public key and address of the peer running the service.
+    pub fn server(&self, config: &Config) -> Result<(String, Endpoint)> {
+        let peer = config.get_peer(&self.peer).ok_or_else(|| {
+            FireguardError::config(None, format!("rendezvous peer {} is not a peer of the repository", self.peer))
+        })?;
+        match peer.candidates()?.into_iter().next() {
+            Some(endpoint) => Ok((peer.public_key.clone(), Endpoint { host: endpoint.host, port: self.port })),
+            None => {
+                let message = format!("rendezvous peer {} needs a public endpoint", self.peer);
+                Err(FireguardError::config(None, message))
+            }
+        }
+    }
+}
+
+/// Peer of a trust repository, named `<username>-<peername>` in the configuration.
 #[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
 pub struct Peer {
     pub username: String,
@@ -94,6 +209,22 @@ pub struct Peer {
     pub pre_down: Option<Vec<String>>,
     pub post_down: Option<Vec<String>>,
     pub dns: Option<Vec<String>>,
+    /// Peer relaying the traffic between this peer and the others, for peers behind NAT.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relay_via: Option<String>,
+    /// Networks behind this peer it routes the other peers to, like its LAN.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub advertised_routes: Vec<String>,
+    /// Whether this peer lets the other peers out to the internet.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub exit_node: bool,
+    /// Further endpoints of the peer, tried in order when the previous ones do not resolve or the
+    /// peer stops answering on them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub endpoints: Vec<String>,
+    /// Firewall settings of this peer, on top of the repository ones.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub firewall: Option<FirewallConfig>,
 }
 
 impl Peer {
@@ -133,6 +264,27 @@ impl Peer {
             post_up,
             post_down,
             dns,
+            relay_via: None,
+            advertised_routes: vec![],
+            exit_node: false,
+            endpoints: vec![],
+            firewall: None,
         }
     }
+
+    /// Candidate endpoints of the peer, `endpoint` first, the port defaulting to `listen_port`.
+    pub fn candidates(&self) -> Result<Vec<Endpoint>> {
+        let name = format!("{}-{}", self.username, self.peername);
+        let port = u16::try_from(self.listen_port).map_err(|_| {
+            FireguardError::config(None, format!("peer {} listen port {} is invalid", name, self.listen_port))
+        })?;
+        self.endpoint
+            .iter()
+            .chain(self.endpoints.iter())
+            .map(|endpoint| {
+                Endpoint::parse(endpoint, port)
+                    .map_err(|e| FireguardError::config(None, format!("peer {}: {}", name, e)))
+            })
+            .collect()
+    }
 }
@@ -0,0 +1,239 @@
+use std::env;
+use std::fmt;
+use std::process::Stdio;
+use std::str::FromStr;
+
+use color_eyre::eyre::{bail, eyre, Error, Result};
+use tokio::process::Command;
+
+use crate::error::FireguardError;
+use crate::runner::Runner;
+
+/// Environment variable the private key is handed to the container with.
+pub const PRIVATE_KEY_ENV: &str = "FIREGUARD_PRIVATE_KEY";
+
+/// Container engine used to run Fireguard commands.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Runtime {
+    Docker,
+    Podman,
+}
+
+impl FromStr for Runtime {
+    type Err = Error;
+
+    fn from_str(runtime: &str) -> Result<Self> {
+        match runtime {
+            "docker" => Ok(Runtime::Docker),
+            "podman" => Ok(Runtime::Podman),
+            _ => Err(eyre!("Unknown container runtime {}, expected docker or podman", runtime)),
+        }
+    }
+}
+
+impl fmt::Display for Runtime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.binary())
+    }
+}
+
+impl Runtime {
+    pub fn binary(&self) -> &'static str {
+        match self {
+            Runtime::Docker => "docker",
+            Runtime::Podman => "podman",
+        }
+    }
+
+    /// First runtime installed on the host, Docker being preferred over Podman.
+    pub fn detect() -> Result<Self> {
+        let paths = env::var_os("PATH").unwrap_or_default();
+        for runtime in &[Runtime::Docker, Runtime::Podman] {
+            if env::split_paths(&paths).any(|dir| dir.join(runtime.binary()).is_file()) {
+                debug!("Detected container runtime {}", runtime);
+                return Ok(*runtime);
+            }
+        }
+        Err(FireguardError::Dependency(
+            "Unable to find a container runtime, please install Docker or Podman".to_string(),
+        )
+        .into())
+    }
+
+    /// Whether containers run inside a user namespace, without real root privileges on the host.
+    pub async fn rootless(&self, runner: &Runner) -> bool {
+        match self {
+            Runtime::Docker => {
+                let info = runner.exec("docker", &["info", "--format", "{{.SecurityOptions}}"], None, true).await;
+                info.success() && info.stdout().contains("rootless")
+            }
+            Runtime::Podman => {
+                let info =
+                    runner.exec("podman", &["info", "--format", "{{.Host.Security.Rootless}}"], None, true).await;
+                info.success() && info.stdout().trim() == "true"
+            }
+        }
+    }
+
+    /// Fully qualified image reference. Podman does not assume Docker Hub for short names.
+    pub fn image(&self, image: &str) -> String {
+        let registry = image.split('/').next().unwrap_or_default();
+        let qualified = image.contains('/') && (registry.contains('.') || registry.contains(':'));
+        match self {
+            Runtime::Podman if !qualified && registry != "localhost" => format!("docker.io/{}", image),
+            _ => image.to_string(),
+        }
+    }
+
+    /// Run the runtime CLI with inherited stdio. The private key is only set in the runtime
+    /// environment, so it never shows up in its command line.
+    pub async fn run(&self, args: &[String], private_key: Option<&str>) -> Result<()> {
+        debug!("Running {} {}", self, args.join(" "));
+        let mut command = Command::new(self.binary());
+        command.args(args).stdin(Stdio::inherit()).stdout(Stdio::inherit()).stderr(Stdio::inherit());
+        if let Some(key) = private_key {
+            command.env(PRIVATE_KEY_ENV, key);
+        }
+        let status = command.status().await?;
+        if status.success() {
+            Ok(())
+        } else {
+            bail!("Command `{} {}` failed with {}", self, args.first().map(String::as_str).unwrap_or_default(), status)
+        }
+    }
+}
+
+/// Network namespace the container runs in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NetworkMode {
+    /// Share the host network, the tunnel is visible to every process on the host.
+    Host,
+    /// Dedicated network namespace, the tunnel is only visible inside the container.
+    Namespace,
+}
+
+impl FromStr for NetworkMode {
+    type Err = Error;
+
+    fn from_str(mode: &str) -> Result<Self> {
+        match mode {
+            "host" => Ok(NetworkMode::Host),
+            "namespace" => Ok(NetworkMode::Namespace),
+            _ => Err(eyre!("Unknown network mode {}, expected host or namespace", mode)),
+        }
+    }
+}
+
+impl fmt::Display for NetworkMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetworkMode::Host => f.write_str("host"),
+            NetworkMode::Namespace => f.write_str("namespace"),
+        }
+    }
+}
+
+/// Privileges granted to a container running a Wireguard tunnel, replacing `--privileged`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sandbox {
+    pub network: NetworkMode,
+    /// Whether the runtime runs containers in a user namespace, without root privileges.
+    pub rootless: bool,
+    /// Whether the Wireguard kernel module is loaded on the host.
+    pub kernel_module: bool,
+    /// Ports published from a dedicated network namespace, like 51820:51820/udp.
+    pub publish: Vec<String>,
+}
+
+impl Sandbox {
+    pub fn new(network: NetworkMode, rootless: bool, kernel_module: bool, publish: &[String]) -> Self {
+        Sandbox { network, rootless, kernel_module, publish: publish.to_vec() }
+    }
+
+    /// Check the sandbox can work with the runtime privileges.
+    pub fn validate(&self) -> Result<()> {
+        if self.network == NetworkMode::Host && !self.publish.is_empty() {
+            bail!("Ports can only be published with the namespace network mode");
+        }
+        if self.rootless && self.network == NetworkMode::Host {
+            bail!("Rootless containers cannot create interfaces on the host network, use the namespace network mode");
+        }
+        Ok(())
+    }
+
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec!["--cap-add".to_string(), "NET_ADMIN".to_string()];
+        if !self.kernel_module {
+            // Let the container try to load the module, then fall back to a userspace tunnel.
+            if !self.rootless {
+                args.extend(vec![
+                    "--cap-add".to_string(),
+                    "SYS_MODULE".to_string(),
+                    "--volume".to_string(),
+                    "/lib/modules:/lib/modules:ro".to_string(),
+                ]);
+            }
+            args.extend(vec!["--device".to_string(), "/dev/net/tun".to_string()]);
+        }
+        match self.network {
+            NetworkMode::Host => args.extend(vec!["--network".to_string(), "host".to_string()]),
+            NetworkMode::Namespace => {
+                // Network sysctls are per namespace, so they can be set without touching the host.
+                for sysctl in &["net.ipv4.ip_forward=1", "net.ipv4.conf.all.src_valid_mark=1"] {
+                    args.extend(vec!["--sysctl".to_string(), sysctl.to_string()]);
+                }
+                for port in self.publish.iter() {
+                    args.extend(vec!["--publish".to_string(), port.clone()]);
+                }
+            }
+        }
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runtime_image() {
+        assert_eq!(Runtime::Docker.image("blackmesalab/fireguard:0.1.0"), "blackmesalab/fireguard:0.1.0");
+        assert_eq!(Runtime::Podman.image("blackmesalab/fireguard:0.1.0"), "docker.io/blackmesalab/fireguard:0.1.0");
+        assert_eq!(Runtime::Podman.image("ghcr.io/blackmesalab/fireguard"), "ghcr.io/blackmesalab/fireguard");
+        assert_eq!(Runtime::Podman.image("localhost/fireguard"), "localhost/fireguard");
+        assert!("rkt".parse::<Runtime>().is_err());
+    }
+
+    #[test]
+    fn test_sandbox_args() {
+        let host = Sandbox::new(NetworkMode::Host, false, true, &[]);
+        assert_eq!(host.args(), vec!["--cap-add", "NET_ADMIN", "--network", "host"]);
+        assert!(host.validate().is_ok());
+        assert!(Sandbox::new(NetworkMode::Host, true, true, &[]).validate().is_err());
+        let namespace = Sandbox::new(NetworkMode::Namespace, false, false, &["51820:51820/udp".to_string()]);
+        assert_eq!(
+            namespace.args(),
+            vec![
+                "--cap-add",
+                "NET_ADMIN",
+                "--cap-add",
+                "SYS_MODULE",
+                "--volume",
+                "/lib/modules:/lib/modules:ro",
+                "--device",
+                "/dev/net/tun",
+                "--sysctl",
+                "net.ipv4.ip_forward=1",
+                "--sysctl",
+                "net.ipv4.conf.all.src_valid_mark=1",
+                "--publish",
+                "51820:51820/udp"
+            ]
+        );
+        assert!(namespace.validate().is_ok());
+        let rootless = Sandbox::new(NetworkMode::Namespace, true, false, &[]);
+        assert!(rootless.validate().is_ok());
+        assert!(!rootless.args().contains(&"SYS_MODULE".to_string()));
+        assert!(Sandbox::new(NetworkMode::Host, false, true, &["51820:51820/udp".to_string()]).validate().is_err());
+    }
+}
@@ -0,0 +1,237 @@
+use std::fs::Permissions;
+use std::io::{BufRead, BufReader as StdBufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use color_eyre::eyre::{bail, Result};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::{self, JoinHandle};
+
+use crate::shutdown::ShutdownSignal;
+use crate::state::{DaemonState, SharedState};
+use crate::wg::quick::WgPeer;
+
+/// Requests understood by the daemon control socket. The protocol is one JSON object per line,
+/// answered by exactly one JSON `Response` line.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "request")]
+pub enum Request {
+    Status,
+    Reload,
+    PullNow,
+    Peers,
+    Shutdown,
+    /// Sent by a newly started daemon, which takes over the tunnel, PID file and control socket
+    /// of the running one.
+    Handover {
+        pid: u32,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "response", content = "data")]
+pub enum Response {
+    Status(Box<DaemonState>),
+    Peers(Vec<PeerStatus>),
+    Done(String),
+    Error(String),
+}
+
+/// Live Wireguard peer data, decorated with the peer name from the trust repository.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerStatus {
+    pub name: Option<String>,
+    #[serde(flatten)]
+    pub peer: WgPeer,
+}
+
+/// A request forwarded to the daemon main loop, which answers on the `reply` channel.
+#[derive(Debug)]
+pub struct ControlCommand {
+    pub request: Request,
+    pub reply: oneshot::Sender<Response>,
+}
+
+pub struct ControlServer {
+    path: PathBuf,
+    listener: UnixListener,
+    state: SharedState,
+}
+
+impl ControlServer {
+    pub async fn bind(path: &Path, state: SharedState) -> Result<Self> {
+        if path.exists() {
+            if UnixStream::connect(path).await.is_ok() {
+                bail!("Control socket {} is already in use, is another Fireguard daemon running?", path.display());
+            }
+            warn!("Removing stale control socket {}", path.display());
+            fs::remove_file(path).await?;
+        }
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, Permissions::from_mode(0o660)).await?;
+        info!("Control socket listening on {}", path.display());
+        Ok(ControlServer { path: path.to_path_buf(), listener, state })
+    }
+
+    pub fn run_in_background(
+        self,
+        commands: mpsc::Sender<ControlCommand>,
+        mut shutdown: ShutdownSignal,
+    ) -> JoinHandle<()> {
+        task::spawn(async move {
+            loop {
+                let accepted = tokio::select! {
+                    accepted = self.listener.accept() => accepted,
+                    _ = shutdown.wait() => break,
+                };
+                match accepted {
+                    Ok((stream, _)) => {
+                        let state = self.state.clone();
+                        let commands = commands.clone();
+                        task::spawn(async move {
+                            if let Err(e) = Self::handle_client(stream, state, commands).await {
+                                error!("Error handling control socket client: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => error!("Error accepting control socket connection on {}: {}", self.path.display(), e),
+                }
+            }
+        })
+    }
+
+    async fn handle_client(
+        stream: UnixStream,
+        state: SharedState,
+        commands: mpsc::Sender<ControlCommand>,
+    ) -> Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        while let Some(line) = lines.next_line().await? {
+            let response = match serde_json::from_str::<Request>(&line) {
+                Ok(request) => {
+                    debug!("Received control request {:?}", request);
+                    Self::handle_request(request, &state, &commands).await
+                }
+                Err(e) => Response::Error(format!("Invalid request {}: {}", line.trim(), e)),
+            };
+            let mut data = serde_json::to_vec(&response)?;
+            data.push(b'\n');
+            writer.write_all(&data).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_request(
+        request: Request,
+        state: &SharedState,
+        commands: &mpsc::Sender<ControlCommand>,
+    ) -> Response {
+        match request {
+            Request::Status => Response::Status(Box::new(state.read().clone())),
+            Request::Peers => Self::peers(state).await,
+            _ => {
+                let (reply, response) = oneshot::channel();
+                if commands.send(ControlCommand { request, reply }).await.is_err() {
+                    return Response::Error("Fireguard daemon main loop is not running".to_string());
+                }
+                response.await.unwrap_or_else(|_| Response::Error("Fireguard daemon dropped the request".to_string()))
+            }
+        }
+    }
+
+    async fn peers(state: &SharedState) -> Response {
+        let (repository, driver, runner) = {
+            let state = state.read();
+            (state.repository.clone(), state.wg_driver, state.runner.clone())
+        };
+        let peers = match driver.tunnel(&repository, &runner) {
+            Ok(wg) => wg.peers().await,
+            Err(e) => Err(e),
+        };
+        match peers {
+            Ok(peers) => {
+                let state = state.read();
+                Response::Peers(
+                    peers
+                        .into_iter()
+                        .map(|peer| PeerStatus { name: state.peer_name(&peer.public_key), peer })
+                        .collect(),
+                )
+            }
+            Err(e) => Response::Error(format!("Unable to read Wireguard peers for {}: {}", repository, e)),
+        }
+    }
+
+    pub async fn remove(path: &Path) -> Result<()> {
+        fs::remove_file(path).await?;
+        info!("Control socket {} removed from disk", path.display());
+        Ok(())
+    }
+}
+
+pub struct ControlClient {}
+
+impl ControlClient {
+    pub async fn request(path: &Path, request: Request) -> Result<Response> {
+        let stream = match UnixStream::connect(path).await {
+            Ok(stream) => stream,
+            Err(e) => bail!(
+                "Unable to connect to control socket {}: {}, did you start Fireguard with `daemon serve` command?",
+                path.display(),
+                e
+            ),
+        };
+        let (reader, mut writer) = stream.into_split();
+        let mut data = serde_json::to_vec(&request)?;
+        data.push(b'\n');
+        writer.write_all(&data).await?;
+        match BufReader::new(reader).lines().next_line().await? {
+            Some(line) => Ok(serde_json::from_str::<Response>(&line)?),
+            None => bail!("Control socket {} closed the connection without answering", path.display()),
+        }
+    }
+
+    /// Blocking flavour of `request`, for callers running outside of the async runtime.
+    pub fn request_blocking(path: &Path, request: Request, timeout: Duration) -> Result<Response> {
+        let mut stream = StdUnixStream::connect(path)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        let mut data = serde_json::to_vec(&request)?;
+        data.push(b'\n');
+        stream.write_all(&data)?;
+        let mut line = String::new();
+        if StdBufReader::new(stream).read_line(&mut line)? == 0 {
+            bail!("Control socket {} closed the connection without answering", path.display());
+        }
+        Ok(serde_json::from_str::<Response>(&line)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_wire_format() {
+        assert_eq!(serde_json::to_string(&Request::PullNow).unwrap(), r#"{"request":"pull_now"}"#);
+        assert_eq!(serde_json::from_str::<Request>(r#"{"request":"shutdown"}"#).unwrap(), Request::Shutdown);
+        assert!(serde_json::from_str::<Request>(r#"{"request":"explode"}"#).is_err());
+        assert_eq!(
+            serde_json::to_string(&Request::Handover { pid: 42 }).unwrap(),
+            r#"{"request":"handover","pid":42}"#
+        );
+    }
+
+    #[test]
+    fn test_response_wire_format() {
+        let response = serde_json::to_string(&Response::Done("Reloaded".to_string())).unwrap();
+        assert_eq!(response, r#"{"response":"done","data":"Reloaded"}"#);
+    }
+}
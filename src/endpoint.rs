@@ -0,0 +1,111 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+use tokio::net;
+
+use crate::error::{FireguardError, Result};
+
+/// Address a peer is reached at: a DNS name or an IP literal, and a UDP port.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Endpoint {
+    /// DNS name or IP address, without the IPv6 brackets.
+    pub host: String,
+    pub port: u16,
+}
+
+impl Endpoint {
+    /// Parse `host`, `host:port`, `[ipv6]:port` or a bare IPv6 address, the port defaulting to
+    /// `default_port`.
+    pub fn parse(endpoint: &str, default_port: u16) -> Result<Self> {
+        let invalid =
+            |message: &str| FireguardError::config(None, format!("invalid endpoint {}: {}", endpoint, message));
+        let endpoint = endpoint.trim();
+        let (host, port) = if let Some(rest) = endpoint.strip_prefix('[') {
+            let (host, port) = rest.split_once(']').ok_or_else(|| invalid("missing closing bracket"))?;
+            if host.parse::<Ipv6Addr>().is_err() {
+                return Err(invalid("only IPv6 addresses go in brackets"));
+            }
+            match port {
+                "" => (host, None),
+                port => (host, Some(port.strip_prefix(':').ok_or_else(|| invalid("expected :port after ]"))?)),
+            }
+        } else if endpoint.matches(':').count() > 1 {
+            if endpoint.parse::<Ipv6Addr>().is_err() {
+                return Err(invalid("IPv6 addresses with a port go in brackets, like [fd00::1]:51820"));
+            }
+            (endpoint, None)
+        } else {
+            match endpoint.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (endpoint, None),
+            }
+        };
+        if host.is_empty() || host.contains(char::is_whitespace) {
+            return Err(invalid("expected a host name or an IP address"));
+        }
+        let port = match port {
+            Some(port) => port.parse::<u16>().ok().filter(|port| *port > 0).ok_or_else(|| invalid("invalid port"))?,
+            None => default_port,
+        };
+        Ok(Endpoint { host: host.to_string(), port })
+    }
+
+    /// IP address of the endpoint, when it is not a DNS name.
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.host.parse().ok()
+    }
+
+    /// Addresses the endpoint resolves to, the IP literals resolving to themselves.
+    pub async fn resolve(&self) -> Result<Vec<SocketAddr>> {
+        if let Some(ip) = self.ip() {
+            return Ok(vec![SocketAddr::new(ip, self.port)]);
+        }
+        let addresses = net::lookup_host((self.host.as_str(), self.port))
+            .await
+            .map_err(|e| FireguardError::Network(format!("unable to resolve endpoint {}: {}", self, e)))?
+            .collect::<Vec<_>>();
+        if addresses.is_empty() {
+            return Err(FireguardError::Network(format!("endpoint {} resolves to no address", self)));
+        }
+        Ok(addresses)
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_endpoints() {
+        let endpoint = |host: &str, port: u16| Endpoint { host: host.to_string(), port };
+        assert_eq!(Endpoint::parse("cloud.bob.net", 6666).unwrap(), endpoint("cloud.bob.net", 6666));
+        assert_eq!(Endpoint::parse("cloud.bob.net:51820", 6666).unwrap(), endpoint("cloud.bob.net", 51820));
+        assert_eq!(Endpoint::parse("1.2.3.4:4500", 6666).unwrap(), endpoint("1.2.3.4", 4500));
+        assert_eq!(Endpoint::parse("[2001:db8::1]:4500", 6666).unwrap(), endpoint("2001:db8::1", 4500));
+        assert_eq!(Endpoint::parse("[2001:db8::1]", 6666).unwrap(), endpoint("2001:db8::1", 6666));
+        assert_eq!(Endpoint::parse("2001:db8::1", 6666).unwrap(), endpoint("2001:db8::1", 6666));
+        assert_eq!(Endpoint::parse("2001:db8::1", 6666).unwrap().to_string(), "[2001:db8::1]:6666");
+        assert_eq!(Endpoint::parse("cloud.bob.net", 6666).unwrap().to_string(), "cloud.bob.net:6666");
+        for invalid in
+            ["", "cloud.bob.net:0", "cloud.bob.net:http", "2001:db8::1:4500:x", "[cloud.bob.net]:1", "[::1"].iter()
+        {
+            assert!(Endpoint::parse(invalid, 6666).is_err(), "{} should not parse", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve_ip_literals() {
+        let endpoint = Endpoint::parse("[2001:db8::1]:4500", 6666).unwrap();
+        assert_eq!(endpoint.resolve().await.unwrap(), vec!["[2001:db8::1]:4500".parse::<SocketAddr>().unwrap()]);
+    }
+}
@@ -0,0 +1,197 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::Report;
+
+use crate::shell::{ShellCommand, ShellResult};
+
+pub type Result<T> = std::result::Result<T, FireguardError>;
+
+/// Exit code of failures without a more specific one.
+pub const EXIT_FAILURE: i32 = 1;
+/// Exit code of invalid command lines, the one clap uses.
+pub const EXIT_USAGE: i32 = 2;
+/// Exit code of invalid or missing configurations.
+pub const EXIT_CONFIG: i32 = 3;
+/// Exit code of missing trust repositories and failed git operations.
+pub const EXIT_REPOSITORY: i32 = 4;
+/// Exit code of Wireguard tunnel failures.
+pub const EXIT_TUNNEL: i32 = 5;
+/// Exit code of missing programs or kernel modules.
+pub const EXIT_DEPENDENCY: i32 = 6;
+/// Exit code of operations lacking privileges.
+pub const EXIT_PERMISSION: i32 = 7;
+/// Exit code of unreachable remote services.
+pub const EXIT_NETWORK: i32 = 8;
+/// Exit code of failed upgrades of the Fireguard executable.
+pub const EXIT_UPGRADE: i32 = 9;
+
+/// Errors of the Fireguard library API.
+#[derive(Debug)]
+pub enum FireguardError {
+    /// Reading or writing a file failed.
+    Io { path: PathBuf, source: io::Error },
+    /// The trust repository configuration is not valid.
+    Config { path: Option<PathBuf>, message: String },
+    /// The peer is not part of the trust repository.
+    PeerNotFound { repository: String, peer: String },
+    /// The network has no address left for a new peer.
+    PoolExhausted,
+    /// Rendering the Wireguard configuration failed.
+    Render(tera::Error),
+    /// A program or kernel module Fireguard needs is missing.
+    Dependency(String),
+    /// An external command failed.
+    Command { command: String, code: Option<i32>, stderr: String },
+    /// Managing the Wireguard tunnel failed.
+    Tunnel { repository: String, message: String },
+    /// The trust repository is missing or a git operation on it failed.
+    Repository { repository: String, message: String },
+    /// Fireguard lacks the privileges for the operation.
+    Permission(String),
+    /// A remote service could not be reached.
+    Network(String),
+    /// Upgrading the Fireguard executable failed.
+    Upgrade(String),
+}
+
+impl FireguardError {
+    pub fn io(path: &Path, source: io::Error) -> Self {
+        FireguardError::Io { path: path.to_path_buf(), source }
+    }
+
+    pub fn config<E: fmt::Display>(path: Option<&Path>, error: E) -> Self {
+        FireguardError::Config { path: path.map(Path::to_path_buf), message: error.to_string() }
+    }
+
+    pub fn command(command: &ShellCommand, result: &ShellResult) -> Self {
+        FireguardError::Command {
+            command: command.to_string(),
+            code: result.code(),
+            stderr: result.stderr().trim().to_string(),
+        }
+    }
+
+    /// Wrap an error of the tunnel implementation, keeping its chain of causes.
+    pub fn tunnel(repository: &str, error: Report) -> Self {
+        FireguardError::Tunnel { repository: repository.to_string(), message: causes(&error) }
+    }
+
+    pub fn repository<M: fmt::Display>(repository: &str, message: M) -> Self {
+        FireguardError::Repository { repository: repository.to_string(), message: message.to_string() }
+    }
+
+    /// Wrap an error of the upgrade process, keeping its chain of causes.
+    pub fn upgrade(error: Report) -> Self {
+        FireguardError::Upgrade(causes(&error))
+    }
+
+    /// Process exit code telling scripts which kind of failure happened.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            FireguardError::Io { source, .. } if source.kind() == io::ErrorKind::PermissionDenied => EXIT_PERMISSION,
+            FireguardError::Io { .. } => EXIT_FAILURE,
+            FireguardError::Config { .. }
+            | FireguardError::PeerNotFound { .. }
+            | FireguardError::PoolExhausted
+            | FireguardError::Render(_) => EXIT_CONFIG,
+            FireguardError::Dependency(_) => EXIT_DEPENDENCY,
+            FireguardError::Command { .. } | FireguardError::Tunnel { .. } => EXIT_TUNNEL,
+            FireguardError::Repository { .. } => EXIT_REPOSITORY,
+            FireguardError::Permission(_) => EXIT_PERMISSION,
+            FireguardError::Network(_) => EXIT_NETWORK,
+            FireguardError::Upgrade(_) => EXIT_UPGRADE,
+        }
+    }
+}
+
+fn causes(error: &Report) -> String {
+    error.chain().map(|cause| cause.to_string()).collect::<Vec<_>>().join(": ")
+}
+
+/// Process exit code of a failed command, from the first error of the chain Fireguard knows about.
+pub fn exit_code(report: &Report) -> i32 {
+    for cause in report.chain() {
+        if let Some(error) = cause.downcast_ref::<FireguardError>() {
+            return error.exit_code();
+        } else if cause.is::<clap::Error>() {
+            return EXIT_USAGE;
+        } else if cause.is::<reqwest::Error>() {
+            return EXIT_NETWORK;
+        } else if let Some(error) = cause.downcast_ref::<io::Error>() {
+            if error.kind() == io::ErrorKind::PermissionDenied {
+                return EXIT_PERMISSION;
+            }
+        }
+    }
+    EXIT_FAILURE
+}
+
+impl fmt::Display for FireguardError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FireguardError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            FireguardError::Config { path: Some(path), message } => {
+                write!(f, "Invalid configuration {}: {}", path.display(), message)
+            }
+            FireguardError::Config { path: None, message } => write!(f, "Invalid configuration: {}", message),
+            FireguardError::PeerNotFound { repository, peer } => {
+                write!(f, "Unable to find peer {} for repository {}", peer, repository)
+            }
+            FireguardError::PoolExhausted => write!(f, "Unable to find a free IPv4 in the pool"),
+            FireguardError::Render(e) => write!(f, "Unable to render the Wireguard configuration: {}", e),
+            FireguardError::Dependency(message) => write!(f, "{}", message),
+            FireguardError::Command { command, code: Some(code), stderr } => {
+                write!(f, "Command `{}` exited with code {}: {}", command, code, stderr)
+            }
+            FireguardError::Command { command, code: None, stderr } => {
+                write!(f, "Command `{}` did not complete: {}", command, stderr)
+            }
+            FireguardError::Tunnel { repository, message } => write!(f, "Wireguard tunnel {}: {}", repository, message),
+            FireguardError::Repository { repository, message } => {
+                write!(f, "Trust repository {}: {}", repository, message)
+            }
+            FireguardError::Permission(message) => write!(f, "Permission denied: {}", message),
+            FireguardError::Network(message) => write!(f, "Network error: {}", message),
+            FireguardError::Upgrade(message) => write!(f, "Upgrade failed: {}", message),
+        }
+    }
+}
+
+impl Error for FireguardError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FireguardError::Io { source, .. } => Some(source),
+            FireguardError::Render(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<tera::Error> for FireguardError {
+    fn from(error: tera::Error) -> Self {
+        FireguardError::Render(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::{eyre, WrapErr};
+
+    use super::*;
+
+    #[test]
+    fn test_exit_codes() {
+        let missing = FireguardError::Dependency("wg-quick is not installed".to_string());
+        assert_eq!(exit_code(&Report::new(missing)), EXIT_DEPENDENCY);
+        let config: std::result::Result<(), _> = Err(FireguardError::config(None, "expected a table"));
+        assert_eq!(exit_code(&config.wrap_err("Unable to start the daemon").unwrap_err()), EXIT_CONFIG);
+        let denied = io::Error::new(io::ErrorKind::PermissionDenied, "denied");
+        assert_eq!(exit_code(&Report::new(FireguardError::io(Path::new("/etc/wireguard"), denied))), EXIT_PERMISSION);
+        let not_found = io::Error::new(io::ErrorKind::NotFound, "not found");
+        assert_eq!(exit_code(&Report::new(not_found)), EXIT_FAILURE);
+        assert_eq!(exit_code(&eyre!("boom")), EXIT_FAILURE);
+    }
+}
@@ -0,0 +1,512 @@
+use std::fmt::Write;
+use std::net::IpAddr;
+
+use ipnet::{IpNet, Ipv4Net};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::error::{FireguardError, Result};
+use crate::policy::{self, PortRange};
+use crate::runner::Runner;
+use crate::shell::ShellCommand;
+use crate::topology;
+
+/// Declarative firewall settings of the whole repository, or of one peer on top of them.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct FirewallConfig {
+    /// Ports accepting connections from every peer of the mesh.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inbound: Vec<PortRange>,
+    /// Let the traffic of the peers out of the tunnel, which is dropped otherwise.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub forward: bool,
+    /// Interfaces the traffic of the peers is forwarded and masqueraded on, like `eth0`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub masquerade: Vec<String>,
+}
+
+/// Traffic accepted on the tunnel interface: from `sources` and to `destinations` when set, going
+/// out of `output`, or out of any other interface than the tunnel when `outside`, when forwarded,
+/// only on `ports` when set.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Accept {
+    pub sources: Vec<IpNet>,
+    pub destinations: Vec<IpNet>,
+    pub output: Option<String>,
+    pub outside: bool,
+    pub ports: Vec<PortRange>,
+}
+
+impl Accept {
+    /// nftables rules matching the traffic of the tunnel `interface`, one per address family and
+    /// port range.
+    fn rules(&self, interface: &str) -> Vec<String> {
+        let mut matches = vec![];
+        let mut prefix = format!("iifname \"{}\"", interface);
+        if let Some(output) = self.output.as_ref() {
+            write!(prefix, " oifname \"{}\"", output).unwrap();
+        } else if self.outside {
+            write!(prefix, " oifname != \"{}\"", interface).unwrap();
+        }
+        if self.sources.is_empty() && self.destinations.is_empty() {
+            matches.push(prefix.clone());
+        }
+        for (family, v4) in [("ip", true), ("ip6", false)].iter() {
+            let sources = self.sources.iter().filter(|net| is_v4(net) == *v4).collect::<Vec<_>>();
+            let destinations = self.destinations.iter().filter(|net| is_v4(net) == *v4).collect::<Vec<_>>();
+            if (!self.sources.is_empty() && sources.is_empty())
+                || (!self.destinations.is_empty() && destinations.is_empty())
+                || (sources.is_empty() && destinations.is_empty())
+            {
+                continue;
+            }
+            let mut rule = prefix.clone();
+            if !sources.is_empty() {
+                write!(rule, " {} saddr {}", family, set(&sources)).unwrap();
+            }
+            if !destinations.is_empty() {
+                write!(rule, " {} daddr {}", family, set(&destinations)).unwrap();
+            }
+            matches.push(rule);
+        }
+        let mut rules = vec![];
+        for rule in matches {
+            if self.ports.is_empty() {
+                rules.push(format!("{} accept", rule));
+            }
+            for ports in self.ports.iter() {
+                let range = if ports.first == ports.last {
+                    ports.first.to_string()
+                } else {
+                    format!("{}-{}", ports.first, ports.last)
+                };
+                match ports.protocol {
+                    Some(protocol) => rules.push(format!("{} {} dport {} accept", rule, protocol, range)),
+                    None => rules.push(format!("{} meta l4proto {{ tcp, udp }} th dport {} accept", rule, range)),
+                }
+            }
+        }
+        rules
+    }
+}
+
+fn is_v4(net: &IpNet) -> bool {
+    matches!(net.addr(), IpAddr::V4(_))
+}
+
+fn set(nets: &[&IpNet]) -> String {
+    format!("{{ {} }}", nets.iter().map(|net| net.to_string()).collect::<Vec<_>>().join(", "))
+}
+
+/// Addresses routed to `peer`, the invalid ones being skipped.
+fn peer_nets(config: &Config, peer: &str) -> Vec<IpNet> {
+    let allowed_ips = config.get_peer(peer).map(|p| p.allowed_ips.as_slice()).unwrap_or_default();
+    allowed_ips
+        .iter()
+        .filter_map(|ip| match ip.parse::<IpNet>() {
+            Ok(net) => Some(net),
+            Err(e) => {
+                warn!("Skipping invalid allowed IP {} of peer {} in the firewall: {}", ip, peer, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// nftables table Fireguard owns for a tunnel interface, filtering the traffic the peers send.
+/// The traffic of the other interfaces is left alone.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Firewall {
+    interface: String,
+    network: Ipv4Net,
+    /// Traffic accepted to the host, everything is when `None`.
+    input: Option<Vec<Accept>>,
+    /// Traffic accepted out of the tunnel, the rest is dropped.
+    forward: Vec<Accept>,
+    masquerade: Vec<String>,
+    /// Whether the traffic of the peers leaving the host is masqueraded, for exit nodes.
+    exit_node: bool,
+    /// Networks advertised by the host, the traffic of the peers to them is masqueraded.
+    advertised_routes: Vec<IpNet>,
+}
+
+impl Firewall {
+    /// Rules of the tunnel interface of the peer `host`, from the access policies, the firewall
+    /// settings of the repository and the peer, the peers it relays and the routes it advertises.
+    /// `None` when there are none of them.
+    pub fn new(config: &Config, repository: &str, host: &str) -> Result<Option<Self>> {
+        let peer = config.get_peer(host);
+        let peer_settings = peer.and_then(|peer| peer.firewall.as_ref());
+        let exit_node = peer.map(|peer| peer.exit_node).unwrap_or_default();
+        let router = exit_node
+            || topology::is_relay(config, host)
+            || peer.map(|peer| !peer.advertised_routes.is_empty()).unwrap_or_default();
+        if config.policies.is_empty() && config.firewall.is_none() && peer_settings.is_none() && !router {
+            return Ok(None);
+        }
+        policy::validate(config)?;
+        let advertised_routes = topology::advertised_routes(config)?
+            .into_iter()
+            .filter(|(name, _)| *name == host)
+            .map(|(_, net)| net)
+            .collect::<Vec<_>>();
+        let settings = [config.firewall.as_ref(), peer_settings];
+        let inbound = settings.iter().flatten().flat_map(|s| s.inbound.iter().cloned()).collect::<Vec<_>>();
+        let masquerade = settings.iter().flatten().flat_map(|s| s.masquerade.iter().cloned()).collect::<Vec<_>>();
+        let forward_all = settings.iter().flatten().any(|s| s.forward);
+        let peers = config.peers.keys().filter(|peer| peer.as_str() != host).collect::<Vec<_>>();
+
+        let input = if config.policies.is_empty() && inbound.is_empty() {
+            None
+        } else {
+            let mut input = vec![];
+            if !config.policies.is_empty() {
+                for peer in peers.iter() {
+                    let sources = peer_nets(config, peer);
+                    if let (Some(ports), false) = (policy::grant(config, peer, host), sources.is_empty()) {
+                        input.push(Accept { sources, ports, ..Default::default() });
+                    }
+                }
+            }
+            if !inbound.is_empty() {
+                input.push(Accept { ports: inbound, ..Default::default() });
+            }
+            Some(input)
+        };
+
+        // Relays forward the traffic between two peers when one of them is relayed through the host.
+        let mut forward = vec![];
+        for from in peers.iter() {
+            for to in peers.iter().filter(|to| to != &from) {
+                let relayed = |peer: &str| topology::relay_of(config, peer) == Some(host);
+                if !relayed(from) && !relayed(to) {
+                    continue;
+                }
+                let (sources, destinations) = (peer_nets(config, from), peer_nets(config, to));
+                if sources.is_empty() || destinations.is_empty() {
+                    continue;
+                }
+                if let Some(ports) = policy::grant(config, from, to) {
+                    let output = Some(repository.to_string());
+                    forward.push(Accept { sources, destinations, output, ports, ..Default::default() });
+                }
+            }
+        }
+        // Peers reaching the host reach the networks it advertises and the internet through it.
+        let allowed = if config.policies.is_empty() {
+            vec![(vec![], vec![])]
+        } else {
+            peers
+                .iter()
+                .filter_map(|peer| policy::grant(config, peer, host).map(|ports| (peer_nets(config, peer), ports)))
+                .filter(|(sources, _)| !sources.is_empty())
+                .collect()
+        };
+        for (sources, ports) in allowed {
+            if exit_node {
+                forward.push(Accept { sources, outside: true, ..Default::default() });
+            } else if !advertised_routes.is_empty() {
+                let destinations = advertised_routes.clone();
+                forward.push(Accept { sources, destinations, ports, ..Default::default() });
+            }
+        }
+        if forward_all {
+            forward.push(Accept::default());
+        } else {
+            for interface in masquerade.iter() {
+                forward.push(Accept { output: Some(interface.clone()), ..Default::default() });
+            }
+        }
+        Ok(Some(Firewall {
+            interface: repository.to_string(),
+            network: config.network_addr,
+            input,
+            forward,
+            masquerade,
+            exit_node,
+            advertised_routes,
+        }))
+    }
+
+    /// Name of the table owned by Fireguard for the tunnel `repository`.
+    pub fn table(repository: &str) -> String {
+        let name = repository.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect::<String>();
+        format!("fireguard_{}", name)
+    }
+
+    /// `nft -f` script replacing the whole table at once.
+    pub fn script(&self) -> String {
+        let table = Firewall::table(&self.interface);
+        let input = format!("iifname \"{}\"", self.interface);
+        let mut script = String::new();
+        // Declaring the table first makes the deletion succeed when it does not exist yet.
+        writeln!(script, "table inet {}", table).unwrap();
+        writeln!(script, "delete table inet {}", table).unwrap();
+        writeln!(script, "table inet {} {{", table).unwrap();
+        let mut chains = vec![];
+        if let Some(accepts) = self.input.as_ref() {
+            chains.push(("input", format!("{} meta l4proto {{ icmp, ipv6-icmp }} accept", input), accepts));
+        }
+        let relayed = format!("{} oifname \"{}\" meta l4proto {{ icmp, ipv6-icmp }} accept", input, self.interface);
+        chains.push(("forward", relayed, &self.forward));
+        for (chain, icmp, accepts) in chains {
+            writeln!(script, "\tchain {} {{", chain).unwrap();
+            writeln!(script, "\t\ttype filter hook {} priority 0; policy accept;", chain).unwrap();
+            writeln!(script, "\t\t{} ct state established,related accept", input).unwrap();
+            writeln!(script, "\t\t{}", icmp).unwrap();
+            for rule in accepts.iter().flat_map(|accept| accept.rules(&self.interface)) {
+                writeln!(script, "\t\t{}", rule).unwrap();
+            }
+            writeln!(script, "\t\t{} drop", input).unwrap();
+            writeln!(script, "\t}}").unwrap();
+        }
+        let network = self.network.trunc();
+        let mut nat = self
+            .masquerade
+            .iter()
+            .map(|interface| format!("oifname \"{}\" ip saddr {} masquerade", interface, network))
+            .collect::<Vec<_>>();
+        if self.exit_node {
+            nat.push(format!("oifname != \"{}\" ip saddr {} masquerade", self.interface, network));
+        } else {
+            let routes = self.advertised_routes.iter().filter(|net| is_v4(net)).collect::<Vec<_>>();
+            if !routes.is_empty() {
+                nat.push(format!("ip saddr {} ip daddr {} masquerade", network, set(&routes)));
+            }
+        }
+        if !nat.is_empty() {
+            writeln!(script, "\tchain postrouting {{").unwrap();
+            writeln!(script, "\t\ttype nat hook postrouting priority 100; policy accept;").unwrap();
+            for rule in nat {
+                writeln!(script, "\t\t{}", rule).unwrap();
+            }
+            writeln!(script, "\t}}").unwrap();
+        }
+        writeln!(script, "}}").unwrap();
+        script
+    }
+
+    /// Replace the rules of the tunnel interface with these ones.
+    pub async fn apply(&self, runner: &Runner) -> Result<()> {
+        if !runner.runnable("nft") {
+            return Err(FireguardError::Dependency("nft is not installed, please install nftables".to_string()));
+        }
+        info!("Applying firewall rules to interface {} in table {}", self.interface, Firewall::table(&self.interface));
+        let script = self.script();
+        debug!("Firewall rules:\n{}", script);
+        let command = ShellCommand::new("nft").args(&["-f", "-"]).stdin(&script);
+        let result = runner.run(&command).await;
+        if result.success() {
+            Ok(())
+        } else {
+            Err(FireguardError::command(&command, &result))
+        }
+    }
+
+    /// Delete the table Fireguard owns for the tunnel `repository`, if any.
+    pub async fn remove(repository: &str, runner: &Runner) -> Result<()> {
+        if !runner.runnable("nft") {
+            return Ok(());
+        }
+        let table = Firewall::table(repository);
+        if !runner.exec("nft", &["list", "table", "inet", &table], None, false).await.success() {
+            return Ok(());
+        }
+        info!("Removing firewall rules of interface {} in table {}", repository, table);
+        let command = ShellCommand::new("nft").args(&["delete", "table", "inet", &table]);
+        let result = runner.run(&command).await;
+        if result.success() {
+            Ok(())
+        } else {
+            Err(FireguardError::command(&command, &result))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::runner::FakeRunner;
+
+    const NODES: &str = r#"repository = "avalon"
+network = "10.0.0.0/24"
+domain = "avalon.lan"
+topology = "hub-spoke"
+hub = "bob-cloud"
+
+[peers.bob-cloud]
+username = "bob"
+peername = "cloud"
+address = "10.0.0.1/24"
+listen_port = 51820
+public_key = "bob"
+allowed_ips = ["10.0.0.1/32", "fd00::1/128"]
+persistent_keepalive = 25
+endpoint = "cloud.bob.net"
+mtu = 1420
+
+[peers.alice-laptop]
+username = "alice"
+peername = "laptop"
+address = "10.0.0.2/24"
+listen_port = 51820
+public_key = "alice"
+allowed_ips = ["10.0.0.2/32"]
+persistent_keepalive = 25
+mtu = 1420
+
+[peers.carol-baremetal]
+username = "carol"
+peername = "baremetal"
+address = "10.0.0.3/24"
+listen_port = 51820
+public_key = "carol"
+allowed_ips = ["10.0.0.3/32"]
+persistent_keepalive = 25
+mtu = 1420
+
+[groups]
+laptops = ["alice-laptop"]
+servers = ["bob-cloud", "carol-baremetal"]
+
+[[policies]]
+from = "laptops"
+to = "servers"
+ports = ["22/tcp", "53"]
+
+[[policies]]
+from = "servers"
+to = "servers"
+"#;
+
+    #[test]
+    fn test_hub_script() {
+        let config = Config::parse(NODES).unwrap();
+        let firewall = Firewall::new(&config, "avalon", "bob-cloud").unwrap().unwrap();
+        let script = firewall.script();
+        assert!(script.starts_with("table inet fireguard_avalon\ndelete table inet fireguard_avalon\n"));
+        assert!(script.contains("\t\tiifname \"avalon\" ip saddr { 10.0.0.2/32 } tcp dport 22 accept\n"));
+        assert!(script.contains(
+            "\t\tiifname \"avalon\" ip saddr { 10.0.0.2/32 } meta l4proto { tcp, udp } th dport 53 accept\n"
+        ));
+        assert!(script.contains("\t\tiifname \"avalon\" ip saddr { 10.0.0.3/32 } accept\n"));
+        assert!(script.contains(
+            "\t\tiifname \"avalon\" oifname \"avalon\" ip saddr { 10.0.0.2/32 } ip daddr { 10.0.0.3/32 } tcp dport 22 accept\n"
+        ));
+        assert!(!script.contains("ip saddr { 10.0.0.3/32 } ip daddr { 10.0.0.2/32 }"));
+        assert_eq!(script.matches("\t\tiifname \"avalon\" drop\n").count(), 2);
+    }
+
+    #[test]
+    fn test_spoke_script() {
+        let config = Config::parse(NODES).unwrap();
+        let script = Firewall::new(&config, "avalon", "carol-baremetal").unwrap().unwrap().script();
+        assert!(script.contains("ip saddr { 10.0.0.1/32 } accept"));
+        assert!(script.contains("ip6 saddr { fd00::1/128 } accept"));
+        assert!(!script.contains("oifname \"avalon\" ip saddr"));
+        let script = Firewall::new(&config, "avalon", "alice-laptop").unwrap().unwrap().script();
+        assert!(!script.contains("saddr"));
+        assert!(script.contains("\t\tiifname \"avalon\" drop\n"));
+    }
+
+    #[test]
+    fn test_no_rules() {
+        let mesh =
+            &NODES[..NODES.find("[groups]").unwrap()].replace("topology = \"hub-spoke\"\nhub = \"bob-cloud\"\n", "");
+        let config = Config::parse(mesh).unwrap();
+        assert!(Firewall::new(&config, "avalon", "bob-cloud").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_relay_script() {
+        let hub_spoke = &NODES[..NODES.find("[groups]").unwrap()];
+        let config = Config::parse(hub_spoke).unwrap();
+        let script = Firewall::new(&config, "avalon", "bob-cloud").unwrap().unwrap().script();
+        assert!(script.contains(
+            "\t\tiifname \"avalon\" oifname \"avalon\" ip saddr { 10.0.0.2/32 } ip daddr { 10.0.0.3/32 } accept\n"
+        ));
+        assert!(script.contains(
+            "\t\tiifname \"avalon\" oifname \"avalon\" ip saddr { 10.0.0.3/32 } ip daddr { 10.0.0.2/32 } accept\n"
+        ));
+        assert!(!script.contains("\t\tiifname \"avalon\" oifname \"avalon\" accept\n"));
+        assert!(!script.contains("chain input"));
+        assert!(script.contains("\t\tiifname \"avalon\" drop\n"));
+
+        let mesh = hub_spoke
+            .replace("topology = \"hub-spoke\"\nhub = \"bob-cloud\"\n", "")
+            .replace("public_key = \"alice\"", "public_key = \"alice\"\nrelay_via = \"carol-baremetal\"");
+        let config = Config::parse(&mesh).unwrap();
+        let script = Firewall::new(&config, "avalon", "carol-baremetal").unwrap().unwrap().script();
+        assert!(script.contains("ip saddr { 10.0.0.1/32 } ip daddr { 10.0.0.2/32 } accept"));
+        assert!(script.contains("ip saddr { 10.0.0.2/32 } ip daddr { 10.0.0.1/32 } accept"));
+        assert!(script.contains("\t\tiifname \"avalon\" drop\n"));
+        assert!(Firewall::new(&config, "avalon", "bob-cloud").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_firewall_settings() {
+        let mesh =
+            &NODES[..NODES.find("[groups]").unwrap()].replace("topology = \"hub-spoke\"\nhub = \"bob-cloud\"\n", "");
+        let nodes = format!(
+            "{}\n[peers.bob-cloud.firewall]\nmasquerade = [\"eth0\"]\n\n[firewall]\ninbound = [\"22/tcp\"]\n",
+            mesh.replace(
+                "[peers.alice-laptop]",
+                "[peers.alice-laptop.firewall]\nforward = true\n\n[peers.alice-laptop]"
+            )
+        );
+        let config = Config::parse(&nodes).unwrap();
+        let script = Firewall::new(&config, "avalon", "bob-cloud").unwrap().unwrap().script();
+        assert!(script.contains("\t\tiifname \"avalon\" tcp dport 22 accept\n"));
+        assert!(script.contains("\t\tiifname \"avalon\" oifname \"eth0\" accept\n"));
+        assert!(script.contains("\t\toifname \"eth0\" ip saddr 10.0.0.0/24 masquerade\n"));
+        assert_eq!(script.matches("\t\tiifname \"avalon\" drop\n").count(), 2);
+        let script = Firewall::new(&config, "avalon", "alice-laptop").unwrap().unwrap().script();
+        assert!(script.contains("\t\tiifname \"avalon\" accept\n"));
+        assert!(!script.contains("masquerade"));
+        let script = Firewall::new(&config, "avalon", "carol-baremetal").unwrap().unwrap().script();
+        assert!(script.contains("\t\tiifname \"avalon\" tcp dport 22 accept\n"));
+        assert!(!script.contains("iifname \"avalon\" accept"));
+    }
+
+    #[test]
+    fn test_exit_node_and_subnet_router() {
+        let nodes = NODES
+            .replace("public_key = \"bob\"", "public_key = \"bob\"\nexit_node = true")
+            .replace("public_key = \"carol\"", "public_key = \"carol\"\nadvertised_routes = [\"192.168.1.0/24\"]");
+        let config = Config::parse(&nodes).unwrap();
+        let script = Firewall::new(&config, "avalon", "bob-cloud").unwrap().unwrap().script();
+        assert!(script.contains("\t\tiifname \"avalon\" oifname != \"avalon\" ip saddr { 10.0.0.2/32 } accept\n"));
+        assert!(script.contains("\t\toifname != \"avalon\" ip saddr 10.0.0.0/24 masquerade\n"));
+        let script = Firewall::new(&config, "avalon", "carol-baremetal").unwrap().unwrap().script();
+        assert!(script.contains(
+            "\t\tiifname \"avalon\" ip saddr { 10.0.0.2/32 } ip daddr { 192.168.1.0/24 } tcp dport 22 accept\n"
+        ));
+        assert!(script.contains("\t\tiifname \"avalon\" ip saddr { 10.0.0.1/32 } ip daddr { 192.168.1.0/24 } accept\n"));
+        assert!(script.contains("\t\tip saddr 10.0.0.0/24 ip daddr { 192.168.1.0/24 } masquerade\n"));
+        let mesh = &nodes[..nodes.find("[groups]").unwrap()];
+        let script =
+            Firewall::new(&Config::parse(mesh).unwrap(), "avalon", "carol-baremetal").unwrap().unwrap().script();
+        assert!(script.contains("\t\tiifname \"avalon\" ip daddr { 192.168.1.0/24 } accept\n"));
+        assert!(!script.contains("chain input"));
+    }
+
+    #[tokio::test]
+    async fn test_apply_and_remove() {
+        let config = Config::parse(NODES).unwrap();
+        let firewall = Firewall::new(&config, "avalon", "bob-cloud").unwrap().unwrap();
+        let fake = Arc::new(FakeRunner::new());
+        let runner = Runner::new(fake.clone());
+        firewall.apply(&runner).await.unwrap();
+        Firewall::remove("avalon", &runner).await.unwrap();
+        assert_eq!(
+            fake.command_lines(),
+            vec!["nft -f -", "nft list table inet fireguard_avalon", "nft delete table inet fireguard_avalon"]
+        );
+        assert_eq!(fake.calls()[0].input(), Some(firewall.script().as_str()));
+        let missing = Runner::new(Arc::new(FakeRunner::new().missing("nft")));
+        assert!(firewall.apply(&missing).await.is_err());
+        Firewall::remove("avalon", &missing).await.unwrap();
+    }
+}
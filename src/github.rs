@@ -1,15 +1,8 @@
-use std::env;
-use std::fs as std_fs;
-use std::os::unix::fs::PermissionsExt;
-
-use color_eyre::eyre::{bail, Result};
-use guess_host_triple::guess_host_triple;
+use color_eyre::eyre::Result;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use tokio::{fs, io::AsyncWriteExt};
 
-use crate::shell::Shell;
-use crate::utils::{build_reqwest_client, current_executable_path, NEW_VERSION_FILE, NEW_VERSION_PATH};
+use crate::release::{http_get, Release, ReleaseAsset};
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -42,8 +35,6 @@ pub struct Releases {
     #[serde(rename = "zipball_url")]
     pub zipball_url: String,
     pub body: Option<String>,
-    #[serde(skip_serializing, skip_deserializing)]
-    pub http_cli: Client,
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
@@ -92,7 +83,7 @@ pub struct Asset {
     #[serde(rename = "node_id")]
     pub node_id: String,
     pub name: String,
-    pub label: String,
+    pub label: Option<String>,
     pub uploader: Uploader,
     #[serde(rename = "content_type")]
     pub content_type: String,
@@ -147,60 +138,29 @@ pub struct Uploader {
 }
 
 impl Releases {
-    pub async fn new(url: &str) -> Result<Self> {
-        let cli = build_reqwest_client(None, None)?;
-        let mut releases = cli.get(url).send().await?.json::<Releases>().await?;
-        releases.http_cli = cli;
-        Ok(releases)
-    }
-
-    pub async fn download_for_triple(self, triple: &str) -> Result<()> {
-        let tar_file = format!("fireguard-{}.tar.gz", triple);
-        let asset = self.assets.into_iter().find(|a| a.name == tar_file);
-        match asset {
-            Some(asset) => {
-                let data = self.http_cli.get(&asset.browser_download_url).send().await?.bytes().await?;
-                info!("Downloaded new version {} from {}", self.tag_name, asset.browser_download_url);
-                let tmp_path = env::temp_dir();
-                let filename = tmp_path.join(asset.name);
-                let mut file = fs::File::create(&filename).await?;
-                file.write_all(&data).await?;
-                info!("Downloaded new version {} on {}", self.tag_name, filename.display());
-                let result = Shell::exec(
-                    "tar",
-                    &format!("xfvz {}", tar_file),
-                    Some(tmp_path.to_str().unwrap_or_default()),
-                    false,
-                )
-                .await;
-                if result.success() {
-                    fs::copy(&tmp_path.join("fireguard"), NEW_VERSION_FILE.as_path()).await?;
-                    info!("Exracted new version {} in {}", self.tag_name, NEW_VERSION_FILE.display());
-                    let mut perms = std_fs::metadata(NEW_VERSION_FILE.as_path())?.permissions();
-                    perms.set_mode(0o755);
-                    std_fs::set_permissions(NEW_VERSION_FILE.as_path(), perms)?;
-                } else {
-                    bail!(
-                        "Unable to extractract new version {} on {}: {}",
-                        self.tag_name,
-                        NEW_VERSION_FILE.display(),
-                        result.stderr()
-                    );
-                }
-            }
-            None => bail!("Unable to find a valid asset for release {} on {}", self.tag_name, triple),
+    /// Fetch the releases from `url`, either a list of releases or a single one, like the
+    /// `releases/latest` endpoint.
+    pub async fn list(client: &Client, url: &str) -> Result<Vec<Self>> {
+        let value = http_get(client, url).await?.json::<serde_json::Value>().await?;
+        if value.is_array() {
+            Ok(serde_json::from_value::<Vec<Releases>>(value)?)
+        } else {
+            Ok(vec![serde_json::from_value::<Releases>(value)?])
         }
-        Ok(())
     }
+}
 
-    pub async fn download(self) -> Result<()> {
-        let host_triple = match guess_host_triple() {
-            Some(t) => {
-                info!("Found rustc triple for current host: {}", t);
-                t
-            }
-            None => bail!("Unable to find rustc host triple for current intallation"),
-        };
-        Ok(self.download_for_triple(host_triple).await?)
+impl From<Releases> for Release {
+    fn from(releases: Releases) -> Self {
+        Release {
+            tag_name: releases.tag_name,
+            prerelease: releases.prerelease,
+            draft: releases.draft,
+            assets: releases
+                .assets
+                .into_iter()
+                .map(|asset| ReleaseAsset { name: asset.name, url: asset.browser_download_url })
+                .collect(),
+        }
     }
 }
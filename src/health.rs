@@ -0,0 +1,287 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use color_eyre::eyre::{bail, Result};
+use ipnet::Ipv4Net;
+use serde::{Deserialize, Serialize};
+use tokio::task::{self, JoinHandle};
+use tokio::time;
+
+use crate::config::HealthConfig;
+use crate::runner::Runner;
+use crate::shell::ShellCommand;
+use crate::shutdown::ShutdownSignal;
+use crate::state::{now, SharedState};
+use crate::utils::build_reqwest_client;
+use crate::wg::quick::WgPeer;
+
+/// Peer health hooks still running after this long are killed.
+const HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerHealth {
+    Unknown,
+    Up,
+    Down,
+}
+
+impl fmt::Display for PeerHealth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PeerHealth::Unknown => write!(f, "unknown"),
+            PeerHealth::Up => write!(f, "up"),
+            PeerHealth::Down => write!(f, "down"),
+        }
+    }
+}
+
+/// A settled change of the health of a peer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Change {
+    previous: PeerHealth,
+    health: PeerHealth,
+}
+
+impl Change {
+    /// The first state a peer settles in after the daemon starts or is handed over is a baseline,
+    /// not a transition to alert about.
+    fn is_baseline(&self) -> bool {
+        self.previous == PeerHealth::Unknown
+    }
+}
+
+/// Debounced health state of a single peer.
+#[derive(Debug, Clone)]
+struct PeerTracker {
+    health: PeerHealth,
+    pending: PeerHealth,
+    count: u32,
+}
+
+impl Default for PeerTracker {
+    fn default() -> Self {
+        PeerTracker { health: PeerHealth::Unknown, pending: PeerHealth::Unknown, count: 0 }
+    }
+}
+
+impl PeerTracker {
+    /// Record one check outcome, returning the change when the peer changed state after
+    /// `debounce` consecutive identical outcomes.
+    fn observe(&mut self, healthy: bool, debounce: u32) -> Option<Change> {
+        let observed = if healthy { PeerHealth::Up } else { PeerHealth::Down };
+        if observed == self.health {
+            self.count = 0;
+            return None;
+        }
+        if observed == self.pending {
+            self.count += 1;
+        } else {
+            self.pending = observed;
+            self.count = 1;
+        }
+        if self.count >= debounce.max(1) {
+            let previous = self.health;
+            self.health = observed;
+            self.count = 0;
+            Some(Change { previous, health: observed })
+        } else {
+            None
+        }
+    }
+}
+
+/// A peer health transition, passed to the user defined hooks.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerEvent {
+    pub repository: String,
+    pub peer: String,
+    pub public_key: String,
+    pub address: Option<String>,
+    pub previous: PeerHealth,
+    pub health: PeerHealth,
+    pub latest_handshake: Option<u64>,
+    pub timestamp: u64,
+}
+
+pub struct HealthChecker {
+    config: HealthConfig,
+    state: SharedState,
+    trackers: HashMap<String, PeerTracker>,
+}
+
+impl HealthChecker {
+    pub fn new(config: HealthConfig, state: SharedState) -> Self {
+        HealthChecker { config, state, trackers: HashMap::new() }
+    }
+
+    pub fn run_in_background(mut self, mut shutdown: ShutdownSignal) -> JoinHandle<()> {
+        task::spawn(async move {
+            let interval = Duration::from_secs(self.config.interval.max(1));
+            info!(
+                "Peer health checks running every {} seconds, stale after {} seconds, debounce {}",
+                interval.as_secs(),
+                self.config.stale_after,
+                self.config.debounce
+            );
+            loop {
+                tokio::select! {
+                    _ = time::sleep(interval) => {},
+                    _ = shutdown.wait() => break,
+                }
+                if let Err(e) = self.check().await {
+                    error!("Unable to check peers health: {}", e);
+                }
+            }
+        })
+    }
+
+    async fn check(&mut self) -> Result<()> {
+        let (repository, driver, runner) = {
+            let state = self.state.read();
+            (state.repository.clone(), state.wg_driver, state.runner.clone())
+        };
+        let peers = driver.tunnel(&repository, &runner)?.peers().await?;
+        let now = now();
+        for peer in peers {
+            let address = self.state.read().peer_address(&peer.public_key);
+            let mut healthy = self.handshake_fresh(&peer, now);
+            if !healthy && self.config.probe {
+                if let Some(address) = address.as_ref() {
+                    healthy = Self::probe(&runner, address).await;
+                }
+            }
+            let tracker = self.trackers.entry(peer.public_key.clone()).or_default();
+            if let Some(change) = tracker.observe(healthy, self.config.debounce) {
+                let peer_name =
+                    self.state.read().peer_name(&peer.public_key).unwrap_or_else(|| peer.public_key.clone());
+                self.state.write().peer_health.insert(peer_name.clone(), change.health == PeerHealth::Up);
+                let event = PeerEvent {
+                    repository: repository.clone(),
+                    peer: peer_name,
+                    public_key: peer.public_key.clone(),
+                    address,
+                    previous: change.previous,
+                    health: change.health,
+                    latest_handshake: peer.latest_handshake,
+                    timestamp: now,
+                };
+                if change.is_baseline() {
+                    info!("Peer {} is {}", event.peer, event.health);
+                } else {
+                    warn!("Peer {} changed state from {} to {}", event.peer, event.previous, event.health);
+                    self.fire_hooks(&runner, &event).await;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handshake_fresh(&self, peer: &WgPeer, now: u64) -> bool {
+        match peer.latest_handshake {
+            Some(handshake) => now.saturating_sub(handshake) <= self.config.stale_after,
+            None => false,
+        }
+    }
+
+    async fn probe(runner: &Runner, address: &str) -> bool {
+        match address.parse::<Ipv4Net>() {
+            Ok(address) => {
+                runner.exec("ping", &["-c", "1", "-W", "2", &address.addr().to_string()], None, true).await.success()
+            }
+            Err(e) => {
+                error!("Unable to parse peer address {}: {}", address, e);
+                false
+            }
+        }
+    }
+
+    async fn fire_hooks(&self, runner: &Runner, event: &PeerEvent) {
+        for command in self.config.commands.iter() {
+            if let Err(e) = Self::run_command(runner, command, event).await {
+                error!("Peer health hook `{}` failed: {}", command, e);
+            }
+        }
+        for webhook in self.config.webhooks.iter() {
+            if let Err(e) = Self::call_webhook(webhook, event).await {
+                error!("Peer health webhook {} failed: {}", webhook, e);
+            }
+        }
+    }
+
+    async fn run_command(runner: &Runner, command: &str, event: &PeerEvent) -> Result<()> {
+        info!("Running peer health hook `{}` for peer {}", command, event.peer);
+        let (health, previous) = (event.health.to_string(), event.previous.to_string());
+        let env: HashMap<&str, &str> = vec![
+            ("FIREGUARD_REPOSITORY", event.repository.as_str()),
+            ("FIREGUARD_PEER", event.peer.as_str()),
+            ("FIREGUARD_PEER_PUBLIC_KEY", event.public_key.as_str()),
+            ("FIREGUARD_PEER_ADDRESS", event.address.as_deref().unwrap_or_default()),
+            ("FIREGUARD_PEER_STATE", health.as_str()),
+            ("FIREGUARD_PEER_PREVIOUS_STATE", previous.as_str()),
+        ]
+        .into_iter()
+        .collect();
+        // A hanging hook would hold the next health check back.
+        let hook = ShellCommand::shell(command).envs(&env).timeout(HOOK_TIMEOUT);
+        let result = runner.run(&hook).await;
+        if result.success() {
+            Ok(())
+        } else {
+            bail!("{}", result.stderr().trim())
+        }
+    }
+
+    async fn call_webhook(url: &str, event: &PeerEvent) -> Result<()> {
+        info!("Calling peer health webhook {} for peer {}", url, event.peer);
+        let client = build_reqwest_client(None, None)?;
+        client.post(url).json(event).send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(previous: PeerHealth, health: PeerHealth) -> Option<Change> {
+        Some(Change { previous, health })
+    }
+
+    #[test]
+    fn test_tracker_debounces_transitions() {
+        let mut tracker = PeerTracker::default();
+        assert_eq!(tracker.observe(true, 2), None);
+        assert_eq!(tracker.observe(true, 2), change(PeerHealth::Unknown, PeerHealth::Up));
+        assert_eq!(tracker.observe(true, 2), None);
+        // A single failed check is not enough to bring the peer down.
+        assert_eq!(tracker.observe(false, 2), None);
+        assert_eq!(tracker.observe(true, 2), None);
+        assert_eq!(tracker.observe(false, 2), None);
+        assert_eq!(tracker.observe(false, 2), change(PeerHealth::Up, PeerHealth::Down));
+        assert_eq!(tracker.health, PeerHealth::Down);
+    }
+
+    #[test]
+    fn test_tracker_without_debounce() {
+        let mut tracker = PeerTracker::default();
+        assert_eq!(tracker.observe(false, 0), change(PeerHealth::Unknown, PeerHealth::Down));
+        assert_eq!(tracker.observe(true, 0), change(PeerHealth::Down, PeerHealth::Up));
+    }
+
+    #[test]
+    fn test_tracker_first_state_is_a_baseline() {
+        // Peers already offline when the daemon starts do not raise alerts.
+        let mut tracker = PeerTracker::default();
+        assert_eq!(tracker.observe(false, 2), None);
+        let down = tracker.observe(false, 2).unwrap();
+        assert_eq!(down.health, PeerHealth::Down);
+        assert!(down.is_baseline());
+        tracker.observe(true, 2);
+        let up = tracker.observe(true, 2).unwrap();
+        assert!(!up.is_baseline());
+        let mut tracker = PeerTracker::default();
+        assert!(tracker.observe(true, 0).unwrap().is_baseline());
+    }
+}
@@ -0,0 +1,482 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{bail, eyre, Result};
+use tokio::fs;
+
+use crate::runner::Runner;
+use crate::wg::backend::{kernel_module_loaded, userspace_implementation};
+
+const OS_RELEASE: &str = "/etc/os-release";
+const SYSCTL_CONF: &str = "/etc/sysctl.d/99-fireguard.conf";
+/// First kernel release shipping Wireguard in mainline.
+const MAINLINE_KERNEL: (u32, u32) = (5, 6);
+const IPV4_FORWARD: &str = "net.ipv4.ip_forward";
+const IPV6_FORWARD: &str = "net.ipv6.conf.all.forwarding";
+/// Binaries Fireguard needs on the host and the package shipping them, named the same on every
+/// supported distribution.
+const TOOLS: &[(&str, &str)] = &[("wg", "wireguard-tools"), ("git", "git"), ("nft", "nftables")];
+
+/// Distribution identification from `/etc/os-release`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OsRelease {
+    pub id: String,
+    pub id_like: Vec<String>,
+    pub name: String,
+}
+
+impl OsRelease {
+    pub fn parse(content: &str) -> Self {
+        let fields: HashMap<&str, &str> = content
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.trim(), value.trim().trim_matches('"').trim_matches('\'')))
+            .collect();
+        let field = |key: &str| fields.get(key).map(|value| value.to_string()).unwrap_or_default();
+        OsRelease {
+            id: field("ID"),
+            id_like: field("ID_LIKE").split_whitespace().map(str::to_string).collect(),
+            name: fields.get("PRETTY_NAME").or_else(|| fields.get("NAME")).unwrap_or(&"Linux").to_string(),
+        }
+    }
+
+    pub async fn load() -> Result<Self> {
+        match fs::read_to_string(OS_RELEASE).await {
+            Ok(content) => Ok(OsRelease::parse(&content)),
+            Err(e) => bail!("Unable to read {} to detect the distribution: {}", OS_RELEASE, e),
+        }
+    }
+}
+
+/// Package manager of the host distribution.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PackageManager {
+    Apt,
+    Dnf,
+    Apk,
+    Pacman,
+}
+
+impl fmt::Display for PackageManager {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.binary())
+    }
+}
+
+impl PackageManager {
+    /// Package manager of the distribution, or of the first one it derives from.
+    pub fn detect(os: &OsRelease) -> Result<Self> {
+        for id in std::iter::once(&os.id).chain(os.id_like.iter()) {
+            match id.as_str() {
+                "debian" | "ubuntu" | "raspbian" => return Ok(PackageManager::Apt),
+                "fedora" | "rhel" | "centos" | "rocky" | "almalinux" => return Ok(PackageManager::Dnf),
+                "alpine" => return Ok(PackageManager::Apk),
+                "arch" | "manjaro" => return Ok(PackageManager::Pacman),
+                _ => continue,
+            }
+        }
+        bail!("Unsupported distribution {}, Fireguard supports apt, dnf, apk and pacman based ones", os.name)
+    }
+
+    pub fn binary(&self) -> &'static str {
+        match self {
+            PackageManager::Apt => "apt-get",
+            PackageManager::Dnf => "dnf",
+            PackageManager::Apk => "apk",
+            PackageManager::Pacman => "pacman",
+        }
+    }
+
+    /// Arguments refreshing the package index, for managers not doing it on install. Pacman is
+    /// left alone, syncing the database without upgrading leads to partial upgrades.
+    fn refresh_args(&self) -> Option<&'static str> {
+        match self {
+            PackageManager::Apt => Some("update"),
+            PackageManager::Apk => Some("update"),
+            PackageManager::Dnf | PackageManager::Pacman => None,
+        }
+    }
+
+    fn install_args(&self, packages: &[String]) -> Vec<String> {
+        let install: &[&str] = match self {
+            PackageManager::Apt => &["-y", "install"],
+            PackageManager::Dnf => &["-y", "install"],
+            PackageManager::Apk => &["add"],
+            PackageManager::Pacman => &["-S", "--needed", "--noconfirm"],
+        };
+        install.iter().map(|arg| arg.to_string()).chain(packages.iter().cloned()).collect()
+    }
+
+    /// Packages building or shipping the Wireguard module for kernels predating mainline support.
+    pub fn module_packages(&self, kernel: &Kernel) -> Vec<String> {
+        match self {
+            PackageManager::Apt if kernel.raspberry() => {
+                vec!["wireguard-dkms".to_string(), "raspberrypi-kernel-headers".to_string()]
+            }
+            PackageManager::Apt => vec!["wireguard-dkms".to_string(), format!("linux-headers-{}", kernel.release)],
+            // Shipped prebuilt for EL kernels by EPEL and ELRepo.
+            PackageManager::Dnf => vec!["kmod-wireguard".to_string()],
+            PackageManager::Apk => vec![format!("wireguard-{}", kernel.flavor())],
+            PackageManager::Pacman => vec!["wireguard-dkms".to_string(), "linux-headers".to_string()],
+        }
+    }
+}
+
+/// Running kernel release, like 5.10.0-8-amd64.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Kernel {
+    pub release: String,
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl Kernel {
+    pub fn parse(release: &str) -> Result<Self> {
+        let release = release.trim();
+        let mut numbers = release.split(|c: char| !c.is_ascii_digit()).map(str::parse::<u32>);
+        match (numbers.next(), numbers.next()) {
+            (Some(Ok(major)), Some(Ok(minor))) => Ok(Kernel { release: release.to_string(), major, minor }),
+            _ => Err(eyre!("Unable to parse kernel release {}", release)),
+        }
+    }
+
+    pub async fn running(runner: &Runner) -> Result<Self> {
+        let uname = runner.exec("uname", &["-r"], None, true).await;
+        if !uname.success() {
+            bail!("Unable to detect the running kernel: {}", uname.stderr());
+        }
+        Kernel::parse(uname.stdout())
+    }
+
+    /// Whether Wireguard is part of the kernel sources.
+    pub fn mainline_wireguard(&self) -> bool {
+        (self.major, self.minor) >= MAINLINE_KERNEL
+    }
+
+    /// Raspberry Pi Foundation kernels, which come with their own headers package.
+    pub fn raspberry(&self) -> bool {
+        ["v7+", "v7l+", "v8+"].iter().any(|suffix| self.release.ends_with(suffix))
+    }
+
+    /// Alpine kernel flavor, like lts or virt.
+    pub fn flavor(&self) -> &str {
+        self.release.rsplit('-').next().unwrap_or("lts")
+    }
+}
+
+/// Current value of a sysctl, if it exists.
+async fn read_sysctl(key: &str) -> Option<String> {
+    let path = Path::new("/proc/sys").join(key.replace('.', "/"));
+    fs::read_to_string(path).await.ok().map(|value| value.trim().to_string())
+}
+
+/// State of the host relevant to running Fireguard.
+#[derive(Debug, Clone)]
+pub struct HostFacts {
+    pub os: OsRelease,
+    pub kernel: Kernel,
+    /// Whether the Wireguard module is loaded, built in or installed.
+    pub module: bool,
+    /// Whether a userspace Wireguard implementation is installed.
+    pub userspace: bool,
+    /// Binaries from `TOOLS` not found on the PATH.
+    pub missing_tools: Vec<String>,
+    /// Whether the kernel has IPv6 enabled.
+    pub ipv6: bool,
+    /// Current values of the sysctls Fireguard cares about.
+    pub sysctls: HashMap<String, String>,
+    /// Current content of the Fireguard sysctl configuration.
+    pub sysctl_conf: Option<String>,
+    /// Directories Fireguard expects which do not exist yet.
+    pub missing_dirs: Vec<PathBuf>,
+}
+
+impl HostFacts {
+    pub async fn gather(config_dir: &str, runner: &Runner) -> Result<Self> {
+        if std::env::consts::OS != "linux" {
+            bail!("Unfortunately {} is not yet supported", std::env::consts::OS);
+        }
+        let os = OsRelease::load().await?;
+        let kernel = Kernel::running(runner).await?;
+        // A dry run of modprobe finds the module without loading it.
+        let module =
+            kernel_module_loaded() || runner.exec("modprobe", &["-n", "-q", "wireguard"], None, true).await.success();
+        let ipv6 = Path::new("/proc/sys/net/ipv6").exists();
+        let mut sysctls = HashMap::new();
+        for key in &[IPV4_FORWARD, IPV6_FORWARD] {
+            if let Some(value) = read_sysctl(key).await {
+                sysctls.insert(key.to_string(), value);
+            }
+        }
+        if ipv6 {
+            let mut interfaces = fs::read_dir("/proc/sys/net/ipv6/conf").await?;
+            while let Some(interface) = interfaces.next_entry().await? {
+                let name = interface.file_name().to_string_lossy().to_string();
+                // Interface names with dots, like VLANs, are spelled with slashes in sysctl keys.
+                if name == "all" || name == "lo" || name.contains('.') {
+                    continue;
+                }
+                let key = accept_ra(&name);
+                if let Some(value) = read_sysctl(&key).await {
+                    sysctls.insert(key, value);
+                }
+            }
+        }
+        Ok(HostFacts {
+            os,
+            kernel,
+            module,
+            userspace: userspace_implementation().is_some(),
+            missing_tools: TOOLS
+                .iter()
+                .filter(|(binary, _)| !runner.runnable(binary))
+                .map(|(binary, _)| binary.to_string())
+                .collect(),
+            ipv6,
+            sysctls,
+            sysctl_conf: fs::read_to_string(SYSCTL_CONF).await.ok(),
+            missing_dirs: vec![PathBuf::from(config_dir)].into_iter().filter(|dir| !dir.is_dir()).collect(),
+        })
+    }
+}
+
+fn accept_ra(interface: &str) -> String {
+    format!("net.ipv6.conf.{}.accept_ra", interface)
+}
+
+/// Sysctls to set for forwarding through the tunnel, in order. Forwarding stops interfaces with
+/// accept_ra set to 1 from autoconfiguring IPv6, 2 keeps router advertisements working.
+pub fn forwarding_sysctls(facts: &HostFacts, ipv6: bool) -> Vec<(String, String)> {
+    let mut sysctls = vec![(IPV4_FORWARD.to_string(), "1".to_string())];
+    if ipv6 && facts.ipv6 {
+        let mut accepting: Vec<&String> = facts
+            .sysctls
+            .iter()
+            .filter(|(key, value)| key.ends_with(".accept_ra") && value.as_str() == "1")
+            .map(|(key, _)| key)
+            .collect();
+        accepting.sort();
+        sysctls.extend(accepting.into_iter().map(|key| (key.clone(), "2".to_string())));
+        sysctls.push((IPV6_FORWARD.to_string(), "1".to_string()));
+    }
+    sysctls
+}
+
+/// Content of the sysctl configuration applying `sysctls` at boot. Interface specific settings are
+/// left out, the interfaces might not exist yet when it is applied.
+fn sysctl_conf(sysctls: &[(String, String)]) -> String {
+    let mut conf = "# Managed by Fireguard, forwarding for the Wireguard tunnels\n".to_string();
+    for (key, value) in sysctls.iter() {
+        let interface = key.strip_prefix("net.ipv6.conf.").and_then(|rest| rest.split('.').next());
+        if interface.is_none() || interface == Some("all") || interface == Some("default") {
+            conf.push_str(&format!("{} = {}\n", key, value));
+        }
+    }
+    conf
+}
+
+/// Step of the host preparation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostAction {
+    RefreshPackages(PackageManager),
+    InstallPackages(PackageManager, Vec<String>),
+    LoadModule,
+    Sysctl(String, String),
+    PersistSysctls(String),
+    CreateDir(PathBuf),
+}
+
+impl fmt::Display for HostAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HostAction::RefreshPackages(manager) => {
+                write!(f, "{} {}", manager, manager.refresh_args().unwrap_or_default())
+            }
+            HostAction::InstallPackages(manager, packages) => {
+                write!(f, "{} {}", manager, manager.install_args(packages).join(" "))
+            }
+            HostAction::LoadModule => write!(f, "modprobe wireguard"),
+            HostAction::Sysctl(key, value) => write!(f, "sysctl -w {}={}", key, value),
+            HostAction::PersistSysctls(_) => write!(f, "write forwarding sysctls to {}", SYSCTL_CONF),
+            HostAction::CreateDir(dir) => write!(f, "mkdir -p {}", dir.display()),
+        }
+    }
+}
+
+impl HostAction {
+    pub async fn run(&self, runner: &Runner) -> Result<()> {
+        let result = match self {
+            HostAction::RefreshPackages(manager) => {
+                runner.exec(manager.binary(), &[manager.refresh_args().unwrap_or_default()], None, false).await
+            }
+            HostAction::InstallPackages(manager, packages) => {
+                runner.exec(manager.binary(), &manager.install_args(packages), None, false).await
+            }
+            HostAction::LoadModule => runner.exec("modprobe", &["wireguard"], None, false).await,
+            HostAction::Sysctl(key, value) => {
+                runner.exec("sysctl", &["-w", &format!("{}={}", key, value)], None, true).await
+            }
+            HostAction::PersistSysctls(conf) => {
+                fs::write(SYSCTL_CONF, conf).await?;
+                return Ok(());
+            }
+            HostAction::CreateDir(dir) => {
+                fs::create_dir_all(dir).await?;
+                return Ok(());
+            }
+        };
+        if result.success() {
+            Ok(())
+        } else {
+            bail!("Unable to {}: {}", self, result.stderr().trim())
+        }
+    }
+}
+
+/// Actions bringing the host to a state able to run Fireguard, empty if it already is. Nothing is
+/// installed for the module when the kernel ships it, or should.
+pub fn plan(facts: &HostFacts, ipv6: bool) -> Result<Vec<HostAction>> {
+    let manager = PackageManager::detect(&facts.os)?;
+    let mut packages: Vec<String> = TOOLS
+        .iter()
+        .filter(|(binary, _)| facts.missing_tools.iter().any(|missing| missing == binary))
+        .map(|(_, package)| package.to_string())
+        .collect();
+    let build_module = !facts.module && !facts.kernel.mainline_wireguard();
+    if build_module {
+        packages.extend(manager.module_packages(&facts.kernel));
+    } else if !facts.module && !facts.userspace {
+        warn!(
+            "Kernel {} is built without Wireguard, the tunnel needs a userspace implementation like boringtun-cli",
+            facts.kernel.release
+        );
+    }
+    let mut actions = vec![];
+    if !packages.is_empty() {
+        if manager.refresh_args().is_some() {
+            actions.push(HostAction::RefreshPackages(manager));
+        }
+        actions.push(HostAction::InstallPackages(manager, packages));
+    }
+    if build_module {
+        actions.push(HostAction::LoadModule);
+    }
+    let sysctls = forwarding_sysctls(facts, ipv6);
+    for (key, value) in sysctls.iter() {
+        if facts.sysctls.get(key) != Some(value) {
+            actions.push(HostAction::Sysctl(key.clone(), value.clone()));
+        }
+    }
+    let conf = sysctl_conf(&sysctls);
+    if facts.sysctl_conf.as_ref() != Some(&conf) {
+        actions.push(HostAction::PersistSysctls(conf));
+    }
+    actions.extend(facts.missing_dirs.iter().cloned().map(HostAction::CreateDir));
+    Ok(actions)
+}
+
+/// Enable IPv4 forwarding for the running system only, for containers sharing the host network.
+pub async fn enable_forwarding(runner: &Runner) -> Result<()> {
+    if std::env::consts::OS != "linux" {
+        bail!("Unfortunately {} is not yet supported", std::env::consts::OS);
+    }
+    if read_sysctl(IPV4_FORWARD).await.as_deref() == Some("1") {
+        info!("IPv4 forwarding already enabled");
+        return Ok(());
+    }
+    info!("IPv4 forwarding disabled, trying to enable it");
+    HostAction::Sysctl(IPV4_FORWARD.to_string(), "1".to_string()).run(runner).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host_facts(os_release: &str, kernel: &str) -> HostFacts {
+        HostFacts {
+            os: OsRelease::parse(os_release),
+            kernel: Kernel::parse(kernel).unwrap(),
+            module: false,
+            userspace: false,
+            missing_tools: vec!["wg".to_string()],
+            ipv6: true,
+            sysctls: vec![
+                (IPV4_FORWARD.to_string(), "0".to_string()),
+                (IPV6_FORWARD.to_string(), "0".to_string()),
+                (accept_ra("eth0"), "1".to_string()),
+                (accept_ra("default"), "1".to_string()),
+                (accept_ra("wlan0"), "0".to_string()),
+            ]
+            .into_iter()
+            .collect(),
+            sysctl_conf: None,
+            missing_dirs: vec![],
+        }
+    }
+
+    #[test]
+    fn test_detect_distribution() {
+        let raspbian =
+            OsRelease::parse("PRETTY_NAME=\"Raspbian GNU/Linux 10 (buster)\"\nID=raspbian\nID_LIKE=debian\n");
+        assert_eq!(raspbian.name, "Raspbian GNU/Linux 10 (buster)");
+        assert_eq!(PackageManager::detect(&raspbian).unwrap(), PackageManager::Apt);
+        let rocky = OsRelease::parse("NAME=\"Rocky Linux\"\nID=\"rocky\"\nID_LIKE=\"rhel centos fedora\"\n");
+        assert_eq!(PackageManager::detect(&rocky).unwrap(), PackageManager::Dnf);
+        assert_eq!(PackageManager::detect(&OsRelease::parse("ID=alpine")).unwrap(), PackageManager::Apk);
+        assert_eq!(
+            PackageManager::detect(&OsRelease::parse("ID=endeavouros\nID_LIKE=arch")).unwrap(),
+            PackageManager::Pacman
+        );
+        assert!(PackageManager::detect(&OsRelease::parse("ID=gentoo")).is_err());
+    }
+
+    #[test]
+    fn test_kernel() {
+        let kernel = Kernel::parse("5.10.0-8-amd64\n").unwrap();
+        assert_eq!((kernel.major, kernel.minor), (5, 10));
+        assert!(kernel.mainline_wireguard());
+        assert!(!Kernel::parse("4.19.0-17-amd64").unwrap().mainline_wireguard());
+        assert!(Kernel::parse("5.4.83-v7l+").unwrap().raspberry());
+        assert_eq!(Kernel::parse("5.4.43-1-virt").unwrap().flavor(), "virt");
+        assert!(Kernel::parse("unknown").is_err());
+    }
+
+    #[test]
+    fn test_plan_old_kernel() {
+        let actions = plan(&host_facts("ID=debian", "4.19.0-17-amd64"), true).unwrap();
+        assert_eq!(actions[0].to_string(), "apt-get update");
+        assert_eq!(
+            actions[1].to_string(),
+            "apt-get -y install wireguard-tools wireguard-dkms linux-headers-4.19.0-17-amd64"
+        );
+        assert_eq!(actions[2], HostAction::LoadModule);
+        assert_eq!(
+            actions[3..7].iter().map(|action| action.to_string()).collect::<Vec<_>>(),
+            vec![
+                "sysctl -w net.ipv4.ip_forward=1",
+                "sysctl -w net.ipv6.conf.default.accept_ra=2",
+                "sysctl -w net.ipv6.conf.eth0.accept_ra=2",
+                "sysctl -w net.ipv6.conf.all.forwarding=1",
+            ]
+        );
+        let conf = "# Managed by Fireguard, forwarding for the Wireguard tunnels\nnet.ipv4.ip_forward = 1\n\
+                    net.ipv6.conf.default.accept_ra = 2\nnet.ipv6.conf.all.forwarding = 1\n";
+        assert_eq!(actions[7], HostAction::PersistSysctls(conf.to_string()));
+        assert_eq!(actions.len(), 8);
+    }
+
+    #[test]
+    fn test_plan_mainline_kernel() {
+        let mut facts = host_facts("ID=fedora", "5.14.10-300.fc35.x86_64");
+        facts.missing_tools.clear();
+        let actions = plan(&facts, false).unwrap();
+        assert_eq!(actions[0], HostAction::Sysctl(IPV4_FORWARD.to_string(), "1".to_string()));
+        assert_eq!(actions.len(), 2);
+        facts.sysctls.insert(IPV4_FORWARD.to_string(), "1".to_string());
+        facts.sysctl_conf = Some(sysctl_conf(&forwarding_sysctls(&facts, false)));
+        assert!(plan(&facts, false).unwrap().is_empty());
+        let alpine = host_facts("ID=alpine", "4.19.80-0-lts");
+        assert_eq!(plan(&alpine, false).unwrap()[1].to_string(), "apk add wireguard-tools wireguard-lts");
+    }
+}
@@ -1,21 +1,25 @@
 use std::net::Ipv4Addr;
 
-use color_eyre::eyre::{bail, Result};
 use ipnet::Ipv4Net;
 use rand::seq::SliceRandom;
 
+use crate::error::{FireguardError, Result};
+
+/// Tunnel addresses of a network, allocating random free ones to new peers.
 #[derive(Debug, Default)]
 pub struct IpPool {
     subnet: Ipv4Net,
-    subnet_lenght: usize,
     free_list: Vec<Ipv4Addr>,
     used_list: Vec<Ipv4Addr>,
 }
 
 impl IpPool {
+    /// Pool of `network`, like 10.123.123.0/24, with the `peers` addresses already taken.
     pub fn new(network: &str, peers: Vec<String>) -> Result<Self> {
-        let subnet = network.parse::<Ipv4Net>()?;
-        info!("Creating new IPv4 pool for subnet {}, capacity {} out of {}", network, peers.len(), subnet.prefix_len());
+        let subnet = network
+            .parse::<Ipv4Net>()
+            .map_err(|e| FireguardError::config(None, format!("network {}: {}", network, e)))?;
+        info!("Creating new IPv4 pool for subnet {}, {} addresses in use", network, peers.len());
         let used_list: Vec<Ipv4Addr> = peers
             .iter()
             .map(|x| match x.parse::<Ipv4Net>() {
@@ -30,25 +34,23 @@ impl IpPool {
         let mut free_list: Vec<Ipv4Addr> = subnet.hosts().collect::<Vec<Ipv4Addr>>();
         free_list.retain(|x| !used_list.contains(x));
         debug!("IPv4 pool free list: {:?}", free_list);
-        Ok(IpPool { subnet, subnet_lenght: subnet.prefix_len() as usize, free_list, used_list })
+        Ok(IpPool { subnet, free_list, used_list })
     }
 
+    /// Allocate a free address, failing when the network is full and needs a larger subnet.
     pub fn ip(&mut self) -> Result<Ipv4Addr> {
-        if self.used_list.len() >= self.subnet_lenght {
-            bail!("The pool is full and cannot take more hosts, you need to scale up you subnet size");
-        }
         let free_ip = match self.free_list.choose(&mut rand::thread_rng()) {
             Some(free_ip) => free_ip,
-            None => bail!("Unable to find a free IPv4 in the pool"),
+            None => return Err(FireguardError::PoolExhausted),
         };
         let free_ip = *free_ip;
         self.free_list.retain(|&x| x != free_ip);
         self.used_list.push(free_ip);
         info!(
-            "Allocating new free IPv4 {} from the IP pool, capacity {} out of {}",
+            "Allocating new free IPv4 {} from the {} IP pool, {} addresses left",
             free_ip,
-            self.used_list.len(),
-            self.subnet.prefix_len()
+            self.subnet,
+            self.free_list.len()
         );
         Ok(free_ip)
     }
@@ -76,7 +78,7 @@ mod tests {
             assert_eq!(pool.free_list.len(), free_len);
             assert_eq!(pool.used_list.len(), used_len);
             for peer in peers {
-                let ipaddr_peer = &Ipv4Addr::from_str(&(peer.splitn(2, "/").collect::<Vec<&str>>())[0]).unwrap();
+                let ipaddr_peer = &Ipv4Addr::from_str((peer.splitn(2, "/").collect::<Vec<&str>>())[0]).unwrap();
                 assert!(!pool.free_list.contains(ipaddr_peer));
             }
         }
@@ -93,6 +95,14 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_allocate_whole_subnet() {
+        let mut pool = IpPool::new("192.168.1.0/28", vec!["192.168.1.1/24".to_string()]).unwrap();
+        let ips = (0..13).map(|_| pool.ip().unwrap()).collect::<Vec<Ipv4Addr>>();
+        assert!(!ips.contains(&Ipv4Addr::new(192, 168, 1, 1)));
+        assert!(matches!(pool.ip(), Err(FireguardError::PoolExhausted)));
+    }
+
     #[test]
     fn test_full_pool_bails() {
         let mut pool =
@@ -1,40 +1,131 @@
+//! Fireguard, a Wireguard based trust network.
+//!
+//! Besides the `fireguard` binary, the crate can be embedded in other tools to manage trust
+//! repositories without shelling out:
+//!
+//! * [`config::Config`] loads and saves the `nodes.toml` of a repository and its [`config::Peer`]s.
+//! * [`ip::IpPool`] allocates tunnel addresses to new peers.
+//! * [`wg::WgConfig`] renders the Wireguard configuration of a peer.
+//! * [`wg::WgKeys`] generates key pairs and [`wg::WgDriver`] controls the tunnels.
+//!
+//! Errors are [`FireguardError`]s, each kind with its own process exit code. External commands go
+//! through a [`Runner`], which [`FakeRunner`] replaces in tests.
+//!
+//! ```
+//! use fireguard::config::{Config, Peer};
+//! use fireguard::ip::IpPool;
+//! use fireguard::wg::WgConfig;
+//!
+//! # fn main() -> Result<(), fireguard::FireguardError> {
+//! let mut config = Config::parse(
+//!     r#"
+//! repository = "avalon"
+//! network = "10.0.0.0/24"
+//! domain = "avalon.local"
+//!
+//! [peers.alice-laptop]
+//! username = "alice"
+//! peername = "laptop"
+//! address = "10.0.0.1/24"
+//! listen_port = 51820
+//! public_key = "zaIzCHJm7WkYyuw7+wCwLUmB1xzs0ZPBQ3XEw5GhCk8="
+//! allowed_ips = ["10.0.0.1/32"]
+//! persistent_keepalive = 25
+//! mtu = 1420
+//! "#,
+//! )?;
+//!
+//! let mut pool = IpPool::new(&config.network, config.get_peers_ips())?;
+//! let address = pool.ip()?;
+//! let peer = Peer {
+//!     username: "bob".to_string(),
+//!     peername: "cloud".to_string(),
+//!     address: format!("{}/24", address),
+//!     listen_port: 51820,
+//!     public_key: "lGjS9Zf0yAQnXbHcDHsOGFmVNK8y5rbQnkKQ0z1o/1A=".to_string(),
+//!     allowed_ips: vec![format!("{}/32", address)],
+//!     mtu: 1420,
+//!     ..Default::default()
+//! };
+//! config.add_peer("bob-cloud", peer);
+//!
+//! let private_key = "YAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=";
+//! let wg_config = WgConfig::new(&config, "avalon", "bob", "cloud", private_key)?;
+//! assert!(wg_config.render_to_string()?.contains("[Interface]"));
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Keys come from `WgKeys::generate(&Runner::default()).await?`, and the rendered configuration
+//! brings the tunnel up with `WgDriver::Netlink.tunnel("avalon", &runner)?.up(&path).await?`.
 extern crate async_trait;
+extern crate base64;
 extern crate chrono;
 #[macro_use]
 extern crate clap;
 extern crate color_eyre;
 extern crate env_logger;
+extern crate flate2;
 extern crate fork;
 extern crate futures;
 extern crate futures_util;
 extern crate guess_host_triple;
+extern crate hex;
+extern crate hmac;
 extern crate ipnet;
 #[macro_use]
 extern crate lazy_static;
+extern crate libc;
 #[macro_use]
 extern crate log;
+extern crate minisign_verify;
+extern crate netlink_sys;
 extern crate nix;
 extern crate parking_lot;
 extern crate pretty_env_logger;
 extern crate rand;
 extern crate read_input;
 extern crate reqwest;
+extern crate semver;
 extern crate serde;
+extern crate serde_json;
+extern crate sha2;
 extern crate signal_hook;
 extern crate signal_hook_tokio;
+extern crate tar;
+extern crate tempfile;
 extern crate tera;
 extern crate tokio;
 extern crate toml;
 extern crate whoami;
+extern crate x25519_dalek;
 
 mod cmd;
-mod config;
+pub mod config;
+mod container;
+mod control;
+pub mod endpoint;
+pub mod error;
+pub mod firewall;
 mod github;
-mod ip;
+mod health;
+mod host;
+pub mod ip;
+mod metrics;
+pub mod policy;
+mod release;
+mod rendezvous;
+mod roaming;
+mod runner;
 mod shell;
+mod shutdown;
+mod state;
+mod systemd;
+pub mod topology;
 mod upgrade;
 mod utils;
-mod wg;
+mod verify;
+pub mod wg;
 
 use std::env;
 
@@ -44,11 +135,31 @@ use color_eyre::eyre::Result;
 use cmd::Fireguard;
 use utils::setup_logging;
 
+pub use error::{exit_code, FireguardError};
+pub use runner::{CommandRunner, FakeRunner, Runner, SystemRunner};
+pub use shell::{ShellCommand, ShellResult};
+
 pub async fn run() -> Result<()> {
     let version = env!("CARGO_PKG_VERSION");
-    let mut cmd = Fireguard::parse();
+    let args: Vec<String> = env::args().collect();
+    let cmd = Fireguard::parse_from(&args);
     setup_logging(cmd.debug);
     info!("Running Fireguard {}", version);
+    execute(cmd, &args, Runner::default()).await
+}
+
+/// Run the Fireguard command line `args`, program name included, with the external commands going
+/// through `runner`. Logging is left to the caller.
+pub async fn run_with(args: &[&str], runner: Runner) -> Result<()> {
+    let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+    let cmd = Fireguard::try_parse_from(&args)?;
+    execute(cmd, &args, runner).await
+}
+
+async fn execute(mut cmd: Fireguard, args: &[String], runner: Runner) -> Result<()> {
+    // The program name is dropped, so the arguments can be passed to another binary.
+    cmd.args = args.iter().skip(1).cloned().collect();
+    cmd.runner = runner;
     debug!("{:#?}", cmd);
-    Ok(cmd.exec().await?)
+    cmd.exec().await
 }
@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use color_eyre::eyre::Result;
+use parking_lot::Mutex;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::{self, JoinHandle};
+
+use crate::shutdown::ShutdownSignal;
+use crate::state::{now, DaemonState, SharedState};
+use crate::wg::quick::WgPeer;
+
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Last endpoint seen for a peer and how many times it changed since the daemon started.
+#[derive(Debug, Default, Clone)]
+struct EndpointTracker {
+    endpoint: Option<String>,
+    changes: u64,
+}
+
+/// Prometheus exporter for the daemon state and the live Wireguard peers.
+#[derive(Clone)]
+pub struct Metrics {
+    state: SharedState,
+    endpoints: Arc<Mutex<HashMap<String, EndpointTracker>>>,
+}
+
+impl Metrics {
+    pub fn new(state: SharedState) -> Self {
+        Metrics { state, endpoints: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    pub async fn run_in_background(self, address: &str, mut shutdown: ShutdownSignal) -> Result<JoinHandle<()>> {
+        let address = address.parse::<SocketAddr>()?;
+        let listener = TcpListener::bind(address).await?;
+        info!("Prometheus metrics exposed on http://{}/metrics", address);
+        Ok(task::spawn(async move {
+            loop {
+                let accepted = tokio::select! {
+                    accepted = listener.accept() => accepted,
+                    _ = shutdown.wait() => break,
+                };
+                match accepted {
+                    Ok((stream, peer)) => {
+                        let metrics = self.clone();
+                        task::spawn(async move {
+                            if let Err(e) = metrics.handle_client(stream).await {
+                                error!("Error serving metrics to {}: {}", peer, e);
+                            }
+                        });
+                    }
+                    Err(e) => error!("Error accepting metrics connection: {}", e),
+                }
+            }
+        }))
+    }
+
+    async fn handle_client(&self, mut stream: TcpStream) -> Result<()> {
+        let mut buffer = vec![0; MAX_REQUEST_SIZE];
+        let mut read = 0;
+        while read < MAX_REQUEST_SIZE {
+            let n = stream.read(&mut buffer[read..]).await?;
+            read += n;
+            if n == 0 || buffer[..read].windows(4).any(|w| w == b"\r\n\r\n") {
+                break;
+            }
+        }
+        let request = String::from_utf8_lossy(&buffer[..read]);
+        let request_line = request.lines().next().unwrap_or_default();
+        let response = if request_line.starts_with("GET /metrics ") {
+            let body = self.scrape().await;
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        } else {
+            debug!("Metrics endpoint received unknown request `{}`", request_line);
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+        };
+        stream.write_all(response.as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn scrape(&self) -> String {
+        let (repository, driver, runner) = {
+            let state = self.state.read();
+            (state.repository.clone(), state.wg_driver, state.runner.clone())
+        };
+        let peers = match driver.tunnel(&repository, &runner) {
+            Ok(wg) => wg.peers().await.unwrap_or_else(|e| {
+                error!("Unable to read Wireguard peers for metrics: {}", e);
+                vec![]
+            }),
+            Err(_) => vec![],
+        };
+        let changes = self.track_endpoints(&peers);
+        let state = self.state.read().clone();
+        render(&state, &peers, &changes, now())
+    }
+
+    fn track_endpoints(&self, peers: &[WgPeer]) -> HashMap<String, u64> {
+        let mut endpoints = self.endpoints.lock();
+        for peer in peers {
+            let tracker = endpoints.entry(peer.public_key.clone()).or_default();
+            if tracker.endpoint.is_some() && peer.endpoint.is_some() && tracker.endpoint != peer.endpoint {
+                tracker.changes += 1;
+            }
+            if peer.endpoint.is_some() {
+                tracker.endpoint = peer.endpoint.clone();
+            }
+        }
+        endpoints.iter().map(|(k, v)| (k.clone(), v.changes)).collect()
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Render the Prometheus text exposition format for the daemon state and the Wireguard peers.
+pub fn render(state: &DaemonState, peers: &[WgPeer], endpoint_changes: &HashMap<String, u64>, now: u64) -> String {
+    let mut out = String::new();
+    let repo = escape(&state.repository);
+
+    header(&mut out, "fireguard_info", "gauge", "Fireguard daemon version and repository.");
+    let _ = writeln!(out, "fireguard_info{{repository=\"{}\",version=\"{}\"}} 1", repo, escape(&state.version));
+    header(&mut out, "fireguard_start_time_seconds", "gauge", "Daemon start time since unix epoch in seconds.");
+    let _ = writeln!(out, "fireguard_start_time_seconds{{repository=\"{}\"}} {}", repo, state.started_at);
+    header(&mut out, "fireguard_tunnel_up", "gauge", "Whether the Wireguard tunnel is up.");
+    let _ = writeln!(out, "fireguard_tunnel_up{{repository=\"{}\"}} {}", repo, state.tunnel_up as u8);
+
+    header(&mut out, "fireguard_config_revision_info", "gauge", "Trust repository revision currently applied.");
+    let revision = state.revision.as_deref().unwrap_or("unknown");
+    let _ =
+        writeln!(out, "fireguard_config_revision_info{{repository=\"{}\",revision=\"{}\"}} 1", repo, escape(revision));
+    header(&mut out, "fireguard_config_peers", "gauge", "Peers defined in the trust repository.");
+    let _ = writeln!(out, "fireguard_config_peers{{repository=\"{}\"}} {}", repo, state.peers);
+    header(&mut out, "fireguard_config_reloads_total", "counter", "Configuration reloads applied to the tunnel.");
+    let _ = writeln!(out, "fireguard_config_reloads_total{{repository=\"{}\"}} {}", repo, state.reloads);
+
+    header(&mut out, "fireguard_repository_pulls_total", "counter", "Trust repository pull attempts.");
+    let _ = writeln!(out, "fireguard_repository_pulls_total{{repository=\"{}\"}} {}", repo, state.pulls);
+    header(&mut out, "fireguard_repository_pull_failures_total", "counter", "Failed trust repository pulls.");
+    let _ =
+        writeln!(out, "fireguard_repository_pull_failures_total{{repository=\"{}\"}} {}", repo, state.pull_failures);
+    header(&mut out, "fireguard_repository_sync_ok", "gauge", "Whether the last trust repository pull succeeded.");
+    let _ = writeln!(
+        out,
+        "fireguard_repository_sync_ok{{repository=\"{}\"}} {}",
+        repo,
+        state.last_pull_error.is_none() as u8
+    );
+    if let Some(last_pull) = state.last_successful_pull {
+        header(
+            &mut out,
+            "fireguard_repository_last_successful_pull_seconds",
+            "gauge",
+            "Last successful trust repository pull since unix epoch in seconds.",
+        );
+        let _ =
+            writeln!(out, "fireguard_repository_last_successful_pull_seconds{{repository=\"{}\"}} {}", repo, last_pull);
+    }
+
+    header(&mut out, "fireguard_upgrade_checks_total", "counter", "Upgrade checks against the releases endpoint.");
+    let _ = writeln!(out, "fireguard_upgrade_checks_total{{repository=\"{}\"}} {}", repo, state.upgrade_checks);
+    header(&mut out, "fireguard_upgrade_check_failures_total", "counter", "Failed upgrade checks.");
+    let _ = writeln!(
+        out,
+        "fireguard_upgrade_check_failures_total{{repository=\"{}\"}} {}",
+        repo, state.upgrade_check_failures
+    );
+    if let Some(last_check) = state.last_upgrade_check {
+        header(
+            &mut out,
+            "fireguard_upgrade_last_check_seconds",
+            "gauge",
+            "Last upgrade check since unix epoch in seconds.",
+        );
+        let _ = writeln!(out, "fireguard_upgrade_last_check_seconds{{repository=\"{}\"}} {}", repo, last_check);
+    }
+    if let Some(latest) = state.latest_release.as_ref() {
+        header(&mut out, "fireguard_upgrade_latest_release_info", "gauge", "Latest release seen by the upgrader.");
+        let _ = writeln!(
+            out,
+            "fireguard_upgrade_latest_release_info{{repository=\"{}\",tag=\"{}\"}} 1",
+            repo,
+            escape(latest)
+        );
+    }
+
+    if !peers.is_empty() {
+        header(&mut out, "fireguard_peer_latest_handshake_seconds", "gauge", "Latest handshake since unix epoch.");
+        for peer in peers {
+            let _ = writeln!(
+                out,
+                "fireguard_peer_latest_handshake_seconds{{{}}} {}",
+                peer_labels(state, peer),
+                peer.latest_handshake.unwrap_or(0)
+            );
+        }
+        header(&mut out, "fireguard_peer_handshake_age_seconds", "gauge", "Seconds since the latest handshake.");
+        for peer in peers.iter().filter(|p| p.latest_handshake.is_some()) {
+            let age = now.saturating_sub(peer.latest_handshake.unwrap_or(0));
+            let _ = writeln!(out, "fireguard_peer_handshake_age_seconds{{{}}} {}", peer_labels(state, peer), age);
+        }
+        header(&mut out, "fireguard_peer_receive_bytes_total", "counter", "Bytes received from the peer.");
+        for peer in peers {
+            let _ = writeln!(
+                out,
+                "fireguard_peer_receive_bytes_total{{{}}} {}",
+                peer_labels(state, peer),
+                peer.transfer_rx.unwrap_or(0)
+            );
+        }
+        header(&mut out, "fireguard_peer_transmit_bytes_total", "counter", "Bytes sent to the peer.");
+        for peer in peers {
+            let _ = writeln!(
+                out,
+                "fireguard_peer_transmit_bytes_total{{{}}} {}",
+                peer_labels(state, peer),
+                peer.transfer_tx.unwrap_or(0)
+            );
+        }
+        header(&mut out, "fireguard_peer_endpoint_changes_total", "counter", "Peer endpoint changes (roaming).");
+        for peer in peers {
+            let _ = writeln!(
+                out,
+                "fireguard_peer_endpoint_changes_total{{{}}} {}",
+                peer_labels(state, peer),
+                endpoint_changes.get(&peer.public_key).unwrap_or(&0)
+            );
+        }
+    }
+    if !state.peer_health.is_empty() {
+        header(&mut out, "fireguard_peer_healthy", "gauge", "Whether the peer passes the health checks.");
+        for (peer, healthy) in state.peer_health.iter() {
+            let _ = writeln!(
+                out,
+                "fireguard_peer_healthy{{repository=\"{}\",peer=\"{}\"}} {}",
+                repo,
+                escape(peer),
+                *healthy as u8
+            );
+        }
+    }
+    out
+}
+
+fn peer_labels(state: &DaemonState, peer: &WgPeer) -> String {
+    format!(
+        "repository=\"{}\",peer=\"{}\",public_key=\"{}\"",
+        escape(&state.repository),
+        escape(&state.peer_name(&peer.public_key).unwrap_or_default()),
+        escape(&peer.public_key)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_metrics() {
+        let mut state = DaemonState::new("avalon");
+        state.tunnel_up = true;
+        state.revision = Some("abc123".to_string());
+        state.pulls = 3;
+        state.pull_failures = 1;
+        state.peer_names.insert("YWxpY2U=".to_string(), "alice-laptop".to_string());
+        let peer = WgPeer {
+            endpoint: Some("1.2.3.4:6666".to_string()),
+            public_key: "YWxpY2U=".to_string(),
+            latest_handshake: Some(1000),
+            transfer_rx: Some(10),
+            transfer_tx: Some(20),
+            persistent_keepalive: None,
+            allowed_ips: vec![],
+        };
+        let mut changes = HashMap::new();
+        changes.insert("YWxpY2U=".to_string(), 2);
+        let metrics = render(&state, &[peer], &changes, 1030);
+        let labels = r#"repository="avalon",peer="alice-laptop",public_key="YWxpY2U=""#;
+        assert!(metrics.contains("fireguard_tunnel_up{repository=\"avalon\"} 1\n"));
+        assert!(metrics.contains("fireguard_config_revision_info{repository=\"avalon\",revision=\"abc123\"} 1\n"));
+        assert!(metrics.contains("fireguard_repository_pull_failures_total{repository=\"avalon\"} 1\n"));
+        assert!(metrics.contains(&format!("fireguard_peer_handshake_age_seconds{{{}}} 30\n", labels)));
+        assert!(metrics.contains(&format!("fireguard_peer_receive_bytes_total{{{}}} 10\n", labels)));
+        assert!(metrics.contains(&format!("fireguard_peer_endpoint_changes_total{{{}}} 2\n", labels)));
+    }
+}
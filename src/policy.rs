@@ -0,0 +1,267 @@
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::error::{FireguardError, Result};
+
+/// Group name matching all the peers of the repository.
+pub const ALL_PEERS: &str = "*";
+
+/// Access policy: the peers of `from` may reach the peers of `to`, only on `ports` when set.
+/// Both name a group, a peer or `*` for all the peers.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct Policy {
+    pub from: String,
+    pub to: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ports: Vec<PortRange>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+/// Port or port range, for both TCP and UDP unless a protocol is set: `22/tcp`,
+/// `60000-61000/udp` or `53`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PortRange {
+    pub first: u16,
+    pub last: u16,
+    pub protocol: Option<Protocol>,
+}
+
+impl FromStr for PortRange {
+    type Err = FireguardError;
+
+    fn from_str(ports: &str) -> Result<Self> {
+        let invalid = || FireguardError::config(None, format!("invalid port {}, expected like 22/tcp or 53", ports));
+        let (range, protocol) = match ports.split_once('/') {
+            Some((range, "tcp")) => (range, Some(Protocol::Tcp)),
+            Some((range, "udp")) => (range, Some(Protocol::Udp)),
+            Some(_) => return Err(invalid()),
+            None => (ports, None),
+        };
+        let (first, last) = match range.split_once('-') {
+            Some((first, last)) => (first, last),
+            None => (range, range),
+        };
+        let first = first.trim().parse::<u16>().map_err(|_| invalid())?;
+        let last = last.trim().parse::<u16>().map_err(|_| invalid())?;
+        if first == 0 || first > last {
+            return Err(invalid());
+        }
+        Ok(PortRange { first, last, protocol })
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.first == self.last {
+            write!(f, "{}", self.first)?;
+        } else {
+            write!(f, "{}-{}", self.first, self.last)?;
+        }
+        match self.protocol {
+            Some(protocol) => write!(f, "/{}", protocol),
+            None => Ok(()),
+        }
+    }
+}
+
+impl TryFrom<String> for PortRange {
+    type Error = FireguardError;
+
+    fn try_from(ports: String) -> Result<Self> {
+        ports.parse()
+    }
+}
+
+impl From<PortRange> for String {
+    fn from(ports: PortRange) -> Self {
+        ports.to_string()
+    }
+}
+
+/// Peers named by `name`: the members of the group, all of them for `*`, or the peer itself.
+pub fn members<'a>(config: &'a Config, name: &'a str) -> Vec<&'a str> {
+    if name == ALL_PEERS {
+        config.peers.keys().map(String::as_str).collect()
+    } else if let Some(group) = config.groups.get(name) {
+        group.iter().map(String::as_str).collect()
+    } else {
+        vec![name]
+    }
+}
+
+/// Check the groups and policies only name peers of the repository.
+pub fn validate(config: &Config) -> Result<()> {
+    for (group, peers) in config.groups.iter() {
+        if config.peers.contains_key(group) || group == ALL_PEERS {
+            return Err(FireguardError::config(None, format!("group {} clashes with a peer name", group)));
+        }
+        if let Some(peer) = peers.iter().find(|peer| !config.peers.contains_key(*peer)) {
+            let message = format!("group {} member {} is not a peer of the repository", group, peer);
+            return Err(FireguardError::config(None, message));
+        }
+    }
+    for policy in config.policies.iter() {
+        for name in [&policy.from, &policy.to].iter() {
+            if name.as_str() != ALL_PEERS && !config.groups.contains_key(*name) && !config.peers.contains_key(*name) {
+                let message =
+                    format!("policy from {} to {}: {} is neither a group nor a peer", policy.from, policy.to, name);
+                return Err(FireguardError::config(None, message));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Ports the peer `from` may reach on the peer `to`, all of them when empty, or `None` when it
+/// may not reach it. Without policies every peer reaches every other one.
+pub fn grant(config: &Config, from: &str, to: &str) -> Option<Vec<PortRange>> {
+    if config.policies.is_empty() {
+        return Some(vec![]);
+    }
+    let mut ports = BTreeSet::new();
+    let mut granted = false;
+    for policy in config.policies.iter() {
+        if members(config, &policy.from).contains(&from) && members(config, &policy.to).contains(&to) {
+            if policy.ports.is_empty() {
+                return Some(vec![]);
+            }
+            granted = true;
+            ports.extend(policy.ports.iter().cloned());
+        }
+    }
+    if granted {
+        Some(ports.into_iter().collect())
+    } else {
+        None
+    }
+}
+
+/// Whether the peers `a` and `b` exchange any traffic, which needs them in each other configuration.
+pub fn connected(config: &Config, a: &str, b: &str) -> bool {
+    grant(config, a, b).is_some() || grant(config, b, a).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NODES: &str = r#"repository = "avalon"
+network = "10.0.0.0/24"
+domain = "avalon.lan"
+
+[peers.bob-cloud]
+username = "bob"
+peername = "cloud"
+address = "10.0.0.1/24"
+listen_port = 51820
+public_key = "bob"
+allowed_ips = ["10.0.0.1/32"]
+persistent_keepalive = 25
+endpoint = "cloud.bob.net"
+mtu = 1420
+
+[peers.carol-baremetal]
+username = "carol"
+peername = "baremetal"
+address = "10.0.0.2/24"
+listen_port = 51820
+public_key = "carol"
+allowed_ips = ["10.0.0.2/32"]
+persistent_keepalive = 25
+endpoint = "baremetal.carol.net"
+mtu = 1420
+
+[peers.alice-laptop]
+username = "alice"
+peername = "laptop"
+address = "10.0.0.3/24"
+listen_port = 51820
+public_key = "alice"
+allowed_ips = ["10.0.0.3/32"]
+persistent_keepalive = 25
+mtu = 1420
+
+[peers.alice-phone]
+username = "alice"
+peername = "phone"
+address = "10.0.0.4/24"
+listen_port = 51820
+public_key = "phone"
+allowed_ips = ["10.0.0.4/32"]
+persistent_keepalive = 25
+mtu = 1420
+
+[groups]
+laptops = ["alice-laptop", "alice-phone"]
+servers = ["bob-cloud", "carol-baremetal"]
+
+[[policies]]
+from = "laptops"
+to = "servers"
+ports = ["22/tcp", "443/tcp", "60000-61000/udp"]
+
+[[policies]]
+from = "servers"
+to = "servers"
+"#;
+
+    #[test]
+    fn test_parse_port_ranges() {
+        assert_eq!(
+            "22/tcp".parse::<PortRange>().unwrap(),
+            PortRange { first: 22, last: 22, protocol: Some(Protocol::Tcp) }
+        );
+        assert_eq!("53".parse::<PortRange>().unwrap(), PortRange { first: 53, last: 53, protocol: None });
+        assert_eq!("60000-61000/udp".parse::<PortRange>().unwrap().to_string(), "60000-61000/udp");
+        assert!("22/sctp".parse::<PortRange>().is_err());
+        assert!("443-22".parse::<PortRange>().is_err());
+        assert!("0".parse::<PortRange>().is_err());
+    }
+
+    #[test]
+    fn test_grants() {
+        let config = Config::parse(NODES).unwrap();
+        validate(&config).unwrap();
+        assert_eq!(grant(&config, "bob-cloud", "carol-baremetal"), Some(vec![]));
+        let ports = grant(&config, "alice-laptop", "bob-cloud").unwrap();
+        assert_eq!(
+            ports.iter().map(|p| p.to_string()).collect::<Vec<_>>(),
+            vec!["22/tcp", "443/tcp", "60000-61000/udp"]
+        );
+        assert_eq!(grant(&config, "bob-cloud", "alice-laptop"), None);
+        assert!(connected(&config, "bob-cloud", "alice-laptop"));
+        assert!(!connected(&config, "alice-laptop", "alice-phone"));
+        let config = Config::parse(&NODES.replace("to = \"servers\"\nports", "to = \"*\"\nports")).unwrap();
+        assert!(connected(&config, "alice-laptop", "alice-phone"));
+    }
+
+    #[test]
+    fn test_invalid_policies() {
+        let config = Config::parse(&NODES.replace("\"alice-phone\"]", "\"dave-phone\"]")).unwrap();
+        assert!(validate(&config).is_err());
+        let config = Config::parse(&NODES.replace("from = \"laptops\"", "from = \"tablets\"")).unwrap();
+        assert!(validate(&config).is_err());
+        assert!(Config::parse(&NODES.replace("\"22/tcp\"", "\"ssh\"")).is_err());
+    }
+}
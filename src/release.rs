@@ -0,0 +1,364 @@
+use std::ffi::OsStr;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use color_eyre::eyre::{bail, Result};
+use flate2::read::GzDecoder;
+use guess_host_triple::guess_host_triple;
+use reqwest::{Client, Response};
+use serde::{Deserialize, Serialize};
+use tar::Archive;
+use tokio::fs;
+use tokio::task;
+
+use crate::error::FireguardError;
+use crate::github::Releases;
+use crate::upgrade::{rename_synced, write_executable};
+use crate::utils::{build_reqwest_client, NEW_VERSION_FILE, NEW_VERSION_PATH};
+use crate::verify::{ReleaseVerifier, CHECKSUMS_ASSET, SIGNATURE_ASSET};
+
+/// Name of the index file listing the releases of an HTTP or local release directory.
+pub const RELEASE_INDEX: &str = "index.json";
+
+/// A release as seen by the upgrader, whatever source it comes from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Release {
+    pub tag_name: String,
+    #[serde(default)]
+    pub prerelease: bool,
+    #[serde(default)]
+    pub draft: bool,
+    pub assets: Vec<ReleaseAsset>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReleaseAsset {
+    pub name: String,
+    pub url: String,
+}
+
+/// Where releases and their assets are fetched from.
+#[async_trait]
+pub trait ReleaseSource: fmt::Display + Send + Sync {
+    async fn releases(&self) -> Result<Vec<Release>>;
+    async fn fetch(&self, asset: &ReleaseAsset) -> Result<Vec<u8>>;
+}
+
+/// Pick the release source for `url`: a path or `file://` URL is a local release directory, the
+/// GitHub API is queried with its own schema and any other URL is an HTTP release directory.
+pub fn release_source(url: &str) -> Result<Box<dyn ReleaseSource>> {
+    if let Some(path) = url.strip_prefix("file://") {
+        Ok(Box::new(LocalSource::new(path)))
+    } else if url.starts_with('/') {
+        Ok(Box::new(LocalSource::new(url)))
+    } else if url.starts_with("https://api.github.com/") {
+        Ok(Box::new(GithubSource::new(url)?))
+    } else if url.starts_with("http://") || url.starts_with("https://") {
+        Ok(Box::new(HttpIndexSource::new(url)?))
+    } else {
+        bail!("Unsupported release source {}, use a GitHub API, HTTP(S) or file:// URL", url)
+    }
+}
+
+/// Index of an HTTP or local release directory. Assets of a release live in a directory named
+/// after its tag, next to the index.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReleaseIndex {
+    pub releases: Vec<IndexEntry>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub tag_name: String,
+    #[serde(default)]
+    pub prerelease: bool,
+    pub assets: Vec<String>,
+}
+
+impl ReleaseIndex {
+    fn into_releases(self, base: &str) -> Vec<Release> {
+        let base = base.trim_end_matches('/');
+        self.releases
+            .into_iter()
+            .map(|entry| Release {
+                assets: entry
+                    .assets
+                    .iter()
+                    .map(|name| ReleaseAsset {
+                        name: name.clone(),
+                        url: format!("{}/{}/{}", base, entry.tag_name, name),
+                    })
+                    .collect(),
+                tag_name: entry.tag_name,
+                prerelease: entry.prerelease,
+                draft: false,
+            })
+            .collect()
+    }
+}
+
+/// GET `url`, failing on error statuses.
+pub async fn http_get(client: &Client, url: &str) -> Result<Response, FireguardError> {
+    let response = client.get(url).send().await.and_then(Response::error_for_status);
+    response.map_err(|e| FireguardError::Network(e.to_string()))
+}
+
+async fn http_fetch(client: &Client, url: &str) -> Result<Vec<u8>> {
+    Ok(http_get(client, url).await?.bytes().await?.to_vec())
+}
+
+/// GitHub releases API, like `https://api.github.com/repos/blackmesalab/fireguard/releases`.
+pub struct GithubSource {
+    url: String,
+    client: Client,
+}
+
+impl GithubSource {
+    pub fn new(url: &str) -> Result<Self> {
+        Ok(GithubSource { url: url.to_string(), client: build_reqwest_client(None, None)? })
+    }
+}
+
+impl fmt::Display for GithubSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "GitHub releases {}", self.url)
+    }
+}
+
+#[async_trait]
+impl ReleaseSource for GithubSource {
+    async fn releases(&self) -> Result<Vec<Release>> {
+        Ok(Releases::list(&self.client, &self.url).await?.into_iter().map(Release::from).collect())
+    }
+
+    async fn fetch(&self, asset: &ReleaseAsset) -> Result<Vec<u8>> {
+        http_fetch(&self.client, &asset.url).await
+    }
+}
+
+/// Plain HTTP directory, like an internal mirror, serving an `index.json`.
+pub struct HttpIndexSource {
+    url: String,
+    client: Client,
+}
+
+impl HttpIndexSource {
+    pub fn new(url: &str) -> Result<Self> {
+        Ok(HttpIndexSource { url: url.trim_end_matches('/').to_string(), client: build_reqwest_client(None, None)? })
+    }
+}
+
+impl fmt::Display for HttpIndexSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HTTP release directory {}", self.url)
+    }
+}
+
+#[async_trait]
+impl ReleaseSource for HttpIndexSource {
+    async fn releases(&self) -> Result<Vec<Release>> {
+        let url = format!("{}/{}", self.url, RELEASE_INDEX);
+        let index = http_get(&self.client, &url).await?.json::<ReleaseIndex>().await?;
+        Ok(index.into_releases(&self.url))
+    }
+
+    async fn fetch(&self, asset: &ReleaseAsset) -> Result<Vec<u8>> {
+        http_fetch(&self.client, &asset.url).await
+    }
+}
+
+/// Release directory on the local filesystem, like a mounted USB drive or NFS share.
+pub struct LocalSource {
+    path: PathBuf,
+}
+
+impl LocalSource {
+    pub fn new(path: &str) -> Self {
+        LocalSource { path: PathBuf::from(path) }
+    }
+}
+
+impl fmt::Display for LocalSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "local release directory {}", self.path.display())
+    }
+}
+
+#[async_trait]
+impl ReleaseSource for LocalSource {
+    async fn releases(&self) -> Result<Vec<Release>> {
+        let data = fs::read(self.path.join(RELEASE_INDEX)).await?;
+        let index = serde_json::from_slice::<ReleaseIndex>(&data)?;
+        Ok(index.into_releases(&self.path.to_string_lossy()))
+    }
+
+    async fn fetch(&self, asset: &ReleaseAsset) -> Result<Vec<u8>> {
+        Ok(fs::read(&asset.url).await?)
+    }
+}
+
+impl Release {
+    fn asset(&self, name: &str) -> Result<&ReleaseAsset> {
+        match self.assets.iter().find(|a| a.name == name) {
+            Some(asset) => Ok(asset),
+            None => bail!("Unable to find asset {} for release {}", name, self.tag_name),
+        }
+    }
+
+    /// Download the release checksums and their signature, check the signature against the
+    /// built in release key and the asset against its checksum.
+    async fn fetch_verified(&self, source: &dyn ReleaseSource, asset: &ReleaseAsset) -> Result<Vec<u8>> {
+        let checksums = source.fetch(self.asset(CHECKSUMS_ASSET)?).await?;
+        let signature = source.fetch(self.asset(SIGNATURE_ASSET)?).await?;
+        let manifest = ReleaseVerifier::builtin()?.verify_manifest(
+            &checksums,
+            &String::from_utf8_lossy(&signature),
+            &self.tag_name,
+        )?;
+        let data = source.fetch(asset).await?;
+        manifest.verify(&asset.name, &data)?;
+        info!("Verified checksum and signature of {} for release {}", asset.name, self.tag_name);
+        Ok(data)
+    }
+
+    pub async fn download_for_triple(&self, source: &dyn ReleaseSource, triple: &str) -> Result<()> {
+        let tar_file = format!("fireguard-{}.tar.gz", triple);
+        let asset = self.asset(&tar_file)?;
+        let data = self.fetch_verified(source, asset).await?;
+        info!("Downloaded new version {} from {}", self.tag_name, asset.url);
+        task::spawn_blocking(move || {
+            // Unpack next to the executable, so the final rename never crosses filesystems.
+            let dir = tempfile::Builder::new().prefix(".fireguard-upgrade-").tempdir_in(NEW_VERSION_PATH.as_path())?;
+            let unpacked = dir.path().join("fireguard");
+            unpack_executable(&data, &unpacked)?;
+            rename_synced(&unpacked, NEW_VERSION_FILE.as_path())
+        })
+        .await??;
+        info!("Extracted new version {} in {}", self.tag_name, NEW_VERSION_FILE.display());
+        Ok(())
+    }
+
+    pub async fn download(&self, source: &dyn ReleaseSource) -> Result<()> {
+        let host_triple = match guess_host_triple() {
+            Some(t) => {
+                info!("Found rustc triple for current host: {}", t);
+                t
+            }
+            None => bail!("Unable to find rustc host triple for current intallation"),
+        };
+        self.download_for_triple(source, host_triple).await
+    }
+}
+
+/// Write the `fireguard` executable found in a gzipped tar archive to `destination`.
+fn unpack_executable(data: &[u8], destination: &Path) -> Result<()> {
+    let mut archive = Archive::new(GzDecoder::new(data));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        if entry.header().entry_type().is_file() && path.file_name() == Some(OsStr::new("fireguard")) {
+            return write_executable(&mut entry, destination);
+        }
+    }
+    bail!("Release archive does not contain a fireguard executable")
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    const INDEX: &str = r#"{"releases": [
+        {"tag_name": "v0.0.13", "assets": ["fireguard-x86_64-unknown-linux-gnu.tar.gz", "SHA256SUMS"]},
+        {"tag_name": "v0.1.0-rc.1", "prerelease": true, "assets": []}
+    ]}"#;
+
+    #[test]
+    fn test_release_source_from_url() {
+        assert!(release_source("https://api.github.com/repos/blackmesalab/fireguard/releases")
+            .unwrap()
+            .to_string()
+            .starts_with("GitHub"));
+        assert!(release_source("http://mirror.lab/fireguard").unwrap().to_string().starts_with("HTTP"));
+        assert!(release_source("file:///mnt/fireguard").unwrap().to_string().starts_with("local"));
+        assert!(release_source("/mnt/fireguard").unwrap().to_string().starts_with("local"));
+        assert!(release_source("ftp://mirror.lab/fireguard").is_err());
+    }
+
+    fn archive(name: &str, content: &[u8]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(vec![], flate2::Compression::default()));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, name, content).unwrap();
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn test_unpack_executable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir::TempDir::new("fireguard-unpack").unwrap();
+        let destination = dir.path().join("fireguard");
+        unpack_executable(&archive("fireguard", b"new binary"), &destination).unwrap();
+        assert_eq!(std::fs::read(&destination).unwrap(), b"new binary");
+        assert_eq!(std::fs::metadata(&destination).unwrap().permissions().mode() & 0o777, 0o755);
+        assert!(unpack_executable(&archive("README.md", b"readme"), &destination).is_err());
+        assert!(unpack_executable(b"not an archive", &destination).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_local_source() {
+        let dir = tempdir::TempDir::new("fireguard-releases").unwrap();
+        fs::write(dir.path().join(RELEASE_INDEX), INDEX).await.unwrap();
+        fs::create_dir(dir.path().join("v0.0.13")).await.unwrap();
+        fs::write(dir.path().join("v0.0.13").join("SHA256SUMS"), "checksums").await.unwrap();
+        let source = LocalSource::new(&dir.path().to_string_lossy());
+        let releases = source.releases().await.unwrap();
+        assert_eq!(releases.len(), 2);
+        assert!(releases[1].prerelease);
+        let asset = releases[0].asset("SHA256SUMS").unwrap();
+        assert_eq!(source.fetch(asset).await.unwrap(), b"checksums");
+    }
+
+    #[tokio::test]
+    async fn test_http_index_source() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buffer = vec![0; 4096];
+                let read = stream.read(&mut buffer).await.unwrap();
+                let request = String::from_utf8_lossy(&buffer[..read]).to_string();
+                let body = if request.starts_with("GET /fireguard/index.json ") {
+                    INDEX
+                } else if request.starts_with("GET /fireguard/v0.0.13/SHA256SUMS ") {
+                    "checksums"
+                } else {
+                    ""
+                };
+                let status = if body.is_empty() { "404 Not Found" } else { "200 OK" };
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        let source = HttpIndexSource::new(&format!("http://{}/fireguard/", address)).unwrap();
+        let releases = source.releases().await.unwrap();
+        assert_eq!(releases[0].tag_name, "v0.0.13");
+        let asset = releases[0].asset("SHA256SUMS").unwrap();
+        assert_eq!(asset.url, format!("http://{}/fireguard/v0.0.13/SHA256SUMS", address));
+        assert_eq!(source.fetch(asset).await.unwrap(), b"checksums");
+        let missing = releases[0].asset("fireguard-x86_64-unknown-linux-gnu.tar.gz").unwrap();
+        assert!(source.fetch(missing).await.is_err());
+    }
+}
@@ -0,0 +1,524 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use color_eyre::eyre::{bail, eyre, Result};
+use hmac::{Hmac, Mac, NewMac};
+use openssl::symm::{self, Cipher};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::task::{self, JoinHandle};
+use tokio::time;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::endpoint::Endpoint;
+use crate::shutdown::ShutdownSignal;
+use crate::state::{now, DaemonState, SharedState};
+use crate::wg::WgPeer;
+
+/// Queries older or newer than this many seconds are refused.
+const MAX_CLOCK_SKEW: u64 = 30;
+/// Seconds to wait for the answer of the rendezvous service.
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_DATAGRAM: usize = 65507;
+
+/// Base64 Wireguard key to its raw bytes.
+fn decode_key(key: &str) -> Result<[u8; 32]> {
+    let data = BASE64.decode(key.trim())?;
+    data.as_slice().try_into().map_err(|_| eyre!("Wireguard keys must be 32 bytes long"))
+}
+
+/// Public key of the Wireguard private key `private_key`.
+pub fn public_key(private_key: &str) -> Result<String> {
+    let secret = StaticSecret::from(decode_key(private_key)?);
+    Ok(BASE64.encode(PublicKey::from(&secret).as_bytes()))
+}
+
+/// Key both ends derive from their Wireguard keys, authenticating the messages between them.
+fn shared_key(private_key: &str, public_key: &str) -> Result<[u8; 32]> {
+    let secret = StaticSecret::from(decode_key(private_key)?);
+    Ok(secret.diffie_hellman(&PublicKey::from(decode_key(public_key)?)).to_bytes())
+}
+
+fn mac(key: &[u8; 32], context: &str, parts: &[&[u8]]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(context.as_bytes());
+    for part in parts {
+        mac.update(&(part.len() as u64).to_be_bytes());
+        mac.update(part);
+    }
+    mac
+}
+
+/// Query of a peer for the endpoints the service sees the other peers at.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Query {
+    pub public_key: String,
+    pub nonce: u64,
+    pub timestamp: u64,
+    pub mac: String,
+}
+
+impl Query {
+    pub fn new(private_key: &str, server_key: &str, nonce: u64, timestamp: u64) -> Result<Self> {
+        let key = shared_key(private_key, server_key)?;
+        let public_key = public_key(private_key)?;
+        let mac = Self::mac(&key, &public_key, nonce, timestamp).finalize().into_bytes();
+        Ok(Query { public_key, nonce, timestamp, mac: hex::encode(mac) })
+    }
+
+    fn mac(key: &[u8; 32], public_key: &str, nonce: u64, timestamp: u64) -> Hmac<Sha256> {
+        mac(key, "fireguard rendezvous query", &[public_key.as_bytes(), &nonce.to_be_bytes(), &timestamp.to_be_bytes()])
+    }
+
+    /// Check the query comes from the holder of the private key of `public_key`, returning the key
+    /// authenticating the answer.
+    pub fn verify(&self, private_key: &str, now: u64) -> Result<[u8; 32]> {
+        if now.saturating_sub(self.timestamp).max(self.timestamp.saturating_sub(now)) > MAX_CLOCK_SKEW {
+            bail!("Query of {} is too old, check the clocks are in sync", self.public_key);
+        }
+        let key = shared_key(private_key, &self.public_key)?;
+        Self::mac(&key, &self.public_key, self.nonce, self.timestamp)
+            .verify(&hex::decode(&self.mac)?)
+            .map_err(|_| eyre!("Query of {} has an invalid signature", self.public_key))?;
+        Ok(key)
+    }
+}
+
+/// Public endpoint the service sees a peer at.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Observed {
+    pub public_key: String,
+    pub endpoint: SocketAddr,
+}
+
+/// Endpoints the service shares with a peer, in answer to its query `nonce`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Answer {
+    pub nonce: u64,
+    pub peers: Vec<Observed>,
+}
+
+/// Answer encrypted with the key the service shares with the querying peer, so that nobody else
+/// learns where its peers are.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct SealedAnswer {
+    nonce: u64,
+    iv: String,
+    peers: String,
+    tag: String,
+}
+
+impl Answer {
+    pub fn new(nonce: u64, peers: Vec<Observed>) -> Self {
+        Answer { nonce, peers }
+    }
+
+    /// Key encrypting the answers, derived from the key shared with the querying peer.
+    fn key(key: &[u8; 32]) -> Vec<u8> {
+        mac(key, "fireguard rendezvous answer", &[]).finalize().into_bytes().to_vec()
+    }
+
+    /// Encrypt and authenticate the answer with `key`, binding it to the query nonce.
+    pub fn seal(&self, key: &[u8; 32]) -> Result<Vec<u8>> {
+        let iv = rand::random::<[u8; 12]>();
+        let mut tag = [0; 16];
+        let peers = symm::encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &Self::key(key),
+            Some(&iv),
+            &self.nonce.to_be_bytes(),
+            &serde_json::to_vec(&self.peers)?,
+            &mut tag,
+        )?;
+        let sealed =
+            SealedAnswer { nonce: self.nonce, iv: hex::encode(iv), peers: BASE64.encode(peers), tag: hex::encode(tag) };
+        Ok(serde_json::to_vec(&sealed)?)
+    }
+
+    /// Decrypt the answer of the service to the query `nonce`, checking it comes from the service.
+    pub fn open(data: &[u8], key: &[u8; 32], nonce: u64) -> Result<Self> {
+        let sealed: SealedAnswer = serde_json::from_slice(data)?;
+        if sealed.nonce != nonce {
+            bail!("Rendezvous answer does not match the query");
+        }
+        let peers = symm::decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &Self::key(key),
+            Some(&hex::decode(&sealed.iv)?),
+            &nonce.to_be_bytes(),
+            &BASE64.decode(&sealed.peers)?,
+            &hex::decode(&sealed.tag)?,
+        )
+        .map_err(|_| eyre!("Rendezvous answer cannot be decrypted, it does not come from the service"))?;
+        Ok(Answer { nonce, peers: serde_json::from_slice(&peers)? })
+    }
+}
+
+/// Nonces of the queries answered lately, per peer, refusing the replays of these queries.
+#[derive(Debug, Default)]
+struct Replays {
+    seen: HashMap<String, HashMap<u64, u64>>,
+}
+
+impl Replays {
+    /// Remember the nonce of the authenticated `query`, failing if it was answered already.
+    fn check(&mut self, query: &Query, now: u64) -> Result<()> {
+        // Queries older than the clock skew are refused anyway, their nonces can be forgotten.
+        for nonces in self.seen.values_mut() {
+            nonces.retain(|_, timestamp| timestamp.saturating_add(MAX_CLOCK_SKEW) >= now);
+        }
+        self.seen.retain(|_, nonces| !nonces.is_empty());
+        let nonces = self.seen.entry(query.public_key.clone()).or_default();
+        if nonces.insert(query.nonce, query.timestamp).is_some() {
+            bail!("Query of {} was already answered, refusing its replay", query.public_key);
+        }
+        Ok(())
+    }
+}
+
+/// UDP socket on `port` of all the IPv6 and IPv4 addresses of the host, or of the IPv4 ones only
+/// when IPv6 is disabled.
+fn bind(port: u16) -> Result<UdpSocket> {
+    let dual_stack = || -> std::io::Result<Socket> {
+        let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_only_v6(false)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+        Ok(socket)
+    };
+    let socket = match dual_stack() {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("Rendezvous service unable to listen on IPv6, listening on IPv4 only: {}", e);
+            let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+            socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)).into())?;
+            socket
+        }
+    };
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// Whether the latest handshake of `peer` is older than `stale_after` seconds, or never happened.
+fn stale(peer: &WgPeer, stale_after: u64, now: u64) -> bool {
+    peer.latest_handshake.map(|handshake| now.saturating_sub(handshake) > stale_after).unwrap_or(true)
+}
+
+/// Endpoints of the live `peers` the author of `query` may connect to, as seen by the service.
+fn observed(state: &DaemonState, query: &Query, peers: &[WgPeer], stale_after: u64, now: u64) -> Vec<Observed> {
+    peers
+        .iter()
+        .filter(|peer| peer.public_key != query.public_key && state.linked(&query.public_key, &peer.public_key))
+        .filter(|peer| !stale(peer, stale_after, now))
+        .filter_map(|peer| {
+            let endpoint = peer.endpoint.as_deref()?.parse().ok()?;
+            Some(Observed { public_key: peer.public_key.clone(), endpoint })
+        })
+        .collect()
+}
+
+/// UDP service run by a peer with a public endpoint, sharing with every peer of the repository the
+/// endpoints it sees the other peers at on its own tunnel, which are their NAT mappings.
+pub struct RendezvousServer {
+    port: u16,
+    private_key: String,
+    stale_after: u64,
+    state: SharedState,
+    replays: Replays,
+}
+
+impl RendezvousServer {
+    pub fn new(port: u16, private_key: &str, stale_after: u64, state: SharedState) -> Self {
+        RendezvousServer { port, private_key: private_key.to_string(), stale_after, state, replays: Replays::default() }
+    }
+
+    pub async fn run_in_background(mut self, mut shutdown: ShutdownSignal) -> Result<JoinHandle<()>> {
+        let socket = bind(self.port)?;
+        info!("Rendezvous service listening on UDP port {}", self.port);
+        Ok(task::spawn(async move {
+            let mut buffer = vec![0; MAX_DATAGRAM];
+            loop {
+                let (length, source) = tokio::select! {
+                    received = socket.recv_from(&mut buffer) => match received {
+                        Ok(received) => received,
+                        Err(e) => {
+                            error!("Rendezvous service unable to receive: {}", e);
+                            continue;
+                        }
+                    },
+                    _ = shutdown.wait() => break,
+                };
+                match self.answer(&buffer[..length]).await {
+                    Ok(answer) => {
+                        if let Err(e) = socket.send_to(&answer, source).await {
+                            error!("Rendezvous service unable to answer {}: {}", source, e);
+                        }
+                    }
+                    Err(e) => warn!("Rendezvous query from {} refused: {}", source, e),
+                }
+            }
+        }))
+    }
+
+    async fn answer(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let query: Query = serde_json::from_slice(data)?;
+        let (repository, driver, runner, known) = {
+            let state = self.state.read();
+            let known = state.peer_name(&query.public_key).is_some();
+            (state.repository.clone(), state.wg_driver, state.runner.clone(), known)
+        };
+        if !known {
+            bail!("{} is not a peer of the repository", query.public_key);
+        }
+        let now = now();
+        let key = query.verify(&self.private_key, now)?;
+        self.replays.check(&query, now)?;
+        let peers = driver.tunnel(&repository, &runner)?.peers().await?;
+        let observed = observed(&self.state.read(), &query, &peers, self.stale_after, now);
+        debug!("Sharing {} peer endpoints with {}", observed.len(), query.public_key);
+        Answer::new(query.nonce, observed).seal(&key)
+    }
+}
+
+/// Peers without endpoint of their own, with no fresh handshake, and the endpoints the service sees
+/// them at.
+fn targets(state: &DaemonState, answer: &Answer, peers: &[WgPeer], stale_after: u64, now: u64) -> Vec<Observed> {
+    answer
+        .peers
+        .iter()
+        .filter(|observed| state.peer_endpoints(&observed.public_key).is_empty())
+        .filter(|observed| {
+            peers.iter().any(|peer| {
+                peer.public_key == observed.public_key
+                    && stale(peer, stale_after, now)
+                    && peer.endpoint.as_deref() != Some(observed.endpoint.to_string().as_str())
+            })
+        })
+        .cloned()
+        .collect()
+}
+
+/// Periodically asks the rendezvous service where the peers without endpoint are, pointing the
+/// tunnel at them so both ends punch through their NAT.
+pub struct RendezvousClient {
+    server: Endpoint,
+    server_key: String,
+    private_key: String,
+    interval: Duration,
+    stale_after: u64,
+    state: SharedState,
+}
+
+impl RendezvousClient {
+    pub fn new(
+        server: Endpoint,
+        server_key: &str,
+        private_key: &str,
+        interval: Duration,
+        stale_after: u64,
+        state: SharedState,
+    ) -> Self {
+        RendezvousClient {
+            server,
+            server_key: server_key.to_string(),
+            private_key: private_key.to_string(),
+            interval,
+            stale_after,
+            state,
+        }
+    }
+
+    pub fn run_in_background(self, mut shutdown: ShutdownSignal) -> JoinHandle<()> {
+        task::spawn(async move {
+            info!(
+                "Asking rendezvous service {} for peer endpoints every {} seconds",
+                self.server,
+                self.interval.as_secs()
+            );
+            loop {
+                tokio::select! {
+                    _ = time::sleep(self.interval) => {},
+                    _ = shutdown.wait() => break,
+                }
+                if let Err(e) = self.check().await {
+                    error!("Unable to query rendezvous service {}: {}", self.server, e);
+                }
+            }
+        })
+    }
+
+    async fn query(&self) -> Result<Answer> {
+        let server = self.server.resolve().await?[0];
+        let local: SocketAddr = match server {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local).await?;
+        let nonce = rand::random::<u64>();
+        let query = Query::new(&self.private_key, &self.server_key, nonce, now())?;
+        socket.send_to(&serde_json::to_vec(&query)?, server).await?;
+        let mut buffer = vec![0; MAX_DATAGRAM];
+        let length = loop {
+            let (length, source) = time::timeout(ANSWER_TIMEOUT, socket.recv_from(&mut buffer))
+                .await
+                .map_err(|_| eyre!("no answer after {} seconds", ANSWER_TIMEOUT.as_secs()))??;
+            if source == server {
+                break length;
+            }
+        };
+        Answer::open(&buffer[..length], &shared_key(&self.private_key, &self.server_key)?, nonce)
+    }
+
+    async fn check(&self) -> Result<()> {
+        let answer = self.query().await?;
+        let (repository, driver, runner) = {
+            let state = self.state.read();
+            (state.repository.clone(), state.wg_driver, state.runner.clone())
+        };
+        let tunnel = driver.tunnel(&repository, &runner)?;
+        let peers = tunnel.peers().await?;
+        let targets = targets(&self.state.read(), &answer, &peers, self.stale_after, now());
+        for target in targets {
+            let name = self.state.read().peer_name(&target.public_key).unwrap_or_else(|| target.public_key.clone());
+            info!("Peer {} is seen at {} by the rendezvous service, connecting to it", name, target.endpoint);
+            tunnel.set_endpoint(&target.public_key, target.endpoint).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn private_key(seed: u8) -> String {
+        BASE64.encode([seed; 32])
+    }
+
+    fn live(public_key: &str, endpoint: Option<&str>, latest_handshake: Option<u64>) -> WgPeer {
+        WgPeer {
+            endpoint: endpoint.map(str::to_string),
+            public_key: public_key.to_string(),
+            latest_handshake,
+            transfer_rx: None,
+            transfer_tx: None,
+            persistent_keepalive: Some(25),
+            allowed_ips: vec![],
+        }
+    }
+
+    fn state(carol_endpoint: &str) -> DaemonState {
+        let peer = |name: &str, seed: u8, endpoint: &str| {
+            let (username, peername) = name.split_once('-').unwrap();
+            format!(
+                "[peers.{}]\nusername = \"{}\"\npeername = \"{}\"\naddress = \"10.0.0.{}/24\"\nlisten_port = 51820\npublic_key = \"{}\"\nallowed_ips = [\"10.0.0.{}/32\"]\npersistent_keepalive = 25\nmtu = 1420\n{}\n",
+                name, username, peername, seed, public_key(&private_key(seed)).unwrap(), seed, endpoint
+            )
+        };
+        let nodes = format!(
+            "repository = \"avalon\"\nnetwork = \"10.0.0.0/24\"\ndomain = \"avalon.lan\"\n\n{}{}{}{}\n[[policies]]\nfrom = \"alice-laptop\"\nto = \"carol-phone\"\n\n[[policies]]\nfrom = \"*\"\nto = \"bob-cloud\"\n\n[rendezvous]\npeer = \"bob-cloud\"\n",
+            peer("bob-cloud", 1, "endpoint = \"cloud.bob.net\""),
+            peer("alice-laptop", 2, ""),
+            peer("carol-phone", 3, carol_endpoint),
+            peer("dave-phone", 4, ""),
+        );
+        let config = Config::parse(&nodes).unwrap();
+        let (server_key, server) = config.rendezvous.as_ref().unwrap().server(&config).unwrap();
+        assert_eq!(server_key, public_key(&private_key(1)).unwrap());
+        assert_eq!(server.to_string(), "cloud.bob.net:51900");
+        let mut state = DaemonState::new("avalon");
+        state.update_config(&config);
+        state
+    }
+
+    #[test]
+    fn test_query_authentication() {
+        let (server, alice) = (private_key(1), private_key(2));
+        let server_key = public_key(&server).unwrap();
+        let query = Query::new(&alice, &server_key, 7, 1000).unwrap();
+        assert_eq!(query.public_key, public_key(&alice).unwrap());
+        let key = query.verify(&server, 1010).unwrap();
+        assert_eq!(key, shared_key(&alice, &server_key).unwrap());
+        assert!(query.verify(&server, 1100).is_err());
+        assert!(Query { nonce: 8, ..query.clone() }.verify(&server, 1010).is_err());
+        assert!(query.verify(&private_key(3), 1010).is_err());
+        let forged = Query::new(&private_key(3), &server_key, 7, 1000).unwrap();
+        assert!(Query { public_key: query.public_key.clone(), ..forged }.verify(&server, 1010).is_err());
+    }
+
+    #[test]
+    fn test_query_replay() {
+        let server_key = public_key(&private_key(1)).unwrap();
+        let query = Query::new(&private_key(2), &server_key, 7, 1000).unwrap();
+        let mut replays = Replays::default();
+        replays.check(&query, 1000).unwrap();
+        assert!(replays.check(&query, 1010).is_err());
+        replays.check(&Query::new(&private_key(2), &server_key, 8, 1010).unwrap(), 1010).unwrap();
+        replays.check(&Query::new(&private_key(3), &server_key, 7, 1010).unwrap(), 1010).unwrap();
+        // The nonces are forgotten once the queries are too old to be accepted.
+        replays.check(&Query::new(&private_key(4), &server_key, 1, 1100).unwrap(), 1100).unwrap();
+        assert_eq!(replays.seen.len(), 1);
+    }
+
+    #[test]
+    fn test_answer_encryption() {
+        let key = shared_key(&private_key(2), &public_key(&private_key(1)).unwrap()).unwrap();
+        let peers = vec![Observed { public_key: "carol".to_string(), endpoint: "203.0.113.3:40000".parse().unwrap() }];
+        let answer = Answer::new(7, peers);
+        let data = answer.seal(&key).unwrap();
+        assert!(!String::from_utf8_lossy(&data).contains("203.0.113.3"));
+        assert_eq!(Answer::open(&data, &key, 7).unwrap(), answer);
+        assert!(Answer::open(&data, &key, 8).is_err());
+        assert!(Answer::open(&data, &[0; 32], 7).is_err());
+        let mut forged: SealedAnswer = serde_json::from_slice(&data).unwrap();
+        forged.nonce = 8;
+        assert!(Answer::open(&serde_json::to_vec(&forged).unwrap(), &key, 8).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bind_dual_stack() {
+        let socket = bind(0).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        client.send_to(b"query", (Ipv4Addr::LOCALHOST, port)).await.unwrap();
+        let mut buffer = [0; 16];
+        let (length, _) = socket.recv_from(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..length], b"query");
+    }
+
+    #[test]
+    fn test_endpoints_shared_with_linked_peers() {
+        let state = state("");
+        let (alice, carol, dave) = (
+            public_key(&private_key(2)).unwrap(),
+            public_key(&private_key(3)).unwrap(),
+            public_key(&private_key(4)).unwrap(),
+        );
+        let query = Query::new(&private_key(2), &public_key(&private_key(1)).unwrap(), 7, 1000).unwrap();
+        let peers = vec![
+            live(&alice, Some("203.0.113.2:41000"), Some(990)),
+            live(&carol, Some("203.0.113.3:42000"), Some(990)),
+            live(&dave, Some("203.0.113.4:43000"), Some(990)),
+        ];
+        let observed = observed(&state, &query, &peers, 180, 1000);
+        assert_eq!(
+            observed,
+            vec![Observed { public_key: carol.clone(), endpoint: "203.0.113.3:42000".parse().unwrap() }]
+        );
+        let answer = Answer::new(7, observed);
+        // Alice points its stale session with Carol at the endpoint the service sees.
+        let targets = targets(&state, &answer, &[live(&carol, None, None)], 180, 1000);
+        assert_eq!(targets, answer.peers);
+        assert!(super::targets(&state, &answer, &[live(&carol, None, Some(990))], 180, 1000).is_empty());
+        assert!(super::targets(&state, &answer, &[live(&carol, Some("203.0.113.3:42000"), None)], 180, 1000).is_empty());
+        // Peers with an endpoint of their own are reached there.
+        let state = super::tests::state("endpoint = \"phone.carol.net\"");
+        assert!(super::targets(&state, &answer, &[live(&carol, None, None)], 180, 1000).is_empty());
+    }
+}
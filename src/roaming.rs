@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use color_eyre::eyre::Result;
+use tokio::task::{self, JoinHandle};
+use tokio::time;
+
+use crate::endpoint::Endpoint;
+use crate::shutdown::ShutdownSignal;
+use crate::state::{now, SharedState};
+use crate::wg::{WgPeer, WgTunnel};
+
+/// Endpoint candidate in use for one peer, and the address it last resolved to.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Roaming {
+    candidate: usize,
+    resolved: Option<SocketAddr>,
+}
+
+/// Periodically resolves the endpoints of the live peers again, pointing the peers at the new
+/// address when their DNS name moves, and at their next candidate when they stop answering.
+pub struct EndpointResolver {
+    interval: Duration,
+    stale_after: u64,
+    state: SharedState,
+    peers: HashMap<String, Roaming>,
+}
+
+impl EndpointResolver {
+    pub fn new(interval: Duration, stale_after: u64, state: SharedState) -> Self {
+        EndpointResolver { interval, stale_after, state, peers: HashMap::new() }
+    }
+
+    pub fn run_in_background(mut self, mut shutdown: ShutdownSignal) -> JoinHandle<()> {
+        task::spawn(async move {
+            info!(
+                "Peer endpoints resolved every {} seconds, moving on to the next candidate after {} seconds without handshake",
+                self.interval.as_secs(),
+                self.stale_after
+            );
+            loop {
+                tokio::select! {
+                    _ = time::sleep(self.interval) => {},
+                    _ = shutdown.wait() => break,
+                }
+                if let Err(e) = self.check().await {
+                    error!("Unable to resolve peer endpoints: {}", e);
+                }
+            }
+        })
+    }
+
+    async fn check(&mut self) -> Result<()> {
+        let (repository, driver, runner) = {
+            let state = self.state.read();
+            (state.repository.clone(), state.wg_driver, state.runner.clone())
+        };
+        let tunnel = driver.tunnel(&repository, &runner)?;
+        let now = now();
+        for peer in tunnel.peers().await? {
+            let candidates = self.state.read().peer_endpoints(&peer.public_key);
+            if let Err(e) = self.roam(tunnel.as_ref(), &peer, &candidates, now).await {
+                error!("Unable to update the endpoint of peer {}: {}", peer.public_key, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Point `peer` at the address of its current candidate when it changed, or when the peer does
+    /// not answer.
+    async fn roam(&mut self, tunnel: &dyn WgTunnel, peer: &WgPeer, candidates: &[Endpoint], now: u64) -> Result<()> {
+        if candidates.is_empty() {
+            return Ok(());
+        }
+        let stale =
+            peer.latest_handshake.map(|handshake| now.saturating_sub(handshake) > self.stale_after).unwrap_or(true);
+        let roaming = self.peers.entry(peer.public_key.clone()).or_default();
+        // Idle peers without keepalive have no fresh handshake either, only the others roam.
+        if stale && roaming.resolved.is_some() && candidates.len() > 1 && peer.persistent_keepalive.is_some() {
+            roaming.candidate = (roaming.candidate + 1) % candidates.len();
+            roaming.resolved = None;
+            info!("Peer {} is not answering, trying endpoint {}", peer.public_key, candidates[roaming.candidate]);
+        }
+        let mut address = None;
+        for offset in 0..candidates.len() {
+            let index = (roaming.candidate + offset) % candidates.len();
+            match candidates[index].resolve().await {
+                Ok(addresses) => {
+                    roaming.candidate = index;
+                    address = addresses.first().cloned();
+                    break;
+                }
+                Err(e) => warn!("{}", e),
+            }
+        }
+        let address = match address {
+            Some(address) => address,
+            None => return Ok(()),
+        };
+        let previous = roaming.resolved.replace(address);
+        let current = peer.endpoint.as_deref().and_then(|endpoint| endpoint.parse::<SocketAddr>().ok());
+        // A peer answering elsewhere roamed there by itself, it is left alone until its name moves.
+        if current == Some(address) || (!stale && previous.map(|previous| previous == address).unwrap_or(true)) {
+            return Ok(());
+        }
+        info!("Peer {} endpoint {} now at {}", peer.public_key, candidates[roaming.candidate], address);
+        tunnel.set_endpoint(&peer.public_key, address).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use async_trait::async_trait;
+    use parking_lot::Mutex;
+
+    use super::*;
+    use crate::error;
+    use crate::state::DaemonState;
+    use crate::wg::WgBackend;
+
+    /// Tunnel recording the endpoints set on its peers.
+    #[derive(Default)]
+    struct FakeTunnel {
+        endpoints: Mutex<Vec<(String, SocketAddr)>>,
+    }
+
+    #[async_trait]
+    impl WgTunnel for FakeTunnel {
+        async fn up(&self, _config: &Path) -> error::Result<()> {
+            Ok(())
+        }
+        async fn down(&self, _config: &Path) -> error::Result<()> {
+            Ok(())
+        }
+        async fn is_up(&self) -> bool {
+            true
+        }
+        async fn sync(&self, _config: &Path) -> error::Result<()> {
+            Ok(())
+        }
+        async fn peers(&self) -> error::Result<Vec<WgPeer>> {
+            Ok(vec![])
+        }
+        async fn set_endpoint(&self, public_key: &str, endpoint: SocketAddr) -> error::Result<()> {
+            self.endpoints.lock().push((public_key.to_string(), endpoint));
+            Ok(())
+        }
+        async fn status(&self) -> error::Result<()> {
+            Ok(())
+        }
+        fn backend(&self) -> Option<WgBackend> {
+            None
+        }
+    }
+
+    fn peer(endpoint: &str, latest_handshake: Option<u64>) -> WgPeer {
+        WgPeer {
+            endpoint: Some(endpoint.to_string()),
+            public_key: "bob".to_string(),
+            latest_handshake,
+            transfer_rx: None,
+            transfer_tx: None,
+            persistent_keepalive: Some(25),
+            allowed_ips: vec![],
+        }
+    }
+
+    fn addr(address: &str) -> SocketAddr {
+        address.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_roaming_to_the_next_candidate() {
+        let mut resolver = EndpointResolver::new(Duration::from_secs(60), 180, DaemonState::new("avalon").shared());
+        let tunnel = FakeTunnel::default();
+        let candidates =
+            vec![Endpoint::parse("1.2.3.4", 6666).unwrap(), Endpoint::parse("[2001:db8::1]:4500", 6666).unwrap()];
+        // A peer answering on its first candidate is left alone.
+        resolver.roam(&tunnel, &peer("1.2.3.4:6666", Some(1000)), &candidates, 1100).await.unwrap();
+        assert!(tunnel.endpoints.lock().is_empty());
+        // Roamed by itself to another address, it keeps it while it answers.
+        resolver.roam(&tunnel, &peer("5.6.7.8:6666", Some(1100)), &candidates, 1200).await.unwrap();
+        assert!(tunnel.endpoints.lock().is_empty());
+        // Once it stops answering, the next candidate is tried.
+        resolver.roam(&tunnel, &peer("5.6.7.8:6666", Some(1100)), &candidates, 1500).await.unwrap();
+        assert_eq!(*tunnel.endpoints.lock(), vec![("bob".to_string(), addr("[2001:db8::1]:4500"))]);
+        resolver.roam(&tunnel, &peer("[2001:db8::1]:4500", Some(1100)), &candidates, 1600).await.unwrap();
+        assert_eq!(tunnel.endpoints.lock().last().unwrap().1, addr("1.2.3.4:6666"));
+    }
+
+    #[tokio::test]
+    async fn test_new_address_is_applied() {
+        let mut resolver = EndpointResolver::new(Duration::from_secs(60), 180, DaemonState::new("avalon").shared());
+        let tunnel = FakeTunnel::default();
+        let old = vec![Endpoint::parse("1.2.3.4", 6666).unwrap()];
+        resolver.roam(&tunnel, &peer("1.2.3.4:6666", Some(1000)), &old, 1100).await.unwrap();
+        let new = vec![Endpoint::parse("1.2.3.5", 6666).unwrap()];
+        resolver.roam(&tunnel, &peer("1.2.3.4:6666", Some(1100)), &new, 1200).await.unwrap();
+        assert_eq!(*tunnel.endpoints.lock(), vec![("bob".to_string(), addr("1.2.3.5:6666"))]);
+        // Peers without endpoint are not touched.
+        resolver.roam(&tunnel, &peer("1.2.3.9:6666", None), &[], 1300).await.unwrap();
+        assert_eq!(tunnel.endpoints.lock().len(), 1);
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use parking_lot::Mutex;
+
+use crate::shell::{Shell, ShellCommand, ShellResult};
+
+/// Runs the external commands Fireguard depends on, so they can be replaced in tests.
+#[async_trait]
+pub trait CommandRunner: Send + Sync {
+    async fn run(&self, command: &ShellCommand) -> ShellResult;
+
+    /// Whether `name` can be run.
+    fn runnable(&self, name: &str) -> bool {
+        Shell::runnable(name)
+    }
+}
+
+/// Runs commands on the host.
+pub struct SystemRunner;
+
+#[async_trait]
+impl CommandRunner for SystemRunner {
+    async fn run(&self, command: &ShellCommand) -> ShellResult {
+        Shell::run(command).await
+    }
+}
+
+/// Shared handle to the runner used by commands, the system one by default.
+#[derive(Clone)]
+pub struct Runner(Arc<dyn CommandRunner>);
+
+impl Default for Runner {
+    fn default() -> Self {
+        Runner(Arc::new(SystemRunner))
+    }
+}
+
+impl fmt::Debug for Runner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Runner")
+    }
+}
+
+impl Runner {
+    pub fn new(runner: Arc<dyn CommandRunner>) -> Self {
+        Runner(runner)
+    }
+
+    pub async fn run(&self, command: &ShellCommand) -> ShellResult {
+        self.0.run(command).await
+    }
+
+    pub fn runnable(&self, name: &str) -> bool {
+        self.0.runnable(name)
+    }
+
+    pub async fn exec<S: AsRef<str>>(
+        &self,
+        command: &str,
+        args: &[S],
+        current_dir: Option<&Path>,
+        sensitive: bool,
+    ) -> ShellResult {
+        let mut command = ShellCommand::new(command).args(args).sensitive(sensitive);
+        if let Some(cwd) = current_dir {
+            command = command.cwd(cwd);
+        }
+        self.run(&command).await
+    }
+
+    pub async fn exec_with_env<S: AsRef<str>>(
+        &self,
+        command: &str,
+        args: &[S],
+        current_dir: Option<&Path>,
+        env: HashMap<&str, &str>,
+        sensitive: bool,
+    ) -> ShellResult {
+        let mut command = ShellCommand::new(command).args(args).envs(&env).sensitive(sensitive);
+        if let Some(cwd) = current_dir {
+            command = command.cwd(cwd);
+        }
+        self.run(&command).await
+    }
+
+    pub async fn exec_with_input<S: AsRef<str>>(
+        &self,
+        command: &str,
+        args: &[S],
+        current_dir: Option<&Path>,
+        stdin: &str,
+        sensitive: bool,
+    ) -> ShellResult {
+        let mut command = ShellCommand::new(command).args(args).stdin(stdin).sensitive(sensitive);
+        if let Some(cwd) = current_dir {
+            command = command.cwd(cwd);
+        }
+        self.run(&command).await
+    }
+}
+
+/// Runner recording the commands instead of running them, answering with scripted results.
+/// Commands without a script succeed with no output.
+#[derive(Default)]
+pub struct FakeRunner {
+    scripts: Vec<(Vec<String>, ShellResult)>,
+    missing: Vec<String>,
+    calls: Mutex<Vec<ShellCommand>>,
+}
+
+impl FakeRunner {
+    pub fn new() -> Self {
+        FakeRunner::default()
+    }
+
+    /// Answer commands starting with `command`, the program followed by leading arguments, with
+    /// `result`. The first matching script wins.
+    pub fn on(mut self, command: &[&str], result: ShellResult) -> Self {
+        self.scripts.push((command.iter().map(|arg| arg.to_string()).collect(), result));
+        self
+    }
+
+    /// Answer commands starting with `command` with `stdout` and a zero exit code.
+    pub fn on_success(self, command: &[&str], stdout: &str) -> Self {
+        self.on(command, ShellResult::new(stdout, "", Some(0)))
+    }
+
+    /// Answer commands starting with `command` with `stderr` and exit code 1.
+    pub fn on_failure(self, command: &[&str], stderr: &str) -> Self {
+        self.on(command, ShellResult::new("", stderr, Some(1)))
+    }
+
+    /// Make `program` look not installed.
+    pub fn missing(mut self, program: &str) -> Self {
+        self.missing.push(program.to_string());
+        self
+    }
+
+    /// Commands run so far, in order.
+    pub fn calls(&self) -> Vec<ShellCommand> {
+        self.calls.lock().clone()
+    }
+
+    /// Command lines run so far, in order.
+    pub fn command_lines(&self) -> Vec<String> {
+        self.calls().iter().map(ShellCommand::to_string).collect()
+    }
+
+    fn matches(script: &[String], command: &ShellCommand) -> bool {
+        match script.split_first() {
+            Some((program, args)) => program == command.program() && command.arguments().starts_with(args),
+            None => false,
+        }
+    }
+}
+
+#[async_trait]
+impl CommandRunner for FakeRunner {
+    async fn run(&self, command: &ShellCommand) -> ShellResult {
+        self.calls.lock().push(command.clone());
+        if self.missing.iter().any(|program| program == command.program()) {
+            return ShellResult::new("", "No such file or directory (os error 2)", None);
+        }
+        self.scripts
+            .iter()
+            .find(|(script, _)| FakeRunner::matches(script, command))
+            .map(|(_, result)| result.clone())
+            .unwrap_or_else(|| ShellResult::new("", "", Some(0)))
+    }
+
+    fn runnable(&self, name: &str) -> bool {
+        !self.missing.iter().any(|program| program == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_fake_runner() {
+        let fake = Arc::new(
+            FakeRunner::new()
+                .on_success(&["wg", "show", "avalon", "dump"], "dump")
+                .on_failure(&["wg", "show"], "No such device")
+                .missing("dnsmasq"),
+        );
+        let runner = Runner::new(fake.clone());
+        assert_eq!(runner.exec("wg", &["show", "avalon", "dump"], None, true).await.stdout(), "dump");
+        assert!(!runner.exec("wg", &["show", "avalon"], None, true).await.success());
+        assert!(runner.exec("git", &["pull"], None, false).await.success());
+        assert!(!runner.runnable("dnsmasq"));
+        assert_eq!(runner.exec::<&str>("dnsmasq", &[], None, false).await.code(), None);
+        assert_eq!(fake.command_lines(), vec!["wg show avalon dump", "wg show avalon", "git pull", "dnsmasq"]);
+    }
+}
@@ -1,23 +1,27 @@
 use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
+use std::time::Duration;
 
-use futures_util::StreamExt;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::process::{Child, Command};
-use tokio_stream::wrappers::LinesStream;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+use tokio::time;
 
 pub struct Shell {}
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ShellResult {
     stdout: String,
     stderr: String,
-    success: bool,
+    code: Option<i32>,
 }
 
 impl ShellResult {
-    pub fn new(stdout: &str, stderr: &str, success: bool) -> Self {
-        Self { stdout: stdout.to_string(), stderr: stderr.to_string(), success }
+    /// `code` is None when the command could not be spawned, timed out or was killed by a signal.
+    pub fn new(stdout: &str, stderr: &str, code: Option<i32>) -> Self {
+        Self { stdout: stdout.to_string(), stderr: stderr.to_string(), code }
     }
     pub fn stdout(&self) -> &str {
         &self.stdout
@@ -25,242 +29,250 @@ impl ShellResult {
     pub fn stderr(&self) -> &str {
         &self.stderr
     }
+    pub fn code(&self) -> Option<i32> {
+        self.code
+    }
     pub fn success(&self) -> bool {
-        self.success
+        self.code == Some(0)
     }
 }
 
-impl Shell {
-    async fn handle_stdout_stderr(&self, child: Child, sensitive: bool) -> ShellResult {
-        // TODO: this method is needed because we cannot stream stdout / stderr when stdin
-        // handling is involved. We have 0 clues why, we are not smart enough to understand
-        // tokio in its fullness.
-        //
-        // Ideas: https://github.com/tokio-rs/tokio/issues/2199
-        //
-        // So we implemented this non streaming method to overcome the limitation and thus be
-        // able to generate Wireguard private and public key.
-        // This might be automatically solved when we move to tokio 1 or when someone smarter
-        // comes around and fix this.
-        match child.wait_with_output().await {
-            Ok(output) => {
-                let mut stdout = String::new();
-                let mut stderr = String::new();
-                if !output.stdout.is_empty() {
-                    stdout = String::from_utf8_lossy(&output.stdout).to_string();
-                    if !sensitive {
-                        info!("Command stdout: {}", stdout.trim());
-                    }
-                }
-                if !output.stderr.is_empty() {
-                    stderr = String::from_utf8_lossy(&output.stderr).to_string();
-                    if !sensitive {
-                        info!("Command stderr: {}", stderr.trim());
-                    }
-                }
-                ShellResult::new(&stdout, &stderr, output.status.success())
-            }
-            Err(e) => {
-                error!("Error waiting for command output: {}", e);
-                ShellResult::new("", &e.to_string(), false)
-            }
+/// Command to run as an argument vector. Arguments reach the program as they are, spaces
+/// included, nothing is interpreted by a shell unless the command is built with `shell`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ShellCommand {
+    program: String,
+    args: Vec<String>,
+    cwd: Option<PathBuf>,
+    env: HashMap<String, String>,
+    stdin: Option<String>,
+    timeout: Option<Duration>,
+    sensitive: bool,
+}
+
+impl ShellCommand {
+    pub fn new(program: &str) -> Self {
+        ShellCommand { program: program.to_string(), ..Default::default() }
+    }
+
+    /// Run `script` with `sh -c`, for pipelines and redirections. Nothing is escaped, so the
+    /// script must not embed untrusted input.
+    pub fn shell(script: &str) -> Self {
+        ShellCommand::new("sh").args(&["-c", script])
+    }
+
+    pub fn arg<S: AsRef<str>>(mut self, arg: S) -> Self {
+        self.args.push(arg.as_ref().to_string());
+        self
+    }
+
+    pub fn args<S: AsRef<str>>(mut self, args: &[S]) -> Self {
+        self.args.extend(args.iter().map(|arg| arg.as_ref().to_string()));
+        self
+    }
+
+    pub fn cwd<P: AsRef<Path>>(mut self, cwd: P) -> Self {
+        self.cwd = Some(cwd.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn envs(mut self, env: &HashMap<&str, &str>) -> Self {
+        self.env.extend(env.iter().map(|(key, value)| (key.to_string(), value.to_string())));
+        self
+    }
+
+    /// Write `input` to the command stdin, which is closed afterwards. Without input stdin is
+    /// connected to /dev/null.
+    pub fn stdin(mut self, input: &str) -> Self {
+        self.stdin = Some(input.to_string());
+        self
+    }
+
+    /// Kill the command if it is still running after `timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Keep the command input and output out of the logs.
+    pub fn sensitive(mut self, sensitive: bool) -> Self {
+        self.sensitive = sensitive;
+        self
+    }
+
+    pub fn program(&self) -> &str {
+        &self.program
+    }
+
+    pub fn arguments(&self) -> &[String] {
+        &self.args
+    }
+
+    pub fn input(&self) -> Option<&str> {
+        self.stdin.as_deref()
+    }
+}
+
+/// Quote `arg` for display, so the logged command line can be pasted back into a shell.
+fn quote(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+impl fmt::Display for ShellCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", quote(&self.program))?;
+        for arg in self.args.iter() {
+            write!(f, " {}", quote(arg))?;
         }
+        Ok(())
     }
+}
 
-    async fn stream_stdout_stderr(&self, mut child: Child, sensitive: bool) -> ShellResult {
-        let stdout = child.stdout.take().expect("Unable to take() child process stdout");
-        let stderr = child.stderr.take().expect("Unable to take() child process stderr");
-        let stdout_result: Vec<_> = LinesStream::new(BufReader::new(stdout).lines())
-            .inspect(|l| {
-                if !sensitive {
-                    match l {
-                        Ok(ref e) => info!("Command stdout: {}", e.trim()),
-                        Err(e) => error!("Stdout error: {}", e),
+impl Shell {
+    /// Log the lines of a command output as they come, returning them joined.
+    async fn stream<R: AsyncRead + Unpin>(output: Option<R>, name: &str, sensitive: bool) -> String {
+        let mut result = vec![];
+        if let Some(output) = output {
+            let mut lines = BufReader::new(output).lines();
+            loop {
+                match lines.next_line().await {
+                    Ok(Some(line)) => {
+                        if !sensitive {
+                            if name == "stderr" {
+                                warn!("Command {}: {}", name, line.trim());
+                            } else {
+                                info!("Command {}: {}", name, line.trim());
+                            }
+                        }
+                        result.push(line);
                     }
-                }
-            })
-            .map(|l| l.unwrap_or_else(|_| "".to_string()))
-            .collect()
-            .await;
-        let stderr_result: Vec<_> = LinesStream::new(BufReader::new(stderr).lines())
-            .inspect(|l| {
-                if !sensitive {
-                    match l {
-                        Ok(ref e) => warn!("Command stderr: {}", e.trim()),
-                        Err(e) => error!("Stderr error: {}", e),
+                    Ok(None) => break,
+                    Err(e) => {
+                        error!("Command {} error: {}", name, e);
+                        break;
                     }
                 }
-            })
-            .map(|l| l.unwrap_or_else(|_| "".to_string()))
-            .collect()
-            .await;
-        match child.wait_with_output().await {
-            Ok(output) => {
-                ShellResult::new(&stdout_result.join("\n"), &stderr_result.join("\n"), output.status.success())
-            }
-            Err(e) => {
-                error!("Error waiting for command output: {}", e);
-                ShellResult::new("", &e.to_string(), false)
             }
         }
+        result.join("\n")
     }
 
-    async fn handle_stdin(&self, stdin: &str, child: &mut Child, sensitive: bool) -> ShellResult {
-        match child.stdin.as_mut() {
-            Some(child) => match child.write(stdin.as_bytes()).await {
-                Ok(n) => {
-                    if !sensitive {
-                        info!("Written {} bytes into command STDIN:\n{}", n, stdin);
+    /// Run `command`, streaming its output while the input is written. Writing stdin from its own
+    /// future and closing it once done lets commands reading until EOF make progress.
+    pub async fn run(command: &ShellCommand) -> ShellResult {
+        info!(
+            "Executing command `{}`, cwd: {}, stdin: {}, env: {:?}, sensitive: {}",
+            command,
+            command.cwd.as_deref().unwrap_or_else(|| Path::new(".")).display(),
+            command.stdin.is_some(),
+            command.env.keys().collect::<Vec<_>>(),
+            command.sensitive
+        );
+        let mut process = Command::new(&command.program);
+        process
+            .args(&command.args)
+            .envs(&command.env)
+            .stdin(if command.stdin.is_some() { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        if let Some(ref cwd) = command.cwd {
+            process.current_dir(cwd);
+        }
+        let mut child = match process.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                error!("Command `{}` failed: {}", command, e);
+                return ShellResult::new("", &e.to_string(), None);
+            }
+        };
+        let (stdin, stdout, stderr) = (child.stdin.take(), child.stdout.take(), child.stderr.take());
+        let sensitive = command.sensitive;
+        let input = command.stdin.clone();
+        let execution = async move {
+            let write = async move {
+                if let (Some(mut stdin), Some(input)) = (stdin, input) {
+                    match stdin.write_all(input.as_bytes()).await {
+                        Ok(()) if !sensitive => info!("Written {} bytes into command STDIN:\n{}", input.len(), input),
+                        Ok(()) => (),
+                        Err(e) => error!("Unable to write to command STDIN: {}", e),
                     }
-                    ShellResult::new("Ok", "", true)
                 }
+            };
+            let (_, stdout, stderr, status) = tokio::join!(
+                write,
+                Shell::stream(stdout, "stdout", sensitive),
+                Shell::stream(stderr, "stderr", sensitive),
+                child.wait()
+            );
+            match status {
+                Ok(status) => ShellResult::new(&stdout, &stderr, status.code()),
                 Err(e) => {
-                    error!("Unable to write to command STDIN: {}", e);
-                    ShellResult::new("", &e.to_string(), false)
+                    error!("Error waiting for command output: {}", e);
+                    ShellResult::new(&stdout, &e.to_string(), None)
                 }
-            },
-            None => {
-                error!("Stdin for process is not available");
-                ShellResult::new("", "Stdin for process is not available", false)
             }
+        };
+        match command.timeout {
+            // Dropping the execution on timeout kills the child.
+            Some(timeout) => match time::timeout(timeout, execution).await {
+                Ok(result) => result,
+                Err(_) => {
+                    error!("Command `{}` timed out after {}s", command, timeout.as_secs_f32());
+                    ShellResult::new("", &format!("timed out after {}s", timeout.as_secs_f32()), None)
+                }
+            },
+            None => execution.await,
         }
     }
 
-    fn log_exec_info(
-        &self,
-        command: &str,
-        args: &str,
-        current_dir: &str,
-        stdin: bool,
-        env: &HashMap<&str, &str>,
-        sensitive: bool,
-    ) {
-        info!(
-            "Executing command `{} {}`, cwd: {}, stdin: {}, env: {:?}, sentitive: {}",
-            command, args, current_dir, stdin, env, sensitive
-        );
-    }
-
+    /// Whether `name` can be found on the PATH.
     pub fn runnable(name: &str) -> bool {
-        match Command::new(name).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn() {
-            Ok(_) => true,
-            Err(_) => {
-                error!("Unable to find runnable command {}", name);
-                false
-            }
+        let paths = env::var_os("PATH").unwrap_or_default();
+        if env::split_paths(&paths).any(|dir| dir.join(name).is_file()) {
+            true
+        } else {
+            error!("Unable to find runnable command {}", name);
+            false
         }
     }
+}
 
-    pub async fn exec(command: &str, args: &str, current_dir: Option<&str>, sensitive: bool) -> ShellResult {
-        let shell = Shell {};
-        let cwd = current_dir.unwrap_or(".");
-        shell.log_exec_info(command, args, cwd, false, &HashMap::new(), sensitive);
-        match Command::new(command)
-            .current_dir(cwd)
-            .args(args.split(' ').collect::<Vec<&str>>())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-        {
-            Ok(child) => shell.stream_stdout_stderr(child, sensitive).await,
-            Err(e) => {
-                error!("Command {} {} failed: {}", command, args, e);
-                ShellResult::new("", &e.to_string(), false)
-            }
-        }
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    #[allow(dead_code)]
-    pub async fn exec_with_env(
-        command: &str,
-        args: &str,
-        current_dir: Option<&str>,
-        env: HashMap<&str, &str>,
-        sensitive: bool,
-    ) -> ShellResult {
-        let shell = Shell {};
-        let cwd = current_dir.unwrap_or(".");
-        shell.log_exec_info(command, args, cwd, false, &HashMap::new(), sensitive);
-        match Command::new(command)
-            .current_dir(cwd)
-            .args(args.split(' ').collect::<Vec<&str>>())
-            .envs(env)
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-        {
-            Ok(child) => shell.stream_stdout_stderr(child, sensitive).await,
-            Err(e) => {
-                error!("Command {} {} failed: {}", command, args, e);
-                ShellResult::new("", &e.to_string(), false)
-            }
-        }
+    #[tokio::test]
+    async fn test_exec_argv() {
+        let result = Shell::run(&ShellCommand::new("printf").args(&["%s|", "with space", "|grep"])).await;
+        assert!(result.success());
+        assert_eq!(result.stdout(), "with space||grep|");
+        let result = Shell::run(&ShellCommand::shell("echo piped | tr a-z A-Z; exit 3")).await;
+        assert_eq!(result.stdout(), "PIPED");
+        assert_eq!(result.code(), Some(3));
+        assert!(!result.success());
+        let missing = Shell::run(&ShellCommand::new("fireguard-missing-command")).await;
+        assert_eq!(missing.code(), None);
     }
 
-    pub async fn exec_with_input(
-        command: &str,
-        args: &str,
-        current_dir: Option<&str>,
-        stdin: &str,
-        sensitive: bool,
-    ) -> ShellResult {
-        let shell = Shell {};
-        let cwd = current_dir.unwrap_or(".");
-        shell.log_exec_info(command, args, cwd, true, &HashMap::new(), sensitive);
-        match Command::new(command)
-            .current_dir(cwd)
-            .args(args.split(' ').collect::<Vec<&str>>())
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-        {
-            Ok(mut child) => {
-                let result = shell.handle_stdin(stdin, &mut child, sensitive).await;
-                if !result.success() {
-                    return result;
-                }
-                shell.handle_stdout_stderr(child, sensitive).await
-            }
-            Err(e) => {
-                error!("Command {} {} failed: {}", command, args, e);
-                ShellResult::new("", &e.to_string(), false)
-            }
-        }
+    #[tokio::test]
+    async fn test_exec_input_and_timeout() {
+        let input = (0..10000).map(|line| format!("line {}\n", line)).collect::<String>();
+        let result = Shell::run(&ShellCommand::new("cat").stdin(&input).sensitive(true)).await;
+        assert_eq!(result.stdout().lines().count(), 10000);
+        let slow = Shell::run(&ShellCommand::new("sleep").arg("5").timeout(Duration::from_millis(100))).await;
+        assert_eq!(slow.code(), None);
+        assert!(slow.stderr().contains("timed out"));
     }
 
-    #[allow(dead_code)]
-    pub async fn exec_with_input_and_env(
-        command: &str,
-        args: &str,
-        current_dir: Option<&str>,
-        stdin: &str,
-        env: HashMap<&str, &str>,
-        sensitive: bool,
-    ) -> ShellResult {
-        let shell = Shell {};
-        let cwd = current_dir.unwrap_or(".");
-        shell.log_exec_info(command, args, cwd, true, &env, sensitive);
-        match Command::new(command)
-            .current_dir(cwd)
-            .args(args.split(' ').collect::<Vec<&str>>())
-            .envs(env)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-        {
-            Ok(mut child) => {
-                let result = shell.handle_stdin(stdin, &mut child, sensitive).await;
-                if !result.success() {
-                    return result;
-                }
-                shell.handle_stdout_stderr(child, sensitive).await
-            }
-            Err(e) => {
-                error!("Command {} {} failed: {}", command, args, e);
-                ShellResult::new("", &e.to_string(), false)
-            }
-        }
+    #[test]
+    fn test_display() {
+        let command = ShellCommand::new("git").args(&["clone", "https://example.com/repo", "/srv/my repos/it's"]);
+        assert_eq!(command.to_string(), "git clone https://example.com/repo '/srv/my repos/it'\\''s'");
     }
 }
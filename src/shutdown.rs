@@ -0,0 +1,167 @@
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+use color_eyre::eyre::Result;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+/// Broadcasts the shutdown request to all the daemon background components and keeps track of
+/// their tasks, so they can be waited on.
+pub struct Shutdown {
+    sender: watch::Sender<bool>,
+    receiver: watch::Receiver<bool>,
+    components: Vec<(&'static str, JoinHandle<()>)>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(false);
+        Shutdown { sender, receiver, components: vec![] }
+    }
+
+    pub fn subscribe(&self) -> ShutdownSignal {
+        ShutdownSignal { receiver: self.receiver.clone() }
+    }
+
+    /// Register the task of a background component to wait for on shutdown.
+    pub fn register(&mut self, name: &'static str, handle: JoinHandle<()>) {
+        self.components.push((name, handle));
+    }
+
+    /// Ask every component to stop, returning their tasks in start order.
+    pub fn trigger(&mut self) -> Vec<(&'static str, JoinHandle<()>)> {
+        let _ = self.sender.send(true);
+        self.components.drain(..).collect()
+    }
+}
+
+/// Handed to every background component, resolves once the daemon is shutting down.
+#[derive(Clone)]
+pub struct ShutdownSignal {
+    receiver: watch::Receiver<bool>,
+}
+
+impl ShutdownSignal {
+    pub async fn wait(&mut self) {
+        while !*self.receiver.borrow() {
+            if self.receiver.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StepOutcome {
+    Ok,
+    Failed(String),
+    TimedOut(Duration),
+}
+
+impl fmt::Display for StepOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StepOutcome::Ok => write!(f, "ok"),
+            StepOutcome::Failed(e) => write!(f, "failed: {}", e),
+            StepOutcome::TimedOut(t) => write!(f, "timed out after {} seconds", t.as_secs()),
+        }
+    }
+}
+
+/// Outcome of every step of the daemon shutdown sequence.
+#[derive(Debug, Default)]
+pub struct ShutdownReport {
+    steps: Vec<(String, StepOutcome)>,
+}
+
+impl ShutdownReport {
+    pub fn new() -> Self {
+        ShutdownReport { steps: vec![] }
+    }
+
+    /// Run one shutdown step, bounded by `timeout`, recording its outcome.
+    pub async fn step<F>(&mut self, name: &str, timeout: Duration, future: F)
+    where
+        F: Future<Output = Result<()>>,
+    {
+        debug!("Shutdown step {} started", name);
+        let outcome = match time::timeout(timeout, future).await {
+            Ok(Ok(())) => StepOutcome::Ok,
+            Ok(Err(e)) => StepOutcome::Failed(e.to_string()),
+            Err(_) => StepOutcome::TimedOut(timeout),
+        };
+        match outcome {
+            StepOutcome::Ok => info!("Shutdown step {}: {}", name, outcome),
+            _ => error!("Shutdown step {}: {}", name, outcome),
+        }
+        self.steps.push((name.to_string(), outcome));
+    }
+
+    /// Wait for a background component to notice the shutdown and terminate.
+    pub async fn join(&mut self, name: &str, timeout: Duration, handle: JoinHandle<()>) {
+        self.step(name, timeout, async move { Ok(handle.await?) }).await
+    }
+
+    pub fn failures(&self) -> Vec<&(String, StepOutcome)> {
+        self.steps.iter().filter(|(_, outcome)| *outcome != StepOutcome::Ok).collect()
+    }
+
+    pub fn success(&self) -> bool {
+        self.failures().is_empty()
+    }
+
+    pub fn log(&self) {
+        let summary =
+            self.steps.iter().map(|(name, outcome)| format!("{}: {}", name, outcome)).collect::<Vec<String>>();
+        if self.success() {
+            info!("Fireguard shutdown completed cleanly [{}]", summary.join(", "));
+        } else {
+            error!("Fireguard shutdown completed with {} failures [{}]", self.failures().len(), summary.join(", "));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::bail;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_report_records_failures_and_timeouts() {
+        let mut report = ShutdownReport::new();
+        report.step("ok", Duration::from_secs(1), async { Ok(()) }).await;
+        report.step("failed", Duration::from_secs(1), async { bail!("boom") }).await;
+        report
+            .step("slow", Duration::from_millis(10), async {
+                time::sleep(Duration::from_secs(5)).await;
+                Ok(())
+            })
+            .await;
+        assert!(!report.success());
+        let failures = report.failures();
+        assert_eq!(failures.len(), 2);
+        assert_eq!(failures[0].1, StepOutcome::Failed("boom".to_string()));
+        assert_eq!(failures[1].1, StepOutcome::TimedOut(Duration::from_millis(10)));
+    }
+
+    #[tokio::test]
+    async fn test_signal_wakes_up_components() {
+        let mut shutdown = Shutdown::new();
+        let mut signal = shutdown.subscribe();
+        shutdown.register("component", tokio::spawn(async move { signal.wait().await }));
+        let mut report = ShutdownReport::new();
+        for (name, handle) in shutdown.trigger() {
+            report.join(name, Duration::from_secs(1), handle).await;
+        }
+        assert!(report.success());
+    }
+}
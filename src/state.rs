@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::process;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::endpoint::Endpoint;
+use crate::policy;
+use crate::runner::Runner;
+use crate::wg::WgDriver;
+
+pub type SharedState = Arc<RwLock<DaemonState>>;
+
+/// Live state of a running Fireguard daemon, shared between the main loop and the control socket.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DaemonState {
+    pub repository: String,
+    pub version: String,
+    pub pid: u32,
+    pub started_at: u64,
+    pub tunnel_up: bool,
+    pub wg_driver: WgDriver,
+    pub revision: Option<String>,
+    pub last_pull: Option<u64>,
+    pub last_successful_pull: Option<u64>,
+    pub last_pull_error: Option<String>,
+    pub pulls: u64,
+    pub pull_failures: u64,
+    pub last_reload: Option<u64>,
+    pub reloads: u64,
+    pub peers: usize,
+    pub upgrade_checks: u64,
+    pub upgrade_check_failures: u64,
+    pub last_upgrade_check: Option<u64>,
+    pub latest_release: Option<String>,
+    pub peer_health: HashMap<String, bool>,
+    #[serde(skip_deserializing, skip_serializing)]
+    pub peer_names: HashMap<String, String>,
+    #[serde(skip_deserializing, skip_serializing)]
+    pub peer_addresses: HashMap<String, String>,
+    #[serde(skip_deserializing, skip_serializing)]
+    pub peer_endpoints: HashMap<String, Vec<Endpoint>>,
+    #[serde(skip_deserializing, skip_serializing)]
+    pub peer_links: HashMap<String, Vec<String>>,
+    #[serde(skip_deserializing, skip_serializing)]
+    pub runner: Runner,
+}
+
+impl DaemonState {
+    pub fn new(repository: &str) -> Self {
+        DaemonState {
+            repository: repository.to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            pid: process::id(),
+            started_at: now(),
+            ..Default::default()
+        }
+    }
+
+    pub fn shared(self) -> SharedState {
+        Arc::new(RwLock::new(self))
+    }
+
+    /// Refresh the peers view from the trust repository configuration.
+    pub fn update_config(&mut self, config: &Config) {
+        self.peers = config.peers.len();
+        self.peer_names = config.peers.iter().map(|(name, peer)| (peer.public_key.clone(), name.clone())).collect();
+        self.peer_addresses =
+            config.peers.values().map(|peer| (peer.public_key.clone(), peer.address.clone())).collect();
+        self.peer_endpoints = config
+            .peers
+            .iter()
+            .map(|(name, peer)| {
+                let candidates = peer.candidates().unwrap_or_else(|e| {
+                    warn!("Ignoring the endpoints of peer {}: {}", name, e);
+                    vec![]
+                });
+                (peer.public_key.clone(), candidates)
+            })
+            .collect();
+        self.peer_links = config
+            .peers
+            .iter()
+            .map(|(name, peer)| {
+                let links = config
+                    .peers
+                    .iter()
+                    .filter(|(other, _)| *other != name && policy::connected(config, name, other))
+                    .map(|(_, other)| other.public_key.clone())
+                    .collect();
+                (peer.public_key.clone(), links)
+            })
+            .collect();
+    }
+
+    pub fn peer_name(&self, public_key: &str) -> Option<String> {
+        self.peer_names.get(public_key).cloned()
+    }
+
+    pub fn peer_address(&self, public_key: &str) -> Option<String> {
+        self.peer_addresses.get(public_key).cloned()
+    }
+
+    /// Whether the policies let the peers `a` and `b` exchange traffic.
+    pub fn linked(&self, a: &str, b: &str) -> bool {
+        self.peer_links.get(a).map(|links| links.iter().any(|link| link == b)).unwrap_or(false)
+    }
+
+    /// Candidate endpoints of the peer, empty for the peers without one.
+    pub fn peer_endpoints(&self, public_key: &str) -> Vec<Endpoint> {
+        self.peer_endpoints.get(public_key).cloned().unwrap_or_default()
+    }
+
+    /// Record the outcome of an upgrade check against the releases endpoint.
+    pub fn record_upgrade_check(&mut self, latest_release: Option<&str>) {
+        self.upgrade_checks += 1;
+        self.last_upgrade_check = Some(now());
+        match latest_release {
+            Some(tag) => self.latest_release = Some(tag.to_string()),
+            None => self.upgrade_check_failures += 1,
+        }
+    }
+}
+
+/// Seconds since the UNIX epoch.
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
@@ -0,0 +1,277 @@
+use std::env;
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::time::Duration;
+
+use color_eyre::eyre::Result;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use parking_lot::RwLock;
+use serde::Serialize;
+use tera::{Context, Tera};
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+static SYSTEMD_UNIT_TMPL: &str = r#"# {{ repository }} - Fireguard daemon
+# Note: this file is managed by fireguard (https://github.com/blackmesalab/fireguard)
+[Unit]
+Description=Fireguard trust network {{ repository }}
+Documentation=https://github.com/blackmesalab/fireguard
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=notify
+NotifyAccess=main
+EnvironmentFile=-{{ environment_file }}
+ExecStart={{ executable }} -c {{ config_dir }} daemon -r {{ repository }} serve -u {{ username }} -p {{ peername }} -c {{ wireguard_dir }}{% for arg in extra_args %} {{ arg }}{% endfor %}
+ExecReload=/bin/kill -HUP $MAINPID
+WatchdogSec={{ watchdog_sec }}
+Restart=on-failure
+RestartSec=5
+TimeoutStopSec=30
+
+[Install]
+WantedBy=multi-user.target
+"#;
+
+lazy_static! {
+    static ref JOURNAL_FIELDS: RwLock<Vec<(String, String)>> = RwLock::new(vec![]);
+}
+
+/// Send a state notification to the service manager. This is a noop when not running under
+/// systemd (no `NOTIFY_SOCKET` in the environment).
+pub fn notify(state: &str) {
+    if let Ok(path) = env::var("NOTIFY_SOCKET") {
+        if let Err(e) = send_notify(&path, state) {
+            error!("Unable to notify systemd with {}: {}", state.trim(), e);
+        } else {
+            debug!("Notified systemd with {}", state.trim());
+        }
+    }
+}
+
+fn send_notify(path: &str, state: &str) -> std::io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+    if let Some(abstract_name) = path.strip_prefix('@') {
+        use std::os::linux::net::SocketAddrExt;
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(abstract_name.as_bytes())?;
+        socket.send_to_addr(state.as_bytes(), &addr)?;
+    } else {
+        socket.send_to(state.as_bytes(), path)?;
+    }
+    Ok(())
+}
+
+pub fn notify_ready(status: &str) {
+    notify(&format!("READY=1\nMAINPID={}\nSTATUS={}", process::id(), status));
+}
+
+pub fn notify_reloading() {
+    notify("RELOADING=1\nSTATUS=Reloading configuration");
+}
+
+pub fn notify_stopping() {
+    notify("STOPPING=1\nSTATUS=Shutting down");
+}
+
+pub fn notify_watchdog() {
+    notify("WATCHDOG=1");
+}
+
+/// Interval the watchdog needs to be fed at, half of what systemd expects, if the watchdog is
+/// enabled for this process. A daemon taking over from `old_pid` inherits its `WATCHDOG_PID` and
+/// feeds the watchdog in its place.
+pub fn watchdog_interval(old_pid: Option<u32>) -> Option<Duration> {
+    let pid = env::var("WATCHDOG_PID").ok();
+    let usec = env::var("WATCHDOG_USEC").ok();
+    watchdog_interval_for(pid.as_deref(), usec.as_deref(), process::id(), old_pid)
+}
+
+fn watchdog_interval_for(
+    pid: Option<&str>,
+    usec: Option<&str>,
+    own_pid: u32,
+    old_pid: Option<u32>,
+) -> Option<Duration> {
+    if let Some(pid) = pid {
+        let pid = pid.parse::<u32>().ok();
+        if pid.is_none() || (pid != Some(own_pid) && pid != old_pid) {
+            return None;
+        }
+    }
+    let usec = usec?.parse::<u64>().ok()?;
+    if usec == 0 {
+        None
+    } else {
+        Some(Duration::from_micros(usec / 2))
+    }
+}
+
+/// Whether stdout / stderr are connected to the journal.
+pub fn journal_available() -> bool {
+    env::var("JOURNAL_STREAM").is_ok() && std::path::Path::new(JOURNAL_SOCKET).exists()
+}
+
+/// Add a structured field to every record sent to the journal.
+pub fn set_journal_field(name: &str, value: &str) {
+    let name = name.to_uppercase();
+    let mut fields = JOURNAL_FIELDS.write();
+    fields.retain(|(k, _)| *k != name);
+    fields.push((name, value.to_string()));
+}
+
+/// Logger speaking the journal native protocol, so records keep their priority, source
+/// location and Fireguard specific fields.
+pub struct JournalLogger {
+    level: LevelFilter,
+    socket: UnixDatagram,
+}
+
+impl JournalLogger {
+    pub fn init(level: LevelFilter) -> Result<()> {
+        let logger = JournalLogger { level, socket: UnixDatagram::unbound()? };
+        log::set_boxed_logger(Box::new(logger))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+
+    fn priority(level: Level) -> u8 {
+        match level {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug | Level::Trace => 7,
+        }
+    }
+}
+
+/// Append one field in the journal native format, using the binary form for values spanning
+/// multiple lines.
+fn journal_field(buffer: &mut Vec<u8>, name: &str, value: &str) {
+    if value.contains('\n') {
+        buffer.extend_from_slice(name.as_bytes());
+        buffer.push(b'\n');
+        buffer.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        buffer.extend_from_slice(value.as_bytes());
+        buffer.push(b'\n');
+    } else {
+        let _ = writeln!(buffer, "{}={}", name, value);
+    }
+}
+
+impl Log for JournalLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut buffer = Vec::new();
+        journal_field(&mut buffer, "MESSAGE", &record.args().to_string());
+        journal_field(&mut buffer, "PRIORITY", &Self::priority(record.level()).to_string());
+        journal_field(&mut buffer, "SYSLOG_IDENTIFIER", env!("CARGO_PKG_NAME"));
+        journal_field(&mut buffer, "TARGET", record.target());
+        if let Some(file) = record.file() {
+            journal_field(&mut buffer, "CODE_FILE", file);
+        }
+        if let Some(line) = record.line() {
+            journal_field(&mut buffer, "CODE_LINE", &line.to_string());
+        }
+        if let Some(module) = record.module_path() {
+            journal_field(&mut buffer, "CODE_MODULE", module);
+        }
+        journal_field(&mut buffer, "FIREGUARD_VERSION", env!("CARGO_PKG_VERSION"));
+        for (name, value) in JOURNAL_FIELDS.read().iter() {
+            journal_field(&mut buffer, name, value);
+        }
+        if let Err(e) = self.socket.send_to(&buffer, JOURNAL_SOCKET) {
+            eprintln!("Unable to write to the journal: {}", e);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[derive(Debug, Serialize)]
+pub struct SystemdUnit {
+    pub repository: String,
+    pub username: String,
+    pub peername: String,
+    pub executable: String,
+    pub config_dir: String,
+    pub wireguard_dir: String,
+    pub environment_file: String,
+    pub watchdog_sec: u64,
+    pub extra_args: Vec<String>,
+}
+
+impl SystemdUnit {
+    pub fn name(&self) -> String {
+        format!("fireguard-{}.service", self.repository)
+    }
+
+    pub fn render(&self) -> Result<String> {
+        let mut tera = Tera::default();
+        tera.add_raw_template("fireguard.service", SYSTEMD_UNIT_TMPL)?;
+        Ok(tera.render("fireguard.service", &Context::from_serialize(self)?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_journal_field_encoding() {
+        let mut buffer = Vec::new();
+        journal_field(&mut buffer, "MESSAGE", "hello");
+        assert_eq!(buffer, b"MESSAGE=hello\n");
+        let mut buffer = Vec::new();
+        journal_field(&mut buffer, "MESSAGE", "a\nb");
+        assert_eq!(buffer, b"MESSAGE\n\x03\x00\x00\x00\x00\x00\x00\x00a\nb\n");
+    }
+
+    #[test]
+    fn test_watchdog_interval() {
+        let interval = Some(Duration::from_secs(15));
+        assert_eq!(watchdog_interval_for(None, Some("30000000"), 42, None), interval);
+        assert_eq!(watchdog_interval_for(Some("42"), Some("30000000"), 42, None), interval);
+        assert_eq!(watchdog_interval_for(Some("7"), Some("30000000"), 42, None), None);
+        assert_eq!(watchdog_interval_for(Some("42"), Some("0"), 42, None), None);
+        assert_eq!(watchdog_interval_for(Some("42"), None, 42, None), None);
+    }
+
+    #[test]
+    fn test_watchdog_interval_after_handover() {
+        // The upgraded daemon inherits the WATCHDOG_PID of the daemon it takes over from.
+        let interval = Some(Duration::from_secs(15));
+        assert_eq!(watchdog_interval_for(Some("7"), Some("30000000"), 42, Some(7)), interval);
+        assert_eq!(watchdog_interval_for(Some("8"), Some("30000000"), 42, Some(7)), None);
+        assert_eq!(watchdog_interval_for(Some("x"), Some("30000000"), 42, None), None);
+    }
+
+    #[test]
+    fn test_render_unit() {
+        let unit = SystemdUnit {
+            repository: "avalon".to_string(),
+            username: "alice".to_string(),
+            peername: "laptop".to_string(),
+            executable: "/usr/bin/fireguard".to_string(),
+            config_dir: "/etc/fireguard".to_string(),
+            wireguard_dir: "/etc/wireguard".to_string(),
+            environment_file: "/etc/fireguard/avalon.env".to_string(),
+            watchdog_sec: 60,
+            extra_args: vec!["--pull-interval".to_string(), "300".to_string()],
+        };
+        let rendered = unit.render().unwrap();
+        assert_eq!(unit.name(), "fireguard-avalon.service");
+        assert!(rendered.contains(
+            "ExecStart=/usr/bin/fireguard -c /etc/fireguard daemon -r avalon serve -u alice -p laptop -c /etc/wireguard --pull-interval 300\n"
+        ));
+        assert!(rendered.contains("EnvironmentFile=-/etc/fireguard/avalon.env\n"));
+        assert!(rendered.contains("WatchdogSec=60\n"));
+    }
+}
@@ -0,0 +1,326 @@
+use std::collections::BTreeMap;
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::error::{FireguardError, Result};
+use crate::policy;
+
+/// Allowed IPs sending all the traffic to a peer.
+pub const DEFAULT_ROUTE: &str = "0.0.0.0/0";
+
+/// How the peers of a trust repository connect to each other.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Topology {
+    /// Every peer connects to every other one, except the peers with a `relay_via`.
+    #[default]
+    Mesh,
+    /// Every peer connects to the hub only, which forwards the traffic between them.
+    HubSpoke,
+}
+
+impl Topology {
+    pub fn is_mesh(&self) -> bool {
+        *self == Topology::Mesh
+    }
+}
+
+/// Wireguard peer of a host, with the addresses routed through it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    /// Peer name, `<username>-<peername>`.
+    pub peer: String,
+    /// Allowed IPs and advertised routes of the peer, followed by the ones of the peers it relays
+    /// to the host.
+    pub allowed_ips: Vec<String>,
+}
+
+/// Relay the peer `name` is reached through, if it is not reached directly.
+pub fn relay_of<'a>(config: &'a Config, name: &str) -> Option<&'a str> {
+    match config.topology {
+        Topology::HubSpoke => config.hub.as_deref().filter(|hub| *hub != name),
+        Topology::Mesh => config.peers.get(name).and_then(|peer| peer.relay_via.as_deref()),
+    }
+}
+
+/// Whether `name` forwards the traffic of other peers, and needs forwarding enabled.
+pub fn is_relay(config: &Config, name: &str) -> bool {
+    config.peers.keys().any(|peer| relay_of(config, peer) == Some(name))
+}
+
+/// Check the hub and the relays are peers of the repository, not relayed themselves.
+pub fn validate(config: &Config) -> Result<()> {
+    if config.topology == Topology::HubSpoke {
+        match config.hub.as_deref() {
+            None => return Err(FireguardError::config(None, "the hub-spoke topology needs a hub")),
+            Some(hub) if !config.peers.contains_key(hub) => {
+                return Err(FireguardError::config(None, format!("hub {} is not a peer of the repository", hub)))
+            }
+            Some(_) => {}
+        }
+    }
+    for name in config.peers.keys() {
+        if let Some(relay) = relay_of(config, name) {
+            if relay == name {
+                return Err(FireguardError::config(None, format!("peer {} cannot relay via itself", name)));
+            }
+            if !config.peers.contains_key(relay) {
+                let message = format!("peer {} relays via {}, which is not a peer of the repository", name, relay);
+                return Err(FireguardError::config(None, message));
+            }
+            if let Some(next) = relay_of(config, relay) {
+                let message = format!("peer {} relays via {}, which relays via {} itself", name, relay, next);
+                return Err(FireguardError::config(None, message));
+            }
+        }
+    }
+    advertised_routes(config)?;
+    Ok(())
+}
+
+/// Routes advertised by the peers, checking they are valid networks which overlap neither the
+/// repository network nor each other.
+pub fn advertised_routes(config: &Config) -> Result<Vec<(&str, IpNet)>> {
+    let network = IpNet::V4(config.network_addr);
+    let mut routes: Vec<(&str, IpNet)> = vec![];
+    let mut names = config.peers.keys().collect::<Vec<_>>();
+    names.sort();
+    for name in names {
+        for route in config.peers[name].advertised_routes.iter() {
+            let invalid =
+                |message: String| FireguardError::config(None, format!("peer {} route {}: {}", name, route, message));
+            let net = route.parse::<IpNet>().map_err(|e| invalid(e.to_string()))?.trunc();
+            if net.prefix_len() == 0 {
+                return Err(invalid("set exit_node to route the internet through the peer".to_string()));
+            }
+            if overlap(&net, &network) {
+                return Err(invalid(format!("overlaps the repository network {}", config.network)));
+            }
+            if let Some((other, _)) = routes.iter().find(|(_, other)| overlap(&net, other)) {
+                return Err(invalid(format!("overlaps a route advertised by {}", other)));
+            }
+            routes.push((name.as_str(), net));
+        }
+    }
+    Ok(routes)
+}
+
+fn overlap(a: &IpNet, b: &IpNet) -> bool {
+    a.contains(&b.network()) || b.contains(&a.network())
+}
+
+/// Whether the peers `a` and `b` have a Wireguard session with each other.
+fn direct(config: &Config, a: &str, b: &str) -> bool {
+    match (relay_of(config, a), relay_of(config, b)) {
+        (None, None) => true,
+        (relay_a, relay_b) => relay_a == Some(b) || relay_b == Some(a),
+    }
+}
+
+/// Peers `host` has a Wireguard session with, in name order, the ones the policies connect it to.
+/// The peers it does not reach directly are routed through their relay when `host` reaches it, or
+/// through the relay of `host`.
+pub fn routes(config: &Config, host: &str) -> Result<Vec<Route>> {
+    validate(config)?;
+    policy::validate(config)?;
+    let mut routes: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+    for (name, peer) in config.peers.iter().filter(|(name, _)| name.as_str() != host) {
+        let relay_link = relay_of(config, name) == Some(host) || relay_of(config, host) == Some(name.as_str());
+        // Relays keep their sessions, the other peers are left out unless a policy connects them.
+        if !relay_link && !policy::connected(config, host, name) {
+            debug!("Peer {} is not allowed to reach {} by the policies", name, host);
+            continue;
+        }
+        let via = if direct(config, host, name) {
+            name.as_str()
+        } else {
+            match (relay_of(config, name), relay_of(config, host)) {
+                (Some(relay), _) if direct(config, host, relay) => relay,
+                (_, Some(relay)) => relay,
+                _ => name.as_str(),
+            }
+        };
+        if via != name {
+            debug!("Routing peer {} through relay {} for {}", name, via, host);
+        }
+        let allowed_ips = routes.entry(via).or_default();
+        let addresses = peer.allowed_ips.iter().chain(peer.advertised_routes.iter()).cloned();
+        // The relay own addresses come first, whatever the iteration order.
+        if via == name {
+            allowed_ips.splice(0..0, addresses);
+        } else {
+            allowed_ips.extend(addresses);
+        }
+    }
+    Ok(routes.into_iter().map(|(peer, allowed_ips)| Route { peer: peer.to_string(), allowed_ips }).collect())
+}
+
+/// Routes of `host`, sending the internet traffic to the peer `exit_node`, which has to be an exit
+/// node `host` reaches directly.
+pub fn exit_routes(config: &Config, host: &str, exit_node: &str) -> Result<Vec<Route>> {
+    let invalid = |message: &str| FireguardError::config(None, format!("exit node {}: {}", exit_node, message));
+    match config.peers.get(exit_node) {
+        None => return Err(invalid("not a peer of the repository")),
+        Some(_) if exit_node == host => return Err(invalid("a peer cannot use itself as exit node")),
+        Some(peer) if !peer.exit_node => return Err(invalid("the peer does not set exit_node")),
+        Some(_) => {}
+    }
+    let mut routes = routes(config, host)?;
+    match routes.iter_mut().find(|route| route.peer == exit_node) {
+        Some(route) => route.allowed_ips.push(DEFAULT_ROUTE.to_string()),
+        None => return Err(invalid(&format!("not reached directly by {}", host))),
+    }
+    Ok(routes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NODES: &str = r#"repository = "avalon"
+network = "10.0.0.0/24"
+domain = "avalon.lan"
+
+[peers.bob-cloud]
+username = "bob"
+peername = "cloud"
+address = "10.0.0.1/24"
+listen_port = 51820
+public_key = "bob"
+allowed_ips = ["10.0.0.1/32"]
+persistent_keepalive = 25
+endpoint = "cloud.bob.net"
+mtu = 1420
+
+[peers.alice-laptop]
+username = "alice"
+peername = "laptop"
+address = "10.0.0.2/24"
+listen_port = 51820
+public_key = "alice"
+allowed_ips = ["10.0.0.2/32"]
+persistent_keepalive = 25
+mtu = 1420
+
+[peers.carol-phone]
+username = "carol"
+peername = "phone"
+address = "10.0.0.3/24"
+listen_port = 51820
+public_key = "carol"
+allowed_ips = ["10.0.0.3/32"]
+persistent_keepalive = 25
+mtu = 1420
+"#;
+
+    fn route(peer: &str, allowed_ips: &[&str]) -> Route {
+        Route { peer: peer.to_string(), allowed_ips: allowed_ips.iter().map(|ip| ip.to_string()).collect() }
+    }
+
+    #[test]
+    fn test_mesh_routes_every_peer_directly() {
+        let config = Config::parse(NODES).unwrap();
+        assert_eq!(
+            routes(&config, "alice-laptop").unwrap(),
+            vec![route("bob-cloud", &["10.0.0.1/32"]), route("carol-phone", &["10.0.0.3/32"])]
+        );
+        assert!(!is_relay(&config, "bob-cloud"));
+    }
+
+    #[test]
+    fn test_hub_spoke_routes_through_the_hub() {
+        let nodes = NODES.replace(
+            "domain = \"avalon.lan\"",
+            "domain = \"avalon.lan\"\ntopology = \"hub-spoke\"\nhub = \"bob-cloud\"",
+        );
+        let config = Config::parse(&nodes).unwrap();
+        assert_eq!(routes(&config, "alice-laptop").unwrap(), vec![route("bob-cloud", &["10.0.0.1/32", "10.0.0.3/32"])]);
+        assert_eq!(
+            routes(&config, "bob-cloud").unwrap(),
+            vec![route("alice-laptop", &["10.0.0.2/32"]), route("carol-phone", &["10.0.0.3/32"])]
+        );
+        assert!(is_relay(&config, "bob-cloud"));
+        assert!(!is_relay(&config, "alice-laptop"));
+    }
+
+    #[test]
+    fn test_relay_via() {
+        let nodes = NODES.replace("public_key = \"carol\"", "public_key = \"carol\"\nrelay_via = \"bob-cloud\"");
+        let config = Config::parse(&nodes).unwrap();
+        assert_eq!(routes(&config, "alice-laptop").unwrap(), vec![route("bob-cloud", &["10.0.0.1/32", "10.0.0.3/32"])]);
+        assert_eq!(routes(&config, "carol-phone").unwrap(), vec![route("bob-cloud", &["10.0.0.1/32", "10.0.0.2/32"])]);
+        assert_eq!(
+            routes(&config, "bob-cloud").unwrap(),
+            vec![route("alice-laptop", &["10.0.0.2/32"]), route("carol-phone", &["10.0.0.3/32"])]
+        );
+    }
+
+    #[test]
+    fn test_policies_filter_routes() {
+        let policies =
+            "\n[groups]\nservers = [\"bob-cloud\"]\n\n[[policies]]\nfrom = \"alice-laptop\"\nto = \"servers\"\n";
+        let config = Config::parse(&format!("{}{}", NODES, policies)).unwrap();
+        assert_eq!(routes(&config, "alice-laptop").unwrap(), vec![route("bob-cloud", &["10.0.0.1/32"])]);
+        assert_eq!(routes(&config, "bob-cloud").unwrap(), vec![route("alice-laptop", &["10.0.0.2/32"])]);
+        assert!(routes(&config, "carol-phone").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_advertised_routes() {
+        let nodes = NODES
+            .replace("public_key = \"carol\"", "public_key = \"carol\"\nrelay_via = \"bob-cloud\"")
+            .replace("public_key = \"carol\"", "public_key = \"carol\"\nadvertised_routes = [\"192.168.1.0/24\"]");
+        let config = Config::parse(&nodes).unwrap();
+        assert_eq!(
+            routes(&config, "alice-laptop").unwrap(),
+            vec![route("bob-cloud", &["10.0.0.1/32", "10.0.0.3/32", "192.168.1.0/24"])]
+        );
+        assert_eq!(
+            routes(&config, "bob-cloud").unwrap(),
+            vec![route("alice-laptop", &["10.0.0.2/32"]), route("carol-phone", &["10.0.0.3/32", "192.168.1.0/24"])]
+        );
+        let advertise = |peer: &str, routes: &str| {
+            NODES.replace(
+                &format!("public_key = \"{}\"", peer),
+                &format!("public_key = \"{}\"\nadvertised_routes = {}", peer, routes),
+            )
+        };
+        let nodes = advertise("carol", "[\"192.168.1.0/24\"]");
+        assert!(validate(&Config::parse(&advertise("alice", "[\"192.168.2.0/24\", \"fd01::/64\"]")).unwrap()).is_ok());
+        let conflicting = advertise("alice", "[\"192.168.1.128/25\"]")
+            .replace("public_key = \"carol\"", "public_key = \"carol\"\nadvertised_routes = [\"192.168.0.0/16\"]");
+        assert!(validate(&Config::parse(&conflicting).unwrap()).is_err());
+        assert!(validate(&Config::parse(&nodes.replace("192.168.1.0/24", "10.0.0.128/25")).unwrap()).is_err());
+        assert!(validate(&Config::parse(&nodes.replace("192.168.1.0/24", "0.0.0.0/0")).unwrap()).is_err());
+        assert!(validate(&Config::parse(&nodes.replace("192.168.1.0/24", "192.168.1.0")).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_exit_routes() {
+        let nodes = NODES.replace("public_key = \"bob\"", "public_key = \"bob\"\nexit_node = true");
+        let config = Config::parse(&nodes).unwrap();
+        assert_eq!(
+            exit_routes(&config, "alice-laptop", "bob-cloud").unwrap(),
+            vec![route("bob-cloud", &["10.0.0.1/32", "0.0.0.0/0"]), route("carol-phone", &["10.0.0.3/32"])]
+        );
+        assert!(exit_routes(&config, "bob-cloud", "bob-cloud").is_err());
+        assert!(exit_routes(&config, "bob-cloud", "alice-laptop").is_err());
+        assert!(exit_routes(&config, "alice-laptop", "dave-server").is_err());
+        let relayed = nodes.replace("public_key = \"alice\"", "public_key = \"alice\"\nrelay_via = \"carol-phone\"");
+        assert!(exit_routes(&Config::parse(&relayed).unwrap(), "alice-laptop", "bob-cloud").is_err());
+    }
+
+    #[test]
+    fn test_invalid_relays() {
+        let nodes = NODES.replace("domain = \"avalon.lan\"", "domain = \"avalon.lan\"\ntopology = \"hub-spoke\"");
+        assert!(validate(&Config::parse(&nodes).unwrap()).is_err());
+        let nodes = NODES.replace("public_key = \"carol\"", "public_key = \"carol\"\nrelay_via = \"dave-server\"");
+        assert!(validate(&Config::parse(&nodes).unwrap()).is_err());
+        let nodes = NODES
+            .replace("public_key = \"carol\"", "public_key = \"carol\"\nrelay_via = \"alice-laptop\"")
+            .replace("public_key = \"alice\"", "public_key = \"alice\"\nrelay_via = \"bob-cloud\"");
+        assert!(validate(&Config::parse(&nodes).unwrap()).is_err());
+    }
+}
@@ -1,28 +1,152 @@
 use std::env;
-use std::path::PathBuf;
-use std::process::{self, Command};
-use std::time::Duration;
+use std::fmt;
+use std::fs::{self as std_fs, Permissions};
+use std::io::{self, Read};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{bail, Result};
 use nix::sys::signal;
+use nix::sys::wait::waitpid;
 use nix::unistd::Pid;
 use rand::Rng;
-use tokio::fs;
-use tokio::task;
+use semver::Version;
+use tokio::task::{self, JoinHandle};
 use tokio::time;
 
-use crate::github::Releases;
-use crate::utils::{NEW_VERSION_FILE, NEW_VERSION_PATH};
+use crate::control::{ControlClient, Request, Response};
+use crate::error::EXIT_UPGRADE;
+use crate::release::{Release, ReleaseSource};
+use crate::shutdown::ShutdownSignal;
+use crate::state::SharedState;
+use crate::utils::NEW_VERSION_FILE;
+
+/// Release channel a node follows.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UpgradeChannel {
+    Stable,
+    Prerelease,
+}
+
+impl FromStr for UpgradeChannel {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "stable" => Ok(UpgradeChannel::Stable),
+            "prerelease" => Ok(UpgradeChannel::Prerelease),
+            _ => Err(format!("Invalid upgrade channel {}, valid channels are stable and prerelease", s)),
+        }
+    }
+}
+
+impl fmt::Display for UpgradeChannel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpgradeChannel::Stable => write!(f, "stable"),
+            UpgradeChannel::Prerelease => write!(f, "prerelease"),
+        }
+    }
+}
+
+/// Which releases a node is allowed to upgrade to.
+#[derive(Debug, Clone)]
+pub struct UpgradePolicy {
+    pub channel: UpgradeChannel,
+    pub pinned: Option<Version>,
+    pub max_version: Option<Version>,
+}
+
+impl UpgradePolicy {
+    fn allows(&self, version: &Version, prerelease: bool) -> bool {
+        if let Some(pinned) = self.pinned.as_ref() {
+            return version == pinned;
+        }
+        if self.channel == UpgradeChannel::Stable && (prerelease || !version.pre.is_empty()) {
+            return false;
+        }
+        match self.max_version.as_ref() {
+            Some(max_version) => version <= max_version,
+            None => true,
+        }
+    }
+
+    /// Pick the release to move to from the `current` version, if any. Without a pinned version
+    /// only newer releases are considered, while a pinned version is honoured even when it is
+    /// older than the running one.
+    pub fn select<'a>(&self, current: &Version, releases: &'a [Release]) -> Option<(Version, &'a Release)> {
+        releases
+            .iter()
+            .filter(|release| !release.draft)
+            .filter_map(|release| release_version(&release.tag_name).map(|version| (version, release)))
+            .filter(|(version, release)| self.allows(version, release.prerelease))
+            .filter(|(version, _)| if self.pinned.is_some() { version != current } else { version > current })
+            .max_by(|(a, _), (b, _)| a.cmp(b))
+    }
+}
+
+/// Parse a release tag like `v0.1.2` into a semantic version.
+pub fn release_version(tag: &str) -> Option<Version> {
+    Version::parse(tag.trim_start_matches('v')).ok()
+}
 
 pub struct UpgradeBin {
     wait_between_checks: Duration,
-    url: String,
-    current_tag: String,
+    source: Box<dyn ReleaseSource>,
+    current: Version,
+    policy: UpgradePolicy,
+    health_timeout: Duration,
+    control_socket: Option<PathBuf>,
+    state: Option<SharedState>,
+    executable: Option<PathBuf>,
 }
 
 impl UpgradeBin {
-    pub fn new(wait_between_checks: Duration, url: &str, current_tag: &str) -> Self {
-        UpgradeBin { wait_between_checks, url: url.to_string(), current_tag: current_tag.to_string() }
+    pub fn new(
+        wait_between_checks: Duration,
+        source: Box<dyn ReleaseSource>,
+        policy: UpgradePolicy,
+        health_timeout: Duration,
+    ) -> Self {
+        UpgradeBin {
+            wait_between_checks,
+            source,
+            current: Version::parse(env!("CARGO_PKG_VERSION")).expect("Invalid package version"),
+            policy,
+            health_timeout,
+            control_socket: None,
+            state: None,
+            executable: None,
+        }
+    }
+
+    /// Report upgrade checks into the daemon state, so they are visible in status and metrics.
+    pub fn with_state(mut self, state: SharedState) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    /// Control socket the upgraded process is expected to answer on once healthy.
+    pub fn with_control_socket(mut self, path: PathBuf) -> Self {
+        self.control_socket = Some(path);
+        self
+    }
+
+    /// Installed executable the upgrades replace, the running one by default. Upgraded daemons
+    /// run from the new version file, so they need to be told.
+    pub fn with_executable(mut self, path: PathBuf) -> Self {
+        self.executable = Some(path);
+        self
+    }
+
+    fn record_check(&self, latest_release: Option<&str>) {
+        if let Some(state) = self.state.as_ref() {
+            state.write().record_upgrade_check(latest_release);
+        }
     }
 
     fn calculate_jitter(&self) -> Duration {
@@ -31,92 +155,171 @@ impl UpgradeBin {
         Duration::from_secs(value)
     }
 
-    pub async fn run_in_background(self, args: &[String]) -> Result<()> {
+    pub async fn run_in_background(self, args: &[String], mut shutdown: ShutdownSignal) -> Result<JoinHandle<()>> {
         let task_args = args.to_vec();
-        task::spawn(async move {
+        Ok(task::spawn(async move {
+            info!(
+                "Fireguard {} following the {} channel from {}{}{}",
+                self.current,
+                self.policy.channel,
+                self.source,
+                self.policy.pinned.as_ref().map(|v| format!(", pinned to {}", v)).unwrap_or_default(),
+                self.policy.max_version.as_ref().map(|v| format!(", up to {}", v)).unwrap_or_default(),
+            );
             loop {
                 let wait_duration = self.wait_between_checks + self.calculate_jitter();
-                match Releases::new(&self.url).await {
-                    Ok(releases) => {
-                        let tag_name = releases.tag_name.clone();
-                        if self.current_tag == tag_name {
-                            info!(
-                                "Fireguard {} is already running, sleeping for {} seconds",
-                                self.current_tag,
-                                wait_duration.as_secs()
-                            );
-                        } else {
-                            info!("Fireguard needs to be updated from {} to {}", self.current_tag, tag_name);
-                            let old_pid = process::id();
-                            match releases.download().await {
-                                Ok(()) => match fork::daemon(true, true) {
-                                    Ok(fork::Fork::Child) => {
-                                        let mut cmd_args = vec!["--old-pid".to_string(), old_pid.to_string()];
-                                        let mut task_args = task_args.clone();
-                                        task_args.remove(0);
-                                        cmd_args.extend(task_args);
-                                        warn!("Cmd args are {:?}", cmd_args);
-                                        match Command::new(NEW_VERSION_FILE.as_path()).args(cmd_args).spawn() {
-                                            Ok(command) => match command.wait_with_output() {
-                                                Ok(output) => {
-                                                    info!(
-                                                        "Forked new executable from {}: {}",
-                                                        NEW_VERSION_FILE.display(),
-                                                        String::from_utf8_lossy(&output.stdout)
-                                                    );
-                                                    break;
-                                                }
-                                                Err(e) => {
-                                                    error!("Error extracting stdout from upgrade command: {}", e);
-                                                }
-                                            },
-                                            Err(e) => {
-                                                error!(
-                                                    "Error forking new executable from {}: {}",
-                                                    NEW_VERSION_FILE.display(),
-                                                    e
-                                                );
-                                            }
-                                        }
-                                    }
-                                    Ok(fork::Fork::Parent(pid)) => {
-                                        debug!("Double fork parent process PID {} noop operation", pid)
-                                    }
-                                    Err(e) => {
-                                        error!("Unable to daemonize new Fireguard instance: {}", e);
-                                    }
-                                },
+                match self.source.releases().await {
+                    Ok(releases) => match self.policy.select(&self.current, &releases) {
+                        Some((version, release)) => {
+                            self.record_check(Some(&release.tag_name));
+                            info!("Fireguard needs to be updated from {} to {}", self.current, version);
+                            match release.download(self.source.as_ref()).await {
+                                Ok(()) => self.spawn_upgrade(&task_args, &version),
                                 Err(e) => {
                                     error!(
-                                        "Unable to dowload Fireguard {}: {}, sleeping for {} seconds",
-                                        tag_name,
+                                        "Unable to download and verify Fireguard {}: {}, retrying in {} seconds",
+                                        version,
                                         e,
                                         wait_duration.as_secs(),
                                     );
                                 }
                             }
                         }
-                    }
+                        None => {
+                            self.record_check(Some(&format!("v{}", self.current)));
+                            info!(
+                                "Fireguard {} is up to date, sleeping for {} seconds",
+                                self.current,
+                                wait_duration.as_secs()
+                            );
+                        }
+                    },
                     Err(e) => {
+                        self.record_check(None);
                         error!(
-                            "Unable to fetch latest Github release: {}, sleeping for {} seconds",
+                            "Unable to fetch Fireguard releases from {}: {}, sleeping for {} seconds",
+                            self.source,
                             e,
                             wait_duration.as_secs(),
                         );
                     }
                 }
                 debug!("Sleeping for {} seconds", wait_duration.as_secs());
-                time::sleep(wait_duration).await;
+                tokio::select! {
+                    _ = time::sleep(wait_duration) => {},
+                    _ = shutdown.wait() => break,
+                }
+            }
+        }))
+    }
+
+    /// Detach an upgrade supervisor from the running daemon, which keeps serving until the new
+    /// process takes over.
+    fn spawn_upgrade(&self, args: &[String], version: &Version) {
+        match fork::fork() {
+            Ok(fork::Fork::Parent(pid)) => {
+                // The intermediate process exits right away, reap it.
+                if let Err(e) = waitpid(Pid::from_raw(pid), None) {
+                    error!("Unable to wait for upgrade supervisor process {}: {}", pid, e);
+                }
+            }
+            Ok(fork::Fork::Child) => {
+                if fork::setsid().is_err() {
+                    error!("Unable to detach upgrade supervisor from the daemon session");
+                }
+                if let Ok(fork::Fork::Child) = fork::fork() {
+                    let code = match self.supervise(args, version) {
+                        Ok(()) => 0,
+                        Err(e) => {
+                            error!("Upgrade to Fireguard {} failed: {}", version, e);
+                            EXIT_UPGRADE
+                        }
+                    };
+                    process::exit(code);
+                }
+                process::exit(0);
+            }
+            Err(e) => error!("Unable to fork upgrade supervisor for Fireguard {}: {}", version, e),
+        }
+    }
+
+    /// Start the new executable and wait for it to report healthy, rolling back to the current
+    /// executable if it does not within the health timeout.
+    fn supervise(&self, args: &[String], version: &Version) -> Result<()> {
+        let old_pid = process::id();
+        let executable = match self.executable.clone() {
+            Some(executable) => executable,
+            None => env::current_exe()?,
+        };
+        let previous = save_previous(&executable)?;
+        info!("Saved Fireguard {} executable as {}", self.current, previous.display());
+        let args = restart_args(args);
+        let mut cmd_args = vec![
+            "--old-pid".to_string(),
+            old_pid.to_string(),
+            "--replace".to_string(),
+            executable.to_string_lossy().to_string(),
+        ];
+        cmd_args.extend(args.iter().cloned());
+        debug!("Starting {} with args {:?}", NEW_VERSION_FILE.display(), cmd_args);
+        let mut child = Command::new(NEW_VERSION_FILE.as_path()).args(cmd_args).spawn()?;
+        info!("Started Fireguard {} with PID {}, waiting for it to become healthy", version, child.id());
+        if self.wait_healthy(&mut child, version) {
+            info!("Fireguard {} with PID {} is healthy, upgrade completed", version, child.id());
+            return Ok(());
+        }
+        error!(
+            "Fireguard {} did not become healthy within {} seconds, rolling back to {}",
+            version,
+            self.health_timeout.as_secs(),
+            self.current
+        );
+        if let Err(e) = signal::kill(Pid::from_raw(child.id() as i32), signal::SIGTERM) {
+            warn!("Unable to terminate Fireguard {} with PID {}: {}", version, child.id(), e);
+        }
+        let _ = child.wait();
+        write_executable(&mut std_fs::File::open(&previous)?, &executable)?;
+        info!("Restored Fireguard {} executable on {}", self.current, executable.display());
+        if signal::kill(Pid::from_raw(old_pid as i32), None).is_ok() {
+            info!("Fireguard {} with PID {} is still running, nothing to restart", self.current, old_pid);
+        } else {
+            let child = Command::new(&executable).args(&args).spawn()?;
+            warn!("Restarted Fireguard {} with PID {}", self.current, child.id());
+        }
+        bail!("rolled back to Fireguard {}", self.current)
+    }
+
+    fn wait_healthy(&self, child: &mut Child, version: &Version) -> bool {
+        let deadline = Instant::now() + self.health_timeout;
+        while Instant::now() < deadline {
+            match child.try_wait() {
+                Ok(Some(status)) => {
+                    error!("Fireguard {} exited during startup with {}", version, status);
+                    return false;
+                }
+                Ok(None) => {}
+                Err(e) => error!("Unable to check Fireguard {} process: {}", version, e),
+            }
+            if let Some(path) = self.control_socket.as_ref() {
+                if Self::reports_healthy(path, child.id(), version) {
+                    return true;
+                }
             }
-        });
-        Ok(())
+            thread::sleep(Duration::from_secs(2));
+        }
+        false
+    }
+
+    fn reports_healthy(path: &Path, pid: u32, version: &Version) -> bool {
+        match ControlClient::request_blocking(path, Request::Status, Duration::from_secs(5)) {
+            Ok(Response::Status(state)) => state.pid == pid && state.version == version.to_string() && state.tunnel_up,
+            _ => false,
+        }
     }
 
     pub async fn flip_binary_on_disk(&self, destination: PathBuf) -> Result<()> {
-        info!("Copying {} to {}", NEW_VERSION_FILE.display(), destination.display());
-        let bytes = fs::copy(NEW_VERSION_FILE.as_path(), &destination).await?;
-        info!("Copied {} bytes executable to {}", bytes, destination.display());
-        Ok(())
+        info!("Replacing {} with {}", destination.display(), NEW_VERSION_FILE.display());
+        task::spawn_blocking(move || flip_binary(NEW_VERSION_FILE.as_path(), &destination)).await?
     }
 
     pub fn terminate_old_process(&self, pid: i32) -> Result<()> {
@@ -124,3 +327,164 @@ impl UpgradeBin {
         Ok(signal::kill(Pid::from_raw(pid), signal::SIGINT)?)
     }
 }
+
+/// Command line of a daemon started by the upgrade supervisor, without the options a previous
+/// upgrade added to it.
+fn restart_args(args: &[String]) -> Vec<String> {
+    let mut restart = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--old-pid" | "--replace" => {
+                args.next();
+            }
+            arg if arg.starts_with("--old-pid=") || arg.starts_with("--replace=") => {}
+            _ => restart.push(arg.clone()),
+        }
+    }
+    restart
+}
+
+/// Replace the installed executable `destination` with the new version `source`, keeping the
+/// installed one as `<destination>.previous`.
+pub fn flip_binary(source: &Path, destination: &Path) -> Result<()> {
+    let previous = save_previous(destination)?;
+    info!("Saved previous executable as {}", previous.display());
+    write_executable(&mut std_fs::File::open(source)?, destination)?;
+    info!("Replaced executable {}", destination.display());
+    Ok(())
+}
+
+/// Where the running executable is saved before an upgrade, to roll back to.
+pub fn previous_executable(executable: &Path) -> PathBuf {
+    let mut previous = executable.as_os_str().to_owned();
+    previous.push(".previous");
+    PathBuf::from(previous)
+}
+
+/// Keep a copy of `executable` as `<executable>.previous`.
+pub fn save_previous(executable: &Path) -> Result<PathBuf> {
+    let previous = previous_executable(executable);
+    write_executable(&mut std_fs::File::open(executable)?, &previous)?;
+    Ok(previous)
+}
+
+/// Write `reader` as an executable to `destination`. The data goes to a private temporary file
+/// in the same directory, synced to disk and renamed over `destination`, which is always either
+/// the old or the new complete file, even if we crash halfway.
+pub fn write_executable<R: Read>(reader: &mut R, destination: &Path) -> Result<()> {
+    let dir = match destination.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut file = tempfile::Builder::new().prefix(".fireguard-").tempfile_in(dir)?;
+    io::copy(reader, file.as_file_mut())?;
+    file.as_file().set_permissions(Permissions::from_mode(0o755))?;
+    file.as_file().sync_all()?;
+    let (_, path) = file.keep()?;
+    rename_synced(&path, destination)
+}
+
+/// Rename `from` to `to` and sync the destination directory, so the rename survives a crash.
+pub fn rename_synced(from: &Path, to: &Path) -> Result<()> {
+    std_fs::rename(from, to)?;
+    if let Some(dir) = to.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std_fs::File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn release(tag: &str, prerelease: bool) -> Release {
+        Release { tag_name: tag.to_string(), prerelease, draft: false, assets: vec![] }
+    }
+
+    fn policy(channel: UpgradeChannel, pinned: Option<&str>, max_version: Option<&str>) -> UpgradePolicy {
+        UpgradePolicy {
+            channel,
+            pinned: pinned.map(|v| Version::parse(v).unwrap()),
+            max_version: max_version.map(|v| Version::parse(v).unwrap()),
+        }
+    }
+
+    fn selected(policy: &UpgradePolicy, current: &str, releases: &[Release]) -> Option<String> {
+        policy.select(&Version::parse(current).unwrap(), releases).map(|(version, _)| version.to_string())
+    }
+
+    #[test]
+    fn test_select_never_downgrades() {
+        let releases = vec![release("v0.0.10", false), release("v0.0.9", false)];
+        let stable = policy(UpgradeChannel::Stable, None, None);
+        assert_eq!(selected(&stable, "0.0.12", &releases), None);
+        assert_eq!(selected(&stable, "0.0.9", &releases), Some("0.0.10".to_string()));
+    }
+
+    #[test]
+    fn test_select_channels() {
+        let releases = vec![
+            release("v0.0.12", false),
+            release("v0.0.13", false),
+            release("v0.1.0-rc.1", true),
+            release("not-a-version", false),
+        ];
+        let stable = policy(UpgradeChannel::Stable, None, None);
+        assert_eq!(selected(&stable, "0.0.12", &releases), Some("0.0.13".to_string()));
+        let prerelease = policy(UpgradeChannel::Prerelease, None, None);
+        assert_eq!(selected(&prerelease, "0.0.12", &releases), Some("0.1.0-rc.1".to_string()));
+    }
+
+    #[test]
+    fn test_select_pinned_and_max_version() {
+        let releases = vec![release("v0.0.11", false), release("v0.0.13", false), release("v0.1.0", false)];
+        let max = policy(UpgradeChannel::Stable, None, Some("0.0.99"));
+        assert_eq!(selected(&max, "0.0.12", &releases), Some("0.0.13".to_string()));
+        let pinned = policy(UpgradeChannel::Stable, Some("0.0.11"), None);
+        assert_eq!(selected(&pinned, "0.0.12", &releases), Some("0.0.11".to_string()));
+        assert_eq!(selected(&pinned, "0.0.11", &releases), None);
+    }
+
+    #[test]
+    fn test_write_executable_keeps_previous() {
+        let dir = tempdir::TempDir::new("fireguard-flip").unwrap();
+        let executable = dir.path().join("fireguard");
+        std_fs::write(&executable, b"old binary").unwrap();
+        let previous = save_previous(&executable).unwrap();
+        write_executable(&mut &b"new binary"[..], &executable).unwrap();
+        assert_eq!(std_fs::read(&executable).unwrap(), b"new binary");
+        assert_eq!(std_fs::read(&previous).unwrap(), b"old binary");
+        assert_eq!(std_fs::metadata(&executable).unwrap().permissions().mode() & 0o777, 0o755);
+        // No temporary files are left behind.
+        assert_eq!(std_fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_flip_replaces_the_installed_executable() {
+        let dir = tempdir::TempDir::new("fireguard-flip").unwrap();
+        let installed = dir.path().join("fireguard");
+        let new_version = dir.path().join(".fireguard");
+        std_fs::write(&installed, b"old binary").unwrap();
+        std_fs::write(&new_version, b"new binary").unwrap();
+        // The upgraded daemon runs from the new version file and flips the installed executable.
+        flip_binary(&new_version, &installed).unwrap();
+        assert_eq!(std_fs::read(&installed).unwrap(), b"new binary");
+        assert_eq!(std_fs::read(previous_executable(&installed)).unwrap(), b"old binary");
+        assert_eq!(std_fs::read(&new_version).unwrap(), b"new binary");
+    }
+
+    #[test]
+    fn test_restart_args_drop_previous_upgrade() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let previous = args(&["--old-pid", "42", "--replace", "/usr/bin/fireguard", "-D", "daemon", "serve"]);
+        assert_eq!(restart_args(&previous), args(&["-D", "daemon", "serve"]));
+        let previous = args(&["-o", "42", "--replace=/usr/bin/fireguard", "daemon", "serve"]);
+        assert_eq!(restart_args(&previous), args(&["daemon", "serve"]));
+    }
+
+    #[test]
+    fn test_previous_executable() {
+        assert_eq!(previous_executable(Path::new("/usr/bin/fireguard")), PathBuf::from("/usr/bin/fireguard.previous"));
+    }
+}
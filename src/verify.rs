@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+
+use color_eyre::eyre::{bail, eyre, Result};
+use minisign_verify::{PublicKey, Signature};
+use sha2::{Digest, Sha256};
+
+/// Minisign public key Fireguard releases are signed with, embedded from
+/// `FIREGUARD_RELEASE_PUBLIC_KEY` at compile time. The release workflow sets it to the public half
+/// of the maintainers' `MINISIGN_SECRET_KEY`, builds without it cannot upgrade.
+pub const RELEASE_PUBLIC_KEY: Option<&str> = option_env!("FIREGUARD_RELEASE_PUBLIC_KEY");
+/// Release asset listing the SHA-256 checksum of every other asset, in `sha256sum` format.
+pub const CHECKSUMS_ASSET: &str = "SHA256SUMS";
+/// Detached minisign signature of the checksums asset.
+pub const SIGNATURE_ASSET: &str = "SHA256SUMS.minisig";
+
+/// SHA-256 checksums of the assets of a release.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manifest {
+    checksums: HashMap<String, String>,
+}
+
+impl Manifest {
+    pub fn parse(data: &str) -> Result<Self> {
+        let mut checksums = HashMap::new();
+        for line in data.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let mut parts = line.splitn(2, char::is_whitespace);
+            let checksum = parts.next().unwrap_or_default().to_lowercase();
+            let name = parts.next().unwrap_or_default().trim_start().trim_start_matches('*');
+            if checksum.len() != 64 || !checksum.chars().all(|c| c.is_ascii_hexdigit()) || name.is_empty() {
+                bail!("Invalid line in release checksums: {}", line);
+            }
+            checksums.insert(name.to_string(), checksum);
+        }
+        Ok(Manifest { checksums })
+    }
+
+    /// Check `data` against the checksum published for the asset `name`.
+    pub fn verify(&self, name: &str, data: &[u8]) -> Result<()> {
+        let expected = self.checksums.get(name).ok_or_else(|| eyre!("Release checksums do not list asset {}", name))?;
+        let actual = hex::encode(Sha256::digest(data));
+        if *expected != actual {
+            bail!("Checksum mismatch for asset {}: expected {}, got {}", name, expected, actual);
+        }
+        Ok(())
+    }
+}
+
+/// Trusted comment the release workflow signs the checksums of the release `tag` with.
+pub fn trusted_comment(tag: &str) -> String {
+    format!("fireguard {}", tag)
+}
+
+pub struct ReleaseVerifier {
+    public_key: PublicKey,
+}
+
+impl ReleaseVerifier {
+    pub fn new(public_key: &str) -> Result<Self> {
+        let public_key = PublicKey::from_base64(public_key)
+            .map_err(|e| eyre!("Invalid release public key {}: {}", public_key, e))?;
+        Ok(ReleaseVerifier { public_key })
+    }
+
+    /// Verifier using the public key built into this binary.
+    pub fn builtin() -> Result<Self> {
+        match RELEASE_PUBLIC_KEY {
+            Some(public_key) => Self::new(public_key),
+            None => bail!("This build has no release public key, rebuild it with FIREGUARD_RELEASE_PUBLIC_KEY set"),
+        }
+    }
+
+    /// Check the detached signature of the checksums of the release `tag`, returning them only
+    /// when it is valid. The signed trusted comment names the release, so the checksums of an
+    /// older release cannot be served under a newer tag.
+    pub fn verify_manifest(&self, manifest: &[u8], signature: &str, tag: &str) -> Result<Manifest> {
+        let signature = Signature::decode(signature).map_err(|e| eyre!("Invalid release signature: {}", e))?;
+        self.public_key
+            .verify(manifest, &signature, false)
+            .map_err(|e| eyre!("Release checksums signature verification failed: {}", e))?;
+        let expected = trusted_comment(tag);
+        if signature.trusted_comment() != expected {
+            bail!("Release checksums are signed for `{}`, expected `{}`", signature.trusted_comment(), expected);
+        }
+        Manifest::parse(std::str::from_utf8(manifest)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUBLIC_KEY: &str = "RWShssPU5fYHGMU6ti0lUCMK+is02cGXMVMjshB1dOjKPAuPwiRVofGA";
+    const MANIFEST: &str = "65180edfb7b57861f79b60739fd36c5620f6090e3da44ba1d15a55799b03ef17  fireguard-x86_64-unknown-linux-gnu.tar.gz\n0000000000000000000000000000000000000000000000000000000000000000 *fireguard-aarch64-unknown-linux-gnu.tar.gz\n";
+    const SIGNATURE: &str = "untrusted comment: signature from fireguard release key
+RUShssPU5fYHGPr505wC2wc0ryRoP/Z2P9eB2ZfCEBG3/YZTtX9tutY9gCi1vJT4SfuKAN4iFmfzMzexO/SC0IOD3BjxvP+WjQ0=
+trusted comment: fireguard v0.0.13
+dkM+fnKI/7xLQsiVCKZxPxkJQ6FsY0bC5neB4rW1jYSxZfJeNC2xtvp6oerVhjbZnVYsP4SAaXdETyDKPrqABQ==
+";
+    const OLD_SIGNATURE: &str = "untrusted comment: signature from fireguard release key
+RUShssPU5fYHGPr505wC2wc0ryRoP/Z2P9eB2ZfCEBG3/YZTtX9tutY9gCi1vJT4SfuKAN4iFmfzMzexO/SC0IOD3BjxvP+WjQ0=
+trusted comment: fireguard v0.0.12
+nodmnt+Q4Qvh8Cdjxn/315q76bS6jHASKyHPqDhl9oUNec3NEeozInDmKhq5GviBKraNAVBtP0FYpHtMKaL8BQ==
+";
+
+    #[test]
+    fn test_builtin_key() {
+        assert_eq!(ReleaseVerifier::builtin().is_ok(), RELEASE_PUBLIC_KEY.is_some());
+    }
+
+    #[test]
+    fn test_verify_signed_manifest() {
+        let verifier = ReleaseVerifier::new(PUBLIC_KEY).unwrap();
+        let manifest = verifier.verify_manifest(MANIFEST.as_bytes(), SIGNATURE, "v0.0.13").unwrap();
+        assert!(manifest.verify("fireguard-x86_64-unknown-linux-gnu.tar.gz", b"fireguard release tarball").is_ok());
+        assert!(manifest.verify("fireguard-x86_64-unknown-linux-gnu.tar.gz", b"tampered tarball").is_err());
+        assert!(manifest.verify("fireguard-aarch64-unknown-linux-gnu.tar.gz", b"fireguard release tarball").is_err());
+        assert!(manifest.verify("fireguard-armv7-unknown-linux-gnueabihf.tar.gz", b"").is_err());
+    }
+
+    #[test]
+    fn test_reject_tampered_manifest() {
+        let verifier = ReleaseVerifier::new(PUBLIC_KEY).unwrap();
+        let tampered = MANIFEST.replace("65180edf", "00000000");
+        assert!(verifier.verify_manifest(tampered.as_bytes(), SIGNATURE, "v0.0.13").is_err());
+    }
+
+    #[test]
+    fn test_reject_replayed_release() {
+        // Validly signed checksums of v0.0.12 served as the newer v0.0.13.
+        let verifier = ReleaseVerifier::new(PUBLIC_KEY).unwrap();
+        assert!(verifier.verify_manifest(MANIFEST.as_bytes(), OLD_SIGNATURE, "v0.0.12").is_ok());
+        assert!(verifier.verify_manifest(MANIFEST.as_bytes(), OLD_SIGNATURE, "v0.0.13").is_err());
+        // The trusted comment is covered by the signature too.
+        let relabeled = OLD_SIGNATURE.replace("fireguard v0.0.12", "fireguard v0.0.13");
+        assert!(verifier.verify_manifest(MANIFEST.as_bytes(), &relabeled, "v0.0.13").is_err());
+    }
+
+    #[test]
+    fn test_reject_other_key() {
+        let verifier = ReleaseVerifier::new("RWQydV2ZJtd9nbKdXBbOOMhZGUHRTw8d0MrObk3a2YRrQuYwmZPGcjQP").unwrap();
+        assert!(verifier.verify_manifest(MANIFEST.as_bytes(), SIGNATURE, "v0.0.13").is_err());
+    }
+}
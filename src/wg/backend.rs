@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::path::Path;
+
+use crate::error::{FireguardError, Result};
+use crate::runner::Runner;
+
+/// Present when the Wireguard kernel module is loaded.
+const KERNEL_MODULE: &str = "/sys/module/wireguard";
+/// Directory holding the control sockets of userspace Wireguard interfaces.
+const USERSPACE_SOCKET_DIR: &str = "/var/run/wireguard";
+/// Userspace implementations, in order of preference. `WG_QUICK_USERSPACE_IMPLEMENTATION` takes
+/// precedence when set.
+const USERSPACE_IMPLEMENTATIONS: &[&str] = &["boringtun-cli", "boringtun"];
+
+/// Implementation running the Wireguard interface.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WgBackend {
+    /// In kernel Wireguard module.
+    Kernel,
+    /// Userspace implementation like boringtun, used when the kernel module is missing.
+    Userspace(String),
+}
+
+impl fmt::Display for WgBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WgBackend::Kernel => write!(f, "kernel"),
+            WgBackend::Userspace(implementation) => write!(f, "userspace ({})", implementation),
+        }
+    }
+}
+
+pub fn kernel_module_loaded() -> bool {
+    Path::new(KERNEL_MODULE).exists()
+}
+
+/// First userspace Wireguard implementation installed.
+pub fn userspace_implementation() -> Option<String> {
+    if let Ok(implementation) = env::var("WG_QUICK_USERSPACE_IMPLEMENTATION") {
+        return Some(implementation);
+    }
+    let paths = env::var_os("PATH").unwrap_or_default();
+    USERSPACE_IMPLEMENTATIONS
+        .iter()
+        .find(|implementation| env::split_paths(&paths).any(|dir| dir.join(implementation).is_file()))
+        .map(|implementation| implementation.to_string())
+}
+
+impl WgBackend {
+    /// Backend to start a new interface with: the kernel module when it can be loaded, a userspace
+    /// implementation otherwise.
+    pub async fn detect(runner: &Runner) -> Result<Self> {
+        if kernel_module_loaded() || runner.exec("modprobe", &["wireguard"], None, true).await.success() {
+            return Ok(WgBackend::Kernel);
+        }
+        match userspace_implementation() {
+            Some(implementation) => {
+                warn!("Wireguard kernel module not available, falling back to userspace {}", implementation);
+                Ok(WgBackend::Userspace(implementation))
+            }
+            None => Err(FireguardError::Dependency(format!(
+                "Wireguard kernel module not available and no userspace implementation found, please install {}",
+                USERSPACE_IMPLEMENTATIONS.join(" or ")
+            ))),
+        }
+    }
+
+    /// Backend of the running `interface`, if any. Userspace implementations expose a control
+    /// socket, the kernel does not.
+    pub fn running(interface: &str) -> Option<Self> {
+        if Path::new(USERSPACE_SOCKET_DIR).join(format!("{}.sock", interface)).exists() {
+            Some(WgBackend::Userspace(userspace_implementation().unwrap_or_else(|| "unknown".to_string())))
+        } else if Path::new("/sys/class/net").join(interface).exists() {
+            Some(WgBackend::Kernel)
+        } else {
+            None
+        }
+    }
+
+    /// Environment for `wg-quick`, which falls back to the userspace implementation by itself
+    /// when it fails to create a kernel interface.
+    pub fn env(&self) -> HashMap<&str, &str> {
+        let mut env = HashMap::new();
+        if let WgBackend::Userspace(implementation) = self {
+            env.insert("WG_QUICK_USERSPACE_IMPLEMENTATION", implementation.as_str());
+            // boringtun refuses to run as root unless told it was started through sudo.
+            env.insert("WG_SUDO", "1");
+        }
+        env
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backend_env() {
+        assert!(WgBackend::Kernel.env().is_empty());
+        let userspace = WgBackend::Userspace("boringtun-cli".to_string());
+        assert_eq!(userspace.env().get("WG_QUICK_USERSPACE_IMPLEMENTATION"), Some(&"boringtun-cli"));
+        assert_eq!(userspace.to_string(), "userspace (boringtun-cli)");
+    }
+}
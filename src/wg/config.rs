@@ -1,22 +1,29 @@
-use std::collections::HashMap;
 use std::path::Path;
 
-use color_eyre::eyre::{bail, Result};
-use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 use tera::{Context, Tera};
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
 
-use crate::config::Peer as ConfigPeer;
+use crate::config::Config;
+use crate::endpoint::Endpoint;
+use crate::error::{FireguardError, Result};
+use crate::topology;
+
+/// Firewall mark and routing table of the tunnel traffic when using an exit node, like `wg-quick`.
+pub const EXIT_FWMARK: u32 = 51820;
 
 static WIREGARD_CONFIG_TMPL: &str = r#"# {{ host.repository }} - {{ host.name }} wireguard configuration
 # Note: this file is managed by fireguard (https://github.com/blackmesalab/fireguard)
-[Interface]
+{% if host.relay %}# Relaying the traffic between the peers of {{ host.repository }}, forwarding has to be enabled
+{% endif %}{% if host.router %}# Routing the traffic of the peers out of the tunnel, forwarding has to be enabled
+{% endif %}{% if host.exit_node %}# Sending the internet traffic through the exit node {{ host.exit_node }}
+{% endif %}[Interface]
 Address = {{ host.address }}
 PrivateKey = {{ host.private_key }}
 {% if host.listen_port > 0 %}ListenPort = {{ host.listen_port }}{% endif %}
 {% if host.dns %}DNS = {{ host.dns | join(sep=",") }}{% endif %}
+{% if host.mtu > 0 %}MTU = {{ host.mtu }}{% endif %}
 {% if host.fwmark > 0 %}FwMark = {{ host.fwmark }}{% endif %}
 {% if host.table > 0 %}Table = {{ host.table }}{% endif %}
 {% if host.pre_up %}PreUp = {{ host.pre_up }}{% endif %}
@@ -26,48 +33,70 @@ PrivateKey = {{ host.private_key }}
 
 {% for peer in host.peers %}# Peer {{ peer.name }}
 [Peer]
-{% if peer.endpoint %}Endpoint = {{ peer.endpoint }}:{{ peer.listen_port }}{% endif %}
+{% if peer.endpoint %}Endpoint = {{ peer.endpoint }}{% endif %}
 PublicKey = {{ peer.public_key }}
 AllowedIps = {{ peer.allowed_ips | join(sep=",") }}
 {% if peer.persistent_keepalive > 0 %}PersistentKeepalive = {{ peer.persistent_keepalive}}{% endif %}
 
 {% endfor -%}"#;
 
+/// Wireguard configuration of one peer of a trust repository, in `wg-quick` format.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct WgConfig {
     host: Host,
-    #[serde(skip_deserializing, skip_serializing)]
-    mutex: Mutex<u8>,
 }
 
 impl WgConfig {
-    pub fn new(
-        peers: HashMap<String, ConfigPeer>,
+    /// Configuration of the peer `<username>-<peername>`, with the peers it reaches in the
+    /// repository topology.
+    pub fn new(config: &Config, repository: &str, username: &str, peername: &str, private_key: &str) -> Result<Self> {
+        Self::with_exit_node(config, repository, username, peername, private_key, None)
+    }
+
+    /// Like [`WgConfig::new`], sending the internet traffic through the peer `exit_node` when set,
+    /// with `wg-quick` style policy routing on the peer fwmark.
+    pub fn with_exit_node(
+        config: &Config,
         repository: &str,
         username: &str,
         peername: &str,
         private_key: &str,
+        exit_node: Option<&str>,
     ) -> Result<Self> {
         let peername = format!("{}-{}", username, peername);
-        let my_peer = peers.get(&peername);
+        let my_peer = config.get_peer(&peername);
         if let Some(my_peer) = my_peer {
-            let mut wg_peers = peers
-                .values()
-                .map(|x| {
-                    Peer::new(
-                        format!("{}-{}", x.username, x.peername),
+            let routes = match exit_node {
+                Some(exit_node) => {
+                    if let Some(table) = my_peer.table {
+                        let message = format!(
+                            "peer {} routes to table {}, the exit node needs the automatic table",
+                            peername, table
+                        );
+                        return Err(FireguardError::config(None, message));
+                    }
+                    topology::exit_routes(config, &peername, exit_node)?
+                }
+                None => topology::routes(config, &peername)?,
+            };
+            let mut wg_peers = vec![];
+            for route in routes {
+                if let Some(x) = config.get_peer(&route.peer) {
+                    // The daemon moves on to the other candidates when the first one stops answering.
+                    let endpoint = x.candidates()?.first().map(Endpoint::to_string);
+                    wg_peers.push(Peer::new(
+                        route.peer.clone(),
                         x.public_key.clone(),
                         x.listen_port,
-                        x.allowed_ips.clone(),
+                        route.allowed_ips,
                         x.persistent_keepalive,
-                        x.endpoint.clone(),
-                    )
-                })
-                .collect::<Vec<Peer>>();
-            wg_peers.retain(|x| x.name != peername);
-            let wg_host = Host::new(
+                        endpoint,
+                    ));
+                }
+            }
+            let mut wg_host = Host::new(
                 repository.to_string(),
-                peername,
+                peername.clone(),
                 my_peer.address.clone(),
                 private_key.to_string(),
                 my_peer.listen_port,
@@ -77,23 +106,41 @@ impl WgConfig {
                 my_peer.post_down.clone().unwrap_or_default(),
                 my_peer.dns.clone().unwrap_or_default(),
                 my_peer.table.unwrap_or(0),
-                my_peer.fwmark.unwrap_or(0),
+                my_peer.fwmark.or_else(|| exit_node.map(|_| EXIT_FWMARK)).unwrap_or(0),
+                my_peer.mtu,
                 wg_peers,
             );
-            Ok(Self { host: wg_host, mutex: Mutex::new(0) })
+            wg_host.relay = topology::is_relay(config, &peername);
+            wg_host.router = my_peer.exit_node || !my_peer.advertised_routes.is_empty();
+            wg_host.exit_node = exit_node.map(str::to_string);
+            Ok(Self { host: wg_host })
         } else {
-            bail!("Unable to find peer {} for repository {}", peername, repository)
+            Err(FireguardError::PeerNotFound { repository: repository.to_string(), peer: peername })
         }
     }
 
-    pub async fn render(&self, config_path: &Path) -> Result<()> {
-        info!("Rendering Wireguard configuration on {}", config_path.display());
+    /// Whether the host relays the traffic of other peers, which needs forwarding enabled.
+    pub fn relay(&self) -> bool {
+        self.host.relay
+    }
+
+    /// Whether the host routes the traffic of other peers out of the tunnel, as an exit node or to
+    /// its advertised routes, which needs forwarding enabled.
+    pub fn router(&self) -> bool {
+        self.host.router
+    }
+
+    pub fn render_to_string(&self) -> Result<String> {
         let mut wg_tera = Tera::default();
         wg_tera.add_raw_template("wireguard.txt", WIREGARD_CONFIG_TMPL)?;
-        let wg_config = wg_tera.render("wireguard.txt", &Context::from_serialize(self)?)?;
-        let mut file = File::create(config_path).await?;
-        file.write_all(&wg_config.as_bytes()).await?;
-        Ok(())
+        Ok(wg_tera.render("wireguard.txt", &Context::from_serialize(self)?)?)
+    }
+
+    pub async fn render(&self, config_path: &Path) -> Result<()> {
+        info!("Rendering Wireguard configuration on {}", config_path.display());
+        let wg_config = self.render_to_string()?;
+        let mut file = File::create(config_path).await.map_err(|e| FireguardError::io(config_path, e))?;
+        file.write_all(wg_config.as_bytes()).await.map_err(|e| FireguardError::io(config_path, e))
     }
 }
 
@@ -111,6 +158,10 @@ pub struct Host {
     pub dns: Vec<String>,
     pub table: u32,
     pub fwmark: u32,
+    pub mtu: u32,
+    pub relay: bool,
+    pub router: bool,
+    pub exit_node: Option<String>,
     pub peers: Vec<Peer>,
 }
 
@@ -129,6 +180,7 @@ impl Host {
         dns: Vec<String>,
         table: u32,
         fwmark: u32,
+        mtu: u32,
         peers: Vec<Peer>,
     ) -> Self {
         Self {
@@ -144,6 +196,10 @@ impl Host {
             dns,
             table,
             fwmark,
+            mtu,
+            relay: false,
+            router: false,
+            exit_node: None,
             peers,
         }
     }
@@ -179,3 +235,49 @@ impl Peer {
         Self { name, public_key, listen_port, allowed_ips, persistent_keepalive, endpoint }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wg::native::TunnelConfig;
+
+    const NODES: &str = r#"repository = "avalon"
+network = "10.0.0.0/24"
+domain = "avalon.lan"
+
+[peers.alice-laptop]
+username = "alice"
+peername = "laptop"
+address = "10.0.0.1/24"
+listen_port = 51820
+public_key = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg="
+allowed_ips = ["10.0.0.1/32"]
+persistent_keepalive = 25
+mtu = 1380
+
+[peers.bob-cloud]
+username = "bob"
+peername = "cloud"
+address = "10.0.0.2/24"
+listen_port = 51820
+public_key = "TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0="
+allowed_ips = ["10.0.0.2/32"]
+persistent_keepalive = 25
+endpoint = "cloud.bob.net"
+mtu = 0
+"#;
+
+    #[test]
+    fn test_render_mtu() {
+        let config = Config::parse(NODES).unwrap();
+        let private_key = "YAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=";
+        let rendered =
+            WgConfig::new(&config, "avalon", "alice", "laptop", private_key).unwrap().render_to_string().unwrap();
+        assert!(rendered.contains("\nMTU = 1380\n"));
+        assert_eq!(TunnelConfig::parse(&rendered).unwrap().mtu, Some(1380));
+        // Without an MTU the interface keeps the default one.
+        let rendered =
+            WgConfig::new(&config, "avalon", "bob", "cloud", private_key).unwrap().render_to_string().unwrap();
+        assert!(!rendered.contains("MTU"));
+    }
+}
@@ -1,7 +1,8 @@
-use color_eyre::eyre::{bail, Result};
-
-use crate::shell::Shell;
+use crate::error::{FireguardError, Result};
+use crate::runner::Runner;
+use crate::shell::ShellCommand;
 
+/// Wireguard key pair, base64 encoded.
 pub struct WgKeys {
     pub public: String,
     pub private: String,
@@ -11,16 +12,21 @@ impl WgKeys {
     pub fn new(public: &str, private: &str) -> Self {
         WgKeys { public: public.to_string(), private: private.to_string() }
     }
-    pub async fn generate() -> Result<Self> {
-        let result = Shell::exec("wg", "genkey", None, true).await;
-        if result.success() {
-            let private = result.stdout();
-            let result = Shell::exec_with_input("wg", "pubkey", None, &private, true).await;
-            let public = result.stdout();
-            Ok(WgKeys { private: private.trim().to_string(), public: public.trim().to_string() })
-        } else {
-            error!("Error generating new Wireguard keys: {}", result.stderr());
-            bail!("Error generatiin Wireguard keys")
+    /// Generate a new key pair with `wg` from wireguard-tools.
+    pub async fn generate(runner: &Runner) -> Result<Self> {
+        if !runner.runnable("wg") {
+            return Err(FireguardError::Dependency("wg is not installed, please install wireguard-tools".to_string()));
+        }
+        let genkey = ShellCommand::new("wg").arg("genkey").sensitive(true);
+        let private = runner.run(&genkey).await;
+        if !private.success() {
+            return Err(FireguardError::command(&genkey, &private));
+        }
+        let pubkey = ShellCommand::new("wg").arg("pubkey").stdin(private.stdout()).sensitive(true);
+        let public = runner.run(&pubkey).await;
+        if !public.success() {
+            return Err(FireguardError::command(&pubkey, &public));
         }
+        Ok(WgKeys { private: private.stdout().trim().to_string(), public: public.stdout().trim().to_string() })
     }
 }
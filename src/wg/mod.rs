@@ -1,7 +1,14 @@
+pub mod backend;
 pub mod config;
 pub mod key;
+mod native;
+mod netlink;
 pub mod quick;
+pub mod tunnel;
 
+pub use backend::WgBackend;
 pub use config::WgConfig;
 pub use key::WgKeys;
-pub use quick::WgQuick;
+pub use native::WgNetlink;
+pub use quick::{WgPeer, WgQuick};
+pub use tunnel::{WgDriver, WgTunnel};
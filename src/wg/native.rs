@@ -0,0 +1,626 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use color_eyre::eyre::{bail, eyre, Report, Result};
+use ipnet::IpNet;
+use tokio::fs::read_to_string;
+use tokio::task;
+
+use crate::error::{self, FireguardError};
+use crate::runner::Runner;
+use crate::wg::netlink::{decode_key, Device, DeviceInfo, Netlink, PeerConfig, Rule, RT_TABLE_MAIN};
+use crate::wg::{WgBackend, WgPeer, WgQuick, WgTunnel};
+
+/// Interface MTU when the configuration does not set one, the same `wg-quick` falls back to.
+const DEFAULT_MTU: u32 = 1420;
+/// Routing table and firewall mark used for default routes, like `wg-quick`.
+const DEFAULT_TABLE: u32 = 51820;
+
+/// Routing table the peers allowed IPs are routed to.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Table {
+    /// Main table, with a policy routing table for default routes.
+    #[default]
+    Auto,
+    /// No routes at all.
+    Off,
+    Id(u32),
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TunnelPeer {
+    pub public_key: String,
+    pub preshared_key: Option<String>,
+    pub endpoint: Option<String>,
+    pub persistent_keepalive: u16,
+    pub allowed_ips: Vec<IpNet>,
+}
+
+/// Wireguard configuration in `wg-quick` format, as rendered by `wg render`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TunnelConfig {
+    pub addresses: Vec<IpNet>,
+    pub private_key: String,
+    pub listen_port: Option<u16>,
+    pub dns: Vec<String>,
+    pub mtu: Option<u32>,
+    pub table: Table,
+    pub fwmark: Option<u32>,
+    pub pre_up: Vec<String>,
+    pub post_up: Vec<String>,
+    pub pre_down: Vec<String>,
+    pub post_down: Vec<String>,
+    pub peers: Vec<TunnelPeer>,
+}
+
+/// Routes and policy routing rules bringing the peers allowed IPs through the interface.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RoutePlan {
+    pub routes: Vec<(IpNet, u32)>,
+    pub rules: Vec<Rule>,
+    pub fwmark: Option<u32>,
+}
+
+fn parse_ip_net(value: &str) -> Result<IpNet> {
+    match value.parse::<IpNet>() {
+        Ok(net) => Ok(net),
+        Err(_) => Ok(IpNet::from(value.parse::<IpAddr>().map_err(|_| eyre!("Invalid address {}", value))?)),
+    }
+}
+
+fn parse_list(value: &str) -> Vec<&str> {
+    value.split(',').map(str::trim).filter(|v| !v.is_empty()).collect()
+}
+
+fn parse_u32(value: &str) -> Result<u32> {
+    let value = value.trim();
+    match value.strip_prefix("0x") {
+        Some(hex) => Ok(u32::from_str_radix(hex, 16)?),
+        None => Ok(value.parse::<u32>()?),
+    }
+}
+
+impl TunnelConfig {
+    pub async fn load(path: &Path) -> Result<Self> {
+        let data = read_to_string(path)
+            .await
+            .map_err(|e| eyre!("Unable to read Wireguard configuration {}: {}", path.display(), e))?;
+        Self::parse(&data)
+    }
+
+    pub fn parse(data: &str) -> Result<Self> {
+        let mut config = TunnelConfig::default();
+        let mut section = String::new();
+        for line in data.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                section = line[1..line.len() - 1].trim().to_lowercase();
+                if section == "peer" {
+                    config.peers.push(TunnelPeer::default());
+                }
+                continue;
+            }
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or_default().trim().to_lowercase();
+            let value = parts.next().ok_or_else(|| eyre!("Invalid Wireguard configuration line: {}", line))?.trim();
+            match (section.as_str(), key.as_str()) {
+                ("interface", "address") => {
+                    for address in parse_list(value) {
+                        config.addresses.push(parse_ip_net(address)?);
+                    }
+                }
+                ("interface", "privatekey") => config.private_key = value.to_string(),
+                ("interface", "listenport") => config.listen_port = Some(value.parse()?),
+                ("interface", "dns") => config.dns.extend(parse_list(value).iter().map(|v| v.to_string())),
+                ("interface", "mtu") => config.mtu = Some(value.parse()?),
+                ("interface", "table") => {
+                    config.table = match value {
+                        "off" => Table::Off,
+                        "auto" => Table::Auto,
+                        id => Table::Id(parse_u32(id)?),
+                    }
+                }
+                ("interface", "fwmark") => {
+                    config.fwmark = match value {
+                        "off" => None,
+                        mark => Some(parse_u32(mark)?).filter(|mark| *mark > 0),
+                    }
+                }
+                ("interface", "preup") => config.pre_up.push(value.to_string()),
+                ("interface", "postup") => config.post_up.push(value.to_string()),
+                ("interface", "predown") => config.pre_down.push(value.to_string()),
+                ("interface", "postdown") => config.post_down.push(value.to_string()),
+                ("interface", "saveconfig") => {}
+                ("peer", _) => {
+                    let peer = config.peers.last_mut().unwrap();
+                    match key.as_str() {
+                        "publickey" => peer.public_key = value.to_string(),
+                        "presharedkey" => peer.preshared_key = Some(value.to_string()),
+                        "endpoint" => peer.endpoint = Some(value.to_string()),
+                        "persistentkeepalive" => {
+                            peer.persistent_keepalive = if value == "off" { 0 } else { value.parse()? }
+                        }
+                        "allowedips" => {
+                            for ip in parse_list(value) {
+                                peer.allowed_ips.push(parse_ip_net(ip)?);
+                            }
+                        }
+                        _ => bail!("Unknown Wireguard peer setting {}", key),
+                    }
+                }
+                _ => bail!("Unknown Wireguard setting {} in section [{}]", key, section),
+            }
+        }
+        if config.private_key.is_empty() {
+            bail!("Wireguard configuration has no private key");
+        }
+        Ok(config)
+    }
+
+    /// Routes for the peers allowed IPs, the way `wg-quick` sets them up: default routes go
+    /// to a dedicated table, selected by policy routing rules for the traffic not coming from
+    /// the tunnel itself.
+    pub fn plan(&self) -> RoutePlan {
+        let mut plan = RoutePlan { fwmark: self.fwmark, ..Default::default() };
+        if self.table == Table::Off {
+            return plan;
+        }
+        let mut allowed_ips =
+            self.peers.iter().flat_map(|p| p.allowed_ips.iter().map(IpNet::trunc)).collect::<Vec<_>>();
+        allowed_ips.sort_by(|a, b| b.prefix_len().cmp(&a.prefix_len()).then(a.cmp(b)));
+        allowed_ips.dedup();
+        for ip in allowed_ips {
+            match self.table {
+                Table::Id(table) => plan.routes.push((ip, table)),
+                Table::Auto if ip.prefix_len() == 0 => {
+                    let table = self.fwmark.unwrap_or(DEFAULT_TABLE);
+                    plan.fwmark = Some(table);
+                    plan.routes.push((ip, table));
+                    let family = Rule::family(&ip);
+                    let rules = vec![
+                        Rule {
+                            family,
+                            table,
+                            fwmark: Some(table),
+                            invert: true,
+                            suppress_prefixlength: None,
+                            priority: None,
+                        },
+                        Rule {
+                            family,
+                            table: RT_TABLE_MAIN,
+                            fwmark: None,
+                            invert: false,
+                            suppress_prefixlength: Some(0),
+                            priority: None,
+                        },
+                    ];
+                    for rule in rules {
+                        if !plan.rules.contains(&rule) {
+                            plan.rules.push(rule);
+                        }
+                    }
+                }
+                _ => plan.routes.push((ip, RT_TABLE_MAIN)),
+            }
+        }
+        plan
+    }
+
+    /// Kernel device settings. Endpoints are resolved here, so this blocks on DNS.
+    pub fn device(&self, fwmark: Option<u32>) -> Result<Device> {
+        let mut peers = vec![];
+        for peer in self.peers.iter() {
+            let endpoint = match peer.endpoint.as_ref() {
+                Some(endpoint) => Some(
+                    endpoint
+                        .to_socket_addrs()
+                        .map_err(|e| eyre!("Unable to resolve endpoint {}: {}", endpoint, e))?
+                        .next()
+                        .ok_or_else(|| eyre!("Endpoint {} resolves to no address", endpoint))?,
+                ),
+                None => None,
+            };
+            peers.push(PeerConfig {
+                public_key: decode_key(&peer.public_key)?,
+                preshared_key: peer.preshared_key.as_deref().map(decode_key).transpose()?,
+                endpoint,
+                persistent_keepalive: peer.persistent_keepalive,
+                allowed_ips: peer.allowed_ips.iter().map(IpNet::trunc).collect(),
+            });
+        }
+        Ok(Device { private_key: decode_key(&self.private_key)?, listen_port: self.listen_port, fwmark, peers })
+    }
+}
+
+/// Run the `wg-quick` style hooks, `%i` standing for the interface name.
+async fn run_hooks(hooks: &[String], interface: &str, runner: &Runner) -> Result<()> {
+    for hook in hooks {
+        let hook = hook.replace("%i", interface);
+        info!("Running hook: {}", hook);
+        let result = runner.exec("bash", &["-c", &hook], None, false).await;
+        match result.code() {
+            Some(0) => (),
+            Some(code) => bail!("Hook `{}` exited with code {}: {}", hook, code, result.stderr()),
+            None => bail!("Hook `{}` did not complete: {}", hook, result.stderr()),
+        }
+    }
+    Ok(())
+}
+
+fn route_family_enabled(plan: &RoutePlan) -> bool {
+    plan.rules.iter().any(|rule| rule.family == libc::AF_INET as u8)
+}
+
+fn link_index(route: &mut Netlink, interface: &str) -> Result<u32> {
+    route.link_index(interface)?.ok_or_else(|| eyre!("Wireguard interface {} is not up", interface))
+}
+
+fn apply_routes(route: &mut Netlink, index: u32, plan: &RoutePlan) -> Result<()> {
+    for (destination, table) in plan.routes.iter() {
+        route
+            .replace_route(index, destination, *table)
+            .map_err(|e| eyre!("Unable to add route {} to table {}: {}", destination, table, e))?;
+    }
+    for rule in plan.rules.iter() {
+        route.add_rule(rule).map_err(|e| eyre!("Unable to add routing rule {:?}: {}", rule, e))?;
+    }
+    if route_family_enabled(plan) {
+        // Let the replies to the marked packets pass the reverse path filter, like wg-quick.
+        fs::write("/proc/sys/net/ipv4/conf/all/src_valid_mark", "1")?;
+    }
+    Ok(())
+}
+
+fn create(interface: &str, config: &TunnelConfig) -> Result<()> {
+    let mut route = Netlink::route()?;
+    if route.link_index(interface)?.is_some() {
+        bail!("Wireguard interface {} already exists", interface);
+    }
+    route.add_wireguard_link(interface).map_err(|e| eyre!("Unable to create interface {}: {}", interface, e))?;
+    let index = link_index(&mut route, interface)?;
+    let configure = |route: &mut Netlink| -> Result<()> {
+        let plan = config.plan();
+        let mut genl = Netlink::generic()?;
+        let family = genl.family_id("wireguard")?;
+        genl.set_device(family, interface, &config.device(plan.fwmark)?, &[])
+            .map_err(|e| eyre!("Unable to configure interface {}: {}", interface, e))?;
+        for address in config.addresses.iter() {
+            route.add_address(index, address).map_err(|e| eyre!("Unable to add address {}: {}", address, e))?;
+        }
+        route.set_link_up(index, config.mtu.unwrap_or(DEFAULT_MTU))?;
+        apply_routes(route, index, &plan)
+    };
+    if let Err(e) = configure(&mut route) {
+        // Do not leave a half configured interface behind.
+        for rule in config.plan().rules.iter() {
+            let _ = route.delete_rule(rule);
+        }
+        let _ = route.delete_link(index);
+        return Err(e);
+    }
+    Ok(())
+}
+
+fn destroy(interface: &str, config: Option<&TunnelConfig>) -> Result<()> {
+    let mut route = Netlink::route()?;
+    let index = link_index(&mut route, interface)?;
+    if let Some(config) = config {
+        for rule in config.plan().rules.iter() {
+            if let Err(e) = route.delete_rule(rule) {
+                warn!("Unable to remove routing rule {:?}: {}", rule, e);
+            }
+        }
+    }
+    // Addresses and routes go away with the interface.
+    route.delete_link(index).map_err(|e| eyre!("Unable to delete interface {}: {}", interface, e))?;
+    Ok(())
+}
+
+fn sync(interface: &str, config: &TunnelConfig) -> Result<()> {
+    let mut route = Netlink::route()?;
+    let index = link_index(&mut route, interface)?;
+    let plan = config.plan();
+    let mut genl = Netlink::generic()?;
+    let family = genl.family_id("wireguard")?;
+    let device = config.device(plan.fwmark)?;
+    let current = genl.get_device(family, interface)?;
+    let mut remove = vec![];
+    for peer in current.peers.iter() {
+        let key = decode_key(&peer.public_key)?;
+        if !device.peers.iter().any(|p| p.public_key == key) {
+            info!("Removing peer {} from interface {}", peer.public_key, interface);
+            remove.push(key);
+        }
+    }
+    genl.set_device(family, interface, &device, &remove)
+        .map_err(|e| eyre!("Unable to configure interface {}: {}", interface, e))?;
+    let tables = plan.routes.iter().map(|(_, table)| *table).collect::<BTreeSet<u32>>();
+    for table in tables {
+        for destination in route.routes(index, table)? {
+            if !plan.routes.contains(&(destination, table)) {
+                info!("Removing route {} from table {}", destination, table);
+                route.delete_route(index, &destination, table)?;
+            }
+        }
+    }
+    apply_routes(&mut route, index, &plan)
+}
+
+fn device_info(interface: &str) -> Result<DeviceInfo> {
+    let mut genl = Netlink::generic()?;
+    let family = genl.family_id("wireguard")?;
+    genl.get_device(family, interface).map_err(|e| eyre!("Unable to read interface {}: {}", interface, e))
+}
+
+fn format_status(interface: &str, info: &DeviceInfo) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+    let mut out = format!("interface: {}\n", interface);
+    if let Some(key) = info.public_key.as_ref() {
+        out.push_str(&format!("  public key: {}\n", key));
+    }
+    out.push_str(&format!("  listening port: {}\n", info.listen_port));
+    if info.fwmark > 0 {
+        out.push_str(&format!("  fwmark: {:#x}\n", info.fwmark));
+    }
+    for peer in info.peers.iter() {
+        out.push_str(&format!("\npeer: {}\n", peer.public_key));
+        if let Some(endpoint) = peer.endpoint.as_ref() {
+            out.push_str(&format!("  endpoint: {}\n", endpoint));
+        }
+        out.push_str(&format!("  allowed ips: {}\n", peer.allowed_ips.join(", ")));
+        if let Some(handshake) = peer.latest_handshake {
+            out.push_str(&format!("  latest handshake: {} seconds ago\n", now.saturating_sub(handshake)));
+        }
+        out.push_str(&format!(
+            "  transfer: {} B received, {} B sent\n",
+            peer.transfer_rx.unwrap_or_default(),
+            peer.transfer_tx.unwrap_or_default()
+        ));
+        if let Some(keepalive) = peer.persistent_keepalive {
+            out.push_str(&format!("  persistent keepalive: every {} seconds\n", keepalive));
+        }
+    }
+    out
+}
+
+/// Native Wireguard interface management over netlink, needing neither wireguard-tools nor
+/// iproute2. Interfaces run by a userspace implementation are handed to `wg-quick`.
+pub struct WgNetlink {
+    repository: String,
+    runner: Runner,
+}
+
+impl WgNetlink {
+    pub fn new(repository: &str, runner: &Runner) -> Self {
+        WgNetlink { repository: repository.to_string(), runner: runner.clone() }
+    }
+
+    fn userspace(&self) -> bool {
+        matches!(WgBackend::running(&self.repository), Some(WgBackend::Userspace(_)))
+    }
+
+    async fn set_dns(&self, config: &TunnelConfig) {
+        if config.dns.is_empty() {
+            return;
+        }
+        let servers = config.dns.iter().map(|server| format!("nameserver {}\n", server)).collect::<String>();
+        let args = ["-a", &self.repository, "-m", "0", "-x"];
+        if !self.runner.exec_with_input("resolvconf", &args, None, &servers, false).await.success() {
+            warn!("Unable to set the DNS servers of interface {} with resolvconf", self.repository);
+        }
+    }
+
+    async fn unset_dns(&self, config: &TunnelConfig) {
+        if !config.dns.is_empty() {
+            self.runner.exec("resolvconf", &["-d", &self.repository, "-f"], None, false).await;
+        }
+    }
+
+    async fn blocking<T, F>(&self, operation: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&str) -> Result<T> + Send + 'static,
+    {
+        let interface = self.repository.clone();
+        task::spawn_blocking(move || operation(&interface)).await?
+    }
+
+    async fn kernel_up(&self, config: &Path) -> Result<()> {
+        info!("Starting new Wireguard instance for repository {} with kernel backend", self.repository);
+        let tunnel = TunnelConfig::load(config).await?;
+        run_hooks(&tunnel.pre_up, &self.repository, &self.runner).await?;
+        let settings = tunnel.clone();
+        self.blocking(move |interface| create(interface, &settings)).await?;
+        self.set_dns(&tunnel).await;
+        run_hooks(&tunnel.post_up, &self.repository, &self.runner).await?;
+        info!("Wireguard instance started successfully");
+        Ok(())
+    }
+
+    async fn kernel_down(&self, config: &Path) -> Result<()> {
+        info!("Stopping Wireguard instance for repository {}", self.repository);
+        let tunnel = match TunnelConfig::load(config).await {
+            Ok(tunnel) => Some(tunnel),
+            Err(e) => {
+                warn!("{}, removing the interface without running its hooks", e);
+                None
+            }
+        };
+        if let Some(tunnel) = tunnel.as_ref() {
+            run_hooks(&tunnel.pre_down, &self.repository, &self.runner).await?;
+        }
+        let settings = tunnel.clone();
+        self.blocking(move |interface| destroy(interface, settings.as_ref())).await?;
+        if let Some(tunnel) = tunnel.as_ref() {
+            self.unset_dns(tunnel).await;
+            run_hooks(&tunnel.post_down, &self.repository, &self.runner).await?;
+        }
+        info!("Wireguard instance stopped successfully");
+        Ok(())
+    }
+
+    async fn kernel_sync(&self, config: &Path) -> Result<()> {
+        info!("Syncing Wireguard instance configuration for repository {}", self.repository);
+        let tunnel = TunnelConfig::load(config).await?;
+        self.blocking(move |interface| sync(interface, &tunnel)).await?;
+        info!("Wireguard instance configuration synced successfully");
+        Ok(())
+    }
+
+    async fn kernel_status(&self) -> Result<()> {
+        let info = self.blocking(device_info).await?;
+        let backend = self.backend().map(|b| b.to_string()).unwrap_or_else(|| "unknown".to_string());
+        info!("Wireguard backend for repository {}: {}", self.repository, backend);
+        info!(
+            "Wireguard statistics for repository {}:\n{}",
+            self.repository,
+            format_status(&self.repository, &info).trim()
+        );
+        Ok(())
+    }
+
+    fn error(&self, error: Report) -> FireguardError {
+        FireguardError::tunnel(&self.repository, error)
+    }
+}
+
+/// Interfaces running in userspace are handed over to `wg-quick`.
+#[async_trait]
+impl WgTunnel for WgNetlink {
+    async fn up(&self, config: &Path) -> error::Result<()> {
+        if let WgBackend::Userspace(implementation) = WgBackend::detect(&self.runner).await? {
+            info!("Interface {} runs in userspace with {}, starting it with wg-quick", self.repository, implementation);
+            return WgQuick::new(&self.repository, &self.runner)?.up(config).await;
+        }
+        self.kernel_up(config).await.map_err(|e| self.error(e))
+    }
+
+    async fn down(&self, config: &Path) -> error::Result<()> {
+        if self.userspace() {
+            return WgQuick::new(&self.repository, &self.runner)?.down(config).await;
+        }
+        self.kernel_down(config).await.map_err(|e| self.error(e))
+    }
+
+    async fn is_up(&self) -> bool {
+        self.blocking(|interface| Ok(Netlink::route()?.link_index(interface)?.is_some())).await.unwrap_or(false)
+    }
+
+    async fn sync(&self, config: &Path) -> error::Result<()> {
+        if self.userspace() {
+            return WgQuick::new(&self.repository, &self.runner)?.sync(config).await;
+        }
+        self.kernel_sync(config).await.map_err(|e| self.error(e))
+    }
+
+    async fn peers(&self) -> error::Result<Vec<WgPeer>> {
+        if self.userspace() {
+            return WgQuick::new(&self.repository, &self.runner)?.peers().await;
+        }
+        Ok(self.blocking(device_info).await.map_err(|e| self.error(e))?.peers)
+    }
+
+    async fn set_endpoint(&self, public_key: &str, endpoint: SocketAddr) -> error::Result<()> {
+        if self.userspace() {
+            return WgQuick::new(&self.repository, &self.runner)?.set_endpoint(public_key, endpoint).await;
+        }
+        let public_key = public_key.to_string();
+        self.blocking(move |interface| {
+            let key = decode_key(&public_key)?;
+            let mut genl = Netlink::generic()?;
+            let family = genl.family_id("wireguard")?;
+            genl.set_peer_endpoint(family, interface, &key, &endpoint)
+                .map_err(|e| eyre!("Unable to set the endpoint of peer {} to {}: {}", public_key, endpoint, e))
+        })
+        .await
+        .map_err(|e| self.error(e))
+    }
+
+    async fn status(&self) -> error::Result<()> {
+        if self.userspace() {
+            return WgQuick::new(&self.repository, &self.runner)?.status().await;
+        }
+        self.kernel_status().await.map_err(|e| self.error(e))
+    }
+
+    fn backend(&self) -> Option<WgBackend> {
+        WgBackend::running(&self.repository)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "# avalon - alice-laptop wireguard configuration
+[Interface]
+Address = 10.0.0.1/24, fd00::1/64
+PrivateKey = YAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=
+ListenPort = 51820
+
+FwMark = 0x10
+PostUp = bash -c \"iptables -A FORWARD -i %i -j ACCEPT\"
+
+# Peer bob-server
+[Peer]
+Endpoint = 1.2.3.4:51820
+PublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=
+AllowedIps = 10.0.0.2/32,192.168.1.0/24
+PersistentKeepalive = 25
+
+[Peer]
+PublicKey = TrMvSoP4jYQlY6RIzBgbssQqY3vxI2Pi+y71lOWWXX0=
+AllowedIPs = 0.0.0.0/0, 10.0.0.2/32
+";
+
+    #[test]
+    fn test_parse_config() {
+        let config = TunnelConfig::parse(CONFIG).unwrap();
+        assert_eq!(config.addresses, vec!["10.0.0.1/24".parse::<IpNet>().unwrap(), "fd00::1/64".parse().unwrap()]);
+        assert_eq!(config.listen_port, Some(51820));
+        assert_eq!(config.fwmark, Some(16));
+        assert_eq!(config.table, Table::Auto);
+        assert_eq!(config.post_up, vec!["bash -c \"iptables -A FORWARD -i %i -j ACCEPT\"".to_string()]);
+        assert_eq!(config.peers.len(), 2);
+        assert_eq!(config.peers[0].endpoint, Some("1.2.3.4:51820".to_string()));
+        assert_eq!(config.peers[0].persistent_keepalive, 25);
+        assert_eq!(config.peers[1].allowed_ips.len(), 2);
+        let device = config.device(None).unwrap();
+        assert_eq!(device.peers[0].endpoint, Some("1.2.3.4:51820".parse().unwrap()));
+        assert!(TunnelConfig::parse("[Interface]\nAddress = 10.0.0.1/24\n").is_err());
+        assert!(TunnelConfig::parse("[Interface]\nPrivateKey = x\nBogus = 1\n").is_err());
+    }
+
+    #[test]
+    fn test_route_plan() {
+        let config = TunnelConfig::parse(CONFIG).unwrap();
+        let plan = config.plan();
+        let net = |s: &str| s.parse::<IpNet>().unwrap();
+        assert_eq!(
+            plan.routes,
+            vec![(net("10.0.0.2/32"), RT_TABLE_MAIN), (net("192.168.1.0/24"), RT_TABLE_MAIN), (net("0.0.0.0/0"), 16)]
+        );
+        assert_eq!(plan.fwmark, Some(16));
+        assert_eq!(plan.rules.len(), 2);
+        assert_eq!(plan.rules[0].fwmark, Some(16));
+        assert!(plan.rules[0].invert);
+        assert_eq!(plan.rules[1].suppress_prefixlength, Some(0));
+
+        let config = TunnelConfig { table: Table::Id(100), fwmark: None, ..config };
+        let plan = config.plan();
+        assert!(plan.routes.iter().all(|(_, table)| *table == 100));
+        assert!(plan.rules.is_empty());
+        assert_eq!(plan.fwmark, None);
+
+        let config = TunnelConfig { table: Table::Off, ..config };
+        assert!(config.plan().routes.is_empty());
+    }
+}
@@ -0,0 +1,765 @@
+use std::convert::TryInto;
+use std::io::{self, Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ipnet::IpNet;
+use netlink_sys::protocols::{NETLINK_GENERIC, NETLINK_ROUTE};
+use netlink_sys::{Socket, SocketAddr as NetlinkAddr};
+
+use crate::wg::quick::WgPeer;
+
+const NLMSG_HEADER_LEN: usize = 16;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_REPLACE: u16 = 0x100;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+const NLM_F_DUMP: u16 = 0x300;
+const NLA_F_NESTED: u16 = 0x8000;
+const NLA_TYPE_MASK: u16 = 0x3fff;
+
+const RTM_NEWLINK: u16 = 16;
+const RTM_DELLINK: u16 = 17;
+const RTM_GETLINK: u16 = 18;
+const RTM_NEWADDR: u16 = 20;
+const RTM_NEWROUTE: u16 = 24;
+const RTM_DELROUTE: u16 = 25;
+const RTM_GETROUTE: u16 = 26;
+const RTM_NEWRULE: u16 = 32;
+const RTM_DELRULE: u16 = 33;
+const IFLA_IFNAME: u16 = 3;
+const IFLA_MTU: u16 = 4;
+const IFLA_LINKINFO: u16 = 18;
+const IFLA_INFO_KIND: u16 = 1;
+const IFF_UP: u32 = 0x1;
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+const RTA_DST: u16 = 1;
+const RTA_OIF: u16 = 4;
+const RTA_TABLE: u16 = 15;
+const RTPROT_BOOT: u8 = 3;
+const RT_SCOPE_LINK: u8 = 253;
+const RTN_UNICAST: u8 = 1;
+const FRA_PRIORITY: u16 = 6;
+const FRA_FWMARK: u16 = 10;
+const FRA_SUPPRESS_PREFIXLEN: u16 = 14;
+const FRA_TABLE: u16 = 15;
+const FR_ACT_TO_TBL: u8 = 1;
+const FIB_RULE_INVERT: u32 = 0x2;
+pub const RT_TABLE_MAIN: u32 = 254;
+
+const GENL_ID_CTRL: u16 = 0x10;
+const CTRL_CMD_GETFAMILY: u8 = 3;
+const CTRL_ATTR_FAMILY_ID: u16 = 1;
+const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+
+const WG_GENL_NAME: &str = "wireguard";
+const WG_GENL_VERSION: u8 = 1;
+const WG_CMD_GET_DEVICE: u8 = 0;
+const WG_CMD_SET_DEVICE: u8 = 1;
+const WGDEVICE_A_IFNAME: u16 = 2;
+const WGDEVICE_A_PRIVATE_KEY: u16 = 3;
+const WGDEVICE_A_PUBLIC_KEY: u16 = 4;
+const WGDEVICE_A_LISTEN_PORT: u16 = 6;
+const WGDEVICE_A_FWMARK: u16 = 7;
+const WGDEVICE_A_PEERS: u16 = 8;
+const WGPEER_A_PUBLIC_KEY: u16 = 1;
+const WGPEER_A_PRESHARED_KEY: u16 = 2;
+const WGPEER_A_FLAGS: u16 = 3;
+const WGPEER_A_ENDPOINT: u16 = 4;
+const WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL: u16 = 5;
+const WGPEER_A_LAST_HANDSHAKE_TIME: u16 = 6;
+const WGPEER_A_RX_BYTES: u16 = 7;
+const WGPEER_A_TX_BYTES: u16 = 8;
+const WGPEER_A_ALLOWEDIPS: u16 = 9;
+const WGPEER_F_REMOVE_ME: u32 = 0x1;
+const WGPEER_F_REPLACE_ALLOWEDIPS: u32 = 0x2;
+const WGALLOWEDIP_A_FAMILY: u16 = 1;
+const WGALLOWEDIP_A_IPADDR: u16 = 2;
+const WGALLOWEDIP_A_CIDR_MASK: u16 = 3;
+/// Peers sent in each set device message, keeping messages well below the socket buffer size.
+const PEERS_PER_MESSAGE: usize = 32;
+
+pub type Key = [u8; 32];
+
+pub fn decode_key(key: &str) -> io::Result<Key> {
+    let data = BASE64.decode(key.trim()).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    data.as_slice().try_into().map_err(|_| Error::new(ErrorKind::InvalidInput, "Wireguard keys must be 32 bytes long"))
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn family(addr: &IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => libc::AF_INET as u8,
+        IpAddr::V6(_) => libc::AF_INET6 as u8,
+    }
+}
+
+fn ip_bytes(addr: &IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+/// Netlink attributes, encoded as they are added.
+#[derive(Default)]
+pub struct Attrs {
+    buf: Vec<u8>,
+}
+
+impl Attrs {
+    pub fn new() -> Self {
+        Attrs::default()
+    }
+
+    pub fn bytes(&mut self, kind: u16, data: &[u8]) -> &mut Self {
+        let len = 4 + data.len();
+        self.buf.extend_from_slice(&(len as u16).to_ne_bytes());
+        self.buf.extend_from_slice(&kind.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        self.buf.resize(align(self.buf.len()), 0);
+        self
+    }
+
+    pub fn u8(&mut self, kind: u16, value: u8) -> &mut Self {
+        self.bytes(kind, &[value])
+    }
+
+    pub fn u16(&mut self, kind: u16, value: u16) -> &mut Self {
+        self.bytes(kind, &value.to_ne_bytes())
+    }
+
+    pub fn u32(&mut self, kind: u16, value: u32) -> &mut Self {
+        self.bytes(kind, &value.to_ne_bytes())
+    }
+
+    pub fn string(&mut self, kind: u16, value: &str) -> &mut Self {
+        let mut data = value.as_bytes().to_vec();
+        data.push(0);
+        self.bytes(kind, &data)
+    }
+
+    pub fn nested<F: FnOnce(&mut Attrs)>(&mut self, kind: u16, build: F) -> &mut Self {
+        let mut nested = Attrs::new();
+        build(&mut nested);
+        self.bytes(kind | NLA_F_NESTED, &nested.buf)
+    }
+}
+
+/// Split `data` into its attributes, as `(type, payload)` pairs.
+pub fn parse_attrs(mut data: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut attrs = vec![];
+    while data.len() >= 4 {
+        let len = u16::from_ne_bytes([data[0], data[1]]) as usize;
+        let kind = u16::from_ne_bytes([data[2], data[3]]) & NLA_TYPE_MASK;
+        if len < 4 || len > data.len() {
+            break;
+        }
+        attrs.push((kind, &data[4..len]));
+        data = &data[align(len).min(data.len())..];
+    }
+    attrs
+}
+
+fn attr_u16(data: &[u8]) -> u16 {
+    data.get(..2).map(|b| u16::from_ne_bytes([b[0], b[1]])).unwrap_or_default()
+}
+
+fn attr_u32(data: &[u8]) -> u32 {
+    data.get(..4).map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]])).unwrap_or_default()
+}
+
+fn attr_u64(data: &[u8]) -> u64 {
+    data.get(..8).map(|b| u64::from_ne_bytes(b.try_into().unwrap())).unwrap_or_default()
+}
+
+fn attr_ip(data: &[u8]) -> Option<IpAddr> {
+    match data.len() {
+        4 => Some(IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3]))),
+        16 => {
+            let octets: [u8; 16] = data.try_into().ok()?;
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        _ => None,
+    }
+}
+
+/// Encode `addr` as a `sockaddr_in` or `sockaddr_in6`.
+pub fn encode_sockaddr(addr: &SocketAddr) -> Vec<u8> {
+    let mut data = vec![];
+    match addr {
+        SocketAddr::V4(addr) => {
+            data.extend_from_slice(&(libc::AF_INET as u16).to_ne_bytes());
+            data.extend_from_slice(&addr.port().to_be_bytes());
+            data.extend_from_slice(&addr.ip().octets());
+            data.extend_from_slice(&[0; 8]);
+        }
+        SocketAddr::V6(addr) => {
+            data.extend_from_slice(&(libc::AF_INET6 as u16).to_ne_bytes());
+            data.extend_from_slice(&addr.port().to_be_bytes());
+            data.extend_from_slice(&addr.flowinfo().to_be_bytes());
+            data.extend_from_slice(&addr.ip().octets());
+            data.extend_from_slice(&addr.scope_id().to_ne_bytes());
+        }
+    }
+    data
+}
+
+pub fn decode_sockaddr(data: &[u8]) -> Option<SocketAddr> {
+    let family = attr_u16(data) as i32;
+    let port = u16::from_be_bytes([*data.get(2)?, *data.get(3)?]);
+    if family == libc::AF_INET && data.len() >= 8 {
+        let ip = Ipv4Addr::new(data[4], data[5], data[6], data[7]);
+        Some(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+    } else if family == libc::AF_INET6 && data.len() >= 28 {
+        let ip: [u8; 16] = data[8..24].try_into().ok()?;
+        let flowinfo = u32::from_be_bytes(data[4..8].try_into().ok()?);
+        Some(SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(ip), port, flowinfo, attr_u32(&data[24..]))))
+    } else {
+        None
+    }
+}
+
+/// A netlink request: the message type and flags, the family specific header and attributes.
+pub struct Message {
+    kind: u16,
+    flags: u16,
+    header: Vec<u8>,
+    pub attrs: Attrs,
+}
+
+impl Message {
+    pub fn new(kind: u16, flags: u16, header: Vec<u8>) -> Self {
+        Message { kind, flags, header, attrs: Attrs::new() }
+    }
+
+    pub fn encode(&self, seq: u32) -> Vec<u8> {
+        let len = NLMSG_HEADER_LEN + self.header.len() + self.attrs.buf.len();
+        let mut data = Vec::with_capacity(len);
+        data.extend_from_slice(&(len as u32).to_ne_bytes());
+        data.extend_from_slice(&self.kind.to_ne_bytes());
+        data.extend_from_slice(&(self.flags | NLM_F_REQUEST | NLM_F_ACK).to_ne_bytes());
+        data.extend_from_slice(&seq.to_ne_bytes());
+        data.extend_from_slice(&0u32.to_ne_bytes());
+        data.extend_from_slice(&self.header);
+        data.extend_from_slice(&self.attrs.buf);
+        data
+    }
+}
+
+/// Header of `RTM_*LINK` messages, `struct ifinfomsg`.
+fn ifinfomsg(index: u32, flags: u32, change: u32) -> Vec<u8> {
+    let mut header = vec![0u8; 4];
+    header.extend_from_slice(&index.to_ne_bytes());
+    header.extend_from_slice(&flags.to_ne_bytes());
+    header.extend_from_slice(&change.to_ne_bytes());
+    header
+}
+
+/// Header of `RTM_*ROUTE` messages, `struct rtmsg`. Rules use `struct fib_rule_hdr`, which has
+/// the same layout.
+fn rtmsg(family: u8, dst_len: u8, table: u32, protocol: u8, scope: u8, kind: u8, flags: u32) -> Vec<u8> {
+    // Tables above 255 only fit in the RTA_TABLE attribute.
+    let table = if table < 256 { table as u8 } else { 0 };
+    let mut header = vec![family, dst_len, 0, 0, table, protocol, scope, kind];
+    header.extend_from_slice(&flags.to_ne_bytes());
+    header
+}
+
+fn genlmsghdr(cmd: u8, version: u8) -> Vec<u8> {
+    vec![cmd, version, 0, 0]
+}
+
+/// Message received from the kernel, without its netlink header.
+pub struct Reply {
+    pub kind: u16,
+    pub payload: Vec<u8>,
+}
+
+/// Split a datagram into netlink messages: `(type, flags, seq, payload)`.
+fn parse_messages(mut data: &[u8]) -> Vec<(u16, u16, u32, &[u8])> {
+    let mut messages = vec![];
+    while data.len() >= NLMSG_HEADER_LEN {
+        let len = attr_u32(data) as usize;
+        if len < NLMSG_HEADER_LEN || len > data.len() {
+            break;
+        }
+        let kind = attr_u16(&data[4..]);
+        let flags = attr_u16(&data[6..]);
+        let seq = attr_u32(&data[8..]);
+        messages.push((kind, flags, seq, &data[NLMSG_HEADER_LEN..len]));
+        data = &data[align(len).min(data.len())..];
+    }
+    messages
+}
+
+/// Blocking netlink socket, talking to the kernel one request at a time.
+pub struct Netlink {
+    socket: Socket,
+    seq: u32,
+}
+
+impl Netlink {
+    fn new(protocol: isize) -> io::Result<Self> {
+        let mut socket = Socket::new(protocol)?;
+        socket.bind_auto()?;
+        socket.connect(&NetlinkAddr::new(0, 0))?;
+        Ok(Netlink { socket, seq: 0 })
+    }
+
+    pub fn route() -> io::Result<Self> {
+        Self::new(NETLINK_ROUTE)
+    }
+
+    pub fn generic() -> io::Result<Self> {
+        Self::new(NETLINK_GENERIC)
+    }
+
+    /// Send `message` and collect the replies until the kernel acknowledges it or ends the dump.
+    pub fn request(&mut self, message: &Message) -> io::Result<Vec<Reply>> {
+        self.seq = self.seq.wrapping_add(1);
+        self.socket.send(&message.encode(self.seq), 0)?;
+        let mut replies = vec![];
+        loop {
+            let (data, _) = self.socket.recv_from_full()?;
+            for (kind, _, seq, payload) in parse_messages(&data) {
+                if seq != self.seq {
+                    continue;
+                }
+                match kind {
+                    NLMSG_ERROR => {
+                        let code = attr_u32(payload) as i32;
+                        return if code == 0 { Ok(replies) } else { Err(Error::from_raw_os_error(-code)) };
+                    }
+                    NLMSG_DONE => return Ok(replies),
+                    // Dumps end with NLMSG_DONE, other requests with their acknowledgement.
+                    _ => replies.push(Reply { kind, payload: payload.to_vec() }),
+                }
+            }
+        }
+    }
+
+    /// Index of the interface `name`, if it exists.
+    pub fn link_index(&mut self, name: &str) -> io::Result<Option<u32>> {
+        let mut message = Message::new(RTM_GETLINK, 0, ifinfomsg(0, 0, 0));
+        message.attrs.string(IFLA_IFNAME, name);
+        match self.request(&message) {
+            Ok(replies) => Ok(replies
+                .iter()
+                .find(|reply| reply.kind == RTM_NEWLINK && reply.payload.len() >= 16)
+                .map(|reply| attr_u32(&reply.payload[4..]))),
+            Err(e) if e.raw_os_error() == Some(libc::ENODEV) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn add_wireguard_link(&mut self, name: &str) -> io::Result<()> {
+        let mut message = Message::new(RTM_NEWLINK, NLM_F_CREATE | NLM_F_EXCL, ifinfomsg(0, 0, 0));
+        message.attrs.string(IFLA_IFNAME, name).nested(IFLA_LINKINFO, |info| {
+            info.string(IFLA_INFO_KIND, WG_GENL_NAME);
+        });
+        self.request(&message).map(|_| ())
+    }
+
+    pub fn set_link_up(&mut self, index: u32, mtu: u32) -> io::Result<()> {
+        let mut message = Message::new(RTM_NEWLINK, 0, ifinfomsg(index, IFF_UP, IFF_UP));
+        message.attrs.u32(IFLA_MTU, mtu);
+        self.request(&message).map(|_| ())
+    }
+
+    pub fn delete_link(&mut self, index: u32) -> io::Result<()> {
+        self.request(&Message::new(RTM_DELLINK, 0, ifinfomsg(index, 0, 0))).map(|_| ())
+    }
+
+    pub fn add_address(&mut self, index: u32, address: &IpNet) -> io::Result<()> {
+        let ip = address.addr();
+        let mut header = vec![family(&ip), address.prefix_len(), 0, 0];
+        header.extend_from_slice(&index.to_ne_bytes());
+        let mut message = Message::new(RTM_NEWADDR, NLM_F_CREATE | NLM_F_REPLACE, header);
+        message.attrs.bytes(IFA_LOCAL, &ip_bytes(&ip)).bytes(IFA_ADDRESS, &ip_bytes(&ip));
+        self.request(&message).map(|_| ())
+    }
+
+    fn route_message(kind: u16, flags: u16, index: u32, destination: &IpNet, table: u32) -> Message {
+        let network = destination.network();
+        let header =
+            rtmsg(family(&network), destination.prefix_len(), table, RTPROT_BOOT, RT_SCOPE_LINK, RTN_UNICAST, 0);
+        let mut message = Message::new(kind, flags, header);
+        if destination.prefix_len() > 0 {
+            message.attrs.bytes(RTA_DST, &ip_bytes(&network));
+        }
+        message.attrs.u32(RTA_OIF, index).u32(RTA_TABLE, table);
+        message
+    }
+
+    pub fn replace_route(&mut self, index: u32, destination: &IpNet, table: u32) -> io::Result<()> {
+        let message = Self::route_message(RTM_NEWROUTE, NLM_F_CREATE | NLM_F_REPLACE, index, destination, table);
+        self.request(&message).map(|_| ())
+    }
+
+    pub fn delete_route(&mut self, index: u32, destination: &IpNet, table: u32) -> io::Result<()> {
+        self.request(&Self::route_message(RTM_DELROUTE, 0, index, destination, table)).map(|_| ())
+    }
+
+    /// Routes through the interface `index` in `table`.
+    pub fn routes(&mut self, index: u32, table: u32) -> io::Result<Vec<IpNet>> {
+        let message = Message::new(RTM_GETROUTE, NLM_F_DUMP, rtmsg(0, 0, 0, 0, 0, 0, 0));
+        let mut routes = vec![];
+        for reply in self.request(&message)?.iter().filter(|r| r.kind == RTM_NEWROUTE && r.payload.len() >= 12) {
+            let (header, attrs) = reply.payload.split_at(12);
+            let mut route_table = header[4] as u32;
+            let mut oif = 0;
+            let mut destination = None;
+            for (kind, data) in parse_attrs(attrs) {
+                match kind {
+                    RTA_TABLE => route_table = attr_u32(data),
+                    RTA_OIF => oif = attr_u32(data),
+                    RTA_DST => destination = attr_ip(data),
+                    _ => {}
+                }
+            }
+            if oif != index || route_table != table {
+                continue;
+            }
+            let destination = match destination {
+                Some(ip) => ip,
+                None if header[0] == libc::AF_INET6 as u8 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            };
+            if let Ok(net) = IpNet::new(destination, header[1]) {
+                routes.push(net);
+            }
+        }
+        Ok(routes)
+    }
+
+    fn rule_message(kind: u16, flags: u16, rule: &Rule) -> Message {
+        let rule_flags = if rule.invert { FIB_RULE_INVERT } else { 0 };
+        let mut message = Message::new(kind, flags, rtmsg(rule.family, 0, rule.table, 0, 0, FR_ACT_TO_TBL, rule_flags));
+        message.attrs.u32(FRA_TABLE, rule.table);
+        if let Some(fwmark) = rule.fwmark {
+            message.attrs.u32(FRA_FWMARK, fwmark);
+        }
+        if let Some(prefix_len) = rule.suppress_prefixlength {
+            message.attrs.u32(FRA_SUPPRESS_PREFIXLEN, prefix_len);
+        }
+        if let Some(priority) = rule.priority {
+            message.attrs.u32(FRA_PRIORITY, priority);
+        }
+        message
+    }
+
+    /// Add the policy routing `rule`, leaving it alone when it already exists.
+    pub fn add_rule(&mut self, rule: &Rule) -> io::Result<()> {
+        match self.request(&Self::rule_message(RTM_NEWRULE, NLM_F_CREATE | NLM_F_EXCL, rule)) {
+            Err(e) if e.raw_os_error() == Some(libc::EEXIST) => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+
+    pub fn delete_rule(&mut self, rule: &Rule) -> io::Result<()> {
+        match self.request(&Self::rule_message(RTM_DELRULE, 0, rule)) {
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+
+    /// Numeric identifier of the generic netlink family `name`.
+    pub fn family_id(&mut self, name: &str) -> io::Result<u16> {
+        let mut message = Message::new(GENL_ID_CTRL, 0, genlmsghdr(CTRL_CMD_GETFAMILY, 1));
+        message.attrs.string(CTRL_ATTR_FAMILY_NAME, name);
+        let replies = self.request(&message)?;
+        replies
+            .iter()
+            .filter(|reply| reply.payload.len() >= 4)
+            .flat_map(|reply| parse_attrs(&reply.payload[4..]))
+            .find(|(kind, _)| *kind == CTRL_ATTR_FAMILY_ID)
+            .map(|(_, data)| attr_u16(data))
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Generic netlink family {} not found", name)))
+    }
+
+    /// Configure the Wireguard interface `name`. Peers are updated in place, so existing sessions
+    /// survive, and the peers listed in `remove` are dropped.
+    pub fn set_device(&mut self, family: u16, name: &str, device: &Device, remove: &[Key]) -> io::Result<()> {
+        let updates = device
+            .peers
+            .iter()
+            .map(PeerUpdate::Set)
+            .chain(remove.iter().map(PeerUpdate::Remove))
+            .collect::<Vec<PeerUpdate>>();
+        let mut message = Message::new(family, 0, genlmsghdr(WG_CMD_SET_DEVICE, WG_GENL_VERSION));
+        message.attrs.string(WGDEVICE_A_IFNAME, name).bytes(WGDEVICE_A_PRIVATE_KEY, &device.private_key);
+        if let Some(port) = device.listen_port {
+            message.attrs.u16(WGDEVICE_A_LISTEN_PORT, port);
+        }
+        message.attrs.u32(WGDEVICE_A_FWMARK, device.fwmark.unwrap_or(0));
+        let mut chunks = updates.chunks(PEERS_PER_MESSAGE);
+        peer_attrs(&mut message.attrs, chunks.next().unwrap_or_default());
+        self.request(&message)?;
+        for updates in chunks {
+            let mut message = Message::new(family, 0, genlmsghdr(WG_CMD_SET_DEVICE, WG_GENL_VERSION));
+            message.attrs.string(WGDEVICE_A_IFNAME, name);
+            peer_attrs(&mut message.attrs, updates);
+            self.request(&message)?;
+        }
+        Ok(())
+    }
+
+    /// Point the peer `public_key` of the Wireguard interface `name` at `endpoint`, leaving the
+    /// rest of its configuration alone.
+    pub fn set_peer_endpoint(
+        &mut self,
+        family: u16,
+        name: &str,
+        public_key: &Key,
+        endpoint: &SocketAddr,
+    ) -> io::Result<()> {
+        let mut message = Message::new(family, 0, genlmsghdr(WG_CMD_SET_DEVICE, WG_GENL_VERSION));
+        message.attrs.string(WGDEVICE_A_IFNAME, name);
+        peer_attrs(&mut message.attrs, &[PeerUpdate::Endpoint(public_key, endpoint)]);
+        self.request(&message).map(|_| ())
+    }
+
+    /// Live configuration of the Wireguard interface `name`.
+    pub fn get_device(&mut self, family: u16, name: &str) -> io::Result<DeviceInfo> {
+        let mut message = Message::new(family, NLM_F_DUMP, genlmsghdr(WG_CMD_GET_DEVICE, WG_GENL_VERSION));
+        message.attrs.string(WGDEVICE_A_IFNAME, name);
+        let mut info = DeviceInfo::default();
+        for reply in self.request(&message)?.iter().filter(|reply| reply.payload.len() >= 4) {
+            info.merge(&reply.payload[4..]);
+        }
+        Ok(info)
+    }
+}
+
+enum PeerUpdate<'a> {
+    Set(&'a PeerConfig),
+    Remove(&'a Key),
+    Endpoint(&'a Key, &'a SocketAddr),
+}
+
+fn peer_attrs(attrs: &mut Attrs, updates: &[PeerUpdate]) {
+    attrs.nested(WGDEVICE_A_PEERS, |list| {
+        for update in updates {
+            let peer = match update {
+                PeerUpdate::Set(peer) => peer,
+                PeerUpdate::Remove(key) => {
+                    list.nested(0, |attrs| {
+                        attrs.bytes(WGPEER_A_PUBLIC_KEY, *key).u32(WGPEER_A_FLAGS, WGPEER_F_REMOVE_ME);
+                    });
+                    continue;
+                }
+                PeerUpdate::Endpoint(key, endpoint) => {
+                    list.nested(0, |attrs| {
+                        attrs.bytes(WGPEER_A_PUBLIC_KEY, *key).bytes(WGPEER_A_ENDPOINT, &encode_sockaddr(endpoint));
+                    });
+                    continue;
+                }
+            };
+            list.nested(0, |attrs| {
+                attrs.bytes(WGPEER_A_PUBLIC_KEY, &peer.public_key).u32(WGPEER_A_FLAGS, WGPEER_F_REPLACE_ALLOWEDIPS);
+                if let Some(key) = peer.preshared_key.as_ref() {
+                    attrs.bytes(WGPEER_A_PRESHARED_KEY, key);
+                }
+                if let Some(endpoint) = peer.endpoint.as_ref() {
+                    attrs.bytes(WGPEER_A_ENDPOINT, &encode_sockaddr(endpoint));
+                }
+                attrs.u16(WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL, peer.persistent_keepalive);
+                attrs.nested(WGPEER_A_ALLOWEDIPS, |ips| {
+                    for ip in peer.allowed_ips.iter() {
+                        ips.nested(0, |attrs| {
+                            let network = ip.network();
+                            attrs
+                                .u16(WGALLOWEDIP_A_FAMILY, family(&network) as u16)
+                                .bytes(WGALLOWEDIP_A_IPADDR, &ip_bytes(&network))
+                                .u8(WGALLOWEDIP_A_CIDR_MASK, ip.prefix_len());
+                        });
+                    }
+                });
+            });
+        }
+    });
+}
+
+/// Policy routing rule, `ip rule` style.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub family: u8,
+    pub table: u32,
+    pub fwmark: Option<u32>,
+    pub invert: bool,
+    pub suppress_prefixlength: Option<u32>,
+    pub priority: Option<u32>,
+}
+
+impl Rule {
+    pub fn family(ip: &IpNet) -> u8 {
+        family(&ip.addr())
+    }
+}
+
+/// Desired Wireguard interface configuration.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Device {
+    pub private_key: Key,
+    pub listen_port: Option<u16>,
+    pub fwmark: Option<u32>,
+    pub peers: Vec<PeerConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerConfig {
+    pub public_key: Key,
+    pub preshared_key: Option<Key>,
+    pub endpoint: Option<SocketAddr>,
+    pub persistent_keepalive: u16,
+    pub allowed_ips: Vec<IpNet>,
+}
+
+/// Wireguard interface configuration read from the kernel.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceInfo {
+    pub public_key: Option<String>,
+    pub listen_port: u16,
+    pub fwmark: u32,
+    pub peers: Vec<WgPeer>,
+}
+
+impl DeviceInfo {
+    /// Merge one message of a device dump. Large devices are split across several messages,
+    /// each repeating the peer it ended with.
+    fn merge(&mut self, attrs: &[u8]) {
+        for (kind, data) in parse_attrs(attrs) {
+            match kind {
+                WGDEVICE_A_PUBLIC_KEY => self.public_key = Some(BASE64.encode(data)),
+                WGDEVICE_A_LISTEN_PORT => self.listen_port = attr_u16(data),
+                WGDEVICE_A_FWMARK => self.fwmark = attr_u32(data),
+                WGDEVICE_A_PEERS => {
+                    for (_, peer) in parse_attrs(data) {
+                        let peer = parse_peer(peer);
+                        match self.peers.last_mut() {
+                            Some(last) if last.public_key == peer.public_key => {
+                                last.allowed_ips.extend(peer.allowed_ips);
+                            }
+                            _ => self.peers.push(peer),
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn parse_peer(attrs: &[u8]) -> WgPeer {
+    let mut peer = WgPeer::default();
+    for (kind, data) in parse_attrs(attrs) {
+        match kind {
+            WGPEER_A_PUBLIC_KEY => peer.public_key = BASE64.encode(data),
+            WGPEER_A_ENDPOINT => peer.endpoint = decode_sockaddr(data).map(|a| a.to_string()),
+            WGPEER_A_PERSISTENT_KEEPALIVE_INTERVAL => {
+                peer.persistent_keepalive = Some(attr_u16(data) as u32).filter(|k| *k > 0)
+            }
+            WGPEER_A_LAST_HANDSHAKE_TIME => peer.latest_handshake = Some(attr_u64(data)).filter(|t| *t > 0),
+            WGPEER_A_RX_BYTES => peer.transfer_rx = Some(attr_u64(data) as u128),
+            WGPEER_A_TX_BYTES => peer.transfer_tx = Some(attr_u64(data) as u128),
+            WGPEER_A_ALLOWEDIPS => {
+                for (_, allowed) in parse_attrs(data) {
+                    let mut ip = None;
+                    let mut cidr = 0;
+                    for (kind, data) in parse_attrs(allowed) {
+                        match kind {
+                            WGALLOWEDIP_A_IPADDR => ip = attr_ip(data),
+                            WGALLOWEDIP_A_CIDR_MASK => cidr = data.first().copied().unwrap_or_default(),
+                            _ => {}
+                        }
+                    }
+                    if let Some(ip) = ip {
+                        peer.allowed_ips.push(format!("{}/{}", ip, cidr));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    peer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_attrs() {
+        let mut attrs = Attrs::new();
+        attrs.string(IFLA_IFNAME, "wg").nested(IFLA_LINKINFO, |info| {
+            info.u16(IFLA_INFO_KIND, 7);
+        });
+        let parsed = parse_attrs(&attrs.buf);
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0], (IFLA_IFNAME, &b"wg\0"[..]));
+        assert_eq!(parsed[1].0, IFLA_LINKINFO);
+        assert_eq!(parse_attrs(parsed[1].1), vec![(IFLA_INFO_KIND, &7u16.to_ne_bytes()[..])]);
+        // Attributes are padded to 4 bytes.
+        assert_eq!(attrs.buf.len(), 8 + 12);
+    }
+
+    #[test]
+    fn test_encode_message() {
+        let mut message = Message::new(RTM_DELLINK, 0, ifinfomsg(3, 0, 0));
+        message.attrs.u32(IFLA_MTU, 1420);
+        let data = message.encode(42);
+        let messages = parse_messages(&data);
+        assert_eq!(messages.len(), 1);
+        let (kind, flags, seq, payload) = messages[0];
+        assert_eq!((kind, flags, seq), (RTM_DELLINK, NLM_F_REQUEST | NLM_F_ACK, 42));
+        assert_eq!(attr_u32(&payload[4..]), 3);
+        assert_eq!(parse_attrs(&payload[16..]), vec![(IFLA_MTU, &1420u32.to_ne_bytes()[..])]);
+    }
+
+    #[test]
+    fn test_sockaddr_roundtrip() {
+        for addr in &["1.2.3.4:51820", "[fd00::1]:51820"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            assert_eq!(decode_sockaddr(&encode_sockaddr(&addr)), Some(addr));
+        }
+    }
+
+    #[test]
+    fn test_parse_device_dump() {
+        let key = [7u8; 32];
+        let peer = PeerConfig {
+            public_key: key,
+            preshared_key: None,
+            endpoint: Some("1.2.3.4:6666".parse().unwrap()),
+            persistent_keepalive: 25,
+            allowed_ips: vec!["10.0.0.2/32".parse().unwrap(), "fd00::/64".parse().unwrap()],
+        };
+        let mut attrs = Attrs::new();
+        attrs.u16(WGDEVICE_A_LISTEN_PORT, 51820);
+        peer_attrs(&mut attrs, &[PeerUpdate::Set(&peer), PeerUpdate::Remove(&[8u8; 32])]);
+        let mut info = DeviceInfo::default();
+        info.merge(&attrs.buf);
+        assert_eq!(info.listen_port, 51820);
+        assert_eq!(info.peers.len(), 2);
+        assert_eq!(info.peers[0].public_key, BASE64.encode(key));
+        assert_eq!(info.peers[0].endpoint, Some("1.2.3.4:6666".to_string()));
+        assert_eq!(info.peers[0].persistent_keepalive, Some(25));
+        assert_eq!(info.peers[0].allowed_ips, vec!["10.0.0.2/32".to_string(), "fd00::/64".to_string()]);
+        assert_eq!(info.peers[0].latest_handshake, None);
+    }
+
+    #[test]
+    fn test_decode_key() {
+        assert_eq!(decode_key(&BASE64.encode([1u8; 32])).unwrap(), [1u8; 32]);
+        assert!(decode_key("c2hvcnQ=").is_err());
+    }
+}
@@ -1,59 +1,210 @@
-use color_eyre::eyre::{bail, Result};
+use std::net::SocketAddr;
+use std::path::Path;
 
-use crate::shell::Shell;
+use async_trait::async_trait;
+use color_eyre::eyre::{self, bail};
+use serde::{Deserialize, Serialize};
 
-#[derive(Default, Debug, Clone)]
+use crate::error::{FireguardError, Result};
+use crate::runner::Runner;
+use crate::shell::{ShellCommand, ShellResult};
+use crate::wg::{WgBackend, WgTunnel};
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct WgPeer {
-    endpoint: Option<String>,
-    public_key: String,
-    latest_handshake: Option<u64>,
-    tranfer_rx: Option<u128>,
-    tranfer_tx: Option<u128>,
-    persistent_keepalive: Option<u32>,
-    allowed_ips: Vec<String>,
+    pub endpoint: Option<String>,
+    pub public_key: String,
+    pub latest_handshake: Option<u64>,
+    pub transfer_rx: Option<u128>,
+    pub transfer_tx: Option<u128>,
+    pub persistent_keepalive: Option<u32>,
+    pub allowed_ips: Vec<String>,
+}
+
+impl WgPeer {
+    /// Parse one peer line of `wg show <interface> dump`. Fields are tab separated:
+    /// public-key, preshared-key, endpoint, allowed-ips, latest-handshake, transfer-rx,
+    /// transfer-tx, persistent-keepalive.
+    pub fn from_dump_line(line: &str) -> eyre::Result<Self> {
+        let fields = line.trim().split('\t').collect::<Vec<&str>>();
+        if fields.len() != 8 {
+            bail!("Invalid Wireguard peer dump line, expected 8 fields, found {}", fields.len());
+        }
+        let endpoint = match fields[2] {
+            "(none)" => None,
+            e => Some(e.to_string()),
+        };
+        let allowed_ips = match fields[3] {
+            "(none)" => vec![],
+            ips => ips.split(',').map(|x| x.to_string()).collect(),
+        };
+        let latest_handshake = match fields[4].parse::<u64>()? {
+            0 => None,
+            t => Some(t),
+        };
+        let persistent_keepalive = match fields[7] {
+            "off" => None,
+            k => Some(k.parse::<u32>()?),
+        };
+        Ok(WgPeer {
+            endpoint,
+            public_key: fields[0].to_string(),
+            latest_handshake,
+            transfer_rx: Some(fields[5].parse::<u128>()?),
+            transfer_tx: Some(fields[6].parse::<u128>()?),
+            persistent_keepalive,
+            allowed_ips,
+        })
+    }
+
+    /// Parse the whole output of `wg show <interface> dump`, skipping the interface line.
+    pub fn from_dump(dump: &str) -> eyre::Result<Vec<Self>> {
+        dump.lines().skip(1).filter(|l| !l.trim().is_empty()).map(WgPeer::from_dump_line).collect()
+    }
 }
 
 pub struct WgQuick {
     repository: String,
+    runner: Runner,
 }
 
 impl WgQuick {
-    pub fn new(repository: &str) -> Result<Self> {
-        if !Shell::runnable("wg-quick") {
-            bail!("Missing command dependency")
+    pub fn new(repository: &str, runner: &Runner) -> Result<Self> {
+        if !runner.runnable("wg-quick") {
+            return Err(FireguardError::Dependency(
+                "wg-quick is not installed, please install wireguard-tools".to_string(),
+            ));
         }
-        Ok(WgQuick { repository: repository.to_string() })
+        Ok(WgQuick { repository: repository.to_string(), runner: runner.clone() })
     }
 
-    pub async fn up(&self) -> Result<()> {
-        info!("Starting new Wireguard instance for repository {}", self.repository);
-        let result = Shell::exec("wg-quick", &format!("up {}", self.repository), None, true).await;
+    /// Run `command`, turning a failure into an error.
+    async fn run(&self, command: ShellCommand) -> Result<ShellResult> {
+        let result = self.runner.run(&command).await;
         if result.success() {
-            info!("Wireguard instance started successfully:\n{}", result.stderr());
-            Ok(())
+            Ok(result)
         } else {
-            bail!("Error running Wireguard instance: {}", result.stderr());
+            Err(FireguardError::command(&command, &result))
         }
     }
+}
+
+/// `wg-quick` takes the configuration path, the interface being named after the file.
+#[async_trait]
+impl WgTunnel for WgQuick {
+    async fn up(&self, config: &Path) -> Result<()> {
+        let backend = WgBackend::detect(&self.runner).await?;
+        info!("Starting new Wireguard instance for repository {} with {} backend", self.repository, backend);
+        let up = ShellCommand::new("wg-quick").args(&["up", &config.to_string_lossy()]).envs(&backend.env());
+        let result = self.run(up.sensitive(true)).await?;
+        info!("Wireguard instance started successfully:\n{}", result.stderr());
+        Ok(())
+    }
 
-    pub async fn down(&self) -> Result<()> {
+    async fn down(&self, config: &Path) -> Result<()> {
         info!("Stopping Wireguard instance for repository {}", self.repository);
-        let result = Shell::exec("wg-quick", &format!("down {}", self.repository), None, true).await;
-        if result.success() {
-            info!("Wireguard instance stopped successfully:\n{}", result.stderr());
-            Ok(())
-        } else {
-            bail!("Error stopping Wireguard instance: {}", result.stderr());
-        }
+        let down = ShellCommand::new("wg-quick").args(&["down", &config.to_string_lossy()]);
+        let result = self.run(down.sensitive(true)).await?;
+        info!("Wireguard instance stopped successfully:\n{}", result.stderr());
+        Ok(())
     }
 
-    pub async fn status(&self) -> Result<()> {
-        let result = Shell::exec("wg", &format!("show {}", self.repository), None, true).await;
-        if result.success() {
-            info!("Wireguard statistics for repository {}:\n{}", self.repository, result.stdout().trim());
-            Ok(())
-        } else {
-            bail!("Error checking Wireguard instance status: {}", result.stderr());
-        }
+    async fn is_up(&self) -> bool {
+        self.runner.exec("wg", &["show", &self.repository], None, true).await.success()
+    }
+
+    async fn sync(&self, config: &Path) -> Result<()> {
+        info!("Syncing Wireguard instance configuration for repository {}", self.repository);
+        let strip = ShellCommand::new("wg-quick").args(&["strip", &config.to_string_lossy()]);
+        let stripped_config = self.run(strip.sensitive(true)).await?;
+        // The stripped configuration holds the private key, it never touches the disk.
+        let syncconf =
+            ShellCommand::new("wg").args(&["syncconf", &self.repository, "/dev/stdin"]).stdin(stripped_config.stdout());
+        self.run(syncconf.sensitive(true)).await?;
+        info!("Wireguard instance configuration synced successfully");
+        Ok(())
+    }
+
+    async fn peers(&self) -> Result<Vec<WgPeer>> {
+        let dump = self.run(ShellCommand::new("wg").args(&["show", &self.repository, "dump"]).sensitive(true)).await?;
+        WgPeer::from_dump(dump.stdout()).map_err(|e| FireguardError::tunnel(&self.repository, e))
+    }
+
+    async fn set_endpoint(&self, public_key: &str, endpoint: SocketAddr) -> Result<()> {
+        let endpoint = endpoint.to_string();
+        let set = ShellCommand::new("wg").args(&["set", &self.repository, "peer", public_key, "endpoint", &endpoint]);
+        self.run(set).await?;
+        Ok(())
+    }
+
+    async fn status(&self) -> Result<()> {
+        let show = self.run(ShellCommand::new("wg").args(&["show", &self.repository]).sensitive(true)).await?;
+        let backend = self.backend().map(|b| b.to_string()).unwrap_or_else(|| "unknown".to_string());
+        info!("Wireguard backend for repository {}: {}", self.repository, backend);
+        info!("Wireguard statistics for repository {}:\n{}", self.repository, show.stdout().trim());
+        Ok(())
+    }
+
+    fn backend(&self) -> Option<WgBackend> {
+        WgBackend::running(&self.repository)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::runner::FakeRunner;
+
+    #[tokio::test]
+    async fn test_sync_pipes_the_private_key() {
+        let stripped = "[Interface]\nPrivateKey = cHJpdmF0ZQ==\n";
+        let fake = Arc::new(FakeRunner::new().on_success(&["wg-quick", "strip"], stripped));
+        let quick = WgQuick::new("avalon", &Runner::new(fake.clone())).unwrap();
+        quick.sync(Path::new("/etc/wireguard/avalon.conf")).await.unwrap();
+        let calls = fake.calls();
+        assert_eq!(calls[1].to_string(), "wg syncconf avalon /dev/stdin");
+        assert_eq!(calls[1].input(), Some(stripped));
+    }
+
+    #[tokio::test]
+    async fn test_up_or_sync() {
+        let config = Path::new("/etc/wireguard/avalon.conf");
+        // An interface left up by a handover or a rolled back upgrade is synced, not created.
+        let fake = Arc::new(FakeRunner::new());
+        WgQuick::new("avalon", &Runner::new(fake.clone())).unwrap().up_or_sync(config).await.unwrap();
+        let lines = fake.command_lines();
+        assert!(lines.contains(&"wg syncconf avalon /dev/stdin".to_string()));
+        assert!(!lines.iter().any(|line| line.starts_with("wg-quick up")));
+        let fake = Arc::new(FakeRunner::new().on_failure(&["wg", "show", "avalon"], "No such device"));
+        WgQuick::new("avalon", &Runner::new(fake.clone())).unwrap().up_or_sync(config).await.unwrap();
+        let lines = fake.command_lines();
+        assert!(lines.contains(&"wg-quick up /etc/wireguard/avalon.conf".to_string()));
+        assert!(!lines.iter().any(|line| line.starts_with("wg syncconf")));
+    }
+
+    #[test]
+    fn test_parse_wg_show_dump() {
+        let dump = "cHJpdmF0ZQ==\tcHVibGlj\t6666\toff\n\
+                    YWxpY2U=\t(none)\t1.2.3.4:6666\t10.0.0.2/32,192.168.1.0/24\t1610000000\t1024\t2048\t25\n\
+                    Ym9i\t(none)\t(none)\t10.0.0.3/32\t0\t0\t0\toff\n";
+        let peers = WgPeer::from_dump(dump).unwrap();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0].public_key, "YWxpY2U=");
+        assert_eq!(peers[0].endpoint, Some("1.2.3.4:6666".to_string()));
+        assert_eq!(peers[0].allowed_ips, vec!["10.0.0.2/32".to_string(), "192.168.1.0/24".to_string()]);
+        assert_eq!(peers[0].latest_handshake, Some(1610000000));
+        assert_eq!(peers[0].transfer_rx, Some(1024));
+        assert_eq!(peers[0].transfer_tx, Some(2048));
+        assert_eq!(peers[0].persistent_keepalive, Some(25));
+        assert_eq!(peers[1].endpoint, None);
+        assert_eq!(peers[1].latest_handshake, None);
+        assert_eq!(peers[1].persistent_keepalive, None);
+    }
+
+    #[test]
+    fn test_parse_invalid_dump_line_bails() {
+        assert!(WgPeer::from_dump_line("YWxpY2U=\t(none)").is_err());
     }
 }
@@ -0,0 +1,85 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use color_eyre::eyre::{self, eyre};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+use crate::runner::Runner;
+use crate::wg::{WgBackend, WgNetlink, WgPeer, WgQuick};
+
+/// Lifecycle of the Wireguard interface of a repository. `config` is the configuration rendered
+/// by `wg render`, in `wg-quick` format.
+#[async_trait]
+pub trait WgTunnel: Send + Sync {
+    /// Create the interface and bring it up.
+    async fn up(&self, config: &Path) -> Result<()>;
+    /// Tear the interface down, with the routes and rules it brought up.
+    async fn down(&self, config: &Path) -> Result<()>;
+    /// Whether the interface exists.
+    async fn is_up(&self) -> bool;
+    /// Apply the configuration to the running interface without tearing it down.
+    async fn sync(&self, config: &Path) -> Result<()>;
+    /// Bring the interface up, or sync it when it is already up, like after a handover or a
+    /// rollback of an upgrade which left the tunnel running.
+    async fn up_or_sync(&self, config: &Path) -> Result<()> {
+        if self.is_up().await {
+            info!("Wireguard interface for {} is already up, syncing its configuration", config.display());
+            self.sync(config).await
+        } else {
+            self.up(config).await
+        }
+    }
+    async fn peers(&self) -> Result<Vec<WgPeer>>;
+    /// Point the live peer `public_key` at `endpoint`, keeping its session.
+    async fn set_endpoint(&self, public_key: &str, endpoint: SocketAddr) -> Result<()>;
+    /// Log the interface status and statistics.
+    async fn status(&self) -> Result<()>;
+    /// Implementation running the interface, if it is up.
+    fn backend(&self) -> Option<WgBackend>;
+}
+
+/// How Fireguard manages the Wireguard interfaces.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WgDriver {
+    /// Native rtnetlink and Wireguard generic netlink, without external tools.
+    #[default]
+    Netlink,
+    /// `wg-quick` and `wg` from wireguard-tools.
+    WgQuick,
+}
+
+impl FromStr for WgDriver {
+    type Err = eyre::Error;
+
+    fn from_str(driver: &str) -> eyre::Result<Self> {
+        match driver {
+            "netlink" => Ok(WgDriver::Netlink),
+            "wg-quick" => Ok(WgDriver::WgQuick),
+            _ => Err(eyre!("Unknown Wireguard driver {}, expected netlink or wg-quick", driver)),
+        }
+    }
+}
+
+impl fmt::Display for WgDriver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WgDriver::Netlink => f.write_str("netlink"),
+            WgDriver::WgQuick => f.write_str("wg-quick"),
+        }
+    }
+}
+
+impl WgDriver {
+    /// Tunnel of `repository`, managed with this driver.
+    pub fn tunnel(&self, repository: &str, runner: &Runner) -> Result<Box<dyn WgTunnel>> {
+        match self {
+            WgDriver::Netlink => Ok(Box::new(WgNetlink::new(repository, runner))),
+            WgDriver::WgQuick => Ok(Box::new(WgQuick::new(repository, runner)?)),
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use fireguard::error::{EXIT_CONFIG, EXIT_DEPENDENCY, EXIT_REPOSITORY, EXIT_TUNNEL, EXIT_USAGE};
+use fireguard::{exit_code, run_with, FakeRunner, Runner, ShellResult};
+use tempdir::TempDir;
+
+const NODES: &str = r#"repository = "avalon"
+network = "10.123.123.0/24"
+domain = "avalon.lan"
+
+[peers.bob-cloud]
+username = "bob"
+peername = "cloud"
+address = "10.123.123.183/24"
+listen_port = 6666
+public_key = "/UYfZPEWuJaZ1EmAc88n7TnZqwkO/HYoRh2iBrj98go="
+allowed_ips = ["10.123.123.183/32"]
+persistent_keepalive = 25
+endpoint = "cloud.bob.net"
+mtu = 1500
+"#;
+
+/// Fireguard config directory holding the avalon repository.
+fn config_dir() -> TempDir {
+    let dir = TempDir::new("fireguard-cli").unwrap();
+    fs::create_dir(dir.path().join("avalon")).unwrap();
+    fs::write(dir.path().join("avalon").join("nodes.toml"), NODES).unwrap();
+    dir
+}
+
+async fn fireguard(fake: &Arc<FakeRunner>, config_dir: &Path, args: &[&str]) -> color_eyre::Result<()> {
+    let config_dir = config_dir.to_string_lossy().to_string();
+    let mut argv = vec!["fireguard", "-c", &config_dir];
+    argv.extend_from_slice(args);
+    run_with(&argv, Runner::new(fake.clone())).await
+}
+
+/// Command lines run by the fake for `program`, ignoring the environment probes.
+fn commands(fake: &FakeRunner, program: &str) -> Vec<String> {
+    fake.command_lines().into_iter().filter(|line| line.starts_with(&format!("{} ", program))).collect()
+}
+
+#[tokio::test]
+async fn test_repo_clone_and_pull() {
+    let dir = TempDir::new("fireguard-cli").unwrap();
+    let fake = Arc::new(FakeRunner::new());
+    fireguard(&fake, dir.path(), &["repo", "-r", "https://git.example.com/friends/avalon.git", "clone"]).await.unwrap();
+    fireguard(&fake, dir.path(), &["repo", "-r", "avalon", "pull"]).await.unwrap();
+    let repository = dir.path().join("avalon");
+    assert_eq!(
+        commands(&fake, "git"),
+        vec![
+            format!("git clone https://git.example.com/friends/avalon.git {}", repository.display()),
+            "git pull".to_string()
+        ]
+    );
+    assert_eq!(fake.calls()[1].arguments(), &["pull".to_string()]);
+}
+
+#[tokio::test]
+async fn test_repo_pull_failure() {
+    let dir = config_dir();
+    let fake = Arc::new(FakeRunner::new().on_failure(&["git", "pull"], "fatal: unable to access remote"));
+    let error = fireguard(&fake, dir.path(), &["repo", "-r", "avalon", "pull"]).await.unwrap_err();
+    assert!(error.to_string().contains("fatal: unable to access remote"));
+}
+
+#[tokio::test]
+async fn test_wg_quick_up_and_down() {
+    let dir = config_dir();
+    let wireguard = TempDir::new("fireguard-wireguard").unwrap();
+    let wg_dir = wireguard.path().to_string_lossy().to_string();
+    let fake = Arc::new(FakeRunner::new());
+    fireguard(&fake, dir.path(), &["-W", "wg-quick", "wg", "-r", "avalon", "up", "-c", &wg_dir]).await.unwrap();
+    fireguard(&fake, dir.path(), &["-W", "wg-quick", "wg", "-r", "avalon", "down", "-c", &wg_dir]).await.unwrap();
+    let config = wireguard.path().join("avalon.conf");
+    assert_eq!(
+        commands(&fake, "wg-quick"),
+        vec![format!("wg-quick up {}", config.display()), format!("wg-quick down {}", config.display())]
+    );
+}
+
+#[tokio::test]
+async fn test_wg_quick_failures() {
+    let dir = config_dir();
+    let missing = Arc::new(FakeRunner::new().missing("wg-quick"));
+    assert!(fireguard(&missing, dir.path(), &["-W", "wg-quick", "wg", "-r", "avalon", "up"]).await.is_err());
+    assert!(commands(&missing, "wg-quick").is_empty());
+    let failing = Arc::new(
+        FakeRunner::new()
+            .on(&["wg-quick", "up"], ShellResult::new("", "RTNETLINK answers: Operation not permitted", Some(1))),
+    );
+    let error = fireguard(&failing, dir.path(), &["-W", "wg-quick", "wg", "-r", "avalon", "up"]).await.unwrap_err();
+    assert!(error.to_string().contains("Operation not permitted"));
+}
+
+#[tokio::test]
+async fn test_exit_codes() {
+    let dir = config_dir();
+    let fake = Arc::new(FakeRunner::new().missing("wg-quick").on_failure(&["git", "pull"], "fatal: no remote"));
+    let code = |args: &'static [&'static str]| {
+        let fake = fake.clone();
+        let dir = dir.path().to_path_buf();
+        async move { exit_code(&fireguard(&fake, &dir, args).await.unwrap_err()) }
+    };
+    assert_eq!(code(&["peer", "-r", "nowhere", "list"]).await, EXIT_REPOSITORY);
+    assert_eq!(code(&["repo", "-r", "avalon", "pull"]).await, EXIT_REPOSITORY);
+    assert_eq!(code(&["-W", "wg-quick", "wg", "-r", "avalon", "up"]).await, EXIT_DEPENDENCY);
+    assert_eq!(code(&["peer", "--no-such-flag"]).await, EXIT_USAGE);
+    fs::write(dir.path().join("avalon").join("nodes.toml"), "network = ").unwrap();
+    assert_eq!(code(&["peer", "-r", "avalon", "list"]).await, EXIT_CONFIG);
+    let failing = Arc::new(FakeRunner::new().on_failure(&["wg-quick", "up"], "Operation not permitted"));
+    let error = fireguard(&failing, dir.path(), &["-W", "wg-quick", "wg", "-r", "avalon", "up"]).await.unwrap_err();
+    assert_eq!(exit_code(&error), EXIT_TUNNEL);
+}
+
+#[tokio::test]
+async fn test_peer_add_generates_keys() {
+    let dir = config_dir();
+    let fake = Arc::new(
+        FakeRunner::new()
+            .on_success(&["wg", "genkey"], "cGhvbmUgcHJpdmF0ZSBrZXkgZm9yIHRlc3RpbmcgISE=")
+            .on_success(&["wg", "pubkey"], "cGhvbmUgcHVibGljIGtleSBmb3IgdGVzdGluZyAhISE="),
+    );
+    fireguard(&fake, dir.path(), &["peer", "-r", "avalon", "add", "-u", "carol", "-p", "phone"]).await.unwrap();
+    let calls = fake.calls();
+    assert_eq!(fake.command_lines(), vec!["wg genkey", "wg pubkey"]);
+    assert_eq!(calls[1].input(), Some("cGhvbmUgcHJpdmF0ZSBrZXkgZm9yIHRlc3RpbmcgISE="));
+    let nodes = fs::read_to_string(dir.path().join("avalon").join("nodes.toml")).unwrap();
+    // The generated private key is handed to the user in /tmp.
+    fs::remove_file("/tmp/fireguard-avalon-carol-phone.priv").unwrap();
+    assert!(nodes.contains("[peers.carol-phone]"));
+    assert!(nodes.contains("public_key = \"cGhvbmUgcHVibGljIGtleSBmb3IgdGVzdGluZyAhISE=\""));
+}
+
+#[tokio::test]
+async fn test_render_with_exit_node() {
+    let dir = config_dir();
+    let fake = Arc::new(FakeRunner::new());
+    let key = "cGhvbmUgcHVibGljIGtleSBmb3IgdGVzdGluZyAhISE=";
+    let add = [
+        "peer",
+        "-r",
+        "avalon",
+        "add",
+        "-u",
+        "dave",
+        "-p",
+        "router",
+        "-K",
+        key,
+        "-X",
+        "-A",
+        "192.168.1.0/24",
+        "-e",
+        "2001:db8::1",
+        "-E",
+        "router.dave.net:4500",
+    ];
+    fireguard(&fake, dir.path(), &add).await.unwrap();
+    fs::remove_file("/tmp/fireguard-avalon-dave-router.priv").unwrap();
+    let wg_dir = dir.path().join("wireguard");
+    fs::create_dir(&wg_dir).unwrap();
+    let wg_dir = wg_dir.to_string_lossy().to_string();
+    let render = ["wg", "-r", "avalon", "render", "-u", "bob", "-p", "cloud", "-P", "private", "-c", &wg_dir];
+    fireguard(&fake, dir.path(), &render).await.unwrap();
+    let rendered = fs::read_to_string(Path::new(&wg_dir).join("avalon.conf")).unwrap();
+    assert!(rendered.contains(",192.168.1.0/24\n"));
+    assert!(!rendered.contains("FwMark"));
+    assert!(rendered.contains("Endpoint = [2001:db8::1]:6666\n"));
+    let exit = [&render[..], &["-x", "dave-router"]].concat();
+    fireguard(&fake, dir.path(), &exit).await.unwrap();
+    let rendered = fs::read_to_string(Path::new(&wg_dir).join("avalon.conf")).unwrap();
+    assert!(rendered.contains("# Sending the internet traffic through the exit node dave-router\n"));
+    assert!(rendered.contains("FwMark = 51820\n"));
+    assert!(rendered.contains(",192.168.1.0/24,0.0.0.0/0\n"));
+    let error = fireguard(&fake, dir.path(), &[&render[..], &["-x", "bob-cloud"]].concat()).await.unwrap_err();
+    assert_eq!(exit_code(&error), EXIT_CONFIG);
+}